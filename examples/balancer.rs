@@ -0,0 +1,138 @@
+//! A layer 4 load balancer example
+//!
+//! Balances the TCP connections to a virtual address on one device over backends attached to
+//! another. By default the connections are translated to the inside address of the balancer,
+//! with `--dsr` the backends must accept the virtual address and reply to the clients directly.
+//! ARP requests for both addresses of the balancer are answered.
+//!
+//! Call example:
+//!
+//! * `balancer '0000:01:00.0' 203.0.113.10:80 '0000:02:00.0' 10.0.0.1 52:54:00:12:34:56
+//!   10.0.0.11:8080@52:54:00:00:00:11 10.0.0.12:8080@52:54:00:00:00:12`
+//!
+//! The fifth argument is the address of the upstream router, it is not resolved with ARP. The
+//! link-layer addresses of the backends are given with them.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use ethox::wire::PayloadMut;
+
+use ixy_net::{Backend, Balancer, BalancerMode, Control, Phy, Poller};
+use ixy::ixy_init;
+
+const PROTO_TCP: u8 = 6;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+    let dsr = args.iter().any(|arg| arg == "--dsr");
+    args.retain(|arg| arg != "--dsr");
+    if args.len() < 7 {
+        eprintln!("Usage: {} [--dsr] <outside pci> <vip:port> <inside pci> <inside ip> \
+            <router mac> <backend ip:port@mac>...", args[0]);
+        std::process::exit(1);
+    }
+
+    let vip: SocketAddrV4 = args[2].parse().expect("Invalid virtual address");
+    let inside_ip: Ipv4Addr = args[4].parse().expect("Invalid inside address");
+    let router = mac(&args[5]);
+
+    let mode = if dsr { BalancerMode::Dsr } else { BalancerMode::FullNat(inside_ip) };
+    let mut balancer = Balancer::new(PROTO_TCP, vip, mode);
+    for backend in &args[6..] {
+        let mut parts = backend.splitn(2, '@');
+        let addr = parts.next().unwrap().parse().expect("Invalid backend address");
+        let mac = mac(parts.next().expect("Missing backend MAC address"));
+        balancer.add_backend(Backend { addr, mac, weight: 1 });
+    }
+
+    let mut poller = Poller::new();
+    let outside = poller.add(phy(&args[1]), 32);
+    let inside = poller.add(phy(&args[3]), 32);
+    let addresses = [*vip.ip(), inside_ip];
+
+    let mut replies = Vec::new();
+    let mut last_report = Instant::now();
+    println!("[+] Balancing {} over {} backends", vip, balancer.backends());
+
+    poller.run(|mut turn| {
+        let (index, budget) = (turn.index(), turn.budget());
+        let other = if index == outside { inside } else { outside };
+        let (from, to) = turn.with(other);
+        let (from_mac, to_mac) = (from.mac_address(), to.mac_address());
+
+        from.forward(to, budget, |packet| {
+            let frame = packet.payload_mut().as_mut_slice();
+            if let Some(reply) = arp_reply(frame, from_mac, addresses[index]) {
+                replies.push(reply);
+                return false;
+            }
+
+            if index == outside {
+                if balancer.forward(frame).is_none() {
+                    return false;
+                }
+            } else {
+                // With direct server return the backends reply on their own.
+                if dsr || !balancer.reply(frame) {
+                    return false;
+                }
+                frame[0..6].copy_from_slice(&router);
+            }
+            frame[6..12].copy_from_slice(&to_mac);
+            true
+        });
+
+        for reply in replies.drain(..) {
+            from.send_frame(&reply);
+        }
+
+        if last_report.elapsed() >= Duration::from_secs(5) {
+            last_report = Instant::now();
+            println!("[+] {} connections, {:?}", balancer.len(), balancer.stats());
+        }
+        Control::Continue
+    });
+}
+
+fn phy(pci: &str) -> Phy<Box<dyn ixy::IxyDevice>> {
+    let ixy = ixy_init(pci, 1, 1)
+        .expect("Couldn't initialize ixy device");
+    let phy = Phy::builder(ixy).build();
+    let link = phy.wait_for_link(Duration::from_secs(10))
+        .expect("Link did not come up");
+    println!("[+] Link of {} up at {} Mbit/s", pci, link.speed);
+    phy
+}
+
+/// The reply to an ARP request for `ip`, answering with `mac`, padded to the minimum length.
+fn arp_reply(frame: &[u8], mac: [u8; 6], ip: Ipv4Addr) -> Option<[u8; 60]> {
+    let request = frame.len() >= 42
+        && frame[12..14] == [0x08, 0x06]
+        && frame[14..22] == [0, 1, 0x08, 0x00, 6, 4, 0, 1]
+        && frame[38..42] == ip.octets();
+    if !request {
+        return None;
+    }
+
+    let mut reply = [0; 60];
+    reply[0..6].copy_from_slice(&frame[6..12]);
+    reply[6..12].copy_from_slice(&mac);
+    reply[12..21].copy_from_slice(&frame[12..21]);
+    // Operation reply.
+    reply[21] = 2;
+    reply[22..28].copy_from_slice(&mac);
+    reply[28..32].copy_from_slice(&ip.octets());
+    reply[32..42].copy_from_slice(&frame[22..32]);
+    Some(reply)
+}
+
+fn mac(address: &str) -> [u8; 6] {
+    let octets = address
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).expect("Invalid MAC address"))
+        .collect::<Vec<_>>();
+    assert_eq!(octets.len(), 6, "Invalid MAC address");
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&octets);
+    mac
+}
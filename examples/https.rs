@@ -0,0 +1,98 @@
+//! An https client example
+//!
+//! Fetches a single page over TLS with the blocking sockets of the `net` module and prints the
+//! response. Requires the `tls` feature.
+//!
+//! Call example:
+//!
+//! * `https '0000:01:00.0' 10.0.0.1/24 10.0.0.254 93.184.215.14 example.com /`
+//!
+//! The address of the server is given directly, there is no resolver on the stack.
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, RootCertStore};
+use rustls::pki_types::ServerName;
+use smoltcp::iface::{EthernetInterfaceBuilder, NeighborCache, Routes};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
+
+use ixy_net::Phy;
+use ixy_net::net::TcpStream;
+use ixy_net::sockets::Stack;
+use ixy_net::tls::TlsStream;
+use ixy::ixy_init;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 7 {
+        eprintln!("Usage: {} <pci> <host/prefix> <gateway> <server> <name> <path>", args[0]);
+        std::process::exit(1);
+    }
+
+    let (host, prefix) = args[2].split_at(args[2].find('/').expect("Host without prefix"));
+    let host = ipv4(host);
+    let prefix = prefix[1..].parse().expect("Invalid prefix length");
+    let gateway = ipv4(&args[3]);
+    let server: Ipv4Addr = args[4].parse().expect("Invalid server address");
+    let (host_name, path) = (&args[5], &args[6]);
+
+    let ixy = ixy_init(&args[1], 1, 1)
+        .expect("Couldn't initialize ixy device");
+    let mut interface = Phy::builder(ixy).build();
+    let link = interface.wait_for_link(Duration::from_secs(10))
+        .expect("Link did not come up");
+    println!("[+] Link up at {} Mbit/s", link.speed);
+    let mac = EthernetAddress(interface.mac_address());
+
+    let mut routes = Routes::new(BTreeMap::new());
+    routes.add_default_ipv4_route(gateway)
+        .expect("Couldn't add the default route");
+    let iface = EthernetInterfaceBuilder::new(interface)
+        .ethernet_addr(mac)
+        .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+        .ip_addrs(vec![IpCidr::new(IpAddress::Ipv4(host), prefix)])
+        .routes(routes)
+        .finalize();
+    let stack = Stack::new(iface);
+
+    let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(host_name.clone())
+        .expect("Invalid server name");
+
+    let mut tcp = TcpStream::connect(&stack, (server, 443))
+        .expect("Couldn't connect");
+    tcp.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    println!("[+] Connected to {}", tcp.peer_addr().unwrap());
+
+    let mut tls = TlsStream::connect(tcp, Arc::new(config), name)
+        .expect("TLS handshake failed");
+    println!("[+] Handshake done, requesting {}", path);
+
+    write!(tls, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, host_name)
+        .expect("Couldn't send the request");
+    tls.flush().expect("Couldn't send the request");
+
+    let mut response = Vec::new();
+    match tls.read_to_end(&mut response) {
+        Ok(_) => (),
+        // Many servers close the connection without a close notification.
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+        Err(err) => panic!("Couldn't read the response: {}", err),
+    }
+    let _ = tls.close();
+
+    println!("{}", String::from_utf8_lossy(&response));
+    stack.with_phy(|phy| phy.shutdown(Duration::from_secs(1)));
+    println!("[+] Done");
+}
+
+fn ipv4(address: &str) -> Ipv4Address {
+    let address: Ipv4Addr = address.parse().expect("Invalid IPv4 address");
+    Ipv4Address::from_bytes(&address.octets())
+}
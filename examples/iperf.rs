@@ -6,9 +6,14 @@
 //! Prepend the ixy/ethox configuration to the usual iperf options. Call example:
 //!
 //! * `iperf3 '0000:01:00.0' 10.0.0.1/24 ab:ff:ff:ff:ff:ff 10.0.0.2/24 -c 10.0.0.2 5001 -n 10000 -l 1470 --udp`
+//!
+//! The host mac argument is ignored, the address programmed into the NIC is used instead.
+
+use std::time::Duration;
 
 use ethox::managed::{List, Slice};
 use ethox::layer::{eth, ip};
+use ethox::wire::ethernet;
 
 use ethox_iperf::{config, iperf2};
 use ixy_net::Phy;
@@ -19,10 +24,19 @@ fn main() {
 
     let ixy = ixy_init(&config.tap, 1, 1)
         .expect("Couldn't initialize ixy device");
-    let pool = ixy.recv_pool(0).unwrap().clone();
-    let mut interface = Phy::new(ixy, pool);
+    // Keep sending from starving the refill of the receive ring.
+    let mut interface = Phy::builder(ixy)
+        .dedicated_tx_pool(512)
+        .expect("Couldn't allocate tx pool")
+        .build();
 
-    let mut eth = eth::Endpoint::new(config.hostmac);
+    let link = interface.wait_for_link(Duration::from_secs(10))
+        .expect("Link did not come up");
+    println!("[+] Link up at {} Mbit/s", link.speed);
+
+    let hostmac = ethernet::Address(interface.mac_address());
+    println!("[+] Using device address {}", hostmac);
+    let mut eth = eth::Endpoint::new(hostmac);
 
     let mut neighbors = [eth::Neighbor::default(); 1];
     let mut routes = [ip::Route::new_ipv4_gateway(config.gateway.address()); 1];
@@ -70,6 +84,12 @@ fn main() {
         _ => unimplemented!("Tcp server is not yet implemented!"),
     };
 
+    // Don't lose the final packets of the run.
+    let drained = interface.shutdown(Duration::from_secs(1));
+    if drained.discarded > 0 {
+        println!("[!] Discarded {} packets on shutdown", drained.discarded);
+    }
+
     println!("[+] Done\n");
     println!("{}", result);
 }
@@ -0,0 +1,143 @@
+//! A NAT gateway example
+//!
+//! Forwards between an internal network on one device and an upstream router on another, with the
+//! internal hosts translated to the address of the outside. ARP requests for both addresses of the
+//! gateway are answered, the addresses of internal hosts are learned from their packets.
+//!
+//! Call example:
+//!
+//! * `nat '0000:01:00.0' 192.168.0.1 '0000:02:00.0' 203.0.113.2 52:54:00:12:34:56`
+//!
+//! The last argument is the address of the upstream router, it is not resolved with ARP.
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use ethox::wire::PayloadMut;
+
+use ixy_net::{Control, Nat, Phy, Poller};
+use ixy::ixy_init;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 6 {
+        eprintln!("Usage: {} <inside pci> <inside ip> <outside pci> <outside ip> <router mac>",
+            args[0]);
+        std::process::exit(1);
+    }
+
+    let inside_ip: Ipv4Addr = args[2].parse().expect("Invalid inside address");
+    let outside_ip: Ipv4Addr = args[4].parse().expect("Invalid outside address");
+    let router = mac(&args[5]);
+
+    let mut poller = Poller::new();
+    let inside = poller.add(phy(&args[1]), 32);
+    let outside = poller.add(phy(&args[3]), 32);
+    let addresses = [inside_ip, outside_ip];
+
+    let mut nat = Nat::new(outside_ip);
+    let mut hosts = HashMap::new();
+    let mut replies = Vec::new();
+    let mut last_report = Instant::now();
+    println!("[+] Translating to {}", outside_ip);
+
+    poller.run(|mut turn| {
+        let (index, budget) = (turn.index(), turn.budget());
+        let other = if index == inside { outside } else { inside };
+        let (from, to) = turn.with(other);
+        let (from_mac, to_mac) = (from.mac_address(), to.mac_address());
+
+        from.forward(to, budget, |packet| {
+            let frame = packet.payload_mut().as_mut_slice();
+            if let Some(reply) = arp_reply(frame, from_mac, addresses[index]) {
+                replies.push(reply);
+                return false;
+            }
+
+            if index == inside {
+                if is_ipv4(frame) {
+                    hosts.insert(ipv4(&frame[26..30]), mac_at(frame, 6));
+                }
+                if !nat.outbound(frame) {
+                    return false;
+                }
+                frame[0..6].copy_from_slice(&router);
+            } else {
+                if !nat.inbound(frame) {
+                    return false;
+                }
+                match hosts.get(&ipv4(&frame[30..34])) {
+                    Some(host) => frame[0..6].copy_from_slice(host),
+                    None => return false,
+                }
+            }
+            frame[6..12].copy_from_slice(&to_mac);
+            true
+        });
+
+        for reply in replies.drain(..) {
+            from.send_frame(&reply);
+        }
+
+        if last_report.elapsed() >= Duration::from_secs(5) {
+            last_report = Instant::now();
+            println!("[+] {} translations, {:?}", nat.len(), nat.stats());
+        }
+        Control::Continue
+    });
+}
+
+fn phy(pci: &str) -> Phy<Box<dyn ixy::IxyDevice>> {
+    let ixy = ixy_init(pci, 1, 1)
+        .expect("Couldn't initialize ixy device");
+    let phy = Phy::builder(ixy).build();
+    let link = phy.wait_for_link(Duration::from_secs(10))
+        .expect("Link did not come up");
+    println!("[+] Link of {} up at {} Mbit/s", pci, link.speed);
+    phy
+}
+
+/// The reply to an ARP request for `ip`, answering with `mac`, padded to the minimum length.
+fn arp_reply(frame: &[u8], mac: [u8; 6], ip: Ipv4Addr) -> Option<[u8; 60]> {
+    let request = frame.len() >= 42
+        && frame[12..14] == [0x08, 0x06]
+        && frame[14..22] == [0, 1, 0x08, 0x00, 6, 4, 0, 1]
+        && frame[38..42] == ip.octets();
+    if !request {
+        return None;
+    }
+
+    let mut reply = [0; 60];
+    reply[0..6].copy_from_slice(&frame[6..12]);
+    reply[6..12].copy_from_slice(&mac);
+    reply[12..21].copy_from_slice(&frame[12..21]);
+    // Operation reply.
+    reply[21] = 2;
+    reply[22..28].copy_from_slice(&mac);
+    reply[28..32].copy_from_slice(&ip.octets());
+    reply[32..42].copy_from_slice(&frame[22..32]);
+    Some(reply)
+}
+
+fn is_ipv4(frame: &[u8]) -> bool {
+    frame.len() >= 34 && frame[12..14] == [0x08, 0x00]
+}
+
+fn ipv4(octets: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])
+}
+
+fn mac_at(frame: &[u8], offset: usize) -> [u8; 6] {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&frame[offset..offset + 6]);
+    mac
+}
+
+fn mac(address: &str) -> [u8; 6] {
+    let octets = address
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).expect("Invalid MAC address"))
+        .collect::<Vec<_>>();
+    assert_eq!(octets.len(), 6, "Invalid MAC address");
+    mac_at(&octets, 0)
+}
@@ -0,0 +1,356 @@
+//! A packet classifier compiling match rules into per-field bit vectors.
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::{read_u16, write_u16};
+use crate::flow::FiveTuple;
+use crate::frame::{self, Headers, ETHERTYPE_QINQ, ETHERTYPE_VLAN};
+use crate::offload::{insert_tag, strip_tag};
+use crate::{Lpm, Prefix};
+
+/// Matches IP packets by their interface, VLAN and flow, `None` fields match everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AclMatch {
+    /// The index of the interface as passed to `Acl::classify`, chosen by its user.
+    pub interface: Option<usize>,
+
+    /// The VLAN identifier of the outermost tag in the frame.
+    pub vlan: Option<u16>,
+    pub src: Option<Prefix>,
+    pub dst: Option<Prefix>,
+
+    /// The first and last source port.
+    pub src_ports: Option<(u16, u16)>,
+
+    /// The first and last destination port.
+    pub dst_ports: Option<(u16, u16)>,
+    pub protocol: Option<u8>,
+}
+
+/// Common actions of a classifier, see `Acl::apply`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AclAction {
+    /// Pass the packet unchanged.
+    Accept,
+
+    /// Discard the packet.
+    Drop,
+
+    /// Set the DSCP of the packet and pass it.
+    Mark(u8),
+
+    /// Hand the packet to the queue or device with this index, chosen by the user.
+    Redirect(usize),
+
+    /// Rewrite the VLAN identifier of the outermost tag, keeping its priority, and pass the
+    /// packet. Untagged packets pass unchanged.
+    SetVlan(u16),
+
+    /// Push an 802.1ad service tag with this VLAN identifier outside of all tags and pass the
+    /// packet, as a provider bridge does on entry.
+    PushVlan(u16),
+
+    /// Remove the outermost tag and pass the packet, as a provider bridge does on exit.
+    PopVlan,
+}
+
+/// A rule of an `Acl`.
+///
+/// A rule without an action only counts the packets it matches, classification continues with
+/// the next rule.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AclRule<A> {
+    pub matches: AclMatch,
+    pub action: Option<A>,
+}
+
+/// Counters of a rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct AclStats {
+    pub packets: u64,
+
+    /// The bytes of the matched frames, including the Ethernet header.
+    pub bytes: u64,
+}
+
+/// Classifies IP packets by the first of a list of rules which matches them.
+///
+/// The rules are compiled into a bit vector of the matching rules for every distinct value of
+/// each field: exact values for interfaces, VLANs and protocols, the longest prefix for addresses
+/// and the elementary interval for ports. Classifying a packet looks up each field once and
+/// intersects the vectors, the first set bit is the first matching rule. The time depends on the
+/// number of rules only through the length of the vectors, one word per 64 rules.
+///
+/// The actions are up to the user, `AclAction` are the common ones. The rules are fixed once
+/// compiled, a changed list is compiled into a new classifier.
+pub struct Acl<A> {
+    rules: Vec<AclRule<A>>,
+    stats: Vec<AclStats>,
+    words: usize,
+    interfaces: Exact<usize>,
+    vlans: Exact<u16>,
+    protocols: Exact<u8>,
+    src: Prefixes,
+    dst: Prefixes,
+    src_ports: Ranges,
+    dst_ports: Ranges,
+}
+
+/// The rules of a field matching exact values.
+struct Exact<K> {
+    values: HashMap<K, Vec<u64>>,
+    any: Vec<u64>,
+}
+
+/// The rules of a field matching address prefixes.
+///
+/// The rules of a prefix are also those of all prefixes containing it, so the longest matching
+/// prefix has all rules matching an address.
+struct Prefixes {
+    prefixes: Lpm<Vec<u64>>,
+    any: Vec<u64>,
+}
+
+/// The rules of a field matching port ranges, by the first port of each elementary interval.
+struct Ranges {
+    starts: Vec<u16>,
+    rules: Vec<Vec<u64>>,
+}
+
+impl<A> Acl<A> {
+    /// Compile a list of rules, earlier rules take precedence.
+    pub fn new(rules: Vec<AclRule<A>>) -> Self {
+        let words = (rules.len() + 63) / 64;
+        let matches = rules.iter().map(|rule| rule.matches).collect::<Vec<_>>();
+        Acl {
+            stats: vec![AclStats::default(); rules.len()],
+            words,
+            interfaces: Exact::new(words, matches.iter().map(|matches| matches.interface)),
+            vlans: Exact::new(words, matches.iter().map(|matches| matches.vlan)),
+            protocols: Exact::new(words, matches.iter().map(|matches| matches.protocol)),
+            src: Prefixes::new(words, matches.iter().map(|matches| matches.src)),
+            dst: Prefixes::new(words, matches.iter().map(|matches| matches.dst)),
+            src_ports: Ranges::new(words, matches.iter().map(|matches| matches.src_ports)),
+            dst_ports: Ranges::new(words, matches.iter().map(|matches| matches.dst_ports)),
+            rules,
+        }
+    }
+
+    pub fn rules(&self) -> &[AclRule<A>] {
+        &self.rules
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The counters of the rule at an index.
+    ///
+    /// ## Panics
+    /// This function panics if there is no rule at `index`.
+    pub fn stats(&self, index: usize) -> AclStats {
+        self.stats[index]
+    }
+
+    pub fn clear_stats(&mut self) {
+        self.stats.iter_mut().for_each(|stats| *stats = AclStats::default());
+    }
+
+    /// The action for a frame received on an interface.
+    ///
+    /// Returns `None` if the frame is not an IP packet or no rule with an action matches.
+    pub fn classify(&mut self, interface: usize, frame: &[u8]) -> Option<&A> {
+        let headers = Headers::parse(frame)?;
+        let flow = FiveTuple::from_headers(frame, &headers)?;
+        self.classify_flow(interface, headers.vlan, &flow, frame.len())
+    }
+
+    /// The action for a packet of a flow, counting `bytes` for the matching rules.
+    pub fn classify_flow(
+        &mut self,
+        interface: usize,
+        vlan: Option<u16>,
+        flow: &FiveTuple,
+        bytes: usize,
+    ) -> Option<&A> {
+        let fields = [
+            self.interfaces.get(Some(interface)),
+            self.vlans.get(vlan),
+            self.protocols.get(Some(flow.protocol)),
+            self.src.get(flow.src),
+            self.dst.get(flow.dst),
+            self.src_ports.get(flow.src_port),
+            self.dst_ports.get(flow.dst_port),
+        ];
+
+        for word in 0..self.words {
+            let mut matching = fields.iter().fold(!0, |matching, field| matching & field[word]);
+            while matching != 0 {
+                let index = word * 64 + matching.trailing_zeros() as usize;
+                matching &= matching - 1;
+                let stats = &mut self.stats[index];
+                stats.packets += 1;
+                stats.bytes += bytes as u64;
+                if let Some(action) = &self.rules[index].action {
+                    return Some(action);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Acl<AclAction> {
+    /// Classify a frame and apply a `Mark` to it in place.
+    ///
+    /// Returns the action to complete with the frame, e.g. dropping or redirecting it. Actions on
+    /// the tags change the length of the frame and are left to `apply_packet`.
+    pub fn apply(&mut self, interface: usize, frame: &mut [u8]) -> Option<AclAction> {
+        let action = *self.classify(interface, frame)?;
+        if let AclAction::Mark(dscp) = action {
+            frame::set_dscp(frame, dscp);
+        }
+        Some(action)
+    }
+
+    /// Classify a packet and apply a `Mark` or an action on its tags to it.
+    ///
+    /// Returns the action to complete with the packet, which is `Drop` if a tag did not fit.
+    pub fn apply_packet(&mut self, interface: usize, packet: &mut IxyPacket)
+        -> Option<AclAction>
+    {
+        let action = *self.classify(interface, &packet[..])?;
+        match action {
+            AclAction::Mark(dscp) => {
+                frame::set_dscp(packet.as_mut(), dscp);
+            },
+            AclAction::SetVlan(vid) => {
+                let tagged = packet.len() >= 18
+                    && [ETHERTYPE_VLAN, ETHERTYPE_QINQ].contains(&read_u16(&packet[..], 12));
+                if tagged {
+                    let tci = read_u16(&packet[..], 14) & 0xf000 | vid & 0x0fff;
+                    write_u16(packet.as_mut(), 14, tci);
+                }
+            },
+            AclAction::PushVlan(vid) => {
+                if !insert_tag(packet, ETHERTYPE_QINQ, vid & 0x0fff) {
+                    return Some(AclAction::Drop);
+                }
+            },
+            AclAction::PopVlan => {
+                strip_tag(packet);
+            },
+            _ => (),
+        }
+        Some(action)
+    }
+}
+
+impl<K: Copy + Eq + Hash> Exact<K> {
+    fn new(words: usize, fields: impl Iterator<Item=Option<K>>) -> Self {
+        let mut any = vec![0; words];
+        let mut values: HashMap<K, Vec<u64>> = HashMap::new();
+        for (index, field) in fields.enumerate() {
+            match field {
+                Some(value) => set(values.entry(value).or_insert_with(|| vec![0; words]), index),
+                None => set(&mut any, index),
+            }
+        }
+
+        for rules in values.values_mut() {
+            union(rules, &any);
+        }
+        Exact { values, any }
+    }
+
+    fn get(&self, key: Option<K>) -> &[u64] {
+        key.and_then(|key| self.values.get(&key)).unwrap_or(&self.any)
+    }
+}
+
+impl Prefixes {
+    fn new(words: usize, fields: impl Iterator<Item=Option<Prefix>>) -> Self {
+        let fields = fields.collect::<Vec<_>>();
+        let mut any = vec![0; words];
+        for (index, field) in fields.iter().enumerate() {
+            if field.is_none() {
+                set(&mut any, index);
+            }
+        }
+
+        let mut prefixes = Lpm::new();
+        for prefix in fields.iter().flatten() {
+            if prefixes.get(*prefix).is_some() {
+                continue;
+            }
+            let mut rules = any.clone();
+            for (index, field) in fields.iter().enumerate() {
+                let contains = field.map_or(false, |other| {
+                    other.prefix_len() <= prefix.prefix_len() && other.contains(prefix.addr())
+                });
+                if contains {
+                    set(&mut rules, index);
+                }
+            }
+            prefixes.insert(*prefix, rules);
+        }
+        Prefixes { prefixes, any }
+    }
+
+    fn get(&self, addr: IpAddr) -> &[u64] {
+        self.prefixes.lookup(addr).unwrap_or(&self.any)
+    }
+}
+
+impl Ranges {
+    fn new(words: usize, fields: impl Iterator<Item=Option<(u16, u16)>>) -> Self {
+        let fields = fields.collect::<Vec<_>>();
+        let mut starts = vec![0];
+        for &(first, last) in fields.iter().flatten() {
+            starts.push(first);
+            if let Some(after) = last.checked_add(1) {
+                starts.push(after);
+            }
+        }
+        starts.sort_unstable();
+        starts.dedup();
+
+        let rules = starts
+            .iter()
+            .map(|&start| {
+                let mut rules = vec![0; words];
+                for (index, field) in fields.iter().enumerate() {
+                    if field.map_or(true, |(first, last)| first <= start && start <= last) {
+                        set(&mut rules, index);
+                    }
+                }
+                rules
+            })
+            .collect();
+        Ranges { starts, rules }
+    }
+
+    fn get(&self, port: u16) -> &[u64] {
+        // The first interval starts at zero, so there is always one.
+        let interval = match self.starts.binary_search(&port) {
+            Ok(interval) => interval,
+            Err(after) => after - 1,
+        };
+        &self.rules[interval]
+    }
+}
+
+fn set(bits: &mut [u64], index: usize) {
+    bits[index / 64] |= 1 << (index % 64);
+}
+
+fn union(bits: &mut [u64], other: &[u64]) {
+    bits.iter_mut().zip(other).for_each(|(bits, other)| *bits |= other);
+}
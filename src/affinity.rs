@@ -0,0 +1,161 @@
+//! Pinning polling threads to cores, and the NUMA topology they run on.
+//!
+//! Reads the topology from sysfs, so these only work on Linux.
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::mem;
+
+/// A logical CPU and its place in the topology.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cpu {
+    /// The index of the logical CPU, as used for pinning.
+    pub id: usize,
+
+    /// The physical core within the package, shared by hyperthread siblings.
+    pub core: u32,
+
+    /// The physical package, i.e. the socket.
+    pub package: u32,
+
+    /// The NUMA node, if the kernel reports one.
+    pub node: Option<u32>,
+}
+
+/// Pin the calling thread to a single logical CPU.
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    // Safety: the set is a plain bitmask, zeroed is empty.
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    let result = unsafe {
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// All online logical CPUs.
+pub fn cpus() -> io::Result<Vec<Cpu>> {
+    let online = fs::read_to_string("/sys/devices/system/cpu/online")?;
+    parse_list(&online)?
+        .into_iter()
+        .map(|id| {
+            let base = format!("/sys/devices/system/cpu/cpu{}", id);
+            Ok(Cpu {
+                id,
+                core: read_number(&format!("{}/topology/core_id", base))?,
+                package: read_number(&format!("{}/topology/physical_package_id", base))?,
+                node: cpu_node(&base)?,
+            })
+        })
+        .collect()
+}
+
+/// The NUMA node of a PCI device, given its address such as `0000:01:00.0`.
+///
+/// `None` if the system does not report one, e.g. with a single node.
+pub fn numa_node(pci_addr: &str) -> io::Result<Option<u32>> {
+    let path = format!("/sys/bus/pci/devices/{}/numa_node", pci_addr);
+    let node = fs::read_to_string(path)?;
+    let node: i64 = node.trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid NUMA node"))?;
+    Ok(if node < 0 { None } else { Some(node as u32) })
+}
+
+/// The NUMA node of the CPU the calling thread currently runs on.
+pub fn current_node() -> io::Result<Option<u32>> {
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    cpu_node(&format!("/sys/devices/system/cpu/cpu{}", cpu))
+}
+
+/// Restrict memory allocations of the calling thread to a node, or lift the restriction.
+///
+/// Affects pages faulted in afterwards, such as the hugepages of a new mempool.
+pub(crate) fn bind_memory(node: Option<u32>) -> io::Result<()> {
+    const MPOL_DEFAULT: libc::c_long = 0;
+    const MPOL_BIND: libc::c_long = 2;
+
+    let (mode, mask) = match node {
+        Some(node) if node < 64 => (MPOL_BIND, 1u64 << node),
+        Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported node")),
+        None => (MPOL_DEFAULT, 0),
+    };
+    let mask_ptr = if node.is_some() { &mask as *const u64 } else { std::ptr::null() };
+
+    // Safety: the mask outlives the call and has the bits announced by `maxnode`.
+    let result = unsafe {
+        libc::syscall(libc::SYS_set_mempolicy, mode, mask_ptr, 65 as libc::c_ulong)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Select logical CPUs on `count` distinct physical cores for queue threads.
+///
+/// Cores on `node` are preferred, e.g. the node of the NIC. The first core of the system is
+/// skipped if possible as it usually services housekeeping and interrupts. Returns fewer CPUs if
+/// there are not enough physical cores.
+pub fn allocate(count: usize, node: Option<u32>) -> io::Result<Vec<usize>> {
+    let mut cpus = cpus()?;
+    // Sort by preference: the node first, then the first core last.
+    let first = cpus.iter().map(|cpu| (cpu.package, cpu.core)).min();
+    cpus.sort_by_key(|cpu| {
+        let remote = node.is_some() && cpu.node != node;
+        let housekeeping = Some((cpu.package, cpu.core)) == first;
+        (remote, housekeeping, cpu.id)
+    });
+
+    let mut used = HashSet::new();
+    Ok(cpus.into_iter()
+        .filter(|cpu| used.insert((cpu.package, cpu.core)))
+        .map(|cpu| cpu.id)
+        .take(count)
+        .collect())
+}
+
+fn cpu_node(base: &str) -> io::Result<Option<u32>> {
+    for entry in fs::read_dir(base)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("node") {
+            if let Ok(node) = name["node".len()..].parse() {
+                return Ok(Some(node));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn read_number(path: &str) -> io::Result<u32> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid number in {}", path)))
+}
+
+/// Parse a kernel cpu list such as `0-3,8-11`.
+fn parse_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid cpu list");
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let start: usize = bounds.next().unwrap().parse().map_err(|_| invalid())?;
+        let end = match bounds.next() {
+            Some(end) => end.parse().map_err(|_| invalid())?,
+            None => start,
+        };
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
@@ -0,0 +1,166 @@
+//! Announcing the addresses of an interface with gratuitous ARP and unsolicited NA.
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::frame::{gratuitous_arp, unsolicited_na};
+use crate::{Phy, Queues};
+
+/// Announces the IP addresses of an interface so that peers and switches update their caches.
+///
+/// An announcement sends a gratuitous ARP for an IPv4 address or an unsolicited neighbor
+/// advertisement to all nodes for an IPv6 address, repeated `count` times an `interval` apart in
+/// case one is lost. The addresses are announced once the announcer is created, i.e. on startup,
+/// and an address again whenever it is added. Call `announce` after anything else that moves the
+/// addresses, e.g. a new MAC address or a failover, as `Bonded` does.
+///
+/// The frames carry the MAC address of the phy passed to `poll`, which sends the due
+/// announcements. Call it regularly, e.g. in each loop iteration, it does nothing while no
+/// announcement is left.
+pub struct Announcer {
+    addresses: Vec<IpAddr>,
+    count: u32,
+    interval: Duration,
+    pending: Vec<Pending>,
+    sent: u64,
+}
+
+/// An address with announcements left.
+struct Pending {
+    address: IpAddr,
+    left: u32,
+    due: Instant,
+}
+
+impl Announcer {
+    /// The default number of announcements of an address.
+    pub const COUNT: u32 = 3;
+
+    /// The default interval between announcements of an address.
+    pub const INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Announce the addresses of an interface, starting with the next poll.
+    pub fn new(addresses: Vec<IpAddr>) -> Self {
+        let mut announcer = Announcer {
+            addresses: Vec::new(),
+            count: Self::COUNT,
+            interval: Self::INTERVAL,
+            pending: Vec::new(),
+            sent: 0,
+        };
+        for address in addresses {
+            announcer.add_address(address);
+        }
+        announcer
+    }
+
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    /// Add an address and announce it.
+    pub fn add_address(&mut self, address: IpAddr) {
+        if !self.addresses.contains(&address) {
+            self.addresses.push(address);
+            self.schedule(address);
+        }
+    }
+
+    /// Stop announcing an address.
+    pub fn remove_address(&mut self, address: IpAddr) {
+        self.addresses.retain(|&other| other != address);
+        self.pending.retain(|pending| pending.address != address);
+    }
+
+    /// Change the addresses, announcing those which are new.
+    pub fn set_addresses(&mut self, addresses: Vec<IpAddr>) {
+        self.pending.retain(|pending| addresses.contains(&pending.address));
+        let old = std::mem::replace(&mut self.addresses, Vec::new());
+        for address in addresses {
+            if self.addresses.contains(&address) {
+                continue;
+            }
+            self.addresses.push(address);
+            if !old.contains(&address) {
+                self.schedule(address);
+            }
+        }
+    }
+
+    /// Announce all addresses again, restarting their repetitions.
+    pub fn announce(&mut self) {
+        for index in 0..self.addresses.len() {
+            self.schedule(self.addresses[index]);
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Send `count` announcements of an address, starting with the next one.
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Check if announcements are left to send.
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The number of frames sent so far.
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// Send the due announcements out of a phy and flush it, returning their number.
+    ///
+    /// An announcement for which the pool had no free buffer is retried with the next poll.
+    pub fn poll<D: Queues>(&mut self, phy: &mut Phy<D>) -> usize {
+        if self.pending.is_empty() {
+            return 0;
+        }
+
+        let now = Instant::now();
+        let mac = phy.mac_address();
+        let mut sent = 0;
+        for pending in self.pending.iter_mut().filter(|pending| pending.due <= now) {
+            let queued = match pending.address {
+                IpAddr::V4(address) => phy.send_frame(&gratuitous_arp(mac, address)),
+                IpAddr::V6(address) => phy.send_frame(&unsolicited_na(mac, address)),
+            };
+            if queued {
+                pending.left -= 1;
+                pending.due = now + self.interval;
+                sent += 1;
+            }
+        }
+
+        self.pending.retain(|pending| pending.left > 0);
+        if sent > 0 {
+            phy.flush();
+        }
+        self.sent += sent as u64;
+        sent
+    }
+
+    fn schedule(&mut self, address: IpAddr) {
+        self.pending.retain(|pending| pending.address != address);
+        if self.count > 0 {
+            self.pending.push(Pending { address, left: self.count, due: Instant::now() });
+        }
+    }
+}
+
+impl Default for Announcer {
+    fn default() -> Self {
+        Announcer::new(Vec::new())
+    }
+}
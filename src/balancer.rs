@@ -0,0 +1,420 @@
+//! Layer 4 load balancing of an IPv4 service over backends.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::flow::FiveTuple;
+use crate::frame::{PROTO_TCP, PROTO_UDP};
+use crate::nat::{self, Flow, Parsed};
+use crate::rss::flow_hash;
+use crate::{Conntrack, ConntrackStats, Maglev, ConntrackTimeouts};
+
+/// How packets reach the backends of a balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BalancerMode {
+    /// Direct server return, only the destination MAC is replaced.
+    ///
+    /// The backends are attached to the balancer and accept the virtual address, e.g. on their
+    /// loopback interface, and reply to the clients directly.
+    Dsr,
+
+    /// Full NAT, the destination is replaced by the backend and the source by the address of
+    /// the balancer with a free port.
+    ///
+    /// The replies of the backends return to this address and are translated back.
+    FullNat(Ipv4Addr),
+}
+
+/// A backend of a balancer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Backend {
+    /// The address of the service on the backend, the port is ignored with direct server return.
+    pub addr: SocketAddrV4,
+
+    /// The link-layer address of the backend, or of the next hop to it.
+    pub mac: [u8; 6],
+
+    /// The share of new connections relative to the other backends.
+    pub weight: u32,
+}
+
+/// Counters of a balancer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BalancerStats {
+    /// Connections assigned to a backend.
+    pub scheduled: u64,
+
+    /// Connections moved since their backend became unhealthy.
+    pub rescheduled: u64,
+
+    /// Packets of clients sent to their backend.
+    pub forwarded: u64,
+
+    /// Replies of backends translated back to the client.
+    pub replied: u64,
+
+    /// Packets of clients dropped since no backend is healthy.
+    pub no_backend: u64,
+
+    /// Replies without a connection.
+    pub unmatched: u64,
+
+    /// Packets of new connections dropped since no source port was free.
+    pub exhausted: u64,
+
+    /// Packets which are not for the service, fragments, or whose TTL expired.
+    pub unsupported: u64,
+}
+
+/// Schedules the connections of a virtual service to healthy backends.
+///
+/// New connections are assigned by a `Maglev` table of the healthy backends, keyed by their
+/// address, from the hash of their 5-tuple. So several balancers with the same backends assign
+/// connections the same way without sharing state. Each connection is remembered, it stays on
+/// its backend when backends are added, and is only moved when its backend becomes unhealthy.
+///
+/// The connections are tracked in a `Conntrack` with the timeouts of a `Nat`, a full table
+/// evicts the least recently used connection. With full NAT the TTL is decremented and the
+/// replies are found by their translated flow. The caller sets the source MAC of forwarded
+/// packets and the Ethernet addresses of replies, see `forward` and `reply`. Expired connections
+/// are removed during these calls about once a second.
+pub struct Balancer {
+    protocol: u8,
+    vip: SocketAddrV4,
+    mode: BalancerMode,
+    backends: Vec<State>,
+    table_size: usize,
+    maglev: Maglev,
+    /// The backend of each index in the lookup table.
+    healthy: Vec<usize>,
+    ports: (u16, u16),
+    next_port: u16,
+    connections: Conntrack<Assignment>,
+    last_sweep: Instant,
+    stats: BalancerStats,
+}
+
+struct State {
+    backend: Backend,
+    healthy: bool,
+}
+
+/// The backend of a connection.
+struct Assignment {
+    backend: usize,
+    client: SocketAddrV4,
+    /// The source address of the balancer, with full NAT.
+    source: Option<SocketAddrV4>,
+}
+
+impl Balancer {
+    /// The default range of source ports with full NAT.
+    pub const PORTS: (u16, u16) = (1024, 65535);
+
+    /// The default maximum number of connections.
+    pub const CAPACITY: usize = 1 << 20;
+
+    /// How often expired connections are removed.
+    const SWEEP: Duration = Duration::from_secs(1);
+
+    /// Balance the TCP or UDP service at a virtual address, without backends.
+    ///
+    /// ## Panics
+    /// This function panics if the protocol is neither TCP nor UDP.
+    pub fn new(protocol: u8, vip: SocketAddrV4, mode: BalancerMode) -> Self {
+        assert!(protocol == PROTO_TCP || protocol == PROTO_UDP, "Only TCP and UDP are balanced");
+        let mut connections = Conntrack::new();
+        connections.set_capacity(Self::CAPACITY);
+        Balancer {
+            protocol,
+            vip,
+            mode,
+            backends: Vec::new(),
+            table_size: Maglev::SIZE,
+            maglev: Maglev::new(Maglev::SIZE, &[]),
+            healthy: Vec::new(),
+            ports: Self::PORTS,
+            next_port: Self::PORTS.0,
+            connections,
+            last_sweep: Instant::now(),
+            stats: BalancerStats::default(),
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn vip(&self) -> SocketAddrV4 {
+        self.vip
+    }
+
+    pub fn mode(&self) -> BalancerMode {
+        self.mode
+    }
+
+    /// Add a healthy backend, returning its index.
+    pub fn add_backend(&mut self, backend: Backend) -> usize {
+        self.backends.push(State { backend, healthy: true });
+        self.rebuild();
+        self.backends.len() - 1
+    }
+
+    pub fn backend(&self, index: usize) -> Backend {
+        self.backends[index].backend
+    }
+
+    /// The number of backends, including unhealthy ones.
+    pub fn backends(&self) -> usize {
+        self.backends.len()
+    }
+
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.backends[index].healthy
+    }
+
+    /// Change the health of a backend, e.g. by the result of a health check.
+    ///
+    /// Unhealthy backends get no new connections, their connections move to another backend
+    /// with their next packet.
+    pub fn set_healthy(&mut self, index: usize, healthy: bool) {
+        if self.backends[index].healthy != healthy {
+            self.backends[index].healthy = healthy;
+            self.rebuild();
+        }
+    }
+
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    /// Change the size of the lookup table, for more backends.
+    ///
+    /// ## Panics
+    /// This function panics if `size` is not a prime.
+    pub fn set_table_size(&mut self, size: usize) {
+        self.maglev = Maglev::new(size, &[]);
+        self.table_size = size;
+        self.rebuild();
+    }
+
+    /// The first and last source port with full NAT.
+    pub fn ports(&self) -> (u16, u16) {
+        self.ports
+    }
+
+    /// Use another range of source ports, for new connections.
+    ///
+    /// ## Panics
+    /// This function panics if `first` is zero or larger than `last`.
+    pub fn set_ports(&mut self, first: u16, last: u16) {
+        assert!(first > 0 && first <= last, "Invalid port range");
+        self.ports = (first, last);
+        self.next_port = first;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.connections.capacity()
+    }
+
+    /// Limit the number of connections, evicting the least recently used ones above it.
+    ///
+    /// ## Panics
+    /// This function panics if the capacity is zero.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.connections.set_capacity(capacity);
+    }
+
+    pub fn timeouts(&self) -> ConntrackTimeouts {
+        self.connections.timeouts()
+    }
+
+    /// Set the idle timeouts of connections, those of ICMP are unused.
+    pub fn set_timeouts(&mut self, timeouts: ConntrackTimeouts) {
+        self.connections.set_timeouts(timeouts);
+    }
+
+    /// The number of connections.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// The counters of the connection table, e.g. of expired connections.
+    pub fn conntrack_stats(&self) -> ConntrackStats {
+        self.connections.stats()
+    }
+
+    pub fn stats(&self) -> BalancerStats {
+        self.stats
+    }
+
+    /// Forget all connections.
+    pub fn clear(&mut self) {
+        self.connections.clear();
+    }
+
+    /// Schedule a frame of a client to the service in place.
+    ///
+    /// Returns the backend, or `None` if the frame must be dropped. The destination MAC is
+    /// already that of the backend, set the source MAC and send it.
+    pub fn forward(&mut self, frame: &mut [u8]) -> Option<usize> {
+        self.sweep();
+        let service = (self.protocol, self.vip);
+        let packet = match nat::parse(frame) {
+            Some(packet) if (packet.flow.protocol, packet.flow.dst) == service => packet,
+            _ => {
+                self.stats.unsupported += 1;
+                return None;
+            },
+        };
+
+        let now = Instant::now();
+        let flow = FiveTuple::from(packet.flow);
+        let current = self.connections
+            .update(&flow, packet.len, packet.closing, now)
+            .map(|connection| (connection.data.backend, connection.data.source));
+        let (backend, source) = match current {
+            Some((backend, source)) if self.backends[backend].healthy => (backend, source),
+            Some(_) => {
+                self.connections.remove(&flow);
+                self.stats.rescheduled += 1;
+                self.schedule(frame, &packet, now)?
+            },
+            None => self.schedule(frame, &packet, now)?,
+        };
+
+        if let Some(source) = source {
+            nat::decrement_ttl(frame, packet.l3);
+            nat::rewrite(frame, &packet, true, self.backends[backend].backend.addr);
+            nat::rewrite(frame, &packet, false, source);
+        }
+        frame[..6].copy_from_slice(&self.backends[backend].backend.mac);
+        self.stats.forwarded += 1;
+        Some(backend)
+    }
+
+    /// Translate a reply of a backend in place, with full NAT.
+    ///
+    /// Returns `false` if the frame must be dropped. Otherwise set its Ethernet addresses for
+    /// the next hop to the client and send it.
+    pub fn reply(&mut self, frame: &mut [u8]) -> bool {
+        self.sweep();
+        let packet = match nat::parse(frame) {
+            Some(packet) => packet,
+            None => {
+                self.stats.unsupported += 1;
+                return false;
+            },
+        };
+
+        let now = Instant::now();
+        let flow = FiveTuple::from(packet.flow);
+        let is_reply = self.connections.get(&flow).map_or(false, |connection| {
+            connection.reply == flow && connection.data.source.is_some()
+        });
+        let updated = if is_reply {
+            self.connections.update(&flow, packet.len, packet.closing, now)
+        } else {
+            None
+        };
+        let client = match updated {
+            Some(connection) => connection.data.client,
+            None => {
+                self.stats.unmatched += 1;
+                return false;
+            },
+        };
+
+        nat::decrement_ttl(frame, packet.l3);
+        nat::rewrite(frame, &packet, false, self.vip);
+        nat::rewrite(frame, &packet, true, client);
+        self.stats.replied += 1;
+        true
+    }
+
+    /// Remove all expired connections, returns how many.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        self.last_sweep = now;
+        self.connections.expire(now)
+    }
+
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() >= Self::SWEEP {
+            self.expire();
+        }
+    }
+
+    /// Rebuild the lookup table of the healthy backends.
+    fn rebuild(&mut self) {
+        self.healthy = (0..self.backends.len()).filter(|&i| self.backends[i].healthy).collect();
+        let keys = self.healthy
+            .iter()
+            .map(|&index| {
+                let addr = self.backends[index].backend.addr;
+                let key = u64::from(u32::from(*addr.ip())) << 16 | u64::from(addr.port());
+                (key, self.backends[index].backend.weight)
+            })
+            .collect::<Vec<_>>();
+        self.maglev = Maglev::new(self.table_size, &keys);
+    }
+
+    /// Assign a new connection to a backend, counting its first packet.
+    fn schedule(&mut self, frame: &[u8], packet: &Parsed, now: Instant)
+        -> Option<(usize, Option<SocketAddrV4>)>
+    {
+        let hash = flow_hash(frame).unwrap_or(0);
+        let backend = match self.maglev.lookup(u64::from(hash)) {
+            Some(index) => self.healthy[index],
+            None => {
+                self.stats.no_backend += 1;
+                return None;
+            },
+        };
+
+        let flow = packet.flow;
+        let original = FiveTuple::from(flow);
+        let (source, reply) = match self.mode {
+            BalancerMode::Dsr => (None, original.reversed()),
+            BalancerMode::FullNat(addr) => match self.allocate(addr, backend) {
+                Some((source, reply)) => (Some(source), reply),
+                None => {
+                    self.stats.exhausted += 1;
+                    return None;
+                },
+            },
+        };
+
+        let assignment = Assignment { backend, client: flow.src, source };
+        self.connections.insert(original, reply, assignment, now);
+        self.connections.update(&original, packet.len, packet.closing, now);
+        self.stats.scheduled += 1;
+        Some((backend, source))
+    }
+
+    /// Find a free source port towards a backend, with the flow of its replies.
+    fn allocate(&mut self, addr: Ipv4Addr, backend: usize) -> Option<(SocketAddrV4, FiveTuple)> {
+        let (first, last) = self.ports;
+        let count = u32::from(last - first) + 1;
+        for _ in 0..count {
+            let port = self.next_port;
+            self.next_port = if port >= last { first } else { port + 1 };
+
+            let source = SocketAddrV4::new(addr, port);
+            let reply = Flow {
+                protocol: self.protocol,
+                src: self.backends[backend].backend.addr,
+                dst: source,
+            };
+            let reply = FiveTuple::from(reply);
+            if self.connections.get(&reply).is_none() {
+                return Some((source, reply));
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,209 @@
+//! Active-backup bonding of two phys.
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+
+use crate::{Announcer, Handle, Packet, Phy, Queues};
+
+/// A member of a bond.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Member {
+    Primary,
+    Backup,
+}
+
+/// Two phys in active-backup mode, used as a single `nic::Device`.
+///
+/// All traffic goes through the active member, the other one is not polled. The link of the
+/// active member is checked periodically and when it is down while the other link is up, the bond
+/// fails over. Packets still queued on the old member are moved to the new one, and every
+/// announced address is announced with a gratuitous ARP or an unsolicited neighbor advertisement
+/// so that peers and switches learn the new port. The bond does not fail back on its own once the
+/// old link recovers, this avoids flapping.
+///
+/// The announcements carry the address of the active device. Either configure both devices with
+/// the same MAC address or make sure the network stack accepts frames to both.
+pub struct Bonded<D1, D2> {
+    primary: Phy<D1>,
+    backup: Phy<D2>,
+    active: Member,
+    /// The addresses announced on failover.
+    announcer: Announcer,
+    link_interval: Duration,
+    last_check: Instant,
+    failovers: u64,
+}
+
+impl<D1, D2> Bonded<D1, D2> {
+    /// The default interval between link checks.
+    pub const LINK_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Bond two phys, starting with the primary as the active member.
+    pub fn new(primary: Phy<D1>, backup: Phy<D2>) -> Self {
+        Bonded {
+            primary,
+            backup,
+            active: Member::Primary,
+            announcer: Announcer::default(),
+            link_interval: Self::LINK_INTERVAL,
+            last_check: Instant::now(),
+            failovers: 0,
+        }
+    }
+
+    /// Announce an address now and on each failover.
+    pub fn add_address(&mut self, address: IpAddr) {
+        self.announcer.add_address(address);
+    }
+
+    /// Stop announcing an address.
+    pub fn remove_address(&mut self, address: IpAddr) {
+        self.announcer.remove_address(address);
+    }
+
+    pub fn addresses(&self) -> &[IpAddr] {
+        self.announcer.addresses()
+    }
+
+    /// The announcer of the addresses, e.g. to change its repetitions.
+    pub fn announcer_mut(&mut self) -> &mut Announcer {
+        &mut self.announcer
+    }
+
+    /// The member currently carrying the traffic.
+    pub fn active(&self) -> Member {
+        self.active
+    }
+
+    /// The number of failovers so far.
+    pub fn failovers(&self) -> u64 {
+        self.failovers
+    }
+
+    pub fn link_interval(&self) -> Duration {
+        self.link_interval
+    }
+
+    /// Change how often the link of the active member is checked.
+    ///
+    /// Reading the link state accesses device registers, so it is not done on every poll.
+    pub fn set_link_interval(&mut self, interval: Duration) {
+        self.link_interval = interval;
+    }
+
+    pub fn primary(&self) -> &Phy<D1> {
+        &self.primary
+    }
+
+    pub fn primary_mut(&mut self) -> &mut Phy<D1> {
+        &mut self.primary
+    }
+
+    pub fn backup(&self) -> &Phy<D2> {
+        &self.backup
+    }
+
+    pub fn backup_mut(&mut self) -> &mut Phy<D2> {
+        &mut self.backup
+    }
+
+    /// Unbond the phys.
+    pub fn into_inner(self) -> (Phy<D1>, Phy<D2>) {
+        (self.primary, self.backup)
+    }
+}
+
+impl<D1: Queues, D2: Queues> Bonded<D1, D2> {
+    /// Make a member active, regardless of its link state.
+    ///
+    /// Performs a failover like a link loss would. Does nothing if the member is already active.
+    pub fn set_active(&mut self, member: Member) {
+        if member == self.active {
+            return;
+        }
+
+        self.active = member;
+        self.failovers += 1;
+
+        match member {
+            Member::Primary => {
+                self.primary.tx_queue.extend(self.backup.tx_queue.drain(..));
+                self.backup.tx_since = None;
+            },
+            Member::Backup => {
+                self.backup.tx_queue.extend(self.primary.tx_queue.drain(..));
+                self.primary.tx_since = None;
+            },
+        }
+
+        self.announcer.announce();
+        self.announce();
+        match member {
+            Member::Primary => self.primary.flush(),
+            Member::Backup => self.backup.flush(),
+        };
+    }
+
+    /// Send the due announcements out of the active member.
+    fn announce(&mut self) {
+        match self.active {
+            Member::Primary => self.announcer.poll(&mut self.primary),
+            Member::Backup => self.announcer.poll(&mut self.backup),
+        };
+    }
+
+    /// Fail over if the link of the active member is down and the other one is up.
+    fn monitor(&mut self) {
+        self.announce();
+        if self.last_check.elapsed() < self.link_interval {
+            return;
+        }
+        self.last_check = Instant::now();
+
+        let (active, other) = match self.active {
+            Member::Primary => (self.primary.link(), self.backup.link()),
+            Member::Backup => (self.backup.link(), self.primary.link()),
+        };
+        if !active.up && other.up {
+            let member = match self.active {
+                Member::Primary => Member::Backup,
+                Member::Backup => Member::Primary,
+            };
+            self.set_active(member);
+        }
+    }
+}
+
+impl<D1: Queues, D2: Queues> nic::Device for Bonded<D1, D2> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        match self.active {
+            Member::Primary => nic::Device::personality(&self.primary),
+            Member::Backup => nic::Device::personality(&self.backup),
+        }
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.monitor();
+        match self.active {
+            Member::Primary => nic::Device::tx(&mut self.primary, max, sender),
+            Member::Backup => nic::Device::tx(&mut self.backup, max, sender),
+        }
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.monitor();
+        match self.active {
+            Member::Primary => nic::Device::rx(&mut self.primary, max, receptor),
+            Member::Backup => nic::Device::rx(&mut self.backup, max, receptor),
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! Tokio handles to sockets served by a dedicated stack thread, enabled by the `bridge` feature.
+//!
+//! The phy, its interface and all sockets live on one thread, optionally pinned to a core, which
+//! runs the stack on a local tokio executor and polls it continuously. Applications on any tokio
+//! runtime talk to it through channels: connecting and accepting are requests to the stack
+//! thread, and the data of each connection moves through a pair of bounded channels. This copies
+//! the payload once into the channel but lets existing async code adopt userspace networking
+//! without moving into the poll loop. UDP sockets are served the same way, each datagram
+//! passing through a channel.
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+
+use smoltcp::wire::IpEndpoint;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, LocalSet};
+use tokio_util::sync::PollSender;
+
+use crate::sockets::{self, Stack};
+use crate::{Queues, affinity};
+
+/// A stack thread serving tokio handles.
+///
+/// Dropping the bridge stops the thread, closing all of its connections.
+pub struct Bridge {
+    commands: mpsc::UnboundedSender<Command>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A TCP connection served by the stack thread.
+///
+/// Shutting down the writing half closes the sending half of the connection. Reading returns
+/// the end of the stream once the peer has closed its sending half, writing continues.
+pub struct TcpStream {
+    remote: IpEndpoint,
+    incoming: mpsc::Receiver<Vec<u8>>,
+    /// Received data not yet read.
+    pending: Vec<u8>,
+    offset: usize,
+    outgoing: PollSender<Vec<u8>>,
+}
+
+/// Accepts TCP connections on a port of the stack thread.
+pub struct TcpListener {
+    port: u16,
+    accepted: mpsc::Receiver<TcpStream>,
+}
+
+/// A UDP socket served by the stack thread.
+///
+/// Datagrams which arrive while `CHANNEL` of them are waiting to be received are dropped.
+pub struct UdpSocket {
+    local: IpEndpoint,
+    pub(crate) incoming: mpsc::Receiver<(Vec<u8>, IpEndpoint)>,
+    pub(crate) outgoing: mpsc::Sender<Datagrams>,
+}
+
+/// Datagrams to one remote, sent by the stack thread in one batch.
+pub(crate) struct Datagrams {
+    pub(crate) remote: IpEndpoint,
+    pub(crate) payload: Vec<u8>,
+    /// The size of each datagram in the payload, only the last may be shorter.
+    pub(crate) segment: usize,
+}
+
+enum Command {
+    Connect {
+        remote: IpEndpoint,
+        reply: oneshot::Sender<io::Result<TcpStream>>,
+    },
+    Listen {
+        port: u16,
+        reply: oneshot::Sender<io::Result<TcpListener>>,
+    },
+    Bind {
+        local: IpEndpoint,
+        reply: oneshot::Sender<io::Result<UdpSocket>>,
+    },
+}
+
+/// The stack thread side of a connection.
+struct Connection {
+    incoming: mpsc::Sender<Vec<u8>>,
+    outgoing: mpsc::Receiver<Vec<u8>>,
+}
+
+impl Bridge {
+    /// The number of chunks buffered in each direction of a connection.
+    pub const CHANNEL: usize = 64;
+
+    /// The size of the chunks read from a connection.
+    pub const CHUNK: usize = 16 * 1024;
+
+    /// Spawn the stack thread, pinned to `cpu` if given.
+    ///
+    /// Devices can not move between threads, so `setup` creates the device, its phy and the
+    /// interface on the stack thread. Fails if the thread could not be pinned, before `setup`
+    /// is called.
+    pub fn spawn<D, F>(cpu: Option<usize>, setup: F) -> io::Result<Self>
+    where
+        D: Queues + 'static,
+        F: FnOnce() -> Stack<D> + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let (pinned, pinning) = std::sync::mpsc::sync_channel(1);
+
+        let thread = thread::Builder::new()
+            .name("ixy-stack".into())
+            .spawn(move || {
+                let pin = cpu.map_or(Ok(()), affinity::pin_current_thread);
+                let failed = pin.is_err();
+                let _ = pinned.send(pin);
+                if failed {
+                    return;
+                }
+                let stack = setup();
+                LocalSet::new().block_on(&runtime, serve(stack, receiver, stopped));
+            })?;
+
+        if let Ok(Err(err)) = pinning.recv() {
+            let _ = thread.join();
+            return Err(err);
+        }
+
+        Ok(Bridge { commands, stop, thread: Some(thread) })
+    }
+
+    /// Connect to a remote endpoint.
+    pub async fn connect(&self, remote: IpEndpoint) -> io::Result<TcpStream> {
+        let (reply, response) = oneshot::channel();
+        self.request(Command::Connect { remote, reply })?;
+        response.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Listen on a local port.
+    pub async fn listen(&self, port: u16) -> io::Result<TcpListener> {
+        let (reply, response) = oneshot::channel();
+        self.request(Command::Listen { port, reply })?;
+        response.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    /// Bind a UDP socket to a local endpoint, port `0` assigns an ephemeral port.
+    pub async fn bind(&self, local: IpEndpoint) -> io::Result<UdpSocket> {
+        let (reply, response) = oneshot::channel();
+        self.request(Command::Bind { local, reply })?;
+        response.await.unwrap_or_else(|_| Err(stopped()))
+    }
+
+    fn request(&self, command: Command) -> io::Result<()> {
+        self.commands.send(command).map_err(|_| stopped())
+    }
+}
+
+impl TcpStream {
+    pub fn remote_endpoint(&self) -> IpEndpoint {
+        self.remote
+    }
+
+    /// Create both ends of a connection.
+    fn pair(remote: IpEndpoint) -> (Self, Connection) {
+        let (incoming, receiver) = mpsc::channel(Bridge::CHANNEL);
+        let (sender, outgoing) = mpsc::channel(Bridge::CHANNEL);
+        let stream = TcpStream {
+            remote,
+            incoming: receiver,
+            pending: Vec::new(),
+            offset: 0,
+            outgoing: PollSender::new(sender),
+        };
+
+        (stream, Connection { incoming, outgoing })
+    }
+}
+
+impl TcpListener {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Wait for the next connection.
+    pub async fn accept(&mut self) -> io::Result<TcpStream> {
+        self.accepted.recv().await.ok_or_else(stopped)
+    }
+}
+
+impl UdpSocket {
+    pub fn local_endpoint(&self) -> IpEndpoint {
+        self.local
+    }
+
+    /// Send a datagram, waiting while the channel to the stack thread is full.
+    pub async fn send_to(&self, buf: &[u8], remote: IpEndpoint) -> io::Result<()> {
+        let datagrams = Datagrams { remote, payload: buf.to_vec(), segment: buf.len() };
+        self.outgoing.send(datagrams).await.map_err(|_| stopped())
+    }
+
+    /// Receive a datagram, truncating it if it does not fit the buffer.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, IpEndpoint)> {
+        let (payload, remote) = self.incoming.recv().await.ok_or_else(stopped)?;
+        let len = payload.len().min(buf.len());
+        buf[..len].copy_from_slice(&payload[..len]);
+        Ok((len, remote))
+    }
+}
+
+/// Run the stack and the sockets of all handles.
+async fn serve<D: Queues + 'static>(
+    stack: Stack<D>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    stop: Arc<AtomicBool>,
+) {
+    let poller = stack.clone();
+    task::spawn_local(async move {
+        while let Some(command) = commands.recv().await {
+            task::spawn_local(execute(stack.clone(), command));
+        }
+    });
+
+    while !stop.load(Ordering::Relaxed) {
+        poller.poll();
+        // Let the socket tasks woken by the poll run.
+        task::yield_now().await;
+    }
+}
+
+async fn execute<D: Queues + 'static>(stack: Stack<D>, command: Command) {
+    match command {
+        Command::Connect { remote, reply } => {
+            match sockets::TcpStream::connect(&stack, remote).await {
+                Ok(socket) => {
+                    let (stream, connection) = TcpStream::pair(remote);
+                    if reply.send(Ok(stream)).is_ok() {
+                        connection.run(socket).await;
+                    }
+                },
+                Err(err) => {
+                    let _ = reply.send(Err(err));
+                },
+            }
+        },
+        Command::Listen { port, reply } => {
+            let mut listener = match sockets::TcpListener::bind(&stack, port) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    let _ = reply.send(Err(err));
+                    return;
+                },
+            };
+
+            let (sender, accepted) = mpsc::channel(Bridge::CHANNEL);
+            if reply.send(Ok(TcpListener { port, accepted })).is_err() {
+                return;
+            }
+
+            loop {
+                let socket = tokio::select! {
+                    socket = listener.accept() => socket,
+                    _ = sender.closed() => return,
+                };
+                let socket = match socket {
+                    Ok(socket) => socket,
+                    Err(_) => continue,
+                };
+
+                let (stream, connection) = TcpStream::pair(socket.remote_endpoint());
+                if sender.send(stream).await.is_err() {
+                    return;
+                }
+                task::spawn_local(connection.run(socket));
+            }
+        },
+        Command::Bind { mut local, reply } => {
+            if local.port == 0 {
+                local.port = stack.ephemeral_port();
+            }
+            let socket = match sockets::UdpSocket::bind(&stack, local) {
+                Ok(socket) => socket,
+                Err(err) => {
+                    let _ = reply.send(Err(err));
+                    return;
+                },
+            };
+
+            let (incoming, receiver) = mpsc::channel(Bridge::CHANNEL);
+            let (sender, outgoing) = mpsc::channel(Bridge::CHANNEL);
+            let handle = UdpSocket { local, incoming: receiver, outgoing: sender };
+            if reply.send(Ok(handle)).is_ok() {
+                serve_udp(socket, incoming, outgoing).await;
+            }
+        },
+    }
+}
+
+/// Move datagrams between the socket and the channels until the handle is dropped.
+async fn serve_udp<D: Queues + 'static>(
+    socket: sockets::UdpSocket<D>,
+    incoming: mpsc::Sender<(Vec<u8>, IpEndpoint)>,
+    mut outgoing: mpsc::Receiver<Datagrams>,
+) {
+    let mut buf = vec![0; Bridge::CHUNK];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, remote)) => match incoming.try_send((buf[..len].to_vec(), remote)) {
+                    Err(mpsc::error::TrySendError::Closed(_)) => return,
+                    // Like a full socket buffer, drop the datagram.
+                    Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => (),
+                },
+                Err(_) => (),
+            },
+            datagrams = outgoing.recv() => match datagrams {
+                // Queue all segments at once, the next poll hands them to the phy as a batch.
+                Some(datagrams) => {
+                    let segments = datagrams.payload.chunks(datagrams.segment.max(1));
+                    for segment in segments {
+                        if socket.send_to(segment, datagrams.remote).await.is_err() {
+                            break;
+                        }
+                    }
+                },
+                None => return,
+            },
+        }
+    }
+}
+
+impl Connection {
+    /// Move data between the socket and the channels until both directions are closed.
+    async fn run<D: Queues + 'static>(self, mut socket: sockets::TcpStream<D>) {
+        let Connection { incoming, mut outgoing } = self;
+        // Dropped once the peer closed its half, which the handle reads as the end of stream.
+        let mut incoming = Some(incoming);
+        let mut buf = vec![0; Bridge::CHUNK];
+        let mut sending = true;
+        while incoming.is_some() || sending {
+            tokio::select! {
+                read = socket.read(&mut buf), if incoming.is_some() => match read {
+                    // Keep sending until the handle shuts down its writing half.
+                    Ok(0) => incoming = None,
+                    Err(_) => break,
+                    Ok(len) => {
+                        let incoming = incoming.as_ref().unwrap();
+                        if incoming.send(buf[..len].to_vec()).await.is_err() {
+                            // The handle was dropped.
+                            break;
+                        }
+                    },
+                },
+                data = outgoing.recv(), if sending => match data {
+                    Some(data) => if socket.write_all(&data).await.is_err() {
+                        break;
+                    },
+                    None => {
+                        sending = false;
+                        socket.close();
+                    },
+                },
+            }
+        }
+        // Dropping the socket closes the connection gracefully.
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf)
+        -> Poll<io::Result<()>>
+    {
+        let this = &mut *self;
+        if this.offset == this.pending.len() {
+            match this.incoming.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                // The end of the stream.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Ready(Some(data)) => {
+                    this.pending = data;
+                    this.offset = 0;
+                },
+            }
+        }
+
+        let len = buf.remaining().min(this.pending.len() - this.offset);
+        buf.put_slice(&this.pending[this.offset..this.offset + len]);
+        this.offset += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let outgoing = &mut self.outgoing;
+        match outgoing.poll_reserve(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(_)) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Ready(Ok(())) => {
+                let sent = outgoing.send_item(buf.to_vec());
+                Poll::Ready(sent.map(|()| buf.len()).map_err(|_| io::ErrorKind::BrokenPipe.into()))
+            },
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        // Written data is already with the stack thread.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.outgoing.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub(crate) fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "The stack thread stopped")
+}
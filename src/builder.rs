@@ -0,0 +1,125 @@
+use std::error::Error;
+use std::rc::Rc;
+use std::time::Duration;
+
+use ixy::IxyDevice;
+use ixy::memory::Mempool;
+
+use super::{Clock, FlushPolicy, Phy, entry_size, tx_pool_entries};
+
+/// Configures the construction of a `Phy`.
+///
+/// Created with `Phy::builder`, all options not set explicitly keep the defaults of `Phy::new`.
+pub struct Builder<D> {
+    device: D,
+    queue: u32,
+    batch_size: usize,
+    tx_pool: Option<Rc<Mempool>>,
+    flush_policy: FlushPolicy,
+    flush_deadline: Option<Duration>,
+    preallocate: usize,
+    clock: Clock,
+}
+
+impl<D: IxyDevice> Builder<D> {
+    pub(crate) fn new(device: D) -> Self {
+        Builder {
+            device,
+            queue: 0,
+            batch_size: Phy::<D>::BATCH_SIZE,
+            tx_pool: None,
+            flush_policy: FlushPolicy::default(),
+            flush_deadline: None,
+            preallocate: 0,
+            clock: Clock::default(),
+        }
+    }
+
+    /// Set the number of packets received or allocated at once.
+    ///
+    /// ## Panics
+    /// This function panics if `batch_size` is zero.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batch size must not be zero");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Select the index of the rx/tx queue pair of the device.
+    ///
+    /// The device must have been initialized with enough queues, e.g. with `ixy_init(addr, 4, 4)`
+    /// for queues `0..4`. Note that the phy owns the device and the only queue pair it services
+    /// is the selected one. Defaults to the first queue.
+    pub fn queue(mut self, queue: u32) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Allocate packets for sending from a dedicated pool.
+    ///
+    /// By default the receive pool of the selected queue is shared for sending, so that heavy
+    /// sending can starve the refill of the receive ring. A separate pool isolates the two
+    /// directions, see `tx_pool_entries` for its size.
+    pub fn tx_pool(mut self, pool: Rc<Mempool>) -> Self {
+        self.tx_pool = Some(pool);
+        self
+    }
+
+    /// Allocate a dedicated pool for sending, sized for a tx ring of `ring_size` descriptors.
+    ///
+    /// The entries fit frames of the standard MTU.
+    pub fn dedicated_tx_pool(self, ring_size: usize) -> Result<Self, Box<dyn Error>> {
+        let entries = tx_pool_entries(ring_size, self.batch_size);
+        let pool = Mempool::allocate(entries, entry_size(Phy::<D>::DEFAULT_MTU))?;
+        Ok(self.tx_pool(pool))
+    }
+
+    /// Set when packets are automatically flushed.
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    /// Bound the time packets wait in the send queue, see `Phy::set_flush_deadline`.
+    pub fn flush_deadline(mut self, deadline: Duration) -> Self {
+        self.flush_deadline = Some(deadline);
+        self
+    }
+
+    /// Allocate some buffers for sending ahead of time.
+    ///
+    /// Fewer buffers are allocated if the pool does not have enough free entries.
+    pub fn preallocate(mut self, count: usize) -> Self {
+        self.preallocate = count;
+        self
+    }
+
+    /// Set the source of packet timestamps.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Finalize the configured phy.
+    ///
+    /// ## Panics
+    /// This function panics if no tx pool was configured and the device has no receive pool for
+    /// the selected queue.
+    pub fn build(self) -> Phy<D> {
+        let pool = match self.tx_pool {
+            Some(pool) => pool,
+            None => self.device
+                .recv_pool(self.queue)
+                .expect("No receive pool for the selected queue")
+                .clone(),
+        };
+
+        let mut phy = Phy::with_batch_size(self.device, pool, self.batch_size);
+        phy.queue = self.queue;
+        phy.flush_policy = self.flush_policy;
+        phy.flush_deadline = self.flush_deadline;
+        phy.clock = self.clock;
+        phy.get_tx(self.preallocate);
+        phy
+    }
+}
@@ -0,0 +1,39 @@
+//! Internet checksum helpers shared by the software packet paths.
+
+/// Add the 16-bit big endian words of `data` to a running sum.
+///
+/// A trailing odd byte is padded with zero.
+pub(crate) fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        acc = acc.wrapping_add(u32::from(u16::from_be_bytes([word[0], word[1]])));
+    }
+    if let [last] = chunks.remainder() {
+        acc = acc.wrapping_add(u32::from(*last) << 8);
+    }
+    acc
+}
+
+/// Fold a running sum into the ones complement checksum.
+pub(crate) fn finish(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+/// Update a checksum for a changed 16-bit word, as in RFC 1624.
+pub(crate) fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    let acc = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    finish(acc)
+}
+
+/// Read the 16-bit big endian word at `offset`.
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Write the 16-bit big endian word at `offset`.
+pub(crate) fn write_u16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
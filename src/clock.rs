@@ -0,0 +1,93 @@
+//! Sources of packet timestamps.
+use std::time::Duration;
+
+use ethox::time::Instant;
+
+/// The source of the timestamps a phy attaches to its packets.
+///
+/// The timestamp is sampled once per poll cycle, i.e. a receive call, and shared by all packets of
+/// the batch and by the next send call.
+#[derive(Clone, Copy, Debug)]
+pub enum Clock {
+    /// The system clock.
+    System,
+
+    /// The time stamp counter of the CPU, see `Tsc`.
+    Tsc(Tsc),
+}
+
+/// The time stamp counter of x86-64 CPUs, calibrated against the system clock.
+///
+/// Reading it is considerably cheaper than a system call or even the vDSO. Only correct on CPUs
+/// with an invariant TSC that is synchronized across cores, check for the `constant_tsc` and
+/// `nonstop_tsc` flags.
+#[derive(Clone, Copy, Debug)]
+pub struct Tsc {
+    base: Instant,
+    base_ticks: u64,
+    ticks_per_milli: f64,
+}
+
+impl Clock {
+    /// Use the time stamp counter if the architecture has one, else the system clock.
+    ///
+    /// Calibration blocks for a few milliseconds.
+    pub fn tsc() -> Self {
+        Tsc::calibrate().map_or(Clock::System, Clock::Tsc)
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            Clock::Tsc(tsc) => tsc.now(),
+        }
+    }
+}
+
+impl Tsc {
+    /// Measure the frequency of the counter, `None` if there is none.
+    pub fn calibrate() -> Option<Self> {
+        let start = std::time::Instant::now();
+        let base = Instant::now();
+        let base_ticks = ticks()?;
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = start.elapsed();
+        // A counter which did not advance, e.g. as the thread moved cores, can not be used.
+        let ticks = ticks()?.checked_sub(base_ticks).filter(|&ticks| ticks > 0)?;
+
+        Some(Tsc {
+            base,
+            base_ticks,
+            ticks_per_milli: ticks as f64 / (elapsed.as_secs_f64() * 1e3),
+        })
+    }
+
+    /// The counter frequency in Hz.
+    pub fn frequency(&self) -> f64 {
+        self.ticks_per_milli * 1e3
+    }
+
+    fn now(&self) -> Instant {
+        // The counters of the cores may be skewed, never report a time before the calibration.
+        let ticks = ticks().unwrap_or(self.base_ticks).saturating_sub(self.base_ticks);
+        let millis = (ticks as f64 / self.ticks_per_milli) as i64;
+        Instant::from_millis(self.base.total_millis() + millis)
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::System
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ticks() -> Option<u64> {
+    // Safety: rdtsc is available on all x86-64 CPUs.
+    Some(unsafe { core::arch::x86_64::_rdtsc() })
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn ticks() -> Option<u64> {
+    None
+}
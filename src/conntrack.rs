@@ -0,0 +1,449 @@
+//! A table of connections by their 5-tuple, shared by the stateful components.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::flow::FiveTuple;
+use crate::frame::{Headers, PROTO_TCP, PROTO_UDP};
+
+/// How long connections stay without packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConntrackTimeouts {
+    /// TCP connections which saw packets in both directions and no FIN or RST.
+    pub tcp_established: Duration,
+
+    /// TCP connections while opening or closing.
+    pub tcp_transitory: Duration,
+
+    pub udp: Duration,
+
+    /// ICMP echo queries.
+    pub icmp: Duration,
+}
+
+/// A tracked connection and the state of its user.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Connection<T> {
+    /// The flow of the packet which created the connection.
+    pub original: FiveTuple,
+
+    /// The flow of its replies, the reversed original flow unless translated.
+    pub reply: FiveTuple,
+
+    /// Packets and their IP bytes of the original flow.
+    pub packets: u64,
+    pub bytes: u64,
+
+    /// Packets and their IP bytes of the reply flow.
+    pub reply_packets: u64,
+    pub reply_bytes: u64,
+
+    /// Whether a FIN or RST of the TCP connection was seen.
+    pub closing: bool,
+    pub created: Instant,
+
+    /// When the last packet was counted.
+    pub last_seen: Instant,
+    pub data: T,
+}
+
+/// Counters of a connection table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ConntrackStats {
+    pub created: u64,
+
+    /// Connections removed for a new one while the table was full.
+    pub evicted: u64,
+
+    /// Connections removed after their timeout.
+    pub expired: u64,
+}
+
+/// Connections by both of their flows, with their counters.
+///
+/// A connection is found by a packet of either direction, its reply flow may differ from the
+/// reversed original one when its packets are translated. Connections expire after they saw no
+/// packet for the timeout of their protocol, TCP connections use the transitory timeout until
+/// they saw a reply and once closing. The timeout of ICMP applies to all protocols other than TCP
+/// and UDP.
+///
+/// The connections are kept in order of their last use, so a full table makes room for a new
+/// connection by evicting the least recently used one, and expiring only looks at the idle end.
+pub struct Conntrack<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+    /// The slot of each original and reply flow.
+    flows: HashMap<FiveTuple, usize>,
+    /// The most recently used slot.
+    head: usize,
+    /// The least recently used slot.
+    tail: usize,
+    capacity: usize,
+    timeouts: ConntrackTimeouts,
+    stats: ConntrackStats,
+}
+
+struct Slot<T> {
+    connection: Option<Connection<T>>,
+    /// The slot used more recently.
+    prev: usize,
+    /// The slot used less recently.
+    next: usize,
+}
+
+const NIL: usize = usize::max_value();
+
+impl<T> Conntrack<T> {
+    /// The default maximum number of connections.
+    pub const CAPACITY: usize = 65536;
+
+    pub fn new() -> Self {
+        Conntrack {
+            slots: Vec::new(),
+            free: Vec::new(),
+            flows: HashMap::new(),
+            head: NIL,
+            tail: NIL,
+            capacity: Self::CAPACITY,
+            timeouts: ConntrackTimeouts::default(),
+            stats: ConntrackStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Limit the number of connections, evicting the least recently used ones above it.
+    ///
+    /// ## Panics
+    /// This function panics if the capacity is zero.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        assert!(capacity > 0, "The capacity must not be zero");
+        self.capacity = capacity;
+        while self.len() > capacity {
+            self.evict();
+        }
+    }
+
+    pub fn timeouts(&self) -> ConntrackTimeouts {
+        self.timeouts
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: ConntrackTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// The number of connections, including expired ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a new connection evicts another one.
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    pub fn stats(&self) -> ConntrackStats {
+        self.stats
+    }
+
+    /// All connections, the most recently used first.
+    pub fn iter(&self) -> impl Iterator<Item=&Connection<T>> {
+        let mut slot = self.head;
+        std::iter::from_fn(move || {
+            let current = self.slots.get(slot)?;
+            slot = current.next;
+            current.connection.as_ref()
+        })
+    }
+
+    /// The connection of a flow of either direction, even if expired.
+    pub fn get(&self, flow: &FiveTuple) -> Option<&Connection<T>> {
+        let &slot = self.flows.get(flow)?;
+        self.slots[slot].connection.as_ref()
+    }
+
+    pub fn get_mut(&mut self, flow: &FiveTuple) -> Option<&mut Connection<T>> {
+        let &slot = self.flows.get(flow)?;
+        self.slots[slot].connection.as_mut()
+    }
+
+    /// Check if a connection saw no packet for its timeout.
+    pub fn is_expired(&self, connection: &Connection<T>, now: Instant) -> bool {
+        now.saturating_duration_since(connection.last_seen) >= self.timeout(connection)
+    }
+
+    /// Add a connection, replacing those of its flows and evicting the least recently used one
+    /// if the table is full.
+    ///
+    /// The connection is the most recently used, without packets.
+    pub fn insert(&mut self, original: FiveTuple, reply: FiveTuple, data: T, now: Instant)
+        -> &mut Connection<T>
+    {
+        self.remove(&original);
+        self.remove(&reply);
+        if self.is_full() {
+            self.evict();
+        }
+
+        let connection = Connection {
+            original,
+            reply,
+            packets: 0,
+            bytes: 0,
+            reply_packets: 0,
+            reply_bytes: 0,
+            closing: false,
+            created: now,
+            last_seen: now,
+            data,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                self.slots[slot].connection = Some(connection);
+                slot
+            },
+            None => {
+                self.slots.push(Slot { connection: Some(connection), prev: NIL, next: NIL });
+                self.slots.len() - 1
+            },
+        };
+        self.flows.insert(original, slot);
+        self.flows.insert(reply, slot);
+        self.push_front(slot);
+        self.stats.created += 1;
+        self.slots[slot].connection.as_mut().unwrap()
+    }
+
+    /// Remove the connection of a flow of either direction.
+    pub fn remove(&mut self, flow: &FiveTuple) -> Option<Connection<T>> {
+        let slot = *self.flows.get(flow)?;
+        Some(self.remove_slot(slot))
+    }
+
+    /// Count a packet of a flow of either direction and mark its connection as used.
+    ///
+    /// Returns `None` if the flow has no connection or it expired, which is removed.
+    pub fn update(&mut self, flow: &FiveTuple, bytes: usize, closing: bool, now: Instant)
+        -> Option<&mut Connection<T>>
+    {
+        let slot = *self.flows.get(flow)?;
+        let expired = self.is_expired(self.slots[slot].connection.as_ref().unwrap(), now);
+        if expired {
+            self.remove_slot(slot);
+            self.stats.expired += 1;
+            return None;
+        }
+
+        self.unlink(slot);
+        self.push_front(slot);
+        let connection = self.slots[slot].connection.as_mut().unwrap();
+        if connection.original == *flow {
+            connection.packets += 1;
+            connection.bytes += bytes as u64;
+        } else {
+            connection.reply_packets += 1;
+            connection.reply_bytes += bytes as u64;
+        }
+        connection.closing |= closing;
+        connection.last_seen = now;
+        Some(connection)
+    }
+
+    /// Count an IP packet in its connection, creating one with the data if it has none.
+    ///
+    /// New connections reply with the reversed flow. Returns `None` for frames other than IP and
+    /// if there was no connection and `create` returned `None`.
+    pub fn track(
+        &mut self,
+        frame: &[u8],
+        now: Instant,
+        create: impl FnOnce(&FiveTuple) -> Option<T>,
+    ) -> Option<&mut Connection<T>> {
+        let headers = Headers::parse(frame)?;
+        let flow = FiveTuple::from_headers(frame, &headers)?;
+        let bytes = headers.end - headers.l3;
+        let closing = match headers.transport(PROTO_TCP) {
+            Some(l4) if l4 + 14 <= headers.end => frame[l4 + 13] & 0x05 != 0,
+            _ => false,
+        };
+
+        if self.flows.contains_key(&flow) && self.update(&flow, bytes, closing, now).is_some() {
+            return self.get_mut(&flow);
+        }
+        let data = create(&flow)?;
+        self.insert(flow, flow.reversed(), data, now);
+        self.update(&flow, bytes, closing, now)
+    }
+
+    /// Remove all expired connections, returns how many.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        self.expire_with(now, drop)
+    }
+
+    /// Remove all expired connections and pass them to a function, e.g. to export them.
+    ///
+    /// Returns the number of expired connections.
+    pub fn expire_with(&mut self, now: Instant, mut expired: impl FnMut(Connection<T>)) -> usize {
+        let shortest = {
+            let ConntrackTimeouts { tcp_established, tcp_transitory, udp, icmp } = self.timeouts;
+            tcp_established.min(tcp_transitory).min(udp).min(icmp)
+        };
+
+        let mut count = 0;
+        let mut slot = self.tail;
+        while slot != NIL {
+            let connection = self.slots[slot].connection.as_ref().unwrap();
+            // All more recently used connections are younger than the shortest timeout.
+            if now.saturating_duration_since(connection.last_seen) < shortest {
+                break;
+            }
+            let prev = self.slots[slot].prev;
+            if self.is_expired(connection, now) {
+                expired(self.remove_slot(slot));
+                count += 1;
+            }
+            slot = prev;
+        }
+
+        self.stats.expired += count as u64;
+        count
+    }
+
+    /// Remove all connections.
+    pub fn clear(&mut self) {
+        self.slots.clear();
+        self.free.clear();
+        self.flows.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    fn timeout(&self, connection: &Connection<T>) -> Duration {
+        let replied = connection.reply_packets > 0;
+        match connection.original.protocol {
+            PROTO_TCP if connection.closing || !replied => self.timeouts.tcp_transitory,
+            PROTO_TCP => self.timeouts.tcp_established,
+            PROTO_UDP => self.timeouts.udp,
+            _ => self.timeouts.icmp,
+        }
+    }
+
+    fn evict(&mut self) {
+        if self.tail != NIL {
+            self.remove_slot(self.tail);
+            self.stats.evicted += 1;
+        }
+    }
+
+    fn remove_slot(&mut self, slot: usize) -> Connection<T> {
+        self.unlink(slot);
+        let connection = self.slots[slot].connection.take().unwrap();
+        self.flows.remove(&connection.original);
+        self.flows.remove(&connection.reply);
+        self.free.push(slot);
+        connection
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.slots[slot].prev = NIL;
+        self.slots[slot].next = self.head;
+        match self.head {
+            NIL => self.tail = slot,
+            head => self.slots[head].prev = slot,
+        }
+        self.head = slot;
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let Slot { prev, next, .. } = self.slots[slot];
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+        self.slots[slot].prev = NIL;
+        self.slots[slot].next = NIL;
+    }
+}
+
+impl<T> Default for Conntrack<T> {
+    fn default() -> Self {
+        Conntrack::new()
+    }
+}
+
+impl Default for ConntrackTimeouts {
+    /// The recommendations of RFC 5382 for TCP and RFC 4787 for UDP.
+    fn default() -> Self {
+        ConntrackTimeouts {
+            tcp_established: Duration::from_secs(7440),
+            tcp_transitory: Duration::from_secs(240),
+            udp: Duration::from_secs(300),
+            icmp: Duration::from_secs(60),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    fn udp(port: u16) -> FiveTuple {
+        FiveTuple {
+            src: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            dst: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            src_port: port,
+            dst_port: 53,
+            protocol: PROTO_UDP,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let now = Instant::now();
+        let mut table = Conntrack::new();
+        table.set_capacity(2);
+        table.insert(udp(1), udp(1).reversed(), 1, now);
+        table.insert(udp(2), udp(2).reversed(), 2, now);
+        // A reply makes the first connection the most recently used.
+        assert!(table.update(&udp(1).reversed(), 64, false, now).is_some());
+
+        table.insert(udp(3), udp(3).reversed(), 3, now);
+        assert_eq!(table.len(), 2);
+        assert!(table.get(&udp(2)).is_none());
+        assert!(table.get(&udp(2).reversed()).is_none());
+        let order: Vec<_> = table.iter().map(|connection| connection.data).collect();
+        assert_eq!(order, [3, 1]);
+        assert_eq!(table.stats().evicted, 1);
+    }
+
+    #[test]
+    fn expires_idle_connections() {
+        let start = Instant::now();
+        let timeout = ConntrackTimeouts::default().udp;
+        let mut table = Conntrack::new();
+        table.insert(udp(1), udp(1).reversed(), (), start);
+        table.insert(udp(2), udp(2).reversed(), (), start);
+
+        let later = start + timeout / 2;
+        assert!(table.update(&udp(2), 64, false, later).is_some());
+        assert_eq!(table.expire(start + timeout - Duration::from_millis(1)), 0);
+        assert_eq!(table.expire(start + timeout), 1);
+        assert!(table.get(&udp(1)).is_none());
+
+        // A packet after the timeout finds no connection and removes the expired one.
+        assert!(table.update(&udp(2), 64, false, later + timeout).is_none());
+        assert!(table.is_empty());
+        assert_eq!(table.stats().expired, 2);
+    }
+}
@@ -0,0 +1,227 @@
+//! A `Send` description of a phy, to move it to another thread.
+use std::error::Error;
+use std::rc::Rc;
+use std::time::Duration;
+
+use ixy::{IxyDevice, ixy_init};
+use ixy::memory::Mempool;
+
+use crate::{Clock, FlowRule, FlushPolicy, MacFilter, Napi, Phy, Queues, tx_pool_entries};
+
+/// A phy detached from its thread, attached again on another one.
+///
+/// A phy itself is not `Send`, see its documentation. This handle only holds the PCI address of
+/// the device and the configuration of the phy, so it can be moved freely. `attach` initializes
+/// the device again on the calling thread, with new mempools, and replays the configuration.
+///
+/// Initialization resets the device: packets in its rings and its counters are lost, and the
+/// link is negotiated again. Call `Phy::shutdown` before detaching to send what is still queued.
+#[derive(Clone, Debug)]
+pub struct Detached {
+    pci_addr: String,
+    rx_queues: u16,
+    tx_queues: u16,
+    queue: u32,
+    batch_size: usize,
+    flush_policy: FlushPolicy,
+    flush_deadline: Option<Duration>,
+    clock: Clock,
+    /// The entry size of a dedicated tx pool, `None` to share the receive pool.
+    tx_pool: Option<usize>,
+    mtu: usize,
+    filter: MacFilter,
+    flow_rules: Vec<FlowRule>,
+    lro: bool,
+    vlan_strip: bool,
+    hardware_offloads: bool,
+    napi: Option<Napi>,
+}
+
+impl Detached {
+    /// The tx ring size for which a dedicated tx pool is allocated, that of the ixy drivers.
+    pub const RING_SIZE: usize = 512;
+
+    /// Describe a device with the queues to initialize and a phy with the defaults of `Phy::new`.
+    pub fn new(pci_addr: &str, rx_queues: u16, tx_queues: u16) -> Self {
+        Detached {
+            pci_addr: pci_addr.to_string(),
+            rx_queues,
+            tx_queues,
+            queue: 0,
+            batch_size: Phy::<Box<dyn IxyDevice>>::BATCH_SIZE,
+            flush_policy: FlushPolicy::default(),
+            flush_deadline: None,
+            clock: Clock::default(),
+            tx_pool: None,
+            mtu: Phy::<Box<dyn IxyDevice>>::DEFAULT_MTU,
+            filter: MacFilter::default(),
+            flow_rules: Vec::new(),
+            lro: false,
+            vlan_strip: false,
+            hardware_offloads: true,
+            napi: None,
+        }
+    }
+
+    pub fn pci_addr(&self) -> &str {
+        &self.pci_addr
+    }
+
+    /// Select the rx/tx queue pair serviced by the phy, see `Builder::queue`.
+    pub fn queue(mut self, queue: u32) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Set the batch size of the phy, see `Builder::batch_size`.
+    ///
+    /// ## Panics
+    /// This function panics if `batch_size` is zero.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batch size must not be zero");
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    pub fn flush_deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.flush_deadline = deadline;
+        self
+    }
+
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Allocate a dedicated tx pool with entries of `entry_size`, see `Builder::tx_pool`.
+    pub fn dedicated_tx_pool(mut self, entry_size: Option<usize>) -> Self {
+        self.tx_pool = entry_size;
+        self
+    }
+
+    /// Initialize the device on this thread and build its phy.
+    pub fn attach(self) -> Result<Phy<Box<dyn IxyDevice>>, Box<dyn Error>> {
+        let device = ixy_init(&self.pci_addr, self.rx_queues, self.tx_queues)?;
+        let mut builder = Phy::builder(device)
+            .queue(self.queue)
+            .batch_size(self.batch_size);
+        if let Some(entry_size) = self.tx_pool {
+            let entries = tx_pool_entries(Self::RING_SIZE, self.batch_size);
+            builder = builder.tx_pool(Mempool::allocate(entries, entry_size)?);
+        }
+
+        let mut phy = builder.build();
+        self.apply(&mut phy)?;
+        Ok(phy)
+    }
+
+    /// Configure a phy as described, except for its device, queue, batch size and tx pool.
+    fn apply<D: Queues>(&self, phy: &mut Phy<D>) -> Result<(), Box<dyn Error>> {
+        phy.set_flush_policy(self.flush_policy);
+        phy.set_flush_deadline(self.flush_deadline);
+        phy.set_clock(self.clock);
+        phy.set_mtu(self.mtu)?;
+        phy.set_promiscuous(self.filter.promiscuous());
+        for &addr in self.filter.unicast() {
+            phy.add_unicast(addr);
+        }
+        for &group in self.filter.multicast() {
+            phy.join_multicast(group);
+        }
+        for rule in &self.flow_rules {
+            phy.add_flow_rule(*rule);
+        }
+        phy.set_lro(self.lro);
+        phy.set_vlan_strip(self.vlan_strip);
+        phy.set_hardware_offloads(self.hardware_offloads);
+        phy.set_napi(self.napi);
+        Ok(())
+    }
+}
+
+impl<D: IxyDevice> Phy<D> {
+    /// Release the device, keeping its description and the configuration of the phy.
+    ///
+    /// The device was initialized with `rx_queues` and `tx_queues`, which ixy does not report.
+    /// Packets still queued are dropped, call `shutdown` before to send them.
+    pub fn detach(self, rx_queues: u16, tx_queues: u16) -> Detached {
+        let shared = self.device
+            .recv_pool(self.queue)
+            .map_or(false, |pool| Rc::ptr_eq(pool, &self.pool));
+        let flow_rules = self.hardware_flow_rules.iter()
+            .chain(&self.flow_rules)
+            .copied()
+            .collect();
+
+        Detached {
+            pci_addr: self.device.get_pci_addr().to_string(),
+            rx_queues,
+            tx_queues,
+            queue: self.queue,
+            batch_size: self.batch_size,
+            flush_policy: self.flush_policy,
+            flush_deadline: self.flush_deadline,
+            clock: self.clock,
+            tx_pool: if shared { None } else { Some(self.pool.entry_size()) },
+            mtu: self.mtu,
+            filter: self.filter.clone(),
+            flow_rules,
+            lro: self.lro,
+            vlan_strip: self.vlan_strip,
+            hardware_offloads: self.hardware_offloads,
+            napi: self.napi(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::test_pool;
+    use crate::{FlowAction, FlowMatch, MockDevice};
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn detached_is_send() {
+        assert_send::<Detached>();
+    }
+
+    #[test]
+    fn configuration_round_trips() {
+        let pool = test_pool();
+        let mut phy = Phy::new(MockDevice::new(pool.clone()), pool.clone());
+        phy.set_flush_policy(FlushPolicy::Packets(8));
+        phy.set_flush_deadline(Some(Duration::from_micros(50)));
+        phy.set_mtu(1400).unwrap();
+        phy.set_promiscuous(false);
+        phy.add_unicast(MockDevice::MAC);
+        phy.join_multicast([0x01, 0, 0x5e, 0, 0, 0xfb]);
+        let rule = FlowRule { matches: FlowMatch::default(), action: FlowAction::Drop };
+        phy.add_flow_rule(rule);
+        phy.set_lro(true);
+        phy.set_vlan_strip(true);
+        phy.set_hardware_offloads(false);
+        phy.set_napi(Some(Napi::default()));
+
+        let detached = phy.detach(1, 1);
+        assert_eq!(detached.tx_pool, None);
+        let mut attached = Phy::new(MockDevice::new(pool.clone()), pool);
+        detached.apply(&mut attached).unwrap();
+
+        assert_eq!(attached.flush_policy(), FlushPolicy::Packets(8));
+        assert_eq!(attached.flush_deadline(), Some(Duration::from_micros(50)));
+        assert_eq!(attached.mtu(), 1400);
+        assert_eq!(attached.mac_filter(), &detached.filter);
+        assert_eq!(attached.software_flow_rules(), [rule]);
+        assert!(attached.lro());
+        assert!(attached.vlan_strip());
+        assert!(!attached.hardware_offloads());
+        assert_eq!(attached.napi(), Some(Napi::default()));
+    }
+}
@@ -0,0 +1,102 @@
+//! Spreading flows across several egress paths.
+use crate::rss::flow_hash;
+use crate::{Phy, Queues};
+
+/// Selects one of several equal-cost paths for each flow.
+///
+/// Flows are assigned by weighted rendezvous hashing of their 5-tuple, the same for both
+/// directions: every path gets a score from the flow hash and its weight, the best path which is
+/// up wins. Paths receive flows in proportion to their weights, and when a path goes down or is
+/// added only the flows of that path move, all others keep their path. Frames other than IP take
+/// the path of hash zero.
+///
+/// The paths are indices chosen by the user, e.g. of a list of phys whose link state is updated
+/// with `refresh`.
+#[derive(Clone, Debug, Default)]
+pub struct Ecmp {
+    paths: Vec<Path>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Path {
+    weight: u32,
+    up: bool,
+}
+
+impl Ecmp {
+    pub fn new() -> Self {
+        Ecmp::default()
+    }
+
+    /// Add a path which is up, returning its index.
+    pub fn add_path(&mut self, weight: u32) -> usize {
+        self.paths.push(Path { weight, up: true });
+        self.paths.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn weight(&self, path: usize) -> u32 {
+        self.paths[path].weight
+    }
+
+    /// Change the share of flows of a path, a weight of zero removes it from the selection.
+    pub fn set_weight(&mut self, path: usize, weight: u32) {
+        self.paths[path].weight = weight;
+    }
+
+    pub fn is_up(&self, path: usize) -> bool {
+        self.paths[path].up
+    }
+
+    /// Mark a path as up or down, the flows of a path which is down fail over to the others.
+    pub fn set_up(&mut self, path: usize, up: bool) {
+        self.paths[path].up = up;
+    }
+
+    /// Update the paths from the link state of the phys at the same index.
+    pub fn refresh<D: Queues>(&mut self, phys: &[Phy<D>]) {
+        for (path, phy) in self.paths.iter_mut().zip(phys) {
+            path.up = phy.link().up;
+        }
+    }
+
+    /// The path of a frame, `None` if no path is up.
+    pub fn select(&self, frame: &[u8]) -> Option<usize> {
+        self.select_hash(flow_hash(frame).unwrap_or(0))
+    }
+
+    /// The path of a flow hash, `None` if no path is up.
+    pub fn select_hash(&self, hash: u32) -> Option<usize> {
+        let mut best = None;
+        let mut best_score = f64::INFINITY;
+        for (index, path) in self.paths.iter().enumerate() {
+            if !path.up || path.weight == 0 {
+                continue;
+            }
+            // An exponential variable with the weight as rate, the smallest one wins.
+            let random = mix(u64::from(hash) << 32 | index as u64);
+            let unit = (random >> 11) as f64 / (1u64 << 53) as f64;
+            let score = -(1.0 - unit).ln() / f64::from(path.weight);
+            if score < best_score {
+                best = Some(index);
+                best_score = score;
+            }
+        }
+        best
+    }
+}
+
+/// A well distributed 64-bit hash, by the finalizer of SplitMix64.
+pub(crate) fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
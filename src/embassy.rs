@@ -0,0 +1,122 @@
+//! The `embassy-net` driver implementation, enabled by the `embassy` feature.
+//!
+//! Like the smoltcp tokens, these hand the DMA buffers of ixy to the stack directly.
+use core::task::Context;
+
+use embassy_net_driver::{self as driver, Capabilities, Checksum, HardwareAddress, LinkState};
+use ixy::memory::Packet as IxyPacket;
+
+use crate::{Phy, Queues, TxOffload, frame_size};
+
+/// A received packet, handed to embassy-net.
+///
+/// Its buffer returns to the mempool when the token is dropped.
+pub struct RxToken(IxyPacket);
+
+/// The right to send a single packet on a phy.
+///
+/// The phy holds at least one free buffer while the token exists.
+pub struct TxToken<'a, D>(&'a mut Phy<D>);
+
+/// Polled by the runner of an embassy-net stack.
+///
+/// The ixy drivers only poll, so the phy can not be woken by the device. When no packet or no
+/// buffer is available the task is woken again immediately, which busy polls the device from the
+/// executor just like a poll loop would. Run the stack on an executor dedicated to the phy.
+impl<D: Queues> driver::Driver for Phy<D> {
+    type RxToken<'a> = RxToken where Self: 'a;
+    type TxToken<'a> = TxToken<'a, D> where Self: 'a;
+
+    fn receive(&mut self, cx: &mut Context) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        // Send what the stack produced before waiting for more.
+        self.poll_flush();
+        self.get_rx(1);
+        self.get_tx(1);
+        if self.rx_queue.is_empty() || self.tx_empty.is_empty() {
+            cx.waker().wake_by_ref();
+            return None;
+        }
+
+        let packet = self.rx_queue.pop_front().unwrap();
+        Some((RxToken(packet), TxToken(self)))
+    }
+
+    fn transmit(&mut self, cx: &mut Context) -> Option<Self::TxToken<'_>> {
+        self.get_tx(1);
+        if self.tx_empty.is_empty() {
+            self.flush();
+            cx.waker().wake_by_ref();
+            return None;
+        }
+
+        Some(TxToken(self))
+    }
+
+    fn link_state(&mut self, cx: &mut Context) -> LinkState {
+        if self.link().up {
+            LinkState::Up
+        } else {
+            // Nothing signals the link coming up.
+            cx.waker().wake_by_ref();
+            LinkState::Down
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let offloads = self.device.offloads();
+        let mut capabilities = Capabilities::default();
+        // The Ethernet header is counted, a VLAN tag is not.
+        capabilities.max_transmission_unit = frame_size(self.mtu) - 4;
+        capabilities.max_burst_size = Some(self.batch_size);
+        capabilities.checksum.ipv4 = tx_checksum(offloads.tx_ipv4_checksum);
+        capabilities.checksum.tcp = tx_checksum(offloads.tx_tcp_checksum);
+        capabilities.checksum.udp = tx_checksum(offloads.tx_udp_checksum);
+        capabilities
+    }
+
+    fn hardware_address(&self) -> HardwareAddress {
+        HardwareAddress::Ethernet(self.mac_address())
+    }
+}
+
+impl driver::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        f(&mut self.0)
+    }
+}
+
+impl<D: Queues> driver::TxToken for TxToken<'_, D> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        let phy = self.0;
+        let mut packet = phy.tx_empty.pop_front()
+            .expect("A tx token without a free buffer");
+        // The stack respects the maximum transmission unit, which fits the pool entries.
+        if packet.try_resize(len, 0u8).is_err() {
+            panic!("Frame of {} bytes larger than the mempool entries", len);
+        }
+
+        let result = f(&mut packet);
+        let offloads = phy.device.offloads();
+        let offload = TxOffload::default().with_advertised(&offloads);
+        if !offload.is_empty() {
+            phy.device.tx_offload(phy.queue, &mut packet, offload);
+        }
+
+        phy.tx_queue.push_back(packet);
+        phy.poll_flush();
+        result
+    }
+}
+
+/// Leave a checksum to the NIC if it inserts it.
+fn tx_checksum(offloaded: bool) -> Checksum {
+    if offloaded {
+        Checksum::Rx
+    } else {
+        Checksum::Both
+    }
+}
@@ -0,0 +1,324 @@
+//! Injecting network faults between a device and its phys.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ixy::memory::{self, Packet as IxyPacket};
+
+use crate::{Error, FlowControl, FlowRule, Link, MacFilter, Metadata, Offloads, PauseStats};
+use crate::{Queues, TxOffload};
+
+/// The probabilities and delays of faults in one direction.
+///
+/// Each probability is in the range `0.0..=1.0` and applies independently to every packet.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Drop the packet.
+    pub loss: f64,
+
+    /// Flip a single random bit of the packet.
+    pub corrupt: f64,
+
+    /// Deliver the packet twice.
+    pub duplicate: f64,
+
+    /// Deliver the packet after the rest of its batch.
+    pub reorder: f64,
+
+    /// Delay every packet by a fixed time.
+    pub delay: Duration,
+
+    /// Delay every packet by an additional uniformly random time up to this.
+    pub jitter: Duration,
+}
+
+/// Counters of injected faults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FaultStats {
+    pub lost: u64,
+    pub corrupted: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delayed: u64,
+}
+
+/// A netem-like wrapper of the queues of a device.
+///
+/// Wrap the device before creating a `Phy` to test the network stack against loss, corruption,
+/// duplication, reordering and delay. Faults are applied to whole ixy packets, before the phy
+/// sees received packets and after it hands over packets for sending. All random decisions come
+/// from a seeded generator, so a run can be reproduced.
+///
+/// Packets for sending are taken from the phy immediately and kept until the device accepts them,
+/// delayed packets are only sent by later calls to `tx_batch`, i.e. later flushes of the phy.
+pub struct Faulty<Q> {
+    inner: Q,
+    rng: Rng,
+    rx: Side,
+    tx: Side,
+    /// Packets ready for the device but not yet accepted, per queue.
+    tx_ready: Vec<VecDeque<IxyPacket>>,
+}
+
+#[derive(Default)]
+struct Side {
+    faults: Faults,
+    stats: FaultStats,
+    held: Vec<Held>,
+}
+
+struct Held {
+    due: Instant,
+    queue: u32,
+    packet: IxyPacket,
+}
+
+/// A xorshift64* generator, good enough for random decisions about packets.
+pub(crate) struct Rng(u64);
+
+impl<Q> Faulty<Q> {
+    /// Wrap the queues without faults, seeding the random generator.
+    pub fn new(inner: Q, seed: u64) -> Self {
+        Faulty {
+            inner,
+            rng: Rng::new(seed),
+            rx: Side::default(),
+            tx: Side::default(),
+            tx_ready: Vec::new(),
+        }
+    }
+
+    pub fn rx_faults(&self) -> Faults {
+        self.rx.faults
+    }
+
+    /// Change the faults of received packets.
+    pub fn set_rx_faults(&mut self, faults: Faults) {
+        self.rx.faults = faults;
+    }
+
+    pub fn tx_faults(&self) -> Faults {
+        self.tx.faults
+    }
+
+    /// Change the faults of sent packets.
+    pub fn set_tx_faults(&mut self, faults: Faults) {
+        self.tx.faults = faults;
+    }
+
+    /// The faults injected into received packets so far.
+    pub fn rx_stats(&self) -> FaultStats {
+        self.rx.stats
+    }
+
+    /// The faults injected into sent packets so far.
+    pub fn tx_stats(&self) -> FaultStats {
+        self.tx.stats
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Q {
+        &mut self.inner
+    }
+
+    /// Unwrap the queues, dropping all held packets.
+    pub fn into_inner(self) -> Q {
+        self.inner
+    }
+}
+
+impl Side {
+    /// Apply the faults to the packets, appending the ones delivered now to `out`.
+    fn apply(
+        &mut self,
+        rng: &mut Rng,
+        queue: u32,
+        packets: &mut VecDeque<IxyPacket>,
+        out: &mut VecDeque<IxyPacket>,
+    ) {
+        let now = Instant::now();
+        let faults = self.faults;
+        let mut reordered = Vec::new();
+
+        for mut packet in packets.drain(..) {
+            if rng.chance(faults.loss) {
+                self.stats.lost += 1;
+                continue;
+            }
+
+            if rng.chance(faults.corrupt) && !packet.is_empty() {
+                let bit = rng.below(packet.len() as u64 * 8) as usize;
+                packet[bit / 8] ^= 1 << (bit % 8);
+                self.stats.corrupted += 1;
+            }
+
+            let duplicate = if rng.chance(faults.duplicate) {
+                let copy = memory::alloc_pkt(packet.get_pool(), packet.len());
+                copy.map(|mut copy| {
+                    copy.copy_from_slice(&packet);
+                    copy
+                })
+            } else {
+                None
+            };
+
+            let jitter = faults.jitter.mul_f64(rng.unit());
+            let delay = faults.delay + jitter;
+            for packet in Some(packet).into_iter().chain(duplicate) {
+                if delay > Duration::from_secs(0) {
+                    self.stats.delayed += 1;
+                    self.held.push(Held { due: now + delay, queue, packet });
+                } else if rng.chance(faults.reorder) {
+                    self.stats.reordered += 1;
+                    reordered.push(packet);
+                } else {
+                    out.push_back(packet);
+                }
+            }
+        }
+
+        out.extend(reordered);
+        self.release(now, queue, out);
+    }
+
+    /// Move the held packets of a queue which are due to `out`, in order of their due time.
+    fn release(&mut self, now: Instant, queue: u32, out: &mut VecDeque<IxyPacket>) {
+        self.held.sort_by_key(|held| held.due);
+        let mut index = 0;
+        while index < self.held.len() {
+            let held = &self.held[index];
+            if held.due > now {
+                break;
+            }
+            if held.queue == queue {
+                out.push_back(self.held.remove(index).packet);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A uniform value in `0.0..1.0`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+impl<Q: Queues> Queues for Faulty<Q> {
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut received = VecDeque::with_capacity(num_packets);
+        self.inner.rx_batch(queue, &mut received, num_packets);
+
+        let before = buffer.len();
+        self.rx.apply(&mut self.rng, queue, &mut received, buffer);
+        buffer.len() - before
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let index = queue as usize;
+        if self.tx_ready.len() <= index {
+            self.tx_ready.resize_with(index + 1, VecDeque::new);
+        }
+
+        let taken = buffer.len();
+        let ready = &mut self.tx_ready[index];
+        self.tx.apply(&mut self.rng, queue, buffer, ready);
+        self.inner.tx_batch(queue, ready);
+        taken
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.inner.mac_address()
+    }
+
+    fn link(&self) -> Link {
+        self.inner.link()
+    }
+
+    fn offloads(&self) -> Offloads {
+        self.inner.offloads()
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        self.inner.set_mtu(mtu)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), Error> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn pause_stats(&self) -> Option<PauseStats> {
+        self.inner.pause_stats()
+    }
+
+    fn set_mac_filter(&mut self, filter: &MacFilter) -> Result<(), Error> {
+        self.inner.set_mac_filter(filter)
+    }
+
+    fn add_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        self.inner.add_flow_rule(rule)
+    }
+
+    fn remove_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        self.inner.remove_flow_rule(rule)
+    }
+
+    fn redirect(&mut self, queue: u32, packet: IxyPacket) -> Result<(), IxyPacket> {
+        self.inner.redirect(queue, packet)
+    }
+
+    fn rx_metadata(&self, queue: u32, packet: &IxyPacket) -> Metadata {
+        self.inner.rx_metadata(queue, packet)
+    }
+
+    fn tx_offload(&mut self, queue: u32, packet: &mut IxyPacket, offload: TxOffload) {
+        self.inner.tx_offload(queue, packet, offload)
+    }
+
+    fn reclaim_tx(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, max: usize) -> usize {
+        self.inner.reclaim_tx(queue, buffer, max)
+    }
+
+    fn tx_in_flight(&mut self, queue: u32) -> Option<usize> {
+        // Delayed packets have not even reached the device yet.
+        let held = self.tx.held.iter().filter(|held| held.queue == queue).count();
+        let ready = self.tx_ready.get(queue as usize).map_or(0, VecDeque::len);
+        match self.inner.tx_in_flight(queue) {
+            Some(in_flight) => Some(in_flight + held + ready),
+            None if held + ready > 0 => Some(held + ready),
+            None => None,
+        }
+    }
+
+    fn wait_rx(&mut self, queue: u32, timeout: Duration) -> Result<(), Error> {
+        self.inner.wait_rx(queue, timeout)
+    }
+}
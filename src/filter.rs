@@ -0,0 +1,83 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The Ethernet destination addresses accepted by a device.
+///
+/// Devices which can not filter in hardware are filtered in software by the `Phy`. Since the ixy
+/// drivers enable promiscuous mode during initialization, the default filter accepts all frames.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MacFilter {
+    promiscuous: bool,
+    unicast: Vec<[u8; 6]>,
+    multicast: Vec<[u8; 6]>,
+}
+
+impl MacFilter {
+    /// Check if frames are accepted regardless of their destination.
+    pub fn promiscuous(&self) -> bool {
+        self.promiscuous
+    }
+
+    /// The accepted unicast addresses.
+    pub fn unicast(&self) -> &[[u8; 6]] {
+        &self.unicast
+    }
+
+    /// The accepted multicast addresses, in addition to broadcast.
+    pub fn multicast(&self) -> &[[u8; 6]] {
+        &self.multicast
+    }
+
+    /// Check if a frame passes the filter.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promiscuous {
+            return true;
+        }
+
+        let destination = match frame.get(..6) {
+            Some(destination) => destination,
+            None => return false,
+        };
+
+        if destination[0] & 1 == 0 {
+            self.unicast.iter().any(|addr| addr[..] == *destination)
+        } else {
+            destination == [0xff; 6] || self.multicast.iter().any(|addr| addr[..] == *destination)
+        }
+    }
+
+    pub(crate) fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.promiscuous = promiscuous;
+    }
+
+    pub(crate) fn unicast_mut(&mut self) -> &mut Vec<[u8; 6]> {
+        &mut self.unicast
+    }
+
+    pub(crate) fn multicast_mut(&mut self) -> &mut Vec<[u8; 6]> {
+        &mut self.multicast
+    }
+}
+
+impl Default for MacFilter {
+    fn default() -> Self {
+        MacFilter {
+            promiscuous: true,
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+        }
+    }
+}
+
+/// The Ethernet multicast address of an IPv4 multicast group.
+pub fn ipv4_multicast(group: Ipv4Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3]]
+}
+
+/// The Ethernet multicast address of an IPv6 multicast group.
+///
+/// Use the solicited-node group of the interface addresses to receive neighbor discovery.
+pub fn ipv6_multicast(group: Ipv6Addr) -> [u8; 6] {
+    let octets = group.octets();
+    [0x33, 0x33, octets[12], octets[13], octets[14], octets[15]]
+}
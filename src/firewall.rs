@@ -0,0 +1,456 @@
+//! A stateful packet filter in front of a phy.
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::read_u16;
+use crate::conntrack::Conntrack;
+use crate::flow::FiveTuple;
+use crate::frame::{self, Headers, IcmpError, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ipv4, ipv6};
+use crate::frame::{PROTO_ICMP, PROTO_ICMPV6, PROTO_TCP, PROTO_UDP};
+use crate::{Acl, AclMatch, AclRule, AclStats, Direction, Handle, Packet, Phy};
+use crate::{ConntrackTimeouts, Prefix, Queues};
+
+/// Matches IP packets by their direction, VLAN and flow, `None` fields match everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FirewallMatch {
+    /// Received or sent packets.
+    pub direction: Option<Direction>,
+
+    /// The VLAN identifier of the outermost tag in the frame, not of a stripped tag.
+    pub vlan: Option<u16>,
+    pub src: Option<Prefix>,
+    pub dst: Option<Prefix>,
+
+    /// The first and last source port.
+    pub src_ports: Option<(u16, u16)>,
+
+    /// The first and last destination port.
+    pub dst_ports: Option<(u16, u16)>,
+    pub protocol: Option<u8>,
+}
+
+/// What to do with the first packet of a flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FirewallAction {
+    /// Pass the packet and all later packets of its flow, in both directions.
+    Accept,
+
+    /// Discard the packet silently.
+    Drop,
+
+    /// Discard the packet and answer it with an ICMP destination unreachable error.
+    Reject,
+}
+
+/// A rule of a `Firewall`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FirewallRule {
+    pub matches: FirewallMatch,
+    pub action: FirewallAction,
+}
+
+/// Counters of a firewall.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FirewallStats {
+    /// Packets accepted by a rule or the policy.
+    pub accepted: u64,
+
+    /// Packets of tracked flows.
+    pub established: u64,
+
+    /// ICMP errors about tracked flows.
+    pub related: u64,
+
+    pub dropped: u64,
+    pub rejected: u64,
+}
+
+/// A stateful firewall between a phy and the network stack.
+///
+/// The IP packets received from and sent to the phy are checked against the rules in order, the
+/// action of the first match applies and the policy if none matches. The rules are compiled into
+/// an `Acl` whenever they change. Accepted flows are tracked,
+/// so that all later packets of the flow and ICMP errors about it pass in both directions without
+/// consulting the rules again. A flow is forgotten after it saw no packet for the timeout, or
+/// when the least recently used flow makes room for a new one. Frames other than IP, e.g. ARP,
+/// always pass.
+///
+/// Received packets are rejected towards their sender. Sent packets are rejected towards the
+/// network stack itself, which receives the error with the next received packets. The ICMP error
+/// has the original destination as its source.
+///
+/// Flows are only identified by their 5-tuple, e.g. TCP connections are not validated and are
+/// tracked until their timeout even after they closed.
+pub struct Firewall<D> {
+    phy: Phy<D>,
+    rules: Vec<FirewallRule>,
+    acl: Acl<FirewallAction>,
+    policy: FirewallAction,
+    flows: Conntrack<()>,
+    timeout: Duration,
+    /// Errors for the network stack about its rejected packets.
+    rejected: VecDeque<IxyPacket>,
+    last_sweep: Instant,
+    stats: FirewallStats,
+}
+
+impl<D> Firewall<D> {
+    /// The default time after which idle flows are forgotten.
+    pub const TIMEOUT: Duration = Duration::from_secs(300);
+
+    /// The default maximum number of tracked flows.
+    pub const CAPACITY: usize = 65536;
+
+    /// How often idle flows are removed.
+    const SWEEP: Duration = Duration::from_secs(1);
+
+    /// Filter the traffic of a phy, accepting everything until rules are added.
+    pub fn new(phy: Phy<D>) -> Self {
+        Firewall {
+            phy,
+            rules: Vec::new(),
+            acl: Acl::new(Vec::new()),
+            policy: FirewallAction::Accept,
+            flows: conntrack(Self::CAPACITY, Self::TIMEOUT),
+            timeout: Self::TIMEOUT,
+            rejected: VecDeque::new(),
+            last_sweep: Instant::now(),
+            stats: FirewallStats::default(),
+        }
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    pub fn rules(&self) -> &[FirewallRule] {
+        &self.rules
+    }
+
+    /// Add a rule after all others.
+    pub fn push_rule(&mut self, rule: FirewallRule) {
+        self.rules.push(rule);
+        self.compile();
+    }
+
+    /// Add a rule at a position, before the rule there.
+    ///
+    /// ## Panics
+    /// This function panics if `index` is greater than the number of rules.
+    pub fn insert_rule(&mut self, index: usize, rule: FirewallRule) {
+        self.rules.insert(index, rule);
+        self.compile();
+    }
+
+    /// Remove the rule at a position, flows it accepted remain tracked.
+    ///
+    /// ## Panics
+    /// This function panics if there is no rule at `index`.
+    pub fn remove_rule(&mut self, index: usize) -> FirewallRule {
+        let rule = self.rules.remove(index);
+        self.compile();
+        rule
+    }
+
+    /// The packets which were decided by the rule at a position, since the rules last changed.
+    ///
+    /// ## Panics
+    /// This function panics if there is no rule at `index`.
+    pub fn rule_stats(&self, index: usize) -> AclStats {
+        self.acl.stats(index)
+    }
+
+    /// The action for packets without a matching rule.
+    pub fn policy(&self) -> FirewallAction {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: FirewallAction) {
+        self.policy = policy;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.flows.set_timeouts(timeouts(timeout));
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.flows.capacity()
+    }
+
+    /// Limit the number of tracked flows, forgetting the least recently used ones above it.
+    ///
+    /// ## Panics
+    /// This function panics if the capacity is zero.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.flows.set_capacity(capacity);
+    }
+
+    /// The number of tracked flows, including idle ones not yet removed.
+    pub fn flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// The tracked flows and their counters.
+    pub fn conntrack(&self) -> &Conntrack<()> {
+        &self.flows
+    }
+
+    /// Forget all tracked flows, later packets are checked against the rules again.
+    pub fn clear_flows(&mut self) {
+        self.flows.clear();
+    }
+
+    pub fn stats(&self) -> FirewallStats {
+        self.stats
+    }
+
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+
+    /// Decide about a frame, tracking it if accepted.
+    fn verdict(&mut self, direction: Direction, frame: &[u8], now: Instant) -> FirewallAction {
+        let headers = match Headers::parse(frame) {
+            Some(headers) => headers,
+            // Frames too short for their headers.
+            None => return self.count(FirewallAction::Drop),
+        };
+        if headers.ethertype != ETHERTYPE_IPV4 && headers.ethertype != ETHERTYPE_IPV6 {
+            return FirewallAction::Accept;
+        }
+        let flow = match FiveTuple::from_headers(frame, &headers) {
+            Some(flow) => flow,
+            None => return FirewallAction::Accept,
+        };
+
+        let bytes = headers.end - headers.l3;
+        if self.flows.update(&flow, bytes, false, now).is_some() {
+            self.stats.established += 1;
+            return FirewallAction::Accept;
+        }
+
+        if let Some(about) = embedded(frame, &headers) {
+            let tracked = self.flows.get(&about);
+            if tracked.map_or(false, |connection| !self.flows.is_expired(connection, now)) {
+                self.stats.related += 1;
+                return FirewallAction::Accept;
+            }
+        }
+
+        let action = self.acl
+            .classify_flow(interface(direction), headers.vlan, &flow, frame.len())
+            .map_or(self.policy, |&action| action);
+        if action == FirewallAction::Accept {
+            self.flows.insert(flow, flow.reversed(), (), now);
+            self.flows.update(&flow, bytes, false, now);
+        }
+        self.count(action)
+    }
+
+    fn compile(&mut self) {
+        let rules = self.rules
+            .iter()
+            .map(|rule| AclRule { matches: rule.matches.into(), action: Some(rule.action) })
+            .collect();
+        self.acl = Acl::new(rules);
+    }
+
+    fn count(&mut self, action: FirewallAction) -> FirewallAction {
+        match action {
+            FirewallAction::Accept => self.stats.accepted += 1,
+            FirewallAction::Drop => self.stats.dropped += 1,
+            FirewallAction::Reject => self.stats.rejected += 1,
+        }
+        action
+    }
+
+    /// Remove idle flows about once a second.
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < Self::SWEEP {
+            return;
+        }
+        self.last_sweep = now;
+        self.flows.expire(now);
+    }
+}
+
+impl<D: Queues> Firewall<D> {
+    /// Receive up to `max` packets into the phy and filter them.
+    fn receive(&mut self, max: usize) {
+        let now = Instant::now();
+        self.sweep(now);
+
+        // Packets buffered from earlier calls have been checked already.
+        let checked = self.phy.rx_queue.len();
+        self.phy.get_rx(max);
+        let received = self.phy.rx_queue.split_off(checked);
+        for mut packet in received {
+            match self.verdict(Direction::Rx, &packet, now) {
+                FirewallAction::Accept => self.phy.rx_queue.push_back(packet),
+                FirewallAction::Drop => (),
+                FirewallAction::Reject => {
+                    if reject(&mut packet) {
+                        self.phy.tx_queue.push_back(packet);
+                    }
+                },
+            }
+        }
+
+        self.phy.rx_queue.extend(self.rejected.drain(..));
+    }
+
+    /// Filter the packets the network stack queued after the first `start`.
+    fn transmit(&mut self, start: usize) {
+        let now = Instant::now();
+        let sent = self.phy.tx_queue.split_off(start);
+        for mut packet in sent {
+            match self.verdict(Direction::Tx, &packet, now) {
+                FirewallAction::Accept => self.phy.tx_queue.push_back(packet),
+                FirewallAction::Drop => (),
+                FirewallAction::Reject => {
+                    if reject(&mut packet) {
+                        self.rejected.push_back(packet);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Run an operation of the network stack, then filter what it queued before sending.
+    fn deferred<T>(&mut self, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, start) = self.phy.deferred(op);
+        self.transmit(start);
+        self.phy.poll_flush();
+        result
+    }
+}
+
+impl FirewallMatch {
+    /// Check if a packet matches.
+    pub fn matches(&self, direction: Direction, vlan: Option<u16>, flow: &FiveTuple) -> bool {
+        let within = |ports: Option<(u16, u16)>, port: u16| {
+            ports.map_or(true, |(first, last)| first <= port && port <= last)
+        };
+        self.direction.map_or(true, |expected| expected == direction)
+            && self.vlan.map_or(true, |expected| Some(expected) == vlan)
+            && self.src.map_or(true, |src| src.contains(flow.src))
+            && self.dst.map_or(true, |dst| dst.contains(flow.dst))
+            && within(self.src_ports, flow.src_port)
+            && within(self.dst_ports, flow.dst_port)
+            && self.protocol.map_or(true, |protocol| protocol == flow.protocol)
+    }
+}
+
+impl From<FirewallMatch> for AclMatch {
+    fn from(matches: FirewallMatch) -> Self {
+        AclMatch {
+            interface: matches.direction.map(interface),
+            vlan: matches.vlan,
+            src: matches.src,
+            dst: matches.dst,
+            src_ports: matches.src_ports,
+            dst_ports: matches.dst_ports,
+            protocol: matches.protocol,
+        }
+    }
+}
+
+impl<D: Queues> nic::Device for Firewall<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.phy)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.tx(max, sender))
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.receive(max);
+        // Only offer the filtered packets, the phy must not fetch unchecked ones.
+        let count = max.min(self.phy.rx_queue.len());
+        self.deferred(|phy| phy.rx(count, receptor))
+    }
+}
+
+/// The interface of the classifier for a direction.
+fn interface(direction: Direction) -> usize {
+    match direction {
+        Direction::Rx => 0,
+        Direction::Tx => 1,
+    }
+}
+
+/// A table tracking all flows for the same timeout.
+fn conntrack(capacity: usize, timeout: Duration) -> Conntrack<()> {
+    let mut flows = Conntrack::new();
+    flows.set_capacity(capacity);
+    flows.set_timeouts(timeouts(timeout));
+    flows
+}
+
+fn timeouts(timeout: Duration) -> ConntrackTimeouts {
+    ConntrackTimeouts {
+        tcp_established: timeout,
+        tcp_transitory: timeout,
+        udp: timeout,
+        icmp: timeout,
+    }
+}
+
+/// Turn a packet into the ICMP error rejecting it.
+fn reject(packet: &mut IxyPacket) -> bool {
+    let udp = FiveTuple::from_frame(packet).map_or(false, |flow| flow.protocol == PROTO_UDP);
+    let error = if udp { IcmpError::PortUnreachable } else { IcmpError::Prohibited };
+    frame::icmp_error(packet, error, None)
+}
+
+/// The flow of the packet quoted by an ICMP error.
+fn embedded(frame: &[u8], headers: &Headers) -> Option<FiveTuple> {
+    let (l4, protocol) = headers.l4?;
+    let quote = l4 + 8;
+    let (src, dst, protocol, transport): (IpAddr, IpAddr, u8, usize) = match protocol {
+        // Destination unreachable, time exceeded and parameter problem.
+        PROTO_ICMP if matches!(*frame.get(l4)?, 3 | 11 | 12) => {
+            let ip = frame.get(quote..quote + 20)?;
+            let ihl = usize::from(ip[0] & 0x0f) * 4;
+            let (src, dst) = (ipv4(&ip[12..16]).into(), ipv4(&ip[16..20]).into());
+            (src, dst, ip[9], quote + ihl)
+        },
+        // The same and packet too big.
+        PROTO_ICMPV6 if matches!(*frame.get(l4)?, 1..=4) => {
+            let ip = frame.get(quote..quote + 40)?;
+            let (src, dst) = (ipv6(&ip[8..24]).into(), ipv6(&ip[24..40]).into());
+            (src, dst, ip[6], quote + 40)
+        },
+        _ => return None,
+    };
+
+    let (src_port, dst_port) = match protocol {
+        PROTO_TCP | PROTO_UDP if transport + 4 <= headers.end => {
+            (read_u16(frame, transport), read_u16(frame, transport + 2))
+        },
+        _ => (0, 0),
+    };
+    Some(FiveTuple { src, dst, src_port, dst_port, protocol })
+}
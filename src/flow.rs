@@ -0,0 +1,120 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::checksum::read_u16;
+use crate::frame::{Headers, ETHERTYPE_IPV4, ETHERTYPE_IPV6, PROTO_TCP, PROTO_UDP};
+
+/// The 5-tuple identifying the flow of an IP packet.
+///
+/// Ports are zero for protocols other than TCP and UDP, and for non-initial fragments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FiveTuple {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+
+/// Matches packets by fields of their 5-tuple, `None` fields match everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FlowMatch {
+    pub src: Option<IpAddr>,
+    pub dst: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub protocol: Option<u8>,
+}
+
+/// What to do with packets of a matching flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FlowAction {
+    /// Deliver the packets on a specific rx queue.
+    Queue(u32),
+
+    /// Discard the packets.
+    Drop,
+}
+
+/// A flow steering rule, see `Phy::add_flow_rule`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowRule {
+    pub matches: FlowMatch,
+    pub action: FlowAction,
+}
+
+impl FiveTuple {
+    /// The flow of an Ethernet frame, if it contains an IP packet.
+    pub fn from_frame(frame: &[u8]) -> Option<Self> {
+        Self::from_headers(frame, &Headers::parse(frame)?)
+    }
+
+    pub(crate) fn from_headers(frame: &[u8], headers: &Headers) -> Option<Self> {
+        let l3 = headers.l3;
+        let (src, dst, protocol) = match headers.ethertype {
+            ETHERTYPE_IPV4 => {
+                let src = ipv4(&frame[l3 + 12..l3 + 16]);
+                let dst = ipv4(&frame[l3 + 16..l3 + 20]);
+                (src.into(), dst.into(), frame[l3 + 9])
+            },
+            ETHERTYPE_IPV6 => {
+                let src = ipv6(&frame[l3 + 8..l3 + 24]);
+                let dst = ipv6(&frame[l3 + 24..l3 + 40]);
+                (src.into(), dst.into(), frame[l3 + 6])
+            },
+            _ => return None,
+        };
+
+        let (src_port, dst_port) = match headers.l4 {
+            Some((l4, PROTO_TCP)) | Some((l4, PROTO_UDP)) if l4 + 4 <= headers.end => {
+                (read_u16(frame, l4), read_u16(frame, l4 + 2))
+            },
+            _ => (0, 0),
+        };
+
+        Some(FiveTuple { src, dst, src_port, dst_port, protocol })
+    }
+
+    /// The flow of packets in the opposite direction.
+    pub fn reversed(&self) -> Self {
+        FiveTuple {
+            src: self.dst,
+            dst: self.src,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+        }
+    }
+}
+
+impl FlowMatch {
+    /// Check if a flow matches.
+    pub fn matches(&self, flow: &FiveTuple) -> bool {
+        self.src.map_or(true, |src| src == flow.src)
+            && self.dst.map_or(true, |dst| dst == flow.dst)
+            && self.src_port.map_or(true, |port| port == flow.src_port)
+            && self.dst_port.map_or(true, |port| port == flow.dst_port)
+            && self.protocol.map_or(true, |protocol| protocol == flow.protocol)
+    }
+}
+
+impl From<FiveTuple> for FlowMatch {
+    fn from(flow: FiveTuple) -> Self {
+        FlowMatch {
+            src: Some(flow.src),
+            dst: Some(flow.dst),
+            src_port: Some(flow.src_port),
+            dst_port: Some(flow.dst_port),
+            protocol: Some(flow.protocol),
+        }
+    }
+}
+
+fn ipv4(bytes: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(bytes);
+    Ipv6Addr::from(octets)
+}
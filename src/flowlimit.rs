@@ -0,0 +1,202 @@
+//! Policing individual flows or aggregates of them with a token bucket each.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use crate::conntrack::{Conntrack, ConntrackStats};
+use crate::flow::FiveTuple;
+use crate::frame::Headers;
+use crate::rate::Bucket;
+use crate::{ConntrackTimeouts, Prefix, Rate, RateLimitStats};
+
+/// How packets are grouped into the flows of a flow limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FlowKey {
+    /// Each 5-tuple, the directions of a connection are separate flows.
+    Flow,
+
+    /// All packets from the same prefix, of the given length for IPv4 and IPv6 respectively.
+    Source { v4: u8, v6: u8 },
+
+    /// All packets to the same prefix, of the given length for IPv4 and IPv6 respectively.
+    Destination { v4: u8, v6: u8 },
+}
+
+/// Token bucket policers for each flow, e.g. to cap the traffic of single hosts.
+///
+/// Every flow, or aggregate of flows with the same source or destination prefix, gets its own
+/// bucket with the same rate when its first packet is seen. A packet passes if the bucket of its
+/// flow has enough tokens, otherwise it should be dropped. Frames other than IP always pass.
+///
+/// The buckets are kept in a connection table: a full table evicts the least recently used
+/// flow, and flows idle for the timeout are removed, starting over with a full bucket. The
+/// connections count all packets of their flow, including those dropped.
+pub struct FlowLimit {
+    key: FlowKey,
+    rate: Rate,
+    timeout: Duration,
+    flows: Conntrack<Bucket>,
+    last_sweep: Instant,
+    stats: RateLimitStats,
+}
+
+impl FlowLimit {
+    /// The default time after which an idle flow is removed.
+    pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// How often idle flows are removed.
+    const SWEEP: Duration = Duration::from_secs(1);
+
+    /// Limit each flow, grouped by the key, to the rate.
+    ///
+    /// ## Panics
+    /// This function panics if a prefix length of the key exceeds the bits of its address.
+    pub fn new(key: FlowKey, rate: Rate) -> Self {
+        match key {
+            FlowKey::Flow => (),
+            FlowKey::Source { v4, v6 } | FlowKey::Destination { v4, v6 } => {
+                assert!(v4 <= 32 && v6 <= 128, "Prefix longer than the address");
+            },
+        }
+
+        let mut flows = Conntrack::new();
+        flows.set_timeouts(timeouts(Self::TIMEOUT));
+        FlowLimit {
+            key,
+            rate,
+            timeout: Self::TIMEOUT,
+            flows,
+            last_sweep: Instant::now(),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    pub fn key(&self) -> FlowKey {
+        self.key
+    }
+
+    pub fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// Change the rate of all flows, starting over with full buckets.
+    pub fn set_rate(&mut self, rate: Rate) {
+        self.rate = rate;
+        self.flows.clear();
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Remove flows after they saw no packet for the timeout.
+    ///
+    /// The timeout should be at least the time to fill an empty bucket, otherwise a flow can
+    /// reset its bucket by pausing.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        self.flows.set_timeouts(timeouts(timeout));
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.flows.capacity()
+    }
+
+    /// Limit the number of tracked flows, evicting the least recently used ones above it.
+    ///
+    /// ## Panics
+    /// This function panics if the capacity is zero.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.flows.set_capacity(capacity);
+    }
+
+    /// The number of tracked flows, including idle ones not yet removed.
+    pub fn flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Remove all flows, starting over with full buckets.
+    pub fn clear_flows(&mut self) {
+        self.flows.clear();
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        self.stats
+    }
+
+    /// The counters of the table of flows.
+    pub fn conntrack_stats(&self) -> ConntrackStats {
+        self.flows.stats()
+    }
+
+    /// Check if a frame conforms to the rate of its flow, taking its tokens if it does.
+    pub fn police(&mut self, frame: &[u8]) -> bool {
+        let now = Instant::now();
+        self.sweep(now);
+
+        let headers = match Headers::parse(frame) {
+            Some(headers) => headers,
+            None => return true,
+        };
+        let flow = match FiveTuple::from_headers(frame, &headers) {
+            Some(flow) => flow,
+            None => return true,
+        };
+        let key = self.aggregate(flow);
+        let bytes = headers.end - headers.l3;
+
+        // Aggregates have no replies, their reply flow is the same key.
+        if self.flows.update(&key, bytes, false, now).is_none() {
+            self.flows.insert(key, key, Bucket::new(self.rate), now);
+            self.flows.update(&key, bytes, false, now);
+        }
+        let bucket = &mut self.flows.get_mut(&key).unwrap().data;
+        bucket.fill(now);
+        if bucket.take(frame.len()) {
+            self.stats.passed += 1;
+            true
+        } else {
+            self.stats.dropped += 1;
+            self.stats.dropped_bytes += frame.len() as u64;
+            false
+        }
+    }
+
+    /// The flow of the bucket of a packet.
+    fn aggregate(&self, flow: FiveTuple) -> FiveTuple {
+        let (addr, v4, v6) = match self.key {
+            FlowKey::Flow => return flow,
+            FlowKey::Source { v4, v6 } => (flow.src, v4, v6),
+            FlowKey::Destination { v4, v6 } => (flow.dst, v4, v6),
+        };
+        let (prefix, unspecified) = match addr {
+            IpAddr::V4(_) => (Prefix::new(addr, v4), IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpAddr::V6(_) => (Prefix::new(addr, v6), IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        FiveTuple {
+            src: prefix.addr(),
+            dst: unspecified,
+            src_port: 0,
+            dst_port: 0,
+            protocol: 0,
+        }
+    }
+
+    /// Remove idle flows about once a second.
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < Self::SWEEP {
+            return;
+        }
+        self.last_sweep = now;
+        self.flows.expire(now);
+    }
+}
+
+/// The same timeout for all protocols.
+fn timeouts(timeout: Duration) -> ConntrackTimeouts {
+    ConntrackTimeouts {
+        tcp_established: timeout,
+        tcp_transitory: timeout,
+        udp: timeout,
+        icmp: timeout,
+    }
+}
@@ -0,0 +1,500 @@
+//! Minimal parsing of Ethernet frames for the software packet paths.
+//!
+//! This only locates headers by their offsets, validation beyond what is required to stay in
+//! bounds is left to the network stack.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::{self, read_u16, write_u16};
+
+pub(crate) const ETHERNET_HEADER: usize = 14;
+
+/// The minimum length of an Ethernet frame, without the frame check sequence.
+pub(crate) const MIN_FRAME: usize = 60;
+
+pub(crate) const BROADCAST: [u8; 6] = [0xff; 6];
+
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_ARP: u16 = 0x0806;
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+pub(crate) const ETHERTYPE_QINQ: u16 = 0x88a8;
+pub(crate) const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub(crate) const ETHERTYPE_MPLS: u16 = 0x8847;
+
+pub(crate) const PROTO_ICMP: u8 = 1;
+pub(crate) const PROTO_TCP: u8 = 6;
+pub(crate) const PROTO_UDP: u8 = 17;
+pub(crate) const PROTO_GRE: u8 = 47;
+pub(crate) const PROTO_ICMPV6: u8 = 58;
+
+/// The offsets of the headers within an Ethernet frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Headers {
+    /// The offset of the network layer header, after all VLAN tags.
+    pub l3: usize,
+
+    /// The ethertype of the network layer.
+    pub ethertype: u16,
+
+    /// The VLAN identifier of the outermost tag.
+    pub vlan: Option<u16>,
+
+    /// The offset and protocol number of the transport header of unfragmented IP packets.
+    pub l4: Option<(usize, u8)>,
+
+    /// The end of the IP packet within the frame, excluding Ethernet padding.
+    pub end: usize,
+}
+
+impl Headers {
+    /// Locate the headers of a frame.
+    ///
+    /// Returns `None` if the frame is too short for its Ethernet or IP headers.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETHERNET_HEADER {
+            return None;
+        }
+
+        let mut l3 = ETHERNET_HEADER;
+        let mut ethertype = read_u16(frame, 12);
+        let mut vlan = None;
+        while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+            if frame.len() < l3 + 4 {
+                return None;
+            }
+            vlan = vlan.or(Some(read_u16(frame, l3) & 0x0fff));
+            ethertype = read_u16(frame, l3 + 2);
+            l3 += 4;
+        }
+
+        let mut headers = Headers { l3, ethertype, vlan, l4: None, end: frame.len() };
+        match ethertype {
+            ETHERTYPE_IPV4 => {
+                let ip = frame.get(l3..)?;
+                if ip.len() < 20 || ip[0] >> 4 != 4 {
+                    return None;
+                }
+                let ihl = usize::from(ip[0] & 0x0f) * 4;
+                let total = usize::from(read_u16(ip, 2));
+                if ihl < 20 || total < ihl || total > ip.len() {
+                    return None;
+                }
+                headers.end = l3 + total;
+                // Only the first fragment without more fragments has a complete transport header.
+                let fragment = read_u16(ip, 6) & 0x3fff;
+                if fragment == 0 {
+                    headers.l4 = Some((l3 + ihl, ip[9]));
+                }
+            },
+            ETHERTYPE_IPV6 => {
+                let ip = frame.get(l3..)?;
+                if ip.len() < 40 || ip[0] >> 4 != 6 {
+                    return None;
+                }
+                let total = 40 + usize::from(read_u16(ip, 4));
+                if total > ip.len() {
+                    return None;
+                }
+                headers.end = l3 + total;
+                // Extension headers are not traversed.
+                headers.l4 = Some((l3 + 40, ip[6]));
+            },
+            _ => (),
+        }
+
+        Some(headers)
+    }
+
+    /// The offset of the transport header if it has the given protocol number.
+    pub fn transport(&self, protocol: u8) -> Option<usize> {
+        match self.l4 {
+            Some((offset, proto)) if proto == protocol => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// The checksum sum of the IP pseudo header for a transport payload of `len` bytes.
+    pub fn pseudo_header(&self, frame: &[u8], protocol: u8, len: usize) -> u32 {
+        let addresses = match self.ethertype {
+            ETHERTYPE_IPV4 => &frame[self.l3 + 12..self.l3 + 20],
+            ETHERTYPE_IPV6 => &frame[self.l3 + 8..self.l3 + 40],
+            _ => &[],
+        };
+        let acc = checksum::sum(addresses, u32::from(protocol));
+        acc.wrapping_add(len as u32)
+    }
+}
+
+/// An Ethernet frame with IPv4, UDP and their headers.
+pub(crate) const UDP_IPV4_HEADERS: usize = ETHERNET_HEADER + 20 + 8;
+
+pub(crate) const ARP_REQUEST: u16 = 1;
+pub(crate) const ARP_REPLY: u16 = 2;
+
+/// The fields of an ARP packet for IPv4 over Ethernet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Arp {
+    pub operation: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Arp {
+    /// Parse the ARP packet of an untagged frame.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        if frame.len() < ETHERNET_HEADER + 28 || read_u16(frame, 12) != ETHERTYPE_ARP {
+            return None;
+        }
+
+        let arp = &frame[ETHERNET_HEADER..];
+        let ethernet_ipv4 = read_u16(arp, 0) == 1 && read_u16(arp, 2) == ETHERTYPE_IPV4;
+        if !ethernet_ipv4 || arp[4] != 6 || arp[5] != 4 {
+            return None;
+        }
+
+        let mut sender_mac = [0; 6];
+        sender_mac.copy_from_slice(&arp[8..14]);
+        Some(Arp {
+            operation: read_u16(arp, 6),
+            sender_mac,
+            sender_ip: ipv4(&arp[14..18]),
+            target_ip: ipv4(&arp[24..28]),
+        })
+    }
+
+    /// The reply to this request, answering that its target address is at `mac`.
+    pub fn reply(&self, mac: [u8; 6]) -> [u8; MIN_FRAME] {
+        arp(self.sender_mac, ARP_REPLY, (mac, self.target_ip), (self.sender_mac, self.sender_ip))
+    }
+}
+
+/// An ARP request from `mac` and `ip` for the address of `target`.
+pub(crate) fn arp_request(mac: [u8; 6], ip: Ipv4Addr, target: Ipv4Addr) -> [u8; MIN_FRAME] {
+    arp(BROADCAST, ARP_REQUEST, (mac, ip), ([0; 6], target))
+}
+
+/// A gratuitous ARP request announcing that `ip` is at `mac`, padded to the minimum length.
+pub(crate) fn gratuitous_arp(mac: [u8; 6], ip: Ipv4Addr) -> [u8; MIN_FRAME] {
+    // The target hardware address is ignored, the target protocol address is the sender's.
+    arp(BROADCAST, ARP_REQUEST, (mac, ip), ([0; 6], ip))
+}
+
+/// The length of an unsolicited neighbor advertisement with the target link-layer address.
+pub(crate) const UNSOLICITED_NA: usize = ETHERNET_HEADER + 40 + 24 + 8;
+
+/// An unsolicited neighbor advertisement to all nodes announcing that `ip` is at `mac`.
+///
+/// The override flag is set so that existing cache entries are updated.
+pub(crate) fn unsolicited_na(mac: [u8; 6], ip: Ipv6Addr) -> [u8; UNSOLICITED_NA] {
+    let mut frame = [0; UNSOLICITED_NA];
+    frame[0..6].copy_from_slice(&[0x33, 0x33, 0, 0, 0, 1]);
+    frame[6..12].copy_from_slice(&mac);
+    write_u16(&mut frame, 12, ETHERTYPE_IPV6);
+
+    let (header, icmp) = frame[ETHERNET_HEADER..].split_at_mut(40);
+    // Hop limit 255, as neighbor discovery requires.
+    header[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 32, PROTO_ICMPV6, 255]);
+    header[8..24].copy_from_slice(&ip.octets());
+    header[24..40].copy_from_slice(&Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1).octets());
+
+    icmp[0] = 136;
+    icmp[4] = 0x20;
+    icmp[8..24].copy_from_slice(&ip.octets());
+    // The target link-layer address option.
+    icmp[24..26].copy_from_slice(&[2, 1]);
+    icmp[26..32].copy_from_slice(&mac);
+    let pseudo = checksum::sum(&header[8..40], u32::from(PROTO_ICMPV6) + 32);
+    let sum = checksum::finish(checksum::sum(icmp, pseudo));
+    write_u16(icmp, 2, sum);
+    frame
+}
+
+/// An ARP packet between a sender and a target, padded to the minimum length.
+fn arp(
+    destination: [u8; 6],
+    operation: u16,
+    (sender_mac, sender_ip): ([u8; 6], Ipv4Addr),
+    (target_mac, target_ip): ([u8; 6], Ipv4Addr),
+) -> [u8; MIN_FRAME] {
+    let mut frame = [0; MIN_FRAME];
+    frame[0..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&sender_mac);
+    write_u16(&mut frame, 12, ETHERTYPE_ARP);
+
+    let arp = &mut frame[ETHERNET_HEADER..];
+    // Ethernet and IPv4 addresses.
+    write_u16(arp, 0, 1);
+    write_u16(arp, 2, ETHERTYPE_IPV4);
+    arp[4] = 6;
+    arp[5] = 4;
+    write_u16(arp, 6, operation);
+    arp[8..14].copy_from_slice(&sender_mac);
+    arp[14..18].copy_from_slice(&sender_ip.octets());
+    arp[18..24].copy_from_slice(&target_mac);
+    arp[24..28].copy_from_slice(&target_ip.octets());
+    frame
+}
+
+/// Write the Ethernet, IPv4 and UDP headers of a datagram filling the rest of the frame.
+///
+/// The UDP checksum is left zero, which IPv4 permits. Fragmentation is forbidden, the frame must
+/// fit the path.
+pub(crate) fn write_udp_ipv4(
+    frame: &mut [u8],
+    source_mac: [u8; 6],
+    destination_mac: [u8; 6],
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+) {
+    let addresses = (*source.ip(), *destination.ip());
+    write_ipv4(frame, source_mac, destination_mac, addresses, PROTO_UDP);
+
+    let ip_len = frame.len() - ETHERNET_HEADER;
+    let udp = &mut frame[ETHERNET_HEADER + 20..];
+    write_u16(udp, 0, source.port());
+    write_u16(udp, 2, destination.port());
+    write_u16(udp, 4, (ip_len - 20) as u16);
+    write_u16(udp, 6, 0);
+}
+
+/// Write the Ethernet and IPv4 headers of a packet filling the rest of the frame.
+///
+/// Fragmentation is forbidden, the frame must fit the path.
+pub(crate) fn write_ipv4(
+    frame: &mut [u8],
+    source_mac: [u8; 6],
+    destination_mac: [u8; 6],
+    (source, destination): (Ipv4Addr, Ipv4Addr),
+    protocol: u8,
+) {
+    frame[0..6].copy_from_slice(&destination_mac);
+    frame[6..12].copy_from_slice(&source_mac);
+    write_u16(frame, 12, ETHERTYPE_IPV4);
+
+    let ip_len = frame.len() - ETHERNET_HEADER;
+    let ip = &mut frame[ETHERNET_HEADER..];
+    ip[0] = 0x45;
+    ip[1] = 0;
+    write_u16(ip, 2, ip_len as u16);
+    write_u16(ip, 4, 0);
+    // Don't fragment.
+    write_u16(ip, 6, 0x4000);
+    ip[8] = 64;
+    ip[9] = protocol;
+    write_u16(ip, 10, 0);
+    ip[12..16].copy_from_slice(&source.octets());
+    ip[16..20].copy_from_slice(&destination.octets());
+    let header = checksum::finish(checksum::sum(&ip[..20], 0));
+    write_u16(ip, 10, header);
+}
+
+/// The IPv4 address in the first four bytes.
+pub(crate) fn ipv4(octets: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3])
+}
+
+/// The IPv6 address in the first sixteen bytes.
+pub(crate) fn ipv6(octets: &[u8]) -> Ipv6Addr {
+    let mut addr = [0; 16];
+    addr.copy_from_slice(&octets[..16]);
+    Ipv6Addr::from(addr)
+}
+
+/// Set the DSCP of an IP packet, keeping its ECN bits.
+///
+/// Returns `false` if the frame is not an IP packet.
+pub(crate) fn set_dscp(frame: &mut [u8], dscp: u8) -> bool {
+    let headers = match Headers::parse(frame) {
+        Some(headers) => headers,
+        None => return false,
+    };
+
+    let (l3, dscp) = (headers.l3, dscp & 0x3f);
+    match headers.ethertype {
+        ETHERTYPE_IPV4 => {
+            let old = read_u16(frame, l3);
+            frame[l3 + 1] = dscp << 2 | frame[l3 + 1] & 0x03;
+            let sum = checksum::update(read_u16(frame, l3 + 10), old, read_u16(frame, l3));
+            write_u16(frame, l3 + 10, sum);
+        },
+        // The traffic class straddles the first two bytes.
+        ETHERTYPE_IPV6 => {
+            frame[l3] = frame[l3] & 0xf0 | dscp >> 2;
+            frame[l3 + 1] = (dscp & 0x03) << 6 | frame[l3 + 1] & 0x3f;
+        },
+        _ => return false,
+    }
+    true
+}
+
+/// Set the ECN field of an IP packet to congestion experienced.
+///
+/// Returns `None` if the frame is not an IP packet, and `false` if the packet is not ECN-capable
+/// so that congestion must be signalled by dropping it instead.
+pub(crate) fn set_ecn_ce(frame: &mut [u8]) -> Option<bool> {
+    let headers = Headers::parse(frame)?;
+    let l3 = headers.l3;
+    match headers.ethertype {
+        ETHERTYPE_IPV4 => {
+            if frame[l3 + 1] & 0x03 == 0 {
+                return Some(false);
+            }
+            let old = read_u16(frame, l3);
+            frame[l3 + 1] |= 0x03;
+            let sum = checksum::update(read_u16(frame, l3 + 10), old, read_u16(frame, l3));
+            write_u16(frame, l3 + 10, sum);
+        },
+        // The ECN bits end the traffic class, in the second byte.
+        ETHERTYPE_IPV6 => {
+            if frame[l3 + 1] & 0x30 == 0 {
+                return Some(false);
+            }
+            frame[l3 + 1] |= 0x30;
+        },
+        _ => return None,
+    }
+    Some(true)
+}
+
+/// The ICMP errors sent in reply to a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum IcmpError {
+    /// No route to the destination network.
+    NetUnreachable,
+    PortUnreachable,
+    /// Communication administratively prohibited.
+    Prohibited,
+    TimeExceeded,
+    /// The packet exceeds the MTU of the next hop, and must not be fragmented.
+    TooBig(u16),
+}
+
+impl IcmpError {
+    /// The type and code for IPv4 or ICMPv6.
+    fn code(self, v6: bool) -> (u8, u8) {
+        match (self, v6) {
+            (IcmpError::NetUnreachable, false) => (3, 0),
+            (IcmpError::PortUnreachable, false) => (3, 3),
+            (IcmpError::Prohibited, false) => (3, 13),
+            (IcmpError::TimeExceeded, false) => (11, 0),
+            (IcmpError::TooBig(_), false) => (3, 4),
+            (IcmpError::NetUnreachable, true) => (1, 0),
+            (IcmpError::PortUnreachable, true) => (1, 4),
+            (IcmpError::Prohibited, true) => (1, 1),
+            (IcmpError::TimeExceeded, true) => (3, 0),
+            (IcmpError::TooBig(_), true) => (2, 0),
+        }
+    }
+}
+
+/// Replace an IP packet with an ICMP error for its sender, in place.
+///
+/// The error quotes the IP header and the first 8 bytes of the payload, and is sent with swapped
+/// Ethernet addresses from `source`, or the original destination if there is none. As required,
+/// no error is generated for ICMP errors or packets to multicast or broadcast addresses, and
+/// `false` is returned. A `source` of the other IP version is ignored.
+pub(crate) fn icmp_error(packet: &mut IxyPacket, error: IcmpError, source: Option<IpAddr>) -> bool {
+    let headers = match Headers::parse(packet) {
+        Some(headers) if headers.vlan.is_none() => headers,
+        _ => return false,
+    };
+    if packet[0] & 1 != 0 {
+        return false;
+    }
+
+    let (l3, end) = (headers.l3, headers.end);
+    let (v6, header, addresses) = match headers.ethertype {
+        ETHERTYPE_IPV4 => (false, usize::from(packet[l3] & 0x0f) * 4, 12..20),
+        ETHERTYPE_IPV6 => (true, 40, 8..40),
+        _ => return false,
+    };
+    let destination = l3 + addresses.start + (addresses.end - addresses.start) / 2;
+    let multicast = if v6 {
+        packet[destination] == 0xff
+    } else {
+        packet[destination] >= 224
+    };
+    if multicast || is_icmp_error(packet, &headers) {
+        return false;
+    }
+
+    let mut quote = [0; 68];
+    let quoted = (end - l3).min(header + 8);
+    quote[..quoted].copy_from_slice(&packet[l3..l3 + quoted]);
+
+    let ip_len = if v6 { 40 } else { 20 };
+    let len = ETHERNET_HEADER + ip_len + 8 + quoted;
+    if packet.try_resize(len.max(MIN_FRAME), 0u8).is_err() {
+        return false;
+    }
+
+    let mut macs = [0; 12];
+    macs.copy_from_slice(&packet[..12]);
+    packet[..6].copy_from_slice(&macs[6..]);
+    packet[6..12].copy_from_slice(&macs[..6]);
+    let ethertype = headers.ethertype;
+    write_u16(packet, 12, ethertype);
+
+    let (icmp_type, code) = error.code(v6);
+    let ip = &mut packet[ETHERNET_HEADER..len];
+    let quoted_addresses = &quote[addresses.start..addresses.end];
+    let (from, original) = quoted_addresses.split_at(quoted_addresses.len() / 2);
+    let mut sender = [0; 16];
+    let to = match source {
+        Some(IpAddr::V4(addr)) if !v6 => {
+            sender[..4].copy_from_slice(&addr.octets());
+            &sender[..4]
+        },
+        Some(IpAddr::V6(addr)) if v6 => {
+            sender.copy_from_slice(&addr.octets());
+            &sender[..]
+        },
+        _ => original,
+    };
+    let payload = 8 + quoted;
+    if v6 {
+        ip[..8].copy_from_slice(&[0x60, 0, 0, 0, 0, 0, PROTO_ICMPV6, 64]);
+        write_u16(ip, 4, payload as u16);
+        ip[8..24].copy_from_slice(to);
+        ip[24..40].copy_from_slice(from);
+    } else {
+        ip[..12].copy_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 64, PROTO_ICMP, 0, 0]);
+        write_u16(ip, 2, (20 + payload) as u16);
+        ip[12..16].copy_from_slice(to);
+        ip[16..20].copy_from_slice(from);
+        let sum = checksum::finish(checksum::sum(&ip[..20], 0));
+        write_u16(ip, 10, sum);
+    }
+
+    let (ip_header, icmp) = ip.split_at_mut(ip_len);
+    icmp[..8].copy_from_slice(&[icmp_type, code, 0, 0, 0, 0, 0, 0]);
+    if let IcmpError::TooBig(mtu) = error {
+        write_u16(icmp, 6, mtu);
+    }
+    icmp[8..].copy_from_slice(&quote[..quoted]);
+    // Only ICMPv6 includes the pseudo header.
+    let pseudo = if v6 {
+        checksum::sum(&ip_header[8..40], u32::from(PROTO_ICMPV6) + payload as u32)
+    } else {
+        0
+    };
+    let sum = checksum::finish(checksum::sum(icmp, pseudo));
+    write_u16(icmp, 2, sum);
+    true
+}
+
+/// Check if an IP packet is an ICMP error, which must not cause another one.
+fn is_icmp_error(frame: &[u8], headers: &Headers) -> bool {
+    match headers.l4 {
+        Some((l4, PROTO_ICMP)) if l4 < headers.end => match frame[l4] {
+            3 | 4 | 5 | 11 | 12 => true,
+            _ => false,
+        },
+        Some((l4, PROTO_ICMPV6)) if l4 < headers.end => frame[l4] < 128,
+        // Fragments after the first may belong to an error.
+        None => true,
+        _ => false,
+    }
+}
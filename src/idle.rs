@@ -0,0 +1,92 @@
+//! Backing off a busy polling loop without traffic.
+use std::time::Duration;
+
+/// What a polling loop does while there is no traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum IdleStrategy {
+    /// Poll again immediately, the lowest latency.
+    Spin,
+
+    /// Hint the CPU that this is a spin loop, saving power and freeing the sibling hyperthread.
+    Pause,
+
+    /// Yield the core to other threads.
+    Yield,
+
+    /// Sleep, doubling the duration from `min` up to `max` while the loop stays idle.
+    Sleep {
+        min: Duration,
+        max: Duration,
+    },
+}
+
+/// Applies an idle strategy after consecutive polls without work.
+///
+/// The strategy only engages after `threshold` polls in a row found no packets, and any poll with
+/// packets disengages it immediately, so the latency under load is unaffected.
+#[derive(Clone, Copy, Debug)]
+pub struct Idler {
+    strategy: IdleStrategy,
+    threshold: u32,
+    empty: u32,
+    sleep: Duration,
+}
+
+impl Idler {
+    /// The default number of empty polls before idling.
+    pub const THRESHOLD: u32 = 64;
+
+    pub fn new(strategy: IdleStrategy) -> Self {
+        Idler {
+            strategy,
+            threshold: Self::THRESHOLD,
+            empty: 0,
+            sleep: Duration::from_secs(0),
+        }
+    }
+
+    /// Set the number of consecutive empty polls before idling.
+    pub fn threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    pub fn strategy(&self) -> IdleStrategy {
+        self.strategy
+    }
+
+    /// If the strategy is currently engaged.
+    pub fn is_idle(&self) -> bool {
+        self.empty >= self.threshold
+    }
+
+    /// Account for a poll, idling if there was no work for long enough.
+    pub fn poll(&mut self, work: bool) {
+        if work {
+            self.empty = 0;
+            self.sleep = Duration::from_secs(0);
+            return;
+        }
+
+        self.empty = self.empty.saturating_add(1);
+        if !self.is_idle() {
+            return;
+        }
+
+        match self.strategy {
+            IdleStrategy::Spin => (),
+            IdleStrategy::Pause => std::hint::spin_loop(),
+            IdleStrategy::Yield => std::thread::yield_now(),
+            IdleStrategy::Sleep { min, max } => {
+                self.sleep = (self.sleep * 2).max(min).min(max);
+                std::thread::sleep(self.sleep);
+            },
+        }
+    }
+}
+
+impl Default for Idler {
+    fn default() -> Self {
+        Idler::new(IdleStrategy::Spin)
+    }
+}
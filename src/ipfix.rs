@@ -0,0 +1,345 @@
+//! Exporting the connections of a connection table as IPFIX flow records.
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::conntrack::{Connection, Conntrack};
+use crate::flow::FiveTuple;
+
+/// Counters of an IPFIX exporter.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IpfixStats {
+    /// Messages sent to the collector, including those with templates only.
+    pub messages: u64,
+
+    /// Flow records sent to the collector.
+    pub records: u64,
+
+    /// Flow records lost because their message could not be sent.
+    pub dropped: u64,
+}
+
+/// Sends the flows of connection tables to an IPFIX collector over UDP.
+///
+/// Each connection is a flow record of its original direction and, once it saw replies, one of
+/// its reply direction, with the total packets and IP bytes, the times of the first and last
+/// packet and why the record was exported. Records of active connections are exported about once
+/// per interval from `export`, records of removed connections are queued with `push`, e.g. from
+/// `Conntrack::expire_with`, and sent with the next export.
+///
+/// The records use one template for IPv4 and one for IPv6, which are sent before the first
+/// records and again after each template interval since collectors forget them. Messages are
+/// split to fit into an Ethernet frame.
+pub struct Ipfix {
+    socket: UdpSocket,
+    domain: u32,
+    interval: Duration,
+    template_interval: Duration,
+    /// The same moment as an instant and the system time, to convert instants.
+    base: (Instant, SystemTime),
+    last_export: Option<Instant>,
+    last_template: Option<Instant>,
+    /// The number of data records sent, the sequence number of the next message.
+    sequence: u32,
+    /// The encoded records waiting for the next message, of IPv4 and IPv6 respectively.
+    v4: Vec<u8>,
+    v6: Vec<u8>,
+    stats: IpfixStats,
+}
+
+/// Why a flow record was exported, the values of the information element `flowEndReason`.
+#[derive(Clone, Copy)]
+enum EndReason {
+    Idle = 1,
+    Active = 2,
+    End = 3,
+}
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET: u16 = 2;
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+
+/// The message and set headers.
+const MESSAGE_HEADER: usize = 16;
+const SET_HEADER: usize = 4;
+
+/// The largest message, to fit an IPv4 and UDP packet into an Ethernet frame.
+const MAX_MESSAGE: usize = 1400;
+
+/// The information elements and their lengths of both templates, the addresses first.
+const FIELDS: [(u16, u16); 8] = [
+    // sourceTransportPort, destinationTransportPort, protocolIdentifier
+    (7, 2), (11, 2), (4, 1),
+    // packetTotalCount, octetTotalCount
+    (86, 8), (85, 8),
+    // flowStartMilliseconds, flowEndMilliseconds, flowEndReason
+    (152, 8), (153, 8), (136, 1),
+];
+
+/// sourceIPv4Address, destinationIPv4Address
+const ADDRESSES_V4: [(u16, u16); 2] = [(8, 4), (12, 4)];
+
+/// sourceIPv6Address, destinationIPv6Address
+const ADDRESSES_V6: [(u16, u16); 2] = [(27, 16), (28, 16)];
+
+/// The length of a record without its addresses.
+const RECORD: usize = 2 + 2 + 1 + 8 + 8 + 8 + 8 + 1;
+
+impl Ipfix {
+    /// The default interval between exports of active connections.
+    pub const INTERVAL: Duration = Duration::from_secs(60);
+
+    /// The default interval between sending the templates.
+    pub const TEMPLATE_INTERVAL: Duration = Duration::from_secs(600);
+
+    /// Export to a collector from an unspecified local address of its IP version.
+    pub fn new(collector: impl ToSocketAddrs) -> io::Result<Self> {
+        let collector = collector
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No collector address"))?;
+        let local = if collector.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(collector)?;
+        Ok(Ipfix::with_socket(socket))
+    }
+
+    /// Export with a socket connected to the collector.
+    pub fn with_socket(socket: UdpSocket) -> Self {
+        Ipfix {
+            socket,
+            domain: 0,
+            interval: Self::INTERVAL,
+            template_interval: Self::TEMPLATE_INTERVAL,
+            base: (Instant::now(), SystemTime::now()),
+            last_export: None,
+            last_template: None,
+            sequence: 0,
+            v4: Vec::new(),
+            v6: Vec::new(),
+            stats: IpfixStats::default(),
+        }
+    }
+
+    /// The observation domain of the messages.
+    pub fn domain(&self) -> u32 {
+        self.domain
+    }
+
+    /// Change the observation domain, which identifies the exporter to the collector.
+    pub fn set_domain(&mut self, domain: u32) {
+        self.domain = domain;
+        self.last_template = None;
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn template_interval(&self) -> Duration {
+        self.template_interval
+    }
+
+    pub fn set_template_interval(&mut self, interval: Duration) {
+        self.template_interval = interval;
+    }
+
+    pub fn stats(&self) -> IpfixStats {
+        self.stats
+    }
+
+    /// The number of records waiting for the next export.
+    pub fn pending(&self) -> usize {
+        self.v4.len() / (RECORD + 8) + self.v6.len() / (RECORD + 32)
+    }
+
+    /// Queue the final records of a connection removed from its table.
+    ///
+    /// The records end for idle timeout, or as ended if the connection was closing.
+    pub fn push<T>(&mut self, connection: &Connection<T>) {
+        let reason = if connection.closing { EndReason::End } else { EndReason::Idle };
+        self.records(connection, reason);
+    }
+
+    /// Export the connections of a table if the interval passed since the last export.
+    ///
+    /// Connections are exported as active unless expired or closing, and sent with the queued
+    /// records. Returns the first error of sending a message, the records of the other messages
+    /// are still sent.
+    pub fn export<T>(&mut self, conntrack: &Conntrack<T>) -> io::Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last_export {
+            if now.duration_since(last) < self.interval {
+                return Ok(());
+            }
+        }
+        self.last_export = Some(now);
+
+        for connection in conntrack.iter() {
+            let reason = if conntrack.is_expired(connection, now) {
+                EndReason::Idle
+            } else if connection.closing {
+                EndReason::End
+            } else {
+                EndReason::Active
+            };
+            self.records(connection, reason);
+        }
+        self.flush()
+    }
+
+    /// Send the queued records now, and the templates if they are due.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let mut result = Ok(());
+
+        let templates_due = self.last_template
+            .map_or(true, |last| now.duration_since(last) >= self.template_interval);
+        if templates_due {
+            self.last_template = Some(now);
+            let message = self.templates();
+            if let Err(err) = self.send(message, 0) {
+                result = Err(err);
+                // Try again with the next flush, the records are useless without them.
+                self.last_template = None;
+            }
+        }
+
+        let v4 = std::mem::replace(&mut self.v4, Vec::new());
+        let v6 = std::mem::replace(&mut self.v6, Vec::new());
+        let sets = [(TEMPLATE_V4, &v4, RECORD + 8), (TEMPLATE_V6, &v6, RECORD + 32)];
+        for &(template, records, len) in sets.iter() {
+            let per_message = (MAX_MESSAGE - MESSAGE_HEADER - SET_HEADER) / len;
+            for chunk in records.chunks(per_message * len) {
+                let count = chunk.len() / len;
+                let mut message = self.header();
+                message.extend_from_slice(&template.to_be_bytes());
+                message.extend_from_slice(&((SET_HEADER + chunk.len()) as u16).to_be_bytes());
+                message.extend_from_slice(chunk);
+                match self.send(message, count) {
+                    Ok(()) => {
+                        self.sequence = self.sequence.wrapping_add(count as u32);
+                    },
+                    Err(err) => {
+                        self.stats.dropped += count as u64;
+                        if result.is_ok() {
+                            result = Err(err);
+                        }
+                    },
+                }
+            }
+        }
+
+        // Keep the allocations for the next records.
+        self.v4 = v4;
+        self.v4.clear();
+        self.v6 = v6;
+        self.v6.clear();
+        result
+    }
+
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Encode the records of both directions of a connection.
+    fn records<T>(&mut self, connection: &Connection<T>, reason: EndReason) {
+        let start = self.millis(connection.created);
+        let end = self.millis(connection.last_seen);
+        self.record(&connection.original, connection.packets, connection.bytes, start, end, reason);
+        if connection.reply_packets > 0 {
+            let (packets, bytes) = (connection.reply_packets, connection.reply_bytes);
+            self.record(&connection.reply, packets, bytes, start, end, reason);
+        }
+    }
+
+    fn record(
+        &mut self,
+        flow: &FiveTuple,
+        packets: u64,
+        bytes: u64,
+        start: u64,
+        end: u64,
+        reason: EndReason,
+    ) {
+        let buffer = match (flow.src, flow.dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                self.v4.extend_from_slice(&src.octets());
+                self.v4.extend_from_slice(&dst.octets());
+                &mut self.v4
+            },
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                self.v6.extend_from_slice(&src.octets());
+                self.v6.extend_from_slice(&dst.octets());
+                &mut self.v6
+            },
+            _ => return,
+        };
+        buffer.extend_from_slice(&flow.src_port.to_be_bytes());
+        buffer.extend_from_slice(&flow.dst_port.to_be_bytes());
+        buffer.push(flow.protocol);
+        buffer.extend_from_slice(&packets.to_be_bytes());
+        buffer.extend_from_slice(&bytes.to_be_bytes());
+        buffer.extend_from_slice(&start.to_be_bytes());
+        buffer.extend_from_slice(&end.to_be_bytes());
+        buffer.push(reason as u8);
+    }
+
+    /// A message with the template set of both templates.
+    fn templates(&self) -> Vec<u8> {
+        let mut set = Vec::new();
+        for &(template, addresses) in &[(TEMPLATE_V4, ADDRESSES_V4), (TEMPLATE_V6, ADDRESSES_V6)] {
+            set.extend_from_slice(&template.to_be_bytes());
+            set.extend_from_slice(&((addresses.len() + FIELDS.len()) as u16).to_be_bytes());
+            for &(element, len) in addresses.iter().chain(FIELDS.iter()) {
+                set.extend_from_slice(&element.to_be_bytes());
+                set.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+
+        let mut message = self.header();
+        message.extend_from_slice(&TEMPLATE_SET.to_be_bytes());
+        message.extend_from_slice(&((SET_HEADER + set.len()) as u16).to_be_bytes());
+        message.extend_from_slice(&set);
+        message
+    }
+
+    /// The message header without its length, which `send` fills in.
+    fn header(&self) -> Vec<u8> {
+        let export_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let mut header = Vec::with_capacity(MAX_MESSAGE);
+        header.extend_from_slice(&VERSION.to_be_bytes());
+        header.extend_from_slice(&[0, 0]);
+        header.extend_from_slice(&export_time.to_be_bytes());
+        header.extend_from_slice(&self.sequence.to_be_bytes());
+        header.extend_from_slice(&self.domain.to_be_bytes());
+        header
+    }
+
+    fn send(&mut self, mut message: Vec<u8>, records: usize) -> io::Result<()> {
+        let len = message.len() as u16;
+        message[2..4].copy_from_slice(&len.to_be_bytes());
+        self.socket.send(&message)?;
+        self.stats.messages += 1;
+        self.stats.records += records as u64;
+        Ok(())
+    }
+
+    /// The milliseconds since the Unix epoch of an instant.
+    fn millis(&self, instant: Instant) -> u64 {
+        let (base, system) = self.base;
+        let time = match instant.checked_duration_since(base) {
+            Some(after) => system + after,
+            None => system - base.duration_since(instant),
+        };
+        time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+    }
+}
@@ -0,0 +1,473 @@
+//! Link aggregation of several phys with LACP, IEEE 802.3ad.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+
+use crate::checksum::{read_u16, write_u16};
+use crate::frame::ETHERNET_HEADER;
+use crate::rss::flow_hash;
+use crate::{Handle, Packet, Phy, Queues};
+
+/// The multicast address of the slow protocols.
+const SLOW_PROTOCOLS: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x02];
+const ETHERTYPE_SLOW: u16 = 0x8809;
+const SUBTYPE_LACP: u8 = 1;
+/// The length of an LACPDU after the Ethernet header.
+const LACPDU: usize = 110;
+
+const STATE_ACTIVITY: u8 = 0x01;
+const STATE_TIMEOUT: u8 = 0x02;
+const STATE_AGGREGATION: u8 = 0x04;
+const STATE_SYNCHRONIZATION: u8 = 0x08;
+const STATE_COLLECTING: u8 = 0x10;
+const STATE_DISTRIBUTING: u8 = 0x20;
+
+/// How often LACPDUs are sent, and requested from the partner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LacpRate {
+    /// Every 30 seconds, the partner expires after 90 seconds.
+    Slow,
+
+    /// Every second, the partner expires after 3 seconds.
+    Fast,
+}
+
+/// Several phys aggregated into a single `nic::Device` with LACP.
+///
+/// Each member speaks LACP in active mode with its link partner, usually a switch whose ports are
+/// configured as one port channel. Members which are in sync with the partner collect and
+/// distribute traffic, all others are only polled for LACPDUs. The LACP frames are handled
+/// internally and never reach the network stack.
+///
+/// Received packets are gathered from all members. Sent packets are spread over the distributing
+/// members by a symmetric hash of their flow, so packets of one flow are never reordered. Frames
+/// without an IP flow are sent on the member which provided their buffer. The buffers for sending
+/// are taken from the members in turn.
+///
+/// The flush policy of each member stays in effect, the aggregate only defers the flushes of a
+/// member until its packets have been spread.
+pub struct Aggregate<D> {
+    ports: Vec<Port<D>>,
+    system_priority: u16,
+    system: [u8; 6],
+    key: u16,
+    rate: LacpRate,
+    /// The member providing the buffers of the next `tx`.
+    next: usize,
+    /// When the members were last maintained.
+    maintained: Instant,
+    /// Whether an LACPDU arrived since.
+    changed: bool,
+}
+
+struct Port<D> {
+    phy: Phy<D>,
+    /// The actor state sent in the last LACPDU.
+    sent: Option<u8>,
+    last_tx: Instant,
+    partner: Option<Partner>,
+}
+
+/// The partner of a port, as of its last LACPDU.
+struct Partner {
+    /// The partner describing itself.
+    actor: Info,
+    /// The partner describing us.
+    partner: Info,
+    received: Instant,
+}
+
+/// The actor or partner information of an LACPDU.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Info {
+    system_priority: u16,
+    system: [u8; 6],
+    key: u16,
+    port_priority: u16,
+    port: u16,
+    state: u8,
+}
+
+/// Offers the packets of several members to a single receiver.
+struct ByRef<'a, R>(&'a mut R);
+
+impl<D> Aggregate<D> {
+    /// The default system priority, the lowest.
+    pub const SYSTEM_PRIORITY: u16 = 0xffff;
+
+    /// The default port priority of all members.
+    pub const PORT_PRIORITY: u16 = 0x00ff;
+
+    /// How often the links of the members are checked.
+    pub const LINK_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// The members in the order of their port numbers, starting at 1.
+    pub fn members(&self) -> impl Iterator<Item=&Phy<D>> {
+        self.ports.iter().map(|port| &port.phy)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    pub fn member(&self, index: usize) -> Option<&Phy<D>> {
+        self.ports.get(index).map(|port| &port.phy)
+    }
+
+    pub fn member_mut(&mut self, index: usize) -> Option<&mut Phy<D>> {
+        self.ports.get_mut(index).map(|port| &mut port.phy)
+    }
+
+    /// The system identifier sent in LACPDUs, a MAC address.
+    pub fn system(&self) -> [u8; 6] {
+        self.system
+    }
+
+    pub fn system_priority(&self) -> u16 {
+        self.system_priority
+    }
+
+    pub fn set_system_priority(&mut self, priority: u16) {
+        self.system_priority = priority;
+        self.changed = true;
+    }
+
+    /// The operational key, identifying the aggregate to the partner.
+    pub fn key(&self) -> u16 {
+        self.key
+    }
+
+    pub fn set_key(&mut self, key: u16) {
+        self.key = key;
+        self.changed = true;
+    }
+
+    pub fn rate(&self) -> LacpRate {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: LacpRate) {
+        self.rate = rate;
+        self.changed = true;
+    }
+
+    /// Check if a member currently carries traffic.
+    pub fn is_distributing(&self, index: usize) -> bool {
+        self.ports.get(index).map_or(false, |port| self.distributes(port))
+    }
+
+    /// The number of members carrying traffic.
+    pub fn distributing(&self) -> usize {
+        self.ports.iter().filter(|port| self.distributes(port)).count()
+    }
+
+    pub fn into_inner(self) -> Vec<Phy<D>> {
+        self.ports.into_iter().map(|port| port.phy).collect()
+    }
+
+    fn distributes(&self, port: &Port<D>) -> bool {
+        port.sent.map_or(false, |state| state & STATE_DISTRIBUTING != 0)
+    }
+
+    fn actor(&self, index: usize, state: u8) -> Info {
+        Info {
+            system_priority: self.system_priority,
+            system: self.system,
+            key: self.key,
+            port_priority: Self::PORT_PRIORITY,
+            port: index as u16 + 1,
+            state,
+        }
+    }
+}
+
+impl<D: Queues> Aggregate<D> {
+    /// Aggregate phys, using the MAC address of the first one as the system identifier.
+    ///
+    /// ## Panics
+    /// This function panics if `members` is empty.
+    pub fn new(members: Vec<Phy<D>>) -> Self {
+        let system = members.first()
+            .expect("An aggregate needs at least one member")
+            .mac_address();
+        let ports = members.into_iter()
+            .map(|phy| Port { phy, sent: None, last_tx: Instant::now(), partner: None })
+            .collect();
+
+        Aggregate {
+            ports,
+            system_priority: Self::SYSTEM_PRIORITY,
+            system,
+            key: 1,
+            rate: LacpRate::Fast,
+            next: 0,
+            maintained: Instant::now(),
+            // Send the first LACPDUs immediately.
+            changed: true,
+        }
+    }
+
+    /// Expire partners, select the members and send due LACPDUs.
+    fn maintain(&mut self) {
+        let now = Instant::now();
+        if !self.changed && now - self.maintained < Self::LINK_INTERVAL {
+            return;
+        }
+        self.maintained = now;
+        self.changed = false;
+
+        // We asked the partner to send at our rate.
+        let timeout = self.rate.period() * 3;
+        for port in &mut self.ports {
+            let expired = match &port.partner {
+                Some(partner) => now - partner.received > timeout,
+                None => false,
+            };
+            if expired || !port.phy.link().up {
+                port.partner = None;
+            }
+        }
+
+        // All members of the aggregate must lead to the same partner system and key.
+        let chosen = self.ports.iter()
+            .filter_map(|port| port.partner.as_ref())
+            .find(|partner| partner.aggregates())
+            .map(|partner| (partner.actor.system, partner.actor.key));
+
+        let period = self.rate.period();
+        for index in 0..self.ports.len() {
+            let port = &self.ports[index];
+            let mut state = STATE_ACTIVITY | STATE_AGGREGATION;
+            if self.rate == LacpRate::Fast {
+                state |= STATE_TIMEOUT;
+            }
+            if let Some(partner) = &port.partner {
+                let selected = Some((partner.actor.system, partner.actor.key)) == chosen
+                    && partner.aggregates();
+                // The partner must agree on our identity before we take traffic.
+                let agreed = partner.partner.system == self.system
+                    && partner.partner.key == self.key
+                    && partner.partner.port == index as u16 + 1;
+                if selected && agreed {
+                    state |= STATE_SYNCHRONIZATION | STATE_COLLECTING;
+                    if partner.actor.state & STATE_COLLECTING != 0 {
+                        state |= STATE_DISTRIBUTING;
+                    }
+                }
+            }
+
+            let due = port.sent != Some(state) || now - port.last_tx >= period;
+            if !due {
+                continue;
+            }
+
+            let actor = self.actor(index, state);
+            let partner = port.partner.as_ref().map_or(Info::default(), |partner| partner.actor);
+            let mac = port.phy.mac_address();
+            let port = &mut self.ports[index];
+            if port.phy.send_frame(&lacpdu(mac, &actor, &partner)) {
+                port.sent = Some(state);
+                port.last_tx = now;
+            }
+        }
+    }
+
+    /// Spread the packets queued on a member over the distributing members by their flow.
+    fn distribute(&mut self, from: usize) {
+        let targets: Vec<usize> = (0..self.ports.len())
+            .filter(|&index| self.is_distributing(index))
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+
+        let queued = std::mem::replace(&mut self.ports[from].phy.tx_queue, VecDeque::new());
+        for packet in queued {
+            let target = match flow_hash(&packet) {
+                Some(hash) => targets[hash as usize % targets.len()],
+                None => from,
+            };
+            self.ports[target].phy.tx_queue.push_back(packet);
+        }
+    }
+
+    /// Defer the flushes of a member while it is used by the network stack.
+    fn deferred<T>(&mut self, index: usize, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, _) = self.ports[index].phy.deferred(op);
+        self.distribute(index);
+        for port in &mut self.ports {
+            port.phy.poll_flush();
+        }
+        result
+    }
+
+    /// Take the LACPDUs out of the received packets of a member.
+    fn receive_lacp(&mut self, index: usize, max: usize) {
+        let now = Instant::now();
+        let port = &mut self.ports[index];
+        port.phy.get_rx(max);
+        let partner = &mut port.partner;
+        let changed = &mut self.changed;
+        port.phy.rx_queue.retain(|packet| match parse_lacpdu(packet) {
+            Some((actor, ours)) => {
+                *partner = Some(Partner { actor, partner: ours, received: now });
+                *changed = true;
+                false
+            },
+            None => !is_slow_protocol(packet),
+        });
+    }
+}
+
+impl Partner {
+    /// Whether the partner is willing to aggregate this link.
+    fn aggregates(&self) -> bool {
+        self.actor.state & STATE_AGGREGATION != 0
+    }
+}
+
+impl LacpRate {
+    fn period(self) -> Duration {
+        match self {
+            LacpRate::Slow => Duration::from_secs(30),
+            LacpRate::Fast => Duration::from_secs(1),
+        }
+    }
+}
+
+impl Info {
+    fn read(tlv: &[u8]) -> Self {
+        let mut system = [0; 6];
+        system.copy_from_slice(&tlv[4..10]);
+        Info {
+            system_priority: read_u16(tlv, 2),
+            system,
+            key: read_u16(tlv, 10),
+            port_priority: read_u16(tlv, 12),
+            port: read_u16(tlv, 14),
+            state: tlv[16],
+        }
+    }
+
+    fn write(&self, tlv: &mut [u8]) {
+        write_u16(tlv, 2, self.system_priority);
+        tlv[4..10].copy_from_slice(&self.system);
+        write_u16(tlv, 10, self.key);
+        write_u16(tlv, 12, self.port_priority);
+        write_u16(tlv, 14, self.port);
+        tlv[16] = self.state;
+    }
+}
+
+fn is_slow_protocol(frame: &[u8]) -> bool {
+    frame.len() >= ETHERNET_HEADER && read_u16(frame, 12) == ETHERTYPE_SLOW
+}
+
+/// The actor and partner information of an LACPDU.
+fn parse_lacpdu(frame: &[u8]) -> Option<(Info, Info)> {
+    if !is_slow_protocol(frame) || frame.len() < ETHERNET_HEADER + LACPDU {
+        return None;
+    }
+
+    let pdu = &frame[ETHERNET_HEADER..];
+    if pdu[0] != SUBTYPE_LACP || pdu[2..4] != [1, 20] || pdu[22..24] != [2, 20] {
+        return None;
+    }
+
+    Some((Info::read(&pdu[2..22]), Info::read(&pdu[22..42])))
+}
+
+fn lacpdu(source: [u8; 6], actor: &Info, partner: &Info) -> [u8; ETHERNET_HEADER + LACPDU] {
+    let mut frame = [0; ETHERNET_HEADER + LACPDU];
+    frame[0..6].copy_from_slice(&SLOW_PROTOCOLS);
+    frame[6..12].copy_from_slice(&source);
+    write_u16(&mut frame, 12, ETHERTYPE_SLOW);
+
+    let pdu = &mut frame[ETHERNET_HEADER..];
+    pdu[0] = SUBTYPE_LACP;
+    pdu[1] = 1;
+    pdu[2..4].copy_from_slice(&[1, 20]);
+    actor.write(&mut pdu[2..22]);
+    pdu[22..24].copy_from_slice(&[2, 20]);
+    partner.write(&mut pdu[22..42]);
+    // Collector information without a maximum delay, then the terminator.
+    pdu[42..44].copy_from_slice(&[3, 16]);
+    frame
+}
+
+impl<D: Queues> nic::Device for Aggregate<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        // Packets may leave on any member, only offloads common to all are usable.
+        let mut offloads = self.ports[0].phy.device.offloads();
+        for port in &self.ports[1..] {
+            offloads = offloads.common(&port.phy.device.offloads());
+        }
+        offloads.personality()
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.maintain();
+        let count = self.ports.len();
+        let index = (0..count)
+            .map(|offset| (self.next + offset) % count)
+            .find(|&index| self.is_distributing(index));
+        let index = match index {
+            Some(index) => index,
+            // Nothing can be sent without a distributing member.
+            None => return Ok(0),
+        };
+
+        self.next = (index + 1) % count;
+        self.deferred(index, |phy| nic::Device::tx(phy, max, sender))
+    }
+
+    fn rx(&mut self, max: usize, mut receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        let mut offered = 0;
+        let mut sent = 0;
+        for index in 0..self.ports.len() {
+            let budget = max - offered;
+            self.receive_lacp(index, budget);
+            if !self.ports[index].sent.map_or(false, |state| state & STATE_COLLECTING != 0) {
+                self.ports[index].phy.rx_queue.clear();
+                continue;
+            }
+
+            if budget == 0 {
+                continue;
+            }
+            offered += self.ports[index].phy.rx_queue.len().min(budget);
+            let receptor = ByRef(&mut receptor);
+            sent += self.deferred(index, |phy| nic::Device::rx(phy, budget, receptor))?;
+        }
+
+        self.maintain();
+        for port in &mut self.ports {
+            port.phy.poll_flush();
+        }
+        Ok(sent)
+    }
+}
+
+impl<H, P, R> nic::Recv<H, P> for ByRef<'_, R>
+where
+    H: ?Sized,
+    P: ?Sized,
+    R: nic::Recv<H, P>,
+{
+    fn receive(&mut self, packet: nic::Packet<H, P>) {
+        self.0.receive(packet)
+    }
+}
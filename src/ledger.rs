@@ -0,0 +1,91 @@
+//! Tracking how long buffers stay with a phy, to find leaks.
+//!
+//! Only compiled with the `leak-check` feature. The network stack only borrows the buffers of a
+//! phy, so a buffer is lost when it stays with the phy forever: received but never consumed,
+//! offered for sending but never used, or queued but never accepted by the device. The ledger
+//! records where each buffer is held and since which poll.
+use std::collections::{HashMap, VecDeque};
+
+use ixy::memory::Packet as IxyPacket;
+
+/// Where a phy holds a buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Held {
+    /// Received and not yet consumed by the network stack.
+    Received,
+
+    /// Allocated for sending and not yet used.
+    Free,
+
+    /// Queued and not yet accepted by the device.
+    Queued,
+}
+
+/// A buffer held by a phy for a while.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Outstanding {
+    /// The address of the buffer, to correlate reports.
+    pub address: usize,
+
+    /// Where the buffer is held now.
+    pub held: Held,
+
+    /// The number of polls since the buffer arrived at the phy.
+    pub age: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct Ledger {
+    /// The poll in which each held buffer was first seen.
+    entries: HashMap<usize, (Held, u64)>,
+    poll: u64,
+}
+
+impl Ledger {
+    /// Record the buffers held after a poll.
+    pub fn reconcile(
+        &mut self,
+        rx_queue: &VecDeque<IxyPacket>,
+        tx_empty: &VecDeque<IxyPacket>,
+        tx_queue: &VecDeque<IxyPacket>,
+    ) {
+        self.poll += 1;
+        let poll = self.poll;
+        let mut previous = std::mem::replace(&mut self.entries, HashMap::new());
+
+        let held = rx_queue.iter().map(|packet| (packet, Held::Received))
+            .chain(tx_empty.iter().map(|packet| (packet, Held::Free)))
+            .chain(tx_queue.iter().map(|packet| (packet, Held::Queued)));
+        for (packet, state) in held {
+            let address = packet.as_ptr() as usize;
+            let since = previous.remove(&address).map_or(poll, |(_, since)| since);
+            self.entries.insert(address, (state, since));
+        }
+    }
+
+    /// The buffers held for at least `min_age` polls, oldest first.
+    pub fn report(&self, min_age: u64) -> Vec<Outstanding> {
+        let mut report: Vec<_> = self.entries
+            .iter()
+            .map(|(&address, &(held, since))| Outstanding {
+                address,
+                held,
+                age: self.poll - since,
+            })
+            .filter(|outstanding| outstanding.age >= min_age)
+            .collect();
+        report.sort_by_key(|outstanding| std::cmp::Reverse(outstanding.age));
+        report
+    }
+}
+
+impl Drop for Ledger {
+    fn drop(&mut self) {
+        let queued = self.entries.values().filter(|(held, _)| *held == Held::Queued).count();
+        let received = self.entries.values().filter(|(held, _)| *held == Held::Received).count();
+        if queued > 0 || received > 0 {
+            eprintln!("Phy dropped with {} queued packets never sent and {} received packets \
+                never consumed", queued, received);
+        }
+    }
+}
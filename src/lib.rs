@@ -1,5 +1,7 @@
-use std::collections::{VecDeque, vec_deque::IterMut};
+use std::collections::VecDeque;
+use std::fmt;
 use std::rc::Rc;
+use std::time::Duration;
 
 use ixy::IxyDevice;
 use ixy::memory::{self, Mempool, Packet as IxyPacket};
@@ -9,13 +11,161 @@ use ethox::nic;
 use ethox::wire;
 use ethox::time::Instant;
 
+mod acl;
+pub mod affinity;
+mod announce;
+mod balancer;
+mod bond;
+#[cfg(feature = "bridge")]
+pub mod bridge;
+mod builder;
+mod checksum;
+mod clock;
+mod conntrack;
+mod detach;
+mod ecmp;
+#[cfg(feature = "embassy")]
+pub mod embassy;
+mod fault;
+mod filter;
+mod firewall;
+mod flow;
+mod flowlimit;
+mod frame;
+mod idle;
+mod ipfix;
+mod lacp;
+#[cfg(feature = "leak-check")]
+mod ledger;
+mod link;
+mod lldp;
+mod loopback;
+mod lpm;
+mod lro;
+mod maglev;
+mod marker;
+mod mirror;
+mod mock;
+mod mpls;
+mod napi;
+mod nat;
+#[cfg(feature = "sockets")]
+pub mod net;
+mod offload;
+mod pacer;
+mod pcap;
+mod poller;
+mod pool;
+mod prefix;
+mod qos;
+#[cfg(feature = "quic")]
+pub mod quic;
+mod queue;
+mod rate;
+mod reactor;
+mod recorder;
+mod replay;
+mod router;
+mod rss;
+mod runtime;
+mod sflow;
+#[cfg(feature = "smoltcp")]
+mod smol;
+#[cfg(feature = "sockets")]
+pub mod sockets;
+pub mod spsc;
+pub mod stats;
+mod switch;
+mod syncookie;
+#[cfg(feature = "metrics")]
+mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod trace;
+mod vlan;
+mod vxlan;
+#[cfg(feature = "wireguard")]
+mod wg;
+mod wheel;
+
+pub use acl::{Acl, AclAction, AclMatch, AclRule, AclStats};
+pub use announce::Announcer;
+pub use balancer::{Backend, Balancer, BalancerMode, BalancerStats};
+pub use bond::{Bonded, Member};
+pub use builder::Builder;
+pub use clock::{Clock, Tsc};
+pub use conntrack::{Connection, Conntrack, ConntrackStats, ConntrackTimeouts};
+pub use detach::Detached;
+pub use ecmp::Ecmp;
+pub use fault::{FaultStats, Faults, Faulty};
+pub use filter::{MacFilter, ipv4_multicast, ipv6_multicast};
+pub use firewall::{Firewall, FirewallAction, FirewallMatch, FirewallRule, FirewallStats};
+pub use flow::{FiveTuple, FlowAction, FlowMatch, FlowRule};
+pub use flowlimit::{FlowKey, FlowLimit};
+pub use idle::{IdleStrategy, Idler};
+pub use ipfix::{Ipfix, IpfixStats};
+pub use lacp::{Aggregate, LacpRate};
+#[cfg(feature = "leak-check")]
+pub use ledger::{Held, Outstanding};
+pub use link::{FlowControl, Link, PauseStats};
+pub use lldp::{Lldp, LldpNeighbor, LldpStats};
+pub use loopback::{LoopbackDevice, pair};
+pub use lpm::Lpm;
+pub use maglev::Maglev;
+pub use marker::{Aqm, Marker, MarkerStats};
+pub use mirror::{Encapsulation, Mirror, MirrorPort, MirrorStats, RemoteMirror};
+pub use mock::MockDevice;
+pub use mpls::{Fec, LabelEntry, LabelOp, Mpls, MplsStats};
+pub use napi::Napi;
+pub use nat::{Nat, NatEntry, NatStats, NatTimeouts};
+pub use offload::{Offloads, TxOffload};
+pub use pacer::{Pacer, PacerStats};
+pub use pcap::{Capture, Direction, Dump, Interface, Pcap, PcapNg, Sampled, Sampling};
+pub use poller::{Poller, Turn};
+pub use pool::{Placement, allocate_pool, tx_pool_entries};
+pub use prefix::Prefix;
+pub use qos::{Qos, QosClass, QosDrop, QosStats};
+pub use queue::{PhyQueue, Queues, Shared};
+pub use rate::{Rate, RateLimit, RateLimitStats};
+pub use reactor::{Control, Reactor, run};
+pub use recorder::FlightRecorder;
+pub use replay::{Pace, Replay, ReplayStats};
+pub use router::{Route, Router, RouterStats};
+pub use rss::Rss;
+pub use runtime::{Runtime, Worker};
+pub use sflow::{Sflow, SflowCounters, SflowInterface, SflowStats};
+#[cfg(feature = "smoltcp")]
+pub use smol::{RxToken, TxToken};
+pub use switch::{PortStats, Switch};
+pub use syncookie::{SynGuard, SynGuardStats};
+pub use trace::{Frame, Hexdump, Tracer};
+pub use vlan::{Vlan, VlanId, VlanStats};
+pub use vxlan::{Vxlan, VxlanStats};
+#[cfg(feature = "wireguard")]
+pub use wg::{Wg, WgStats};
+pub use wheel::{TimerId, TimerWheel};
+
+use stats::{PhyStats, PoolUsage, QueueStats};
+
 /// A generic ixy device as an ethox phy device.
 ///
 /// Newtype wrapper so that this struct can live in an external crate instead of ixy-rs itself.
+///
+/// ## Threads
+///
+/// A phy is not `Send`. Every ixy packet refers to its mempool through an `Rc` and the pool type
+/// is fixed by ixy, so neither the buffers held by the phy nor the device can move to another
+/// thread. Create the device and its phy on the thread which polls them, e.g. one device or
+/// virtual function per worker thread. To move a phy regardless, `detach` it into a `Detached`
+/// handle, which is `Send`, and `attach` that on the target thread to initialize the device
+/// there again.
 pub struct Phy<D> {
     /// The underlying device.
     device: D,
 
+    /// The index of the rx/tx queue pair to use.
+    queue: u32,
+
     /// Packets to be processed in receive.
     rx_queue: VecDeque<IxyPacket>,
 
@@ -27,30 +177,405 @@ pub struct Phy<D> {
 
     /// Memory pool to use for allocation.
     pool: Rc<Mempool>,
+
+    /// The number of packets received or allocated at once.
+    batch_size: usize,
+
+    /// Handles of the current batch, reused across calls.
+    handles: Vec<Handle>,
+
+    /// The source of packet timestamps.
+    clock: Clock,
+
+    /// The timestamp of the current poll cycle, sampled by `rx` and reused by the next `tx`.
+    cycle: Option<Instant>,
+
+    /// When to flush the send queue automatically.
+    flush_policy: FlushPolicy,
+
+    /// The time of the last flush.
+    last_flush: std::time::Instant,
+
+    /// The maximum time packets may wait in the send queue.
+    flush_deadline: Option<Duration>,
+
+    /// Since when the oldest packet has been waiting in the send queue.
+    tx_since: Option<std::time::Instant>,
+
+    /// Whether the send queue is held back from flushes, see `deferred`.
+    held: bool,
+
+    /// Whether to coalesce received TCP segments.
+    lro: bool,
+
+    /// The configured maximum transmission unit.
+    mtu: usize,
+
+    /// The accepted destination addresses.
+    filter: MacFilter,
+
+    /// Whether the filter must be applied in software.
+    soft_filter: bool,
+
+    /// Flow steering rules which the device could not install.
+    flow_rules: Vec<FlowRule>,
+
+    /// Flow steering rules installed in the device, to install them again on another one.
+    hardware_flow_rules: Vec<FlowRule>,
+
+    /// Whether to strip VLAN tags from received frames in software.
+    vlan_strip: bool,
+
+    /// Whether the offloads of the device are offered to the network stack.
+    hardware_offloads: bool,
+
+    /// Switching to interrupts at low receive rates, if enabled.
+    napi: Option<napi::NapiState>,
+
+    /// Counters of the queue.
+    stats: QueueStats,
+
+    /// Counters of software drops.
+    phy_stats: PhyStats,
+
+    /// Forwards the counters to the `metrics` recorder.
+    #[cfg(feature = "metrics")]
+    telemetry: telemetry::Reporter,
+
+    /// Tracks the buffers held by the phy.
+    #[cfg(feature = "leak-check")]
+    ledger: ledger::Ledger,
+}
+
+/// Determines when `Phy` automatically hands queued packets to the device.
+///
+/// Flushing after every call has the lowest latency but defeats batching when the network stack
+/// emits only a few packets per poll. The other policies trade latency for throughput.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FlushPolicy {
+    /// Flush at the end of every `tx` and `rx` call.
+    Always,
+
+    /// Flush once at least this many packets are waiting.
+    Packets(usize),
+
+    /// Flush when at least this much time has passed since the last flush.
+    Interval(Duration),
+
+    /// Only flush on explicit calls to `Phy::flush`.
+    Manual,
+}
+
+/// The outcome of `Phy::shutdown`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Drained {
+    /// Queued packets handed to the device during the shutdown.
+    pub sent: usize,
+
+    /// Queued packets the device did not accept before the timeout.
+    pub discarded: usize,
+
+    /// Whether the device reported all transmissions as completed.
+    ///
+    /// Always `false` for devices which do not report completions, such as the ixy drivers.
+    pub completed: bool,
+
+    /// Buffers returned to their mempool, including the discarded packets.
+    pub released: usize,
+}
+
+/// Errors when configuring a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Error {
+    /// The device does not support the operation.
+    Unsupported,
+
+    /// The mempool entries are too small for the requested frame size.
+    EntrySize,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct Handle {
     queued: bool,
     timestamp: Instant,
+    metadata: Metadata,
+    offloads: Offloads,
+    tx_offload: TxOffload,
+}
+
+/// Per-packet metadata from the receive descriptor.
+///
+/// Fields the driver does not report keep their default value. The ixy drivers do not expose
+/// their descriptors, only devices implementing `Queues` directly can provide them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Metadata {
+    /// The index of the queue on which the packet was received.
+    pub queue: u32,
+
+    /// The RSS hash calculated by the NIC.
+    pub rss_hash: Option<u32>,
+
+    /// The VLAN tag stripped by the NIC.
+    pub vlan: Option<u16>,
+
+    /// The result of the IPv4 header checksum validation by the NIC.
+    pub ipv4_checksum: Option<bool>,
+
+    /// The result of the TCP or UDP checksum validation by the NIC.
+    pub l4_checksum: Option<bool>,
 }
 
 #[repr(transparent)]
 pub struct Packet(IxyPacket);
 
 impl<D> Phy<D> {
-    const BATCH_SIZE: usize = 32;
+    /// The batch size used by `new`.
+    pub const BATCH_SIZE: usize = 32;
+
+    /// The standard Ethernet MTU which all devices support.
+    pub const DEFAULT_MTU: usize = 1500;
 
-    pub fn new(device: D, pool: Rc<Mempool>) -> Self where D: IxyDevice {
+    pub fn new(device: D, pool: Rc<Mempool>) -> Self where D: Queues {
+        Self::with_batch_size(device, pool, Self::BATCH_SIZE)
+    }
+
+    /// Create a phy which receives and allocates packets in batches of a custom size.
+    ///
+    /// The optimal size depends on the device, e.g. a 10G ixgbe profits from larger batches than
+    /// a virtio device inside a VM.
+    ///
+    /// ## Panics
+    /// This function panics if `batch_size` is zero.
+    pub fn with_batch_size(device: D, pool: Rc<Mempool>, batch_size: usize) -> Self
+        where D: Queues
+    {
+        assert!(batch_size > 0, "Batch size must not be zero");
         Phy {
             device,
-            rx_queue: VecDeque::with_capacity(Self::BATCH_SIZE),
-            tx_empty: VecDeque::with_capacity(Self::BATCH_SIZE),
-            tx_queue: VecDeque::with_capacity(Self::BATCH_SIZE),
+            queue: 0,
+            rx_queue: VecDeque::with_capacity(batch_size),
+            tx_empty: VecDeque::with_capacity(batch_size),
+            tx_queue: VecDeque::with_capacity(batch_size),
             pool,
+            batch_size,
+            handles: Vec::with_capacity(batch_size),
+            clock: Clock::default(),
+            cycle: None,
+            flush_policy: FlushPolicy::default(),
+            last_flush: std::time::Instant::now(),
+            flush_deadline: None,
+            tx_since: None,
+            held: false,
+            lro: false,
+            mtu: Self::DEFAULT_MTU,
+            filter: MacFilter::default(),
+            soft_filter: false,
+            flow_rules: Vec::new(),
+            hardware_flow_rules: Vec::new(),
+            vlan_strip: false,
+            hardware_offloads: true,
+            napi: None,
+            stats: QueueStats::default(),
+            phy_stats: PhyStats::default(),
+            #[cfg(feature = "metrics")]
+            telemetry: telemetry::Reporter::new(),
+            #[cfg(feature = "leak-check")]
+            ledger: ledger::Ledger::default(),
+        }
+    }
+
+    /// Configure a phy with more options.
+    ///
+    /// The tx mempool defaults to the receive pool of the queue, see `Builder::tx_pool`.
+    pub fn builder(device: D) -> Builder<D> where D: IxyDevice {
+        Builder::new(device)
+    }
+
+    /// The index of the rx/tx queue pair used by this phy.
+    pub fn queue(&self) -> u32 {
+        self.queue
+    }
+
+    /// The number of packets received or allocated at once.
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The policy for automatic flushes.
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    /// Change when packets are automatically flushed.
+    ///
+    /// Packets which are already queued are not flushed by this call, even when the new policy
+    /// would demand it. They are sent on the next `tx` or `rx` call instead.
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    /// The maximum time packets may wait in the send queue, if any.
+    pub fn flush_deadline(&self) -> Option<Duration> {
+        self.flush_deadline
+    }
+
+    /// Bound the time packets wait in the send queue.
+    ///
+    /// Independent of the flush policy, the next `tx` or `rx` call flushes when any packet has
+    /// been waiting for longer than the deadline. This bounds the latency of low-rate traffic
+    /// while keeping full batches at high rates.
+    pub fn set_flush_deadline(&mut self, deadline: Option<Duration>) {
+        self.flush_deadline = deadline;
+    }
+
+    /// Whether received TCP segments are coalesced in software.
+    pub fn lro(&self) -> bool {
+        self.lro
+    }
+
+    /// Coalesce consecutive received TCP segments of the same flow in software.
+    ///
+    /// Merged packets are copied into buffers from the tx pool, so this requires a pool with
+    /// entries larger than the MTU, e.g. a dedicated one configured with `Builder::tx_pool`.
+    /// Note that frames larger than a single receive buffer are never delivered by the ixy
+    /// drivers, only the coalescing is performed.
+    pub fn set_lro(&mut self, enabled: bool) {
+        self.lro = enabled;
+    }
+
+    /// Whether VLAN tags of received frames are stripped in software.
+    pub fn vlan_strip(&self) -> bool {
+        self.vlan_strip
+    }
+
+    /// Strip the VLAN tag of received frames into their `Metadata`.
+    ///
+    /// The upper layers then only see untagged frames. Tags already stripped by the NIC are
+    /// always reported in the metadata.
+    pub fn set_vlan_strip(&mut self, enabled: bool) {
+        self.vlan_strip = enabled;
+    }
+
+    /// Whether the network stack may use the offloads of the device.
+    pub fn hardware_offloads(&self) -> bool {
+        self.hardware_offloads
+    }
+
+    /// Offer the offloads of the device to the network stack, enabled by default.
+    ///
+    /// Disable them when frames are transformed between the stack and the device, e.g. by an
+    /// encapsulation, as the device would then checksum or segment the outer packet. The stack
+    /// computes checksums itself and requested offloads are performed in software.
+    pub fn set_hardware_offloads(&mut self, enabled: bool) {
+        self.hardware_offloads = enabled;
+    }
+
+    /// The maximum transmission unit.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// The thresholds for switching to interrupts, `None` if the phy always polls.
+    pub fn napi(&self) -> Option<Napi> {
+        self.napi.as_ref().map(|state| state.config)
+    }
+
+    /// Switch to interrupts at low receive rates, or always poll with `None`.
+    ///
+    /// Requires a device supporting interrupts, see `Queues::wait_rx`. Otherwise the phy returns
+    /// to polling on the first attempt to wait.
+    pub fn set_napi(&mut self, napi: Option<Napi>) {
+        self.napi = napi.map(napi::NapiState::new);
+    }
+
+    /// Whether the phy currently waits for interrupts when there are no packets.
+    pub fn is_interrupt_mode(&self) -> bool {
+        self.napi.as_ref().map_or(false, napi::NapiState::interrupts)
+    }
+
+    /// The source of packet timestamps.
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Change the source of packet timestamps, e.g. to `Clock::tsc()`.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+        self.cycle = None;
+    }
+
+    /// The counters of the queue serviced by this phy.
+    pub fn queue_stats(&self) -> QueueStats {
+        QueueStats {
+            queue: self.queue,
+            ..self.stats
         }
     }
 
+    /// Reset the counters of the queue.
+    pub fn reset_queue_stats(&mut self) {
+        self.stats = QueueStats::default();
+    }
+
+    /// The counters of packets dropped in software.
+    pub fn phy_stats(&self) -> PhyStats {
+        self.phy_stats
+    }
+
+    /// Reset the counters of packets dropped in software.
+    pub fn reset_phy_stats(&mut self) {
+        self.phy_stats = PhyStats::default();
+    }
+
+    /// The destination addresses accepted by the device.
+    pub fn mac_filter(&self) -> &MacFilter {
+        &self.filter
+    }
+
+    /// The flow steering rules applied in software.
+    pub fn software_flow_rules(&self) -> &[FlowRule] {
+        &self.flow_rules
+    }
+
+    /// The number of packets queued for sending but not yet handed to the device.
+    pub fn tx_pending(&self) -> usize {
+        self.tx_queue.len()
+    }
+
+    /// The number of allocated buffers available for sending.
+    pub fn tx_free(&self) -> usize {
+        self.tx_empty.len()
+    }
+
+    /// The mempool from which buffers for sending are allocated.
+    pub fn tx_pool(&self) -> &Rc<Mempool> {
+        &self.pool
+    }
+
+    /// The buffers held by the phy for at least `min_age` polls, oldest first.
+    ///
+    /// A buffer which stays for many polls is effectively leaked. Each call to `rx` or `tx` is a
+    /// poll. Buffers still held when the phy is dropped are reported on stderr.
+    #[cfg(feature = "leak-check")]
+    pub fn outstanding(&self, min_age: u64) -> Vec<Outstanding> {
+        self.ledger.report(min_age)
+    }
+
+    /// The buffers currently held by the phy.
+    pub fn pool_usage(&self) -> PoolUsage {
+        PoolUsage {
+            entry_size: self.pool.entry_size(),
+            tx_free: self.tx_empty.len(),
+            tx_pending: self.tx_queue.len(),
+            rx_buffered: self.rx_queue.len(),
+        }
+    }
+
+    /// The number of received packets not yet handed to the network stack.
+    pub fn rx_buffered(&self) -> usize {
+        self.rx_queue.len()
+    }
+
     /// Inspect the inner device.
     ///
     /// Useful to gather the stats or link metadata.
@@ -63,7 +588,7 @@ impl<D> Phy<D> {
     }
 }
 
-impl<D: IxyDevice> Phy<D> {
+impl<D: Queues> Phy<D> {
     /// Empty the send buffer.
     ///
     /// The network stack of `smoltcp` only gives an interface for sending single packets. In order
@@ -72,26 +597,548 @@ impl<D: IxyDevice> Phy<D> {
     ///
     /// Returns the number of packets sent due to this call to flush.
     pub fn flush(&mut self) -> usize {
-        self.device.tx_batch(0, &mut self.tx_queue)
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("flush",
+            queue = self.queue,
+            pending = self.tx_queue.len(),
+            sent = tracing::field::Empty,
+        ).entered();
+
+        self.last_flush = std::time::Instant::now();
+        let bytes = queued_bytes(&self.tx_queue);
+        let sent = Queues::tx_batch(&mut self.device, self.queue, &mut self.tx_queue);
+        #[cfg(feature = "tracing")]
+        span.record("sent", &sent);
+        if sent > 0 {
+            self.stats.tx_flushes += 1;
+            self.stats.tx_packets += sent as u64;
+            self.stats.tx_bytes += bytes - queued_bytes(&self.tx_queue);
+        }
+        if !self.tx_queue.is_empty() {
+            self.phy_stats.tx_ring_full += 1;
+        }
+        if self.tx_queue.is_empty() {
+            self.tx_since = None;
+        }
+        sent
     }
 
-    fn get_rx(&mut self) -> IterMut<IxyPacket> {
-        if self.rx_queue.is_empty() {
-            self.device.rx_batch(0, &mut self.rx_queue, Self::BATCH_SIZE);
+    /// Queue a copy of a raw frame for sending, bypassing the network stack.
+    ///
+    /// Meant for control frames generated by the phy layer itself, e.g. gratuitous ARP. The frame
+    /// is sent with the next flush. Returns `false` if the pool had no free buffer.
+    pub fn send_frame(&mut self, frame: &[u8]) -> bool {
+        let mut packet = match memory::alloc_pkt(&self.pool, frame.len()) {
+            Some(packet) => packet,
+            None => return false,
+        };
+
+        packet.copy_from_slice(frame);
+        self.tx_queue.push_back(packet);
+        true
+    }
+
+    /// Send all queued packets and return every buffer of the phy to its mempool.
+    ///
+    /// Flushes until the send queue is empty, then waits for the device to complete the
+    /// transmissions, both for at most `timeout` in total. Afterwards all received packets not yet
+    /// consumed, unused send buffers and packets still queued are dropped, i.e. freed to their
+    /// pool. Dropping a phy instead discards the queued packets without sending them, so call this
+    /// before a short-lived tool exits.
+    ///
+    /// The phy remains usable, later calls to `tx` and `rx` allocate and receive as usual.
+    pub fn shutdown(&mut self, timeout: Duration) -> Drained {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut drained = Drained::default();
+
+        loop {
+            drained.sent += self.flush();
+            if self.tx_queue.is_empty() || std::time::Instant::now() >= deadline {
+                break;
+            }
+            std::hint::spin_loop();
         }
 
-        // Receive in correct time order.
-        self.rx_queue.iter_mut()
+        // Sending an empty batch only cleans the tx ring.
+        let mut empty = VecDeque::new();
+        while let Some(in_flight) = Queues::tx_in_flight(&mut self.device, self.queue) {
+            if in_flight == 0 {
+                drained.completed = true;
+                break;
+            }
+            if std::time::Instant::now() >= deadline {
+                break;
+            }
+            Queues::tx_batch(&mut self.device, self.queue, &mut empty);
+            std::hint::spin_loop();
+        }
+
+        drained.discarded = self.tx_queue.len();
+        self.phy_stats.tx_dropped += drained.discarded as u64;
+        drained.released = self.rx_queue.len() + self.tx_empty.len() + self.tx_queue.len();
+        self.rx_queue.clear();
+        self.tx_empty.clear();
+        self.tx_queue.clear();
+        self.tx_since = None;
+        #[cfg(feature = "leak-check")]
+        self.ledger.reconcile(&self.rx_queue, &self.tx_empty, &self.tx_queue);
+        drained
     }
 
-    fn get_tx(&mut self) -> IterMut<IxyPacket> {
-        if self.tx_empty.is_empty() {
-            let max_size = self.pool.entry_size();
-            memory::alloc_pkt_batch(&self.pool, &mut self.tx_empty, Self::BATCH_SIZE, max_size);
+    /// Configure the maximum transmission unit, e.g. for jumbo frames.
+    ///
+    /// The entries of the tx pool must fit frames of this MTU, see `frame_size`. Any MTU above
+    /// the standard of 1500 bytes must also be configured on the device.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        if frame_size(mtu) > self.pool.entry_size() {
+            return Err(Error::EntrySize);
+        }
+
+        if mtu > Self::DEFAULT_MTU || self.mtu > Self::DEFAULT_MTU {
+            self.device.set_mtu(mtu)?;
         }
 
-        // Back is the last sent packet, best chance to still be in TLB/mmio cache?
-        self.tx_empty.iter_mut()
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// The MAC address programmed into the device.
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.device.mac_address()
+    }
+
+    /// The current state of the physical link.
+    pub fn link(&self) -> Link {
+        self.device.link()
+    }
+
+    /// Block until the link is up, or at most for `timeout`.
+    ///
+    /// Useful directly after driver initialization, as the link takes a few seconds to come up
+    /// and packets sent before are lost. Returns `None` if the link is still down.
+    pub fn wait_for_link(&self, timeout: Duration) -> Option<Link> {
+        link::wait_for(|| self.device.link(), timeout)
+    }
+
+    /// Configure 802.3x flow control.
+    ///
+    /// Backpressure through pause frames materially changes forwarding behavior, e.g. a slow
+    /// receiver throttles the sender instead of dropping packets.
+    pub fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), Error> {
+        self.device.set_flow_control(flow_control)
+    }
+
+    /// The pause frame counters of the device, if it provides them.
+    pub fn pause_stats(&self) -> Option<PauseStats> {
+        self.device.pause_stats()
+    }
+
+    /// Enable or disable promiscuous mode.
+    ///
+    /// When disabled, only frames to the addresses of the filter table and broadcast are
+    /// received. Note that the table starts empty, add the address of the device with
+    /// `add_unicast(phy.mac_address())`. Devices without hardware filters are filtered in
+    /// software.
+    pub fn set_promiscuous(&mut self, promiscuous: bool) {
+        self.filter.set_promiscuous(promiscuous);
+        self.apply_filter();
+    }
+
+    /// Accept frames to an additional unicast address.
+    pub fn add_unicast(&mut self, addr: [u8; 6]) {
+        if !self.filter.unicast().contains(&addr) {
+            self.filter.unicast_mut().push(addr);
+            self.apply_filter();
+        }
+    }
+
+    /// Stop accepting frames to a unicast address.
+    pub fn remove_unicast(&mut self, addr: [u8; 6]) {
+        self.filter.unicast_mut().retain(|other| *other != addr);
+        self.apply_filter();
+    }
+
+    /// Join an Ethernet multicast group.
+    ///
+    /// Frames to the group are received even when promiscuous mode is disabled. Use
+    /// `ipv4_multicast` and `ipv6_multicast` for the addresses of IP groups, e.g. for mDNS or
+    /// IPv6 neighbor discovery.
+    pub fn join_multicast(&mut self, group: [u8; 6]) {
+        if !self.filter.multicast().contains(&group) {
+            self.filter.multicast_mut().push(group);
+            self.apply_filter();
+        }
+    }
+
+    /// Leave an Ethernet multicast group.
+    pub fn leave_multicast(&mut self, group: [u8; 6]) {
+        self.filter.multicast_mut().retain(|other| *other != group);
+        self.apply_filter();
+    }
+
+    /// Steer the packets of matching flows to a queue, or drop them.
+    ///
+    /// The rule is installed in the flow director of the NIC where available. Otherwise, received
+    /// packets are classified in software, and those for another queue are only delivered there
+    /// for phys created with `PhyQueue::split`. Rules apply in the order they were added.
+    ///
+    /// Returns whether the rule was installed in hardware.
+    pub fn add_flow_rule(&mut self, rule: FlowRule) -> bool {
+        match self.device.add_flow_rule(&rule) {
+            Ok(()) => {
+                self.hardware_flow_rules.push(rule);
+                true
+            },
+            Err(_) => {
+                self.flow_rules.push(rule);
+                false
+            },
+        }
+    }
+
+    /// Remove a previously added flow steering rule.
+    pub fn remove_flow_rule(&mut self, rule: &FlowRule) {
+        let before = self.flow_rules.len();
+        self.flow_rules.retain(|other| other != rule);
+        if self.flow_rules.len() == before {
+            self.hardware_flow_rules.retain(|other| other != rule);
+            let _ = self.device.remove_flow_rule(rule);
+        }
+    }
+
+    /// Apply the software flow steering rules to the receive buffer.
+    fn steer(&mut self) {
+        let rules = &self.flow_rules;
+        let device = &mut self.device;
+        let queue = self.queue;
+        let stats = &mut self.phy_stats;
+        let mut kept = VecDeque::with_capacity(self.rx_queue.len());
+
+        for packet in self.rx_queue.drain(..) {
+            let action = FiveTuple::from_frame(&packet)
+                .and_then(|flow| rules.iter().find(|rule| rule.matches.matches(&flow)))
+                .map(|rule| rule.action);
+            match action {
+                Some(FlowAction::Queue(target)) if target != queue => {
+                    // Dropped if the device has no such queue.
+                    if device.redirect(target, packet).is_err() {
+                        stats.rx_steered_dropped += 1;
+                    }
+                },
+                Some(FlowAction::Drop) => stats.rx_steered_dropped += 1,
+                _ => kept.push_back(packet),
+            }
+        }
+
+        self.rx_queue = kept;
+    }
+
+    /// Program the filter into the device, or fall back to software filtering.
+    fn apply_filter(&mut self) {
+        self.soft_filter = match self.device.set_mac_filter(&self.filter) {
+            Ok(()) => false,
+            Err(_) => !self.filter.promiscuous(),
+        };
+    }
+
+    /// Forward received packets into the send queue of another phy without copying them.
+    ///
+    /// Receives up to `max` packets and offers each to `filter` which may modify it in place.
+    /// Packets for which it returns `true` are moved to the send queue of `to`, all others are
+    /// dropped. Like in the ixy forwarder, the buffers stay in the receive pool and return to it
+    /// when the other device has sent them. Use `nic::Handle::queue` in `rx` to send packets back
+    /// on the same phy instead.
+    ///
+    /// Returns the number of forwarded packets.
+    pub fn forward<E: Queues>(
+        &mut self,
+        to: &mut Phy<E>,
+        max: usize,
+        mut filter: impl FnMut(&mut Packet) -> bool,
+    ) -> usize {
+        self.get_rx(max);
+        let count = self.rx_queue.len().min(max);
+
+        let mut forwarded = 0;
+        for mut packet in self.rx_queue.drain(..count) {
+            if filter(Packet::from_mut(&mut packet)) {
+                to.tx_queue.push_back(packet);
+                forwarded += 1;
+            }
+        }
+
+        to.poll_flush();
+        forwarded
+    }
+
+    /// Flush if the flush policy demands it.
+    pub(crate) fn poll_flush(&mut self) -> usize {
+        let sent = self.flush_if_due();
+        #[cfg(feature = "leak-check")]
+        self.ledger.reconcile(&self.rx_queue, &self.tx_empty, &self.tx_queue);
+        sent
+    }
+
+    /// Run an operation of the network stack, holding back the packets it queues.
+    ///
+    /// Neither the flush policy nor the flush deadline flush while the operation runs, so a
+    /// wrapper can process the queued packets before they are sent, e.g. encrypt or filter them.
+    /// Returns the result and the index of the first packet the operation queued. Call
+    /// `poll_flush` once the packets are processed.
+    pub(crate) fn deferred<T>(&mut self, op: impl FnOnce(&mut Self) -> T) -> (T, usize) {
+        let held = std::mem::replace(&mut self.held, true);
+        let start = self.tx_queue.len();
+        let result = op(self);
+        self.held = held;
+        (result, start)
+    }
+
+    /// Flush if the policy or the deadline demand it.
+    fn flush_if_due(&mut self) -> usize {
+        if self.held {
+            return 0;
+        }
+
+        let due = match self.flush_policy {
+            FlushPolicy::Always => true,
+            FlushPolicy::Packets(count) => self.tx_queue.len() >= count,
+            FlushPolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+            FlushPolicy::Manual => false,
+        };
+
+        #[cfg(feature = "metrics")]
+        self.telemetry.tick(&self.queue_stats(), &self.phy_stats, self.tx_queue.len());
+
+        if due || self.deadline_expired() {
+            self.flush()
+        } else {
+            0
+        }
+    }
+
+    /// Check if queued packets have been waiting for longer than the flush deadline.
+    fn deadline_expired(&mut self) -> bool {
+        let deadline = match self.flush_deadline {
+            Some(deadline) => deadline,
+            None => return false,
+        };
+
+        if self.tx_queue.is_empty() {
+            return false;
+        }
+
+        let now = std::time::Instant::now();
+        let since = *self.tx_since.get_or_insert(now);
+        now.duration_since(since) >= deadline
+    }
+
+    /// Ensure that up to `max` packets are available for receiving, if the device has them.
+    fn get_rx(&mut self, max: usize) {
+        if self.rx_queue.len() < max {
+            let missing = max - self.rx_queue.len();
+            let count = missing.max(self.batch_size);
+            let mut received = Queues::rx_batch(&mut self.device, self.queue, &mut self.rx_queue, count);
+            if received == 0 && self.wait_rx() {
+                self.stats.rx_polls += 1;
+                self.stats.rx_empty_polls += 1;
+                received = Queues::rx_batch(&mut self.device, self.queue, &mut self.rx_queue, count);
+            }
+            self.stats.rx_polls += 1;
+            if received == 0 {
+                self.stats.rx_empty_polls += 1;
+                return;
+            }
+
+            self.stats.rx_packets += received as u64;
+            if let Some(napi) = &mut self.napi {
+                napi.poll(received);
+            }
+            #[cfg(feature = "metrics")]
+            self.telemetry.rx_batch(received);
+            self.stats.rx_bytes += self.rx_queue
+                .iter()
+                .skip(self.rx_queue.len() - received)
+                .map(|packet| packet.len() as u64)
+                .sum::<u64>();
+
+            if self.soft_filter {
+                let filter = &self.filter;
+                let before = self.rx_queue.len();
+                self.rx_queue.retain(|packet| filter.accepts(packet));
+                self.phy_stats.rx_filtered += (before - self.rx_queue.len()) as u64;
+            }
+            if !self.flow_rules.is_empty() {
+                self.steer();
+            }
+            if self.lro {
+                let (device, queue) = (&self.device, self.queue);
+                lro::coalesce(&mut self.rx_queue, &self.pool, |packet| {
+                    device.rx_metadata(queue, packet)
+                });
+            }
+        }
+    }
+
+    /// Wait for an interrupt if the receive rate is low, returns if it waited.
+    fn wait_rx(&mut self) -> bool {
+        let timeout = match &mut self.napi {
+            Some(napi) if napi.poll(0) => napi.config.timeout,
+            _ => return false,
+        };
+
+        // Nothing may linger in the send queue while blocked, held back packets prevent blocking.
+        if !self.tx_queue.is_empty() {
+            if self.held {
+                return false;
+            }
+            self.flush();
+        }
+
+        match Queues::wait_rx(&mut self.device, self.queue, timeout) {
+            Ok(()) => true,
+            Err(_) => {
+                self.napi = None;
+                false
+            },
+        }
+    }
+
+    /// Ensure that up to `max` buffers are available for sending, if the pool has them.
+    ///
+    /// Reuses buffers of completed transmissions first, then allocates in units of the batch
+    /// size until enough buffers are available or the pool has been exhausted.
+    fn get_tx(&mut self, max: usize) {
+        if self.tx_empty.len() < max {
+            let missing = max - self.tx_empty.len();
+            Queues::reclaim_tx(&mut self.device, self.queue, &mut self.tx_empty, missing);
+        }
+
+        let max_size = self.pool.entry_size();
+        while self.tx_empty.len() < max {
+            let allocated = memory::alloc_pkt_batch(
+                &self.pool, &mut self.tx_empty, self.batch_size, max_size);
+            if allocated == 0 {
+                self.phy_stats.tx_alloc_failed += 1;
+                break;
+            }
+        }
+    }
+
+    /// The offloads offered to the network stack.
+    fn offloads(&self) -> Offloads {
+        if self.hardware_offloads {
+            self.device.offloads()
+        } else {
+            Offloads::default()
+        }
+    }
+
+    /// Prepare the handles for a batch of `count` packets.
+    ///
+    /// The handles are reused between batches, only their per-packet state is reset.
+    fn reset_handles(&mut self, count: usize, now: Instant) {
+        let offloads = self.offloads();
+        self.handles.truncate(count);
+        for handle in self.handles.iter_mut() {
+            handle.queued = false;
+            handle.timestamp = now;
+            handle.metadata = Metadata::default();
+            handle.offloads = offloads;
+            handle.tx_offload = TxOffload::default();
+        }
+
+        let mut handle = Handle::new(now);
+        handle.offloads = offloads;
+        self.handles.resize(count, handle);
+    }
+}
+
+/// The maximum size of an Ethernet frame for an MTU.
+///
+/// Includes the Ethernet header and room for a VLAN tag, but not the frame check sequence as the
+/// NIC appends it.
+pub fn frame_size(mtu: usize) -> usize {
+    mtu + 14 + 4
+}
+
+/// A mempool entry size sufficient for frames of an MTU.
+///
+/// Rounded up to a power of two, at least 2048 as used by ixy, such that entries divide the huge
+/// page size as required by `Mempool::allocate`.
+pub fn entry_size(mtu: usize) -> usize {
+    frame_size(mtu).next_power_of_two().max(2048)
+}
+
+/// The total length of the packets in a queue.
+fn queued_bytes(queue: &VecDeque<IxyPacket>) -> u64 {
+    queue.iter().map(|packet| packet.len() as u64).sum()
+}
+
+/// Queue a packet for sending, applying the offloads of its handle.
+///
+/// Without hardware support, VLAN tags are spliced in software and segmentation is performed in
+/// software into new buffers from the pool, to which the device applies the remaining offloads.
+/// The original buffer is then returned for reuse, as is the buffer of a packet dropped for lack
+/// of room for its tag.
+fn enqueue<D: Queues>(
+    device: &mut D,
+    queue: u32,
+    pool: &Rc<Mempool>,
+    tx_queue: &mut VecDeque<IxyPacket>,
+    stats: &mut PhyStats,
+    mut packet: IxyPacket,
+    handle: &Handle,
+) -> Option<IxyPacket> {
+    let mut offload = handle.pending_offload();
+    if let Some(tci) = offload.vlan {
+        if !handle.offloads.vlan_insert {
+            if !offload::insert_vlan(&mut packet, tci) {
+                stats.tx_dropped += 1;
+                return Some(packet);
+            }
+            offload.vlan = None;
+        }
+    }
+
+    if let Some(mss) = offload.mss {
+        if !handle.offloads.tso {
+            let start = tx_queue.len();
+            if let Some(lost) = offload::segment(&packet, mss.into(), pool, tx_queue) {
+                stats.tx_dropped += lost as u64;
+                // The segments have their checksums, a tag may still be inserted by the device.
+                let rest = TxOffload { vlan: offload.vlan, ..TxOffload::default() };
+                if !rest.is_empty() {
+                    for segment in tx_queue.range_mut(start..) {
+                        device.tx_offload(queue, segment, rest);
+                    }
+                }
+                return Some(packet);
+            }
+        }
+    }
+
+    if !offload.is_empty() {
+        device.tx_offload(queue, &mut packet, offload);
+    }
+
+    tx_queue.push_back(packet);
+    None
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsupported => write!(f, "operation not supported by the device"),
+            Error::EntrySize => write!(f, "mempool entries too small for the frame size"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy::Always
     }
 }
 
@@ -100,8 +1147,36 @@ impl Handle {
         Handle {
             queued: false,
             timestamp: now,
+            metadata: Metadata::default(),
+            offloads: Offloads::default(),
+            tx_offload: TxOffload::default(),
         }
     }
+
+    /// The offloads requested for sending this packet.
+    pub fn tx_offload(&self) -> TxOffload {
+        self.tx_offload
+    }
+
+    /// Request offloads for sending this packet.
+    ///
+    /// Checksums are inserted in software when the device does not support it.
+    pub fn set_tx_offload(&mut self, offload: TxOffload) {
+        self.tx_offload = offload;
+    }
+
+    /// The offloads to perform when this packet is queued.
+    fn pending_offload(&self) -> TxOffload {
+        self.tx_offload.with_advertised(&self.offloads)
+    }
+
+    /// The hardware metadata of a received packet.
+    ///
+    /// Always the default value for packets provided for sending. Attach a VLAN tag to sent
+    /// packets with `set_tx_offload` instead.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
 }
 
 impl Packet {
@@ -109,92 +1184,205 @@ impl Packet {
         // Safety: marked with `repr(transparent)`. Doesn't change mutability.
         unsafe { core::mem::transmute(ixy) }
     }
+
+    /// The maximum length of the packet, the entry size of its mempool.
+    pub fn capacity(&self) -> usize {
+        self.0.get_pool().entry_size()
+    }
+
+    /// Shorten the packet, keeping the first `len` bytes.
+    ///
+    /// Has no effect if the packet is not longer than `len`.
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.0.len() {
+            // Shrinking within the mempool entry can not fail.
+            let _ = self.0.try_resize(len, 0u8);
+        }
+    }
+
+    /// Lengthen the packet to `len` bytes, zeroing the new bytes.
+    ///
+    /// Has no effect if the packet is not shorter than `len`. Fails without modifying the packet
+    /// if `len` exceeds the capacity of the mempool entry.
+    pub fn extend_to(&mut self, len: usize) -> Result<(), wire::PayloadError> {
+        if len <= self.0.len() {
+            return Ok(());
+        }
+
+        if len > self.capacity() {
+            return Err(wire::PayloadError::BadSize);
+        }
+
+        self.0.try_resize(len, 0u8)
+            .map_err(|_| wire::PayloadError::BadSize)
+    }
 }
 
-impl<D: IxyDevice> nic::Device for Phy<D> {
+impl<D: Queues> nic::Device for Phy<D> {
     type Handle = Handle;
     type Payload = Packet;
 
     fn personality(&self) -> nic::Personality {
-        nic::Personality::baseline()
+        self.offloads().personality()
     }
 
     fn tx(&mut self, max: usize, mut sender: impl nic::Send<Self::Handle, Self::Payload>)
         -> NicResult<usize>
     {
-        let now = Instant::now();
-        let mut handles = [Handle::new(now); 32];
-        
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("tx",
+            queue = self.queue,
+            max,
+            pending = self.tx_queue.len(),
+            queued = tracing::field::Empty,
+        ).entered();
+
+        let now = match self.cycle.take() {
+            Some(now) => now,
+            None => self.clock.now(),
+        };
+        self.get_tx(max);
+        let count = self.tx_empty.len().min(max);
+        self.reset_handles(count, now);
+
         // Provide packets to the sender.
-        let packets = self
-            .get_tx()
-            .zip(handles.iter_mut())
+        // Back is the last sent packet, best chance to still be in TLB/mmio cache?
+        let packets = self.tx_empty
+            .iter_mut()
+            .zip(self.handles.iter_mut())
             .map(|(packet, handle)| {
                 nic::Packet {
                     handle,
                     payload: Packet::from_mut(packet),
                 }
-            })
-            .take(max);
+            });
 
-        let count = packets.len();
         sender.sendv(packets);
 
         // Gather potentially sent and step through those that were marked as sent.
+        let device = &mut self.device;
+        let queue = self.queue;
+        let pool = &self.pool;
         let tx_queue = &mut self.tx_queue;
-        let sent = self.tx_empty
-            .drain(..count)
-            .zip(handles.iter())
-            .fold(0, |count, (packet, handle)| {
-                count + if handle.queued {
-                    tx_queue.push_back(packet);
-                    1
+        let tx_empty = &mut self.tx_empty;
+        let batch_size = self.batch_size;
+        let stats = &mut self.phy_stats;
+        let mut pending = self.handles.len();
+        let sent = self.handles
+            .iter()
+            .fold(0, |count, handle| {
+                // There is one handle for each of the buffers at the front.
+                let packet = tx_empty.pop_front().unwrap();
+                pending -= 1;
+                let (sent, recycled) = if handle.queued {
+                    // A buffer whose contents were copied into segments is recycled.
+                    (1, enqueue(device, queue, pool, tx_queue, stats, packet, handle))
                 } else {
-                    // Drops packet
-                    0
+                    stats.tx_unused += 1;
+                    (0, Some(packet))
+                };
+                // Keep a batch of buffers for sending, drop all others. Those still pending at
+                // the front belong to the remaining handles.
+                if let Some(packet) = recycled {
+                    if tx_empty.len() - pending < batch_size {
+                        tx_empty.push_back(packet);
+                    }
                 }
+                count + sent
             });
-        self.flush();
+        #[cfg(feature = "tracing")]
+        span.record("queued", &sent);
+        self.poll_flush();
         Ok(sent)
     }
 
     fn rx(&mut self, max: usize, mut receptor: impl nic::Recv<Self::Handle, Self::Payload>)
         -> NicResult<usize>
     {
-        let now = Instant::now();
-        let mut handles = [Handle::new(now); 32];
+        #[cfg(feature = "tracing")]
+        let span = tracing::trace_span!("rx",
+            queue = self.queue,
+            max,
+            buffered = self.rx_queue.len(),
+            received = tracing::field::Empty,
+        ).entered();
+
+        let now = self.clock.now();
+        self.cycle = Some(now);
+        self.get_rx(max);
+        let count = self.rx_queue.len().min(max);
+        #[cfg(feature = "tracing")]
+        span.record("received", &count);
+        self.reset_handles(count, now);
 
-        // Provide packets to the receiver.
-        let packets = self
-            .get_rx()
-            .zip(handles.iter_mut())
+        // Provide packets to the receiver, in correct time order.
+        let device = &self.device;
+        let queue = self.queue;
+        let vlan_strip = self.vlan_strip;
+        let hardware_offloads = self.hardware_offloads;
+        let packets = self.rx_queue
+            .iter_mut()
+            .zip(self.handles.iter_mut())
             .map(|(packet, handle)| {
+                handle.metadata = device.rx_metadata(queue, packet);
+                if vlan_strip && handle.metadata.vlan.is_none() {
+                    handle.metadata.vlan = offload::strip_vlan(packet);
+                }
+                // Only report the checksums which were actually validated as correct.
+                if hardware_offloads {
+                    handle.offloads.rx_ipv4_checksum = handle.metadata.ipv4_checksum == Some(true);
+                    handle.offloads.rx_l4_checksum = handle.metadata.l4_checksum == Some(true);
+                }
                 nic::Packet {
                     handle,
                     payload: Packet::from_mut(packet),
                 }
-            })
-            .take(max);
-        let count = packets.len();
+            });
         receptor.receivev(packets);
 
-        // Gather those sent again immediately
+        // Consume all packets provided to the receptor, each exactly once. Those sent again
+        // immediately are moved to the send queue, all others are returned to their pool. Any
+        // packets beyond `max` remain buffered for the next call.
+        let device = &mut self.device;
+        let pool = &self.pool;
         let tx_queue = &mut self.tx_queue;
-        let sent = self.rx_queue
-            .drain(..count)
-            .zip(handles.iter())
-            .fold(0, |count, (packet, handle)| {
+        let tx_empty = &mut self.tx_empty;
+        let batch_size = self.batch_size;
+        let rx_queue = &mut self.rx_queue;
+        let stats = &mut self.phy_stats;
+        let sent = self.handles
+            .iter()
+            .fold(0, |count, handle| {
+                // There is one handle for each of the packets at the front.
+                let packet = rx_queue.pop_front().unwrap();
                 count + if handle.queued {
-                    tx_queue.push_back(packet);
+                    // A segmented packet is dropped.
+                    enqueue(device, queue, pool, tx_queue, stats, packet, handle);
                     1
                 } else {
-                    // Drops packet
+                    // Keep a batch of buffers of the tx pool for sending, drop all others.
+                    if tx_empty.len() < batch_size && Rc::ptr_eq(packet.get_pool(), pool) {
+                        tx_empty.push_back(packet);
+                    }
                     0
                 }
             });
-        self.flush();
+        self.poll_flush();
         Ok(sent)
+    }
+}
+
+/// A handle which reveals if its packet was queued.
+///
+/// Needed by wrappers around a device, e.g. `Capture`, which observe the decision of the inner
+/// sender or receiver.
+pub trait Queued: nic::Handle {
+    fn is_queued(&self) -> bool;
+}
 
+impl Queued for Handle {
+    fn is_queued(&self) -> bool {
+        self.queued
     }
 }
 
@@ -214,7 +1402,7 @@ impl nic::Info for Handle {
     }
 
     fn capabilities(&self) -> nic::Capabilities {
-        nic::Capabilities::no_support()
+        self.offloads.capabilities()
     }
 }
 
@@ -235,7 +1423,97 @@ impl wire::PayloadMut for Packet {
     }
 
     fn reframe(&mut self, reframe: wire::Reframe) -> Result<(), wire::PayloadError> {
-        // We always preserve the full prefix.
-        wire::PayloadMut::resize(self, reframe.length)
+        // Grow first such that both payload ranges are in bounds, the mempool entry size limits
+        // the total length.
+        let length = reframe.length;
+        let grown = length.max(self.0.len());
+        wire::PayloadMut::resize(self, grown)?;
+        self.0.as_mut().copy_within(reframe.old_payload, reframe.new_payload.start);
+        wire::PayloadMut::resize(self, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::test_pool;
+
+    fn phy(policy: FlushPolicy) -> Phy<MockDevice> {
+        let pool = test_pool();
+        let mut phy = Phy::new(MockDevice::new(pool.clone()), pool);
+        phy.set_flush_policy(policy);
+        phy
+    }
+
+    /// A sender which does not use any of its buffers.
+    struct Unused;
+
+    impl nic::Send<Handle, Packet> for Unused {
+        fn send(&mut self, _: nic::Packet<Handle, Packet>) {}
+    }
+
+    #[test]
+    fn recycles_at_most_a_batch_of_buffers() {
+        let mut phy = phy(FlushPolicy::Manual);
+        let max = 2 * Phy::<MockDevice>::BATCH_SIZE;
+        assert_eq!(nic::Device::tx(&mut phy, max, Unused).unwrap(), 0);
+        assert_eq!(phy.tx_free(), Phy::<MockDevice>::BATCH_SIZE);
+        assert_eq!(phy.phy_stats().tx_unused, max as u64);
+    }
+
+    #[test]
+    fn flushes_by_packet_count() {
+        let mut phy = phy(FlushPolicy::Packets(3));
+        for _ in 0..2 {
+            assert!(phy.send_frame(&[0; 60]));
+            assert_eq!(phy.poll_flush(), 0);
+        }
+        assert!(phy.send_frame(&[0; 60]));
+        assert_eq!(phy.poll_flush(), 3);
+        assert_eq!(phy.ixy().transmitted().len(), 3);
+        assert_eq!(phy.queue_stats().tx_flushes, 1);
+        assert_eq!(phy.queue_stats().tx_bytes, 180);
+    }
+
+    #[test]
+    fn full_ring_keeps_packets_queued() {
+        let mut phy = phy(FlushPolicy::Manual);
+        phy.device.push_tx_capacity(1);
+        for _ in 0..3 {
+            assert!(phy.send_frame(&[0; 60]));
+        }
+        assert_eq!(phy.flush(), 1);
+        assert_eq!(phy.phy_stats().tx_ring_full, 1);
+        assert_eq!(phy.flush(), 2);
+        assert_eq!(phy.ixy().tx_calls(), 2);
+        assert_eq!(phy.queue_stats().tx_packets, 3);
+    }
+
+    #[test]
+    fn deadline_flushes_manual_policy() {
+        let mut phy = phy(FlushPolicy::Manual);
+        phy.set_flush_deadline(Some(Duration::from_millis(5)));
+        assert!(phy.send_frame(&[0; 60]));
+        // The wait starts with the first poll that sees the packet.
+        assert_eq!(phy.poll_flush(), 0);
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(phy.poll_flush(), 1);
+
+        // An empty queue restarts the deadline.
+        assert!(phy.send_frame(&[0; 60]));
+        assert_eq!(phy.poll_flush(), 0);
+    }
+
+    #[test]
+    fn deferred_suspends_the_deadline() {
+        let mut phy = phy(FlushPolicy::Always);
+        phy.set_flush_deadline(Some(Duration::from_secs(0)));
+        let ((), start) = phy.deferred(|phy| {
+            assert!(phy.send_frame(&[0; 60]));
+            assert_eq!(phy.poll_flush(), 0);
+        });
+        assert_eq!(start, 0);
+        assert!(phy.ixy().transmitted().is_empty());
+        assert_eq!(phy.poll_flush(), 1);
     }
 }
@@ -0,0 +1,69 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The state of the physical link of a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Link {
+    /// Whether the link is established.
+    pub up: bool,
+
+    /// The negotiated speed in Mbit/s, zero while the link is down.
+    pub speed: u32,
+
+    /// Whether the link operates in full duplex.
+    pub full_duplex: bool,
+}
+
+/// The 802.3x flow control configuration of a device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FlowControl {
+    /// Pause sending when receiving pause frames from the link partner.
+    pub rx_pause: bool,
+
+    /// Send pause frames when the receive buffers fill up.
+    pub tx_pause: bool,
+}
+
+/// Counters of 802.3x pause frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PauseStats {
+    /// The number of pause frames received.
+    pub rx_pause_frames: u64,
+
+    /// The number of pause frames sent.
+    pub tx_pause_frames: u64,
+}
+
+impl Link {
+    /// The link state from the speed reported by an ixy driver.
+    ///
+    /// Ixy reports a speed of zero while the link is down. All supported NICs operate in full
+    /// duplex only.
+    pub fn from_speed(speed: u16) -> Self {
+        Link {
+            up: speed != 0,
+            speed: speed.into(),
+            full_duplex: speed != 0,
+        }
+    }
+}
+
+/// Poll the link state until it is up or the timeout expired.
+pub(crate) fn wait_for(mut link: impl FnMut() -> Link, timeout: Duration) -> Option<Link> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(10);
+    let start = Instant::now();
+
+    loop {
+        let state = link();
+        if state.up {
+            return Some(state);
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return None;
+        }
+
+        thread::sleep(POLL_INTERVAL.min(timeout - elapsed));
+    }
+}
@@ -0,0 +1,447 @@
+//! Announcing a phy to its neighbors and discovering them with LLDP, IEEE 802.1AB.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+
+use crate::checksum::read_u16;
+use crate::frame::{ETHERNET_HEADER, MIN_FRAME};
+use crate::{Handle, Packet, Phy, Queues};
+
+/// The multicast address of the nearest bridge, which no bridge forwards.
+const NEAREST_BRIDGE: [u8; 6] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x0e];
+const ETHERTYPE_LLDP: u16 = 0x88cc;
+
+const TLV_END: u8 = 0;
+const TLV_CHASSIS_ID: u8 = 1;
+const TLV_PORT_ID: u8 = 2;
+const TLV_TTL: u8 = 3;
+const TLV_PORT_DESCRIPTION: u8 = 4;
+const TLV_SYSTEM_NAME: u8 = 5;
+const TLV_SYSTEM_DESCRIPTION: u8 = 6;
+const TLV_MANAGEMENT_ADDRESS: u8 = 8;
+
+const CHASSIS_MAC: u8 = 4;
+const PORT_NAME: u8 = 5;
+
+/// The address families of management addresses.
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+
+/// The longest string of a TLV.
+const MAX_STRING: usize = 255;
+
+/// A neighbor of a phy, as of its last LLDPDU.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LldpNeighbor {
+    /// The source address of its LLDPDUs.
+    pub source: [u8; 6],
+
+    /// The subtype and value of the chassis identifier, subtype 4 is a MAC address.
+    pub chassis_id: (u8, Vec<u8>),
+
+    /// The subtype and value of the port identifier, subtype 5 is an interface name.
+    pub port_id: (u8, Vec<u8>),
+    pub port_description: Option<String>,
+    pub system_name: Option<String>,
+    pub system_description: Option<String>,
+
+    /// The IP addresses at which the neighbor is managed.
+    pub management: Vec<IpAddr>,
+
+    /// How long the information is valid after it was received.
+    pub ttl: Duration,
+    pub received: Instant,
+}
+
+/// Counters of an LLDP agent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct LldpStats {
+    /// LLDPDUs sent and received.
+    pub sent: u64,
+    pub received: u64,
+
+    /// Received LLDPDUs without the mandatory TLVs or with a truncated TLV.
+    pub invalid: u64,
+
+    /// Neighbors removed since their information expired.
+    pub aged: u64,
+
+    /// New neighbors ignored since the table was full.
+    pub overflow: u64,
+}
+
+/// A phy announcing itself with LLDP and keeping a table of its neighbors.
+///
+/// The phy is advertised every interval with the chassis of its MAC address, the given port name
+/// and the optional descriptions, system name and management addresses. Changing them announces
+/// the phy again immediately. The information is valid for four intervals.
+///
+/// Received LLDPDUs update the table of neighbors, identified by their chassis and port, which
+/// documents the topology of the link. A neighbor is removed when its information expires or it
+/// announces its shutdown. The LLDP frames are handled internally and never reach the network
+/// stack, everything else passes unchanged. The phy is maintained whenever it is polled.
+pub struct Lldp<D> {
+    phy: Phy<D>,
+    port_id: String,
+    port_description: Option<String>,
+    system_name: Option<String>,
+    system_description: Option<String>,
+    management: Vec<IpAddr>,
+    interval: Duration,
+    /// When the last LLDPDU was sent, `None` to send one immediately.
+    last_tx: Option<Instant>,
+    neighbors: Vec<LldpNeighbor>,
+    max_neighbors: usize,
+    stats: LldpStats,
+}
+
+impl<D> Lldp<D> {
+    /// The default interval between LLDPDUs.
+    pub const INTERVAL: Duration = Duration::from_secs(30);
+
+    /// The number of intervals for which the neighbors keep the information.
+    pub const HOLD: u32 = 4;
+
+    /// The default number of neighbors in the table.
+    pub const MAX_NEIGHBORS: usize = 16;
+
+    /// Announce the phy under a port name, e.g. its interface name.
+    pub fn new(phy: Phy<D>, port_id: String) -> Self {
+        Lldp {
+            phy,
+            port_id,
+            port_description: None,
+            system_name: None,
+            system_description: None,
+            management: Vec::new(),
+            interval: Self::INTERVAL,
+            last_tx: None,
+            neighbors: Vec::new(),
+            max_neighbors: Self::MAX_NEIGHBORS,
+            stats: LldpStats::default(),
+        }
+    }
+
+    pub fn port_id(&self) -> &str {
+        &self.port_id
+    }
+
+    pub fn set_port_id(&mut self, port_id: String) {
+        self.port_id = port_id;
+        self.last_tx = None;
+    }
+
+    pub fn port_description(&self) -> Option<&str> {
+        self.port_description.as_deref()
+    }
+
+    pub fn set_port_description(&mut self, description: Option<String>) {
+        self.port_description = description;
+        self.last_tx = None;
+    }
+
+    pub fn system_name(&self) -> Option<&str> {
+        self.system_name.as_deref()
+    }
+
+    pub fn set_system_name(&mut self, name: Option<String>) {
+        self.system_name = name;
+        self.last_tx = None;
+    }
+
+    pub fn system_description(&self) -> Option<&str> {
+        self.system_description.as_deref()
+    }
+
+    pub fn set_system_description(&mut self, description: Option<String>) {
+        self.system_description = description;
+        self.last_tx = None;
+    }
+
+    pub fn management(&self) -> &[IpAddr] {
+        &self.management
+    }
+
+    /// Advertise the IP addresses at which the system is managed.
+    pub fn set_management(&mut self, addresses: Vec<IpAddr>) {
+        self.management = addresses;
+        self.last_tx = None;
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Send LLDPDUs every `interval`.
+    ///
+    /// ## Panics
+    /// This function panics if the interval is zero.
+    pub fn set_interval(&mut self, interval: Duration) {
+        assert!(interval > Duration::from_secs(0), "The interval must not be zero");
+        self.interval = interval;
+        self.last_tx = None;
+    }
+
+    pub fn max_neighbors(&self) -> usize {
+        self.max_neighbors
+    }
+
+    /// Limit the table of neighbors, an existing neighbor beyond the limit stays until it expires.
+    pub fn set_max_neighbors(&mut self, max: usize) {
+        self.max_neighbors = max;
+    }
+
+    /// The neighbors in the order they were discovered.
+    pub fn neighbors(&self) -> &[LldpNeighbor] {
+        &self.neighbors
+    }
+
+    pub fn stats(&self) -> LldpStats {
+        self.stats
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+
+    /// The time to live advertised to the neighbors.
+    fn ttl(&self) -> u16 {
+        let ttl = self.interval.as_secs().saturating_mul(u64::from(Self::HOLD));
+        ttl.max(1).min(u64::from(u16::MAX)) as u16
+    }
+
+    /// The LLDPDU announcing the phy with its MAC address.
+    fn lldpdu(&self, mac: [u8; 6], ttl: u16) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(256);
+        frame.extend_from_slice(&NEAREST_BRIDGE);
+        frame.extend_from_slice(&mac);
+        frame.extend_from_slice(&ETHERTYPE_LLDP.to_be_bytes());
+
+        tlv(&mut frame, TLV_CHASSIS_ID, &[&[CHASSIS_MAC], &mac]);
+        tlv(&mut frame, TLV_PORT_ID, &[&[PORT_NAME], string(&self.port_id)]);
+        tlv(&mut frame, TLV_TTL, &[&ttl.to_be_bytes()]);
+        if let Some(description) = &self.port_description {
+            tlv(&mut frame, TLV_PORT_DESCRIPTION, &[string(description)]);
+        }
+        if let Some(name) = &self.system_name {
+            tlv(&mut frame, TLV_SYSTEM_NAME, &[string(name)]);
+        }
+        if let Some(description) = &self.system_description {
+            tlv(&mut frame, TLV_SYSTEM_DESCRIPTION, &[string(description)]);
+        }
+        for address in &self.management {
+            let (family, octets) = match address {
+                IpAddr::V4(address) => (FAMILY_IPV4, address.octets().to_vec()),
+                IpAddr::V6(address) => (FAMILY_IPV6, address.octets().to_vec()),
+            };
+            // The length of family and address, then an unknown interface and no OID.
+            let header = [octets.len() as u8 + 1, family];
+            tlv(&mut frame, TLV_MANAGEMENT_ADDRESS, &[&header, &octets, &[1, 0, 0, 0, 0, 0]]);
+        }
+        tlv(&mut frame, TLV_END, &[]);
+
+        frame.resize(frame.len().max(MIN_FRAME), 0);
+        frame
+    }
+
+    /// Remove the neighbors whose information expired.
+    fn expire(&mut self, now: Instant) {
+        let before = self.neighbors.len();
+        self.neighbors.retain(|neighbor| now - neighbor.received < neighbor.ttl);
+        self.stats.aged += (before - self.neighbors.len()) as u64;
+    }
+
+    /// Update the table with a received LLDPDU.
+    fn learn(&mut self, neighbor: LldpNeighbor) {
+        let known = self.neighbors.iter().position(|known| {
+            known.chassis_id == neighbor.chassis_id && known.port_id == neighbor.port_id
+        });
+        match known {
+            // A time to live of zero announces the shutdown of the neighbor.
+            Some(index) if neighbor.ttl == Duration::from_secs(0) => {
+                self.neighbors.remove(index);
+            },
+            Some(index) => self.neighbors[index] = neighbor,
+            None if neighbor.ttl == Duration::from_secs(0) => (),
+            None if self.neighbors.len() >= self.max_neighbors => self.stats.overflow += 1,
+            None => self.neighbors.push(neighbor),
+        }
+    }
+}
+
+impl<D: Queues> Lldp<D> {
+    /// Announce the shutdown of the phy, so that the neighbors forget it immediately.
+    ///
+    /// The phy is announced again once polled. Returns `false` if the pool had no free buffer.
+    pub fn withdraw(&mut self) -> bool {
+        let frame = self.lldpdu(self.phy.mac_address(), 0);
+        if !self.phy.send_frame(&frame) {
+            return false;
+        }
+        self.stats.sent += 1;
+        self.phy.poll_flush();
+        true
+    }
+
+    /// Expire neighbors and send a due LLDPDU.
+    fn maintain(&mut self) {
+        let now = Instant::now();
+        self.expire(now);
+
+        let due = self.last_tx.map_or(true, |last_tx| now - last_tx >= self.interval);
+        if !due {
+            return;
+        }
+        let frame = self.lldpdu(self.phy.mac_address(), self.ttl());
+        if self.phy.send_frame(&frame) {
+            self.stats.sent += 1;
+            self.last_tx = Some(now);
+        }
+    }
+
+    /// Take the LLDPDUs out of the received packets.
+    fn receive_lldp(&mut self, max: usize) {
+        let now = Instant::now();
+        self.phy.get_rx(max);
+        let mut received = Vec::new();
+        let mut invalid = 0;
+        self.phy.rx_queue.retain(|packet| {
+            if !is_lldp(packet) {
+                return true;
+            }
+            match parse_lldpdu(packet, now) {
+                Some(neighbor) => received.push(neighbor),
+                None => invalid += 1,
+            }
+            false
+        });
+
+        self.stats.invalid += invalid;
+        self.stats.received += received.len() as u64 + invalid;
+        for neighbor in received {
+            self.learn(neighbor);
+        }
+    }
+}
+
+fn is_lldp(frame: &[u8]) -> bool {
+    frame.len() >= ETHERNET_HEADER && read_u16(frame, 12) == ETHERTYPE_LLDP
+}
+
+/// The neighbor described by an LLDPDU.
+fn parse_lldpdu(frame: &[u8], now: Instant) -> Option<LldpNeighbor> {
+    let mut source = [0; 6];
+    source.copy_from_slice(&frame[6..12]);
+    let (mut chassis_id, mut port_id, mut ttl) = (None, None, None);
+    let mut neighbor = LldpNeighbor {
+        source,
+        chassis_id: (0, Vec::new()),
+        port_id: (0, Vec::new()),
+        port_description: None,
+        system_name: None,
+        system_description: None,
+        management: Vec::new(),
+        ttl: Duration::from_secs(0),
+        received: now,
+    };
+
+    let mut tlvs = &frame[ETHERNET_HEADER..];
+    loop {
+        if tlvs.len() < 2 {
+            return None;
+        }
+        let header = read_u16(tlvs, 0);
+        let len = usize::from(header & 0x01ff);
+        let value = tlvs.get(2..2 + len)?;
+        match (header >> 9) as u8 {
+            TLV_END => break,
+            TLV_CHASSIS_ID if len >= 2 => chassis_id = Some((value[0], value[1..].to_vec())),
+            TLV_PORT_ID if len >= 2 => port_id = Some((value[0], value[1..].to_vec())),
+            TLV_TTL if len >= 2 => ttl = Some(read_u16(value, 0)),
+            TLV_PORT_DESCRIPTION => neighbor.port_description = Some(text(value)),
+            TLV_SYSTEM_NAME => neighbor.system_name = Some(text(value)),
+            TLV_SYSTEM_DESCRIPTION => neighbor.system_description = Some(text(value)),
+            TLV_MANAGEMENT_ADDRESS => neighbor.management.extend(management_address(value)),
+            _ => (),
+        }
+        tlvs = &tlvs[2 + len..];
+    }
+
+    neighbor.chassis_id = chassis_id?;
+    neighbor.port_id = port_id?;
+    neighbor.ttl = Duration::from_secs(ttl?.into());
+    Some(neighbor)
+}
+
+/// The IP address of a management address TLV.
+fn management_address(value: &[u8]) -> Option<IpAddr> {
+    let len = usize::from(*value.first()?);
+    let address = value.get(2..1 + len)?;
+    match (value[1], address.len()) {
+        (FAMILY_IPV4, 4) => {
+            Some(IpAddr::V4(Ipv4Addr::new(address[0], address[1], address[2], address[3])))
+        },
+        (FAMILY_IPV6, 16) => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(address);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => None,
+    }
+}
+
+/// Append a TLV whose value is the concatenation of `parts`.
+fn tlv(frame: &mut Vec<u8>, kind: u8, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let header = u16::from(kind) << 9 | len as u16;
+    frame.extend_from_slice(&header.to_be_bytes());
+    for part in parts {
+        frame.extend_from_slice(part);
+    }
+}
+
+/// A string truncated to the length of a TLV.
+fn string(value: &str) -> &[u8] {
+    let bytes = value.as_bytes();
+    &bytes[..bytes.len().min(MAX_STRING)]
+}
+
+fn text(value: &[u8]) -> String {
+    String::from_utf8_lossy(value).into_owned()
+}
+
+impl<D: Queues> nic::Device for Lldp<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.phy)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.maintain();
+        nic::Device::tx(&mut self.phy, max, sender)
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.receive_lldp(max);
+        let received = nic::Device::rx(&mut self.phy, max, receptor)?;
+        self.maintain();
+        self.phy.poll_flush();
+        Ok(received)
+    }
+}
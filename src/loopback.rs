@@ -0,0 +1,171 @@
+//! Devices reflecting sent packets, for testing without a NIC.
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::rc::Rc;
+
+use ixy::{DeviceStats, IxyDevice};
+use ixy::memory::{Mempool, Packet as IxyPacket};
+
+/// An ixy device which receives every packet sent on the same queue.
+///
+/// Packets are moved from tx to rx without copying, all queues share one mempool. No PCI device
+/// is required, but the mempool of ixy is still allocated in hugepages.
+///
+/// Connected to another device by `pair`, the packets are received by that device instead.
+pub struct LoopbackDevice {
+    pool: Rc<Mempool>,
+    mac: Cell<[u8; 6]>,
+    /// The rings of received packets, per queue.
+    rx: Rings,
+    /// The rings into which sent packets are moved, the own rings for loopback.
+    tx: Rings,
+    ring_size: usize,
+    /// Counters since the last read.
+    stats: Cell<DeviceStats>,
+}
+
+type Rings = Rc<RefCell<Vec<VecDeque<IxyPacket>>>>;
+
+/// Create two connected devices, like a veth pair.
+///
+/// Frames sent on a queue of one device are received on the same queue of the other. Both use
+/// the same pool and have distinct addresses, so that two network stacks can talk to each other
+/// in a single process.
+///
+/// ## Panics
+/// This function panics if `queues` is zero.
+pub fn pair(pool: Rc<Mempool>, queues: u32) -> (LoopbackDevice, LoopbackDevice) {
+    let mut first = LoopbackDevice::new(pool.clone(), queues);
+    let mut second = LoopbackDevice::new(pool, queues);
+    std::mem::swap(&mut first.rx, &mut second.rx);
+    second.mac.set(LoopbackDevice::PEER_MAC);
+    (first, second)
+}
+
+impl LoopbackDevice {
+    /// The default number of packets in flight per queue.
+    pub const RING_SIZE: usize = 512;
+
+    /// The default number of entries of an allocated mempool.
+    pub const POOL_ENTRIES: usize = 4096;
+
+    /// A locally administered address.
+    pub const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+    /// The address of the second device of a `pair`.
+    pub const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x03];
+
+    /// Create a device with some queues using packets from a pool.
+    ///
+    /// ## Panics
+    /// This function panics if `queues` is zero.
+    pub fn new(pool: Rc<Mempool>, queues: u32) -> Self {
+        Self::with_ring_size(pool, queues, Self::RING_SIZE)
+    }
+
+    /// Create a device holding up to `ring_size` packets per queue.
+    ///
+    /// Sending on a full queue fails until packets have been received.
+    pub fn with_ring_size(pool: Rc<Mempool>, queues: u32, ring_size: usize) -> Self {
+        assert!(queues > 0, "Loopback needs at least one queue");
+        let rings: Vec<_> = (0..queues).map(|_| VecDeque::with_capacity(ring_size)).collect();
+        let rings = Rc::new(RefCell::new(rings));
+        LoopbackDevice {
+            pool,
+            mac: Cell::new(Self::MAC),
+            rx: rings.clone(),
+            tx: rings,
+            ring_size,
+            stats: Cell::new(DeviceStats::default()),
+        }
+    }
+
+    /// Create a device with a newly allocated mempool of 2048 byte entries.
+    pub fn allocate(queues: u32) -> Result<Self, Box<dyn Error>> {
+        let pool = Mempool::allocate(Self::POOL_ENTRIES, 2048)?;
+        Ok(Self::new(pool, queues))
+    }
+
+    /// The number of packets waiting to be received on a queue.
+    pub fn pending(&self, queue: u32) -> usize {
+        self.rx.borrow().get(queue as usize).map_or(0, VecDeque::len)
+    }
+}
+
+impl IxyDevice for LoopbackDevice {
+    fn get_driver_name(&self) -> &str {
+        "loopback"
+    }
+
+    fn get_pci_addr(&self) -> &str {
+        "loopback"
+    }
+
+    fn get_mac_addr(&self) -> [u8; 6] {
+        self.mac.get()
+    }
+
+    fn set_mac_addr(&self, mac: [u8; 6]) {
+        self.mac.set(mac)
+    }
+
+    fn rx_batch(&mut self, queue_id: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut rings = self.rx.borrow_mut();
+        let ring = match rings.get_mut(queue_id as usize) {
+            Some(ring) => ring,
+            None => return 0,
+        };
+
+        let count = ring.len().min(num_packets);
+        let mut stats = self.stats.get();
+        for packet in ring.drain(..count) {
+            stats.rx_pkts += 1;
+            stats.rx_bytes += packet.len() as u64;
+            buffer.push_back(packet);
+        }
+        self.stats.set(stats);
+        count
+    }
+
+    fn tx_batch(&mut self, queue_id: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let mut rings = self.tx.borrow_mut();
+        let ring = match rings.get_mut(queue_id as usize) {
+            Some(ring) => ring,
+            None => return 0,
+        };
+
+        let count = (self.ring_size - ring.len()).min(buffer.len());
+        let mut stats = self.stats.get();
+        for packet in buffer.drain(..count) {
+            stats.tx_pkts += 1;
+            stats.tx_bytes += packet.len() as u64;
+            ring.push_back(packet);
+        }
+        self.stats.set(stats);
+        count
+    }
+
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        // Like the hardware counters, these are cleared on read.
+        let new = self.stats.replace(DeviceStats::default());
+        stats.rx_pkts += new.rx_pkts;
+        stats.tx_pkts += new.tx_pkts;
+        stats.rx_bytes += new.rx_bytes;
+        stats.tx_bytes += new.tx_bytes;
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.set(DeviceStats::default());
+    }
+
+    fn get_link_speed(&self) -> u16 {
+        10_000
+    }
+
+    fn recv_pool(&self, _: u32) -> Option<&Rc<Mempool>> {
+        Some(&self.pool)
+    }
+}
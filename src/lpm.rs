@@ -0,0 +1,312 @@
+//! A longest prefix match table for IPv4 and IPv6 addresses.
+use std::collections::BTreeMap;
+use std::mem;
+use std::net::IpAddr;
+use std::ops::Range;
+
+use crate::Prefix;
+
+/// Maps prefixes to values and finds the longest prefix containing an address.
+///
+/// Each IP version has a multibit trie with expanded prefixes, the first level indexed by 16 bits
+/// of the address and every level below by 8 bits. A lookup reads one slot per level, at most 3
+/// for IPv4 and 15 for IPv6, regardless of the number of prefixes. The first level of a version
+/// is allocated with its first prefix, the tables below when a longer prefix needs them. Tables
+/// are kept when their prefixes are removed, until the table is cleared.
+pub struct Lpm<T> {
+    prefixes: BTreeMap<Prefix, u32>,
+    values: Vec<Option<(Prefix, T)>>,
+    free: Vec<u32>,
+    v4: Trie,
+    v6: Trie,
+}
+
+#[derive(Default)]
+struct Trie {
+    slots: Vec<Slot>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    /// The offset of the table below, zero if there is none.
+    child: u32,
+    /// The index of the value plus one, zero if there is none.
+    value: u32,
+    /// The length of the prefix of the value.
+    len: u8,
+}
+
+/// The state of one address in a batch lookup.
+#[derive(Clone, Copy, Default)]
+struct Cursor {
+    v6: bool,
+    key: u128,
+    slot: Option<usize>,
+    value: u32,
+}
+
+const ROOT_BITS: u32 = 16;
+const LEVEL_BITS: u32 = 8;
+
+/// The number of addresses looked up in lockstep.
+const BATCH: usize = 16;
+
+impl<T> Lpm<T> {
+    pub fn new() -> Self {
+        Lpm {
+            prefixes: BTreeMap::new(),
+            values: Vec::new(),
+            free: Vec::new(),
+            v4: Trie::default(),
+            v6: Trie::default(),
+        }
+    }
+
+    /// Insert the value of a prefix, returning the value it replaced.
+    pub fn insert(&mut self, prefix: Prefix, value: T) -> Option<T> {
+        if let Some(&id) = self.prefixes.get(&prefix) {
+            if let Some((_, old)) = &mut self.values[id as usize] {
+                return Some(mem::replace(old, value));
+            }
+        }
+
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.values[id as usize] = Some((prefix, value));
+                id
+            },
+            None => {
+                self.values.push(Some((prefix, value)));
+                (self.values.len() - 1) as u32
+            },
+        };
+        self.prefixes.insert(prefix, id);
+
+        let len = prefix.prefix_len();
+        let (trie, key) = self.trie_mut(prefix.addr());
+        for slot in trie.expand(key, len) {
+            trie.fill(slot, id + 1, len);
+        }
+        None
+    }
+
+    /// Remove a prefix, returning its value.
+    ///
+    /// Addresses of the prefix match the next shorter prefix afterwards.
+    pub fn remove(&mut self, prefix: Prefix) -> Option<T> {
+        let id = self.prefixes.remove(&prefix)?;
+        let len = prefix.prefix_len();
+        let (covering, covering_len) = (0..len)
+            .rev()
+            .find_map(|shorter| {
+                let id = self.prefixes.get(&Prefix::new(prefix.addr(), shorter))?;
+                Some((id + 1, shorter))
+            })
+            .unwrap_or((0, 0));
+
+        let (trie, key) = self.trie_mut(prefix.addr());
+        for slot in trie.expand(key, len) {
+            trie.replace(slot, id + 1, covering, covering_len);
+        }
+        self.free.push(id);
+        self.values[id as usize].take().map(|(_, value)| value)
+    }
+
+    /// The value of exactly this prefix.
+    pub fn get(&self, prefix: Prefix) -> Option<&T> {
+        let &id = self.prefixes.get(&prefix)?;
+        self.entry(id + 1).map(|(_, value)| value)
+    }
+
+    /// The value of the longest prefix containing the address.
+    pub fn lookup(&self, addr: IpAddr) -> Option<&T> {
+        self.longest_match(addr).map(|(_, value)| value)
+    }
+
+    /// The longest prefix containing the address, and its value.
+    pub fn longest_match(&self, addr: IpAddr) -> Option<(Prefix, &T)> {
+        let (trie, key) = self.trie(addr);
+        self.entry(trie.lookup(key))
+    }
+
+    /// Look up the values of a batch of addresses, into the results at the same index.
+    ///
+    /// Up to 16 addresses descend the tries in lockstep, so the memory accesses of their levels
+    /// overlap instead of waiting for each other.
+    ///
+    /// ## Panics
+    /// This function panics if there are fewer results than addresses.
+    pub fn lookup_batch<'a>(&'a self, addrs: &[IpAddr], results: &mut [Option<&'a T>]) {
+        assert!(results.len() >= addrs.len(), "Fewer results than addresses");
+        for (addrs, results) in addrs.chunks(BATCH).zip(results.chunks_mut(BATCH)) {
+            let mut cursors = [Cursor::default(); BATCH];
+            let cursors = &mut cursors[..addrs.len()];
+            for (cursor, &addr) in cursors.iter_mut().zip(addrs) {
+                let (trie, key) = self.trie(addr);
+                cursor.v6 = addr.is_ipv6();
+                cursor.key = key;
+                if !trie.slots.is_empty() {
+                    cursor.slot = Some(index(key, 0, ROOT_BITS));
+                }
+            }
+
+            let mut start = ROOT_BITS;
+            let mut descending = true;
+            while descending {
+                descending = false;
+                for cursor in cursors.iter_mut() {
+                    let position = match cursor.slot {
+                        Some(position) => position,
+                        None => continue,
+                    };
+                    let trie = if cursor.v6 { &self.v6 } else { &self.v4 };
+                    let slot = trie.slots[position];
+                    if slot.child == 0 {
+                        cursor.value = slot.value;
+                        cursor.slot = None;
+                    } else {
+                        let below = index(cursor.key, start, LEVEL_BITS);
+                        cursor.slot = Some(slot.child as usize + below);
+                        descending = true;
+                    }
+                }
+                start += LEVEL_BITS;
+            }
+
+            for (result, cursor) in results.iter_mut().zip(cursors.iter()) {
+                *result = self.entry(cursor.value).map(|(_, value)| value);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.prefixes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+
+    /// All prefixes and their values, ordered by prefix.
+    pub fn iter(&self) -> impl Iterator<Item=(Prefix, &T)> {
+        self.prefixes.values().filter_map(move |&id| self.entry(id + 1))
+    }
+
+    /// All values, in no particular order.
+    pub fn values_mut(&mut self) -> impl Iterator<Item=&mut T> {
+        self.values.iter_mut().filter_map(|entry| entry.as_mut().map(|(_, value)| value))
+    }
+
+    /// Remove all prefixes and release the tables.
+    pub fn clear(&mut self) {
+        *self = Lpm::new();
+    }
+
+    fn entry(&self, value: u32) -> Option<(Prefix, &T)> {
+        let index = (value as usize).checked_sub(1)?;
+        let (prefix, value) = self.values.get(index)?.as_ref()?;
+        Some((*prefix, value))
+    }
+
+    fn trie(&self, addr: IpAddr) -> (&Trie, u128) {
+        match addr {
+            IpAddr::V4(addr) => (&self.v4, u128::from(u32::from(addr)) << 96),
+            IpAddr::V6(addr) => (&self.v6, u128::from(addr)),
+        }
+    }
+
+    fn trie_mut(&mut self, addr: IpAddr) -> (&mut Trie, u128) {
+        match addr {
+            IpAddr::V4(addr) => (&mut self.v4, u128::from(u32::from(addr)) << 96),
+            IpAddr::V6(addr) => (&mut self.v6, u128::from(addr)),
+        }
+    }
+}
+
+impl<T> Default for Lpm<T> {
+    fn default() -> Self {
+        Lpm::new()
+    }
+}
+
+impl Trie {
+    fn lookup(&self, key: u128) -> u32 {
+        if self.slots.is_empty() {
+            return 0;
+        }
+
+        let mut slot = self.slots[index(key, 0, ROOT_BITS)];
+        let mut start = ROOT_BITS;
+        while slot.child != 0 {
+            slot = self.slots[slot.child as usize + index(key, start, LEVEL_BITS)];
+            start += LEVEL_BITS;
+        }
+        slot.value
+    }
+
+    /// The slots a prefix expands to, creating the tables above them.
+    ///
+    /// A new table starts with the value of the slot above it for all of its slots.
+    fn expand(&mut self, key: u128, len: u8) -> Range<usize> {
+        if self.slots.is_empty() {
+            self.slots = vec![Slot::default(); 1 << ROOT_BITS];
+        }
+
+        let len = u32::from(len);
+        let (mut offset, mut start, mut stride) = (0, 0, ROOT_BITS);
+        while len > start + stride {
+            let above = offset + index(key, start, stride);
+            if self.slots[above].child == 0 {
+                let child = self.slots.len();
+                let inherited = Slot { child: 0, ..self.slots[above] };
+                self.slots.resize(child + (1 << LEVEL_BITS), inherited);
+                self.slots[above].child = child as u32;
+            }
+            offset = self.slots[above].child as usize;
+            start += stride;
+            stride = LEVEL_BITS;
+        }
+
+        let span = 1 << (start + stride - len);
+        let first = offset + (index(key, start, stride) & !(span - 1));
+        first..first + span
+    }
+
+    /// Set a slot and the tables below it to a value, unless they have a longer prefix.
+    fn fill(&mut self, position: usize, value: u32, len: u8) {
+        let slot = self.slots[position];
+        // The slots below never have a shorter prefix than the slot above them.
+        if slot.len > len {
+            return;
+        }
+
+        self.slots[position] = Slot { value, len, ..slot };
+        if slot.child != 0 {
+            let child = slot.child as usize;
+            for below in child..child + (1 << LEVEL_BITS) {
+                self.fill(below, value, len);
+            }
+        }
+    }
+
+    /// Replace a value in a slot and the tables below it.
+    fn replace(&mut self, position: usize, old: u32, value: u32, len: u8) {
+        let slot = self.slots[position];
+        if slot.value != old {
+            return;
+        }
+
+        self.slots[position] = Slot { value, len, ..slot };
+        if slot.child != 0 {
+            let child = slot.child as usize;
+            for below in child..child + (1 << LEVEL_BITS) {
+                self.replace(below, old, value, len);
+            }
+        }
+    }
+}
+
+/// The `stride` bits of a key after the first `start`.
+fn index(key: u128, start: u32, stride: u32) -> usize {
+    ((key << start) >> (128 - stride)) as usize
+}
@@ -0,0 +1,328 @@
+//! Software large receive offload.
+//!
+//! Coalesces consecutive TCP segments of the same flow into a single packet before they are
+//! handed to the network stack, reducing the per-packet overhead of the stack at high rates.
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ixy::memory::{self, Mempool, Packet as IxyPacket};
+
+use crate::Metadata;
+use crate::checksum::{self, read_u16, write_u16};
+use crate::frame::{Headers, ETHERTYPE_IPV4, PROTO_TCP};
+use crate::offload::{insert_checksums, TxOffload};
+
+/// TCP flags allowed in coalesced segments, ACK and PSH.
+const MERGEABLE_FLAGS: u8 = 0x18;
+const ACK: u8 = 0x10;
+
+/// The location of a coalescable TCP segment in a frame.
+struct Segment {
+    headers: Headers,
+    /// The offset of the TCP header.
+    tcp: usize,
+    /// The offset of the TCP payload.
+    payload: usize,
+    seq: u32,
+    /// The VLAN tag stripped by the NIC.
+    vlan: Option<u16>,
+}
+
+impl Segment {
+    /// Parse a segment whose checksums are valid, as reported by the NIC or verified in software.
+    fn parse(frame: &[u8], metadata: &Metadata) -> Option<Self> {
+        let headers = Headers::parse(frame)?;
+        let tcp = headers.transport(PROTO_TCP)?;
+        if tcp + 20 > headers.end {
+            return None;
+        }
+
+        let flags = frame[tcp + 13];
+        if flags & !MERGEABLE_FLAGS != 0 || flags & ACK == 0 {
+            return None;
+        }
+
+        let payload = tcp + usize::from(frame[tcp + 12] >> 4) * 4;
+        if payload >= headers.end {
+            return None;
+        }
+
+        let ipv4 = headers.ethertype == ETHERTYPE_IPV4;
+        let ipv4_valid = !ipv4 || metadata.ipv4_checksum.unwrap_or_else(|| {
+            let (l3, ihl) = (headers.l3, usize::from(frame[headers.l3] & 0x0f) * 4);
+            checksum::finish(checksum::sum(&frame[l3..l3 + ihl], 0)) == 0
+        });
+        let tcp_valid = metadata.l4_checksum.unwrap_or_else(|| {
+            let acc = headers.pseudo_header(frame, PROTO_TCP, headers.end - tcp);
+            checksum::finish(checksum::sum(&frame[tcp..headers.end], acc)) == 0
+        });
+        if !ipv4_valid || !tcp_valid {
+            return None;
+        }
+
+        let seq = u32::from_be_bytes([
+            frame[tcp + 4], frame[tcp + 5], frame[tcp + 6], frame[tcp + 7],
+        ]);
+        Some(Segment { headers, tcp, payload, seq, vlan: metadata.vlan })
+    }
+
+    fn len(&self) -> usize {
+        self.headers.end - self.payload
+    }
+
+    /// Check if `next` continues the flow of this segment directly after `end_seq`.
+    fn continued_by(&self, frame: &[u8], next: &Segment, other: &[u8], end_seq: u32) -> bool {
+        let (l3, other_l3) = (self.headers.l3, next.headers.l3);
+        let (addresses, fixed) = match self.headers.ethertype {
+            ETHERTYPE_IPV4 => (12..20, 20),
+            _ => (8..40, 40),
+        };
+
+        self.headers.ethertype == next.headers.ethertype
+            && self.payload - self.tcp == next.payload - next.tcp
+            && l3 == other_l3
+            && self.tcp - l3 == next.tcp - other_l3
+            // Addresses and VLAN tags, both those in the frame and those stripped by the NIC.
+            && frame[..l3] == other[..other_l3]
+            && self.vlan == next.vlan
+            && frame[l3 + addresses.start..l3 + addresses.end]
+                == other[l3 + addresses.start..l3 + addresses.end]
+            // The merged packet carries the options of the first segment only.
+            && frame[l3 + fixed..self.tcp] == other[other_l3 + fixed..next.tcp]
+            && frame[self.tcp..self.tcp + 4] == other[next.tcp..next.tcp + 4]
+            && next.seq == end_seq
+    }
+}
+
+/// Coalesce consecutive segments in the queue into buffers from the pool.
+///
+/// Segments are only merged up to the entry size of the pool, so this has no effect unless its
+/// entries are larger than the received frames. The merged packet carries the TCP header of the
+/// last segment, i.e. its acknowledgment and window, and freshly calculated checksums. Only
+/// segments with valid checksums are merged, those the NIC did not validate are verified here.
+pub(crate) fn coalesce(
+    queue: &mut VecDeque<IxyPacket>,
+    pool: &Rc<Mempool>,
+    metadata: impl Fn(&IxyPacket) -> Metadata,
+) {
+    let limit = pool.entry_size();
+    let mut out = VecDeque::with_capacity(queue.len());
+
+    while let Some(first) = queue.pop_front() {
+        let head = match Segment::parse(&first, &metadata(&first)) {
+            Some(head) => head,
+            None => {
+                out.push_back(first);
+                continue;
+            },
+        };
+
+        let max_ip = match head.headers.ethertype {
+            ETHERTYPE_IPV4 => 0xffff,
+            _ => 0xffff + 40,
+        };
+
+        let mut run = Vec::new();
+        let mut total = head.headers.end;
+        let mut end_seq = head.seq.wrapping_add(head.len() as u32);
+        while let Some(next) = queue.front() {
+            let segment = match Segment::parse(next, &metadata(next)) {
+                Some(segment) => segment,
+                None => break,
+            };
+
+            let len = total + segment.len();
+            if len > limit || len - head.headers.l3 > max_ip
+                || !head.continued_by(&first, &segment, next, end_seq)
+            {
+                break;
+            }
+
+            total = len;
+            end_seq = end_seq.wrapping_add(segment.len() as u32);
+            run.push((queue.pop_front().unwrap(), segment));
+        }
+
+        if run.is_empty() {
+            out.push_back(first);
+            continue;
+        }
+
+        let mut merged = match memory::alloc_pkt(pool, total) {
+            Some(merged) => merged,
+            None => {
+                out.push_back(first);
+                out.extend(run.into_iter().map(|(packet, _)| packet));
+                continue;
+            },
+        };
+
+        {
+            let buffer: &mut [u8] = merged.as_mut();
+            let (last, last_segment) = run.last().unwrap();
+            // Headers up to and including the TCP header of the last segment.
+            buffer[..head.payload].copy_from_slice(&last[..last_segment.payload]);
+            buffer[head.tcp + 4..head.tcp + 8].copy_from_slice(&head.seq.to_be_bytes());
+
+            let mut offset = head.payload;
+            let mut push = first[head.tcp + 13];
+            buffer[offset..offset + head.len()]
+                .copy_from_slice(&first[head.payload..head.headers.end]);
+            offset += head.len();
+            for (packet, segment) in run.iter() {
+                buffer[offset..offset + segment.len()]
+                    .copy_from_slice(&packet[segment.payload..segment.headers.end]);
+                offset += segment.len();
+                push |= packet[segment.tcp + 13];
+            }
+            buffer[head.tcp + 13] |= push & 0x08;
+
+            let l3 = head.headers.l3;
+            if head.headers.ethertype == ETHERTYPE_IPV4 {
+                write_u16(buffer, l3 + 2, (total - l3) as u16);
+                let ident = read_u16(&first, l3 + 4);
+                write_u16(buffer, l3 + 4, ident);
+            } else {
+                write_u16(buffer, l3 + 4, (total - l3 - 40) as u16);
+            }
+
+            insert_checksums(buffer, TxOffload {
+                ipv4_checksum: true,
+                l4_checksum: true,
+                mss: None,
+                vlan: None,
+            });
+        }
+
+        out.push_back(merged);
+    }
+
+    *queue = out;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::frame::{write_ipv4, ETHERNET_HEADER};
+    use crate::mock::test_pool;
+    use crate::offload::insert_vlan;
+
+    /// A TCP segment with IPv4 options, the payload, the flags and valid checksums.
+    fn segment(
+        pool: &Rc<Mempool>,
+        seq: u32,
+        options: &[u8],
+        payload: &[u8],
+        flags: u8,
+    ) -> IxyPacket {
+        let tcp = ETHERNET_HEADER + 20 + options.len();
+        let mut frame = vec![0; tcp + 20 + payload.len()];
+        let addresses = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        write_ipv4(&mut frame, [0x02, 0, 0, 0, 0, 1], [0x02, 0, 0, 0, 0, 2], addresses, PROTO_TCP);
+        frame[ETHERNET_HEADER] = 0x45 + (options.len() / 4) as u8;
+        frame[ETHERNET_HEADER + 20..tcp].copy_from_slice(options);
+        write_u16(&mut frame, tcp, 1234);
+        write_u16(&mut frame, tcp + 2, 80);
+        frame[tcp + 4..tcp + 8].copy_from_slice(&seq.to_be_bytes());
+        frame[tcp + 12] = 5 << 4;
+        frame[tcp + 13] = flags;
+        frame[tcp + 20..].copy_from_slice(payload);
+        insert_checksums(&mut frame, TxOffload {
+            ipv4_checksum: true,
+            l4_checksum: true,
+            mss: None,
+            vlan: None,
+        });
+
+        let mut packet = memory::alloc_pkt(pool, frame.len()).unwrap();
+        packet.copy_from_slice(&frame);
+        packet
+    }
+
+    fn unvalidated(_: &IxyPacket) -> Metadata {
+        Metadata::default()
+    }
+
+    #[test]
+    fn keeps_segments_with_other_ip_options() {
+        let pool = test_pool();
+        let mut queue = VecDeque::new();
+        queue.push_back(segment(&pool, 1000, &[], &[0; 100], ACK));
+        queue.push_back(segment(&pool, 1100, &[1, 1, 1, 0], &[0; 100], ACK));
+        queue.push_back(segment(&pool, 1200, &[1, 1, 1, 1], &[0; 100], ACK));
+        coalesce(&mut queue, &pool, unvalidated);
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn keeps_segments_with_invalid_checksums() {
+        let pool = test_pool();
+        let mut queue = VecDeque::new();
+        for index in 0..3 {
+            queue.push_back(segment(&pool, 1000 + 100 * index, &[], &[0; 100], ACK));
+        }
+        let last = queue[1].len() - 1;
+        queue[1][last] ^= 0xff;
+        coalesce(&mut queue, &pool, unvalidated);
+        assert_eq!(queue.len(), 3);
+
+        // Segments the NIC reported as invalid are not merged either.
+        coalesce(&mut queue, &pool, |_| Metadata {
+            l4_checksum: Some(false),
+            ..Metadata::default()
+        });
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn keeps_segments_with_other_vlan_tags() {
+        let pool = test_pool();
+        let mut queue = VecDeque::new();
+        for index in 0..2 {
+            let mut packet = segment(&pool, 1000 + 100 * index, &[], &[0; 100], ACK);
+            assert!(insert_vlan(&mut packet, 10 + index as u16));
+            queue.push_back(packet);
+        }
+        coalesce(&mut queue, &pool, unvalidated);
+        assert_eq!(queue.len(), 2);
+
+        // Or with other tags stripped by the NIC.
+        let packets = (0..2).map(|index| segment(&pool, 1000 + 100 * index, &[], &[0; 100], ACK));
+        let mut queue: VecDeque<_> = packets.collect();
+        // Tag each segment with the low byte of its sequence number.
+        coalesce(&mut queue, &pool, |packet| Metadata {
+            vlan: Some(u16::from(packet[ETHERNET_HEADER + 20 + 7])),
+            ..Metadata::default()
+        });
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn merges_consecutive_segments() {
+        let pool = test_pool();
+        let mut queue = VecDeque::new();
+        for index in 0..3 {
+            // Set PSH on the last segment.
+            let flags = if index == 2 { ACK | 0x08 } else { ACK };
+            let payload = [index as u8; 100];
+            queue.push_back(segment(&pool, 1000 + 100 * index, &[], &payload, flags));
+        }
+        // Append one which leaves a gap in the sequence.
+        queue.push_back(segment(&pool, 1400, &[], &[0; 100], ACK));
+        coalesce(&mut queue, &pool, unvalidated);
+
+        assert_eq!(queue.len(), 2);
+        let merged = &queue[0];
+        let (l3, tcp) = (ETHERNET_HEADER, ETHERNET_HEADER + 20);
+        assert_eq!(merged.len(), tcp + 20 + 300);
+        assert_eq!(usize::from(read_u16(merged, l3 + 2)), 20 + 20 + 300);
+        assert_eq!(merged[tcp + 4..tcp + 8], 1000u32.to_be_bytes());
+        assert_eq!(merged[tcp + 13], ACK | 0x08);
+        assert!(merged[tcp + 20..].chunks(100).enumerate()
+            .all(|(index, chunk)| chunk.iter().all(|&byte| byte == index as u8)));
+        assert!(Segment::parse(merged, &Metadata::default()).is_some());
+        assert_eq!(queue[1].len(), tcp + 20 + 100);
+    }
+}
@@ -0,0 +1,80 @@
+//! Consistent hashing by the lookup table of Maglev.
+use crate::ecmp::mix;
+
+/// A lookup table assigning hashes to weighted backends, as in the Maglev load balancer.
+///
+/// Each backend fills the free slots of the table in the order of its own permutation, taking
+/// turns in proportion to its weight, until the table is full. Backends get nearly the same share
+/// of slots per weight, and adding or removing a backend only moves few of the slots of others.
+/// Backends are identified by keys, e.g. a hash of their address, so the permutations do not
+/// depend on the order or number of the backends.
+#[derive(Clone, Debug)]
+pub struct Maglev {
+    /// The index of the backend of each slot, `NONE` if there is no backend.
+    table: Vec<u32>,
+}
+
+const NONE: u32 = u32::max_value();
+
+impl Maglev {
+    /// The default number of slots, a prime about a hundred times the number of backends.
+    pub const SIZE: usize = 65537;
+
+    /// Build the table of backends given by their key and weight, zero weights get no slots.
+    ///
+    /// ## Panics
+    /// This function panics if `size` is not a prime.
+    pub fn new(size: usize, backends: &[(u64, u32)]) -> Self {
+        assert!(is_prime(size), "The table size must be a prime");
+        let mut table = vec![NONE; size];
+        let size = size as u64;
+
+        // The permutation of a backend is an offset and a skip coprime to the size.
+        let permutations = backends
+            .iter()
+            .map(|&(key, _)| (mix(key) % size, mix(!key) % (size - 1) + 1))
+            .collect::<Vec<_>>();
+        let mut next = vec![0u64; backends.len()];
+        let mut filled = 0;
+        let total: u64 = backends.iter().map(|&(_, weight)| u64::from(weight)).sum();
+
+        while total > 0 {
+            for (index, &(_, weight)) in backends.iter().enumerate() {
+                let (offset, skip) = permutations[index];
+                for _ in 0..weight {
+                    let slot = loop {
+                        let slot = ((offset + next[index] * skip) % size) as usize;
+                        next[index] += 1;
+                        if table[slot] == NONE {
+                            break slot;
+                        }
+                    };
+                    table[slot] = index as u32;
+                    filled += 1;
+                    if filled == table.len() {
+                        return Maglev { table };
+                    }
+                }
+            }
+        }
+
+        Maglev { table }
+    }
+
+    /// The number of slots.
+    pub fn size(&self) -> usize {
+        self.table.len()
+    }
+
+    /// The index of the backend of a hash, `None` if there are no backends.
+    pub fn lookup(&self, hash: u64) -> Option<usize> {
+        match self.table[(hash % self.table.len() as u64) as usize] {
+            NONE => None,
+            index => Some(index as usize),
+        }
+    }
+}
+
+fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
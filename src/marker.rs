@@ -0,0 +1,169 @@
+//! Marking packets by classifier verdicts and queue occupancy.
+use std::time::{Duration, Instant};
+
+use crate::fault::Rng;
+use crate::frame;
+use crate::Acl;
+
+/// When a queue signals congestion, by its occupancy in packets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aqm {
+    /// Random early detection on the moving average of the occupancy.
+    ///
+    /// The probability of a signal rises linearly from zero at `min` packets to `probability` at
+    /// `max`, above which every packet signals congestion.
+    Red { min: usize, max: usize, probability: f64 },
+
+    /// Signal once the occupancy stayed above `target` for an interval, then ever more often
+    /// while it stays there, like CoDel does for the sojourn time.
+    CoDel { target: usize, interval: Duration },
+}
+
+/// Counters of a marker.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MarkerStats {
+    /// Packets whose DSCP was rewritten by the classifier.
+    pub remarked: u64,
+
+    /// ECN-capable packets marked with congestion experienced.
+    pub ce_marked: u64,
+
+    /// Packets which were not ECN-capable, dropped to signal congestion.
+    pub dropped: u64,
+}
+
+/// Rewrites the DSCP and signals congestion in IP packets entering a queue.
+///
+/// The DSCP is taken from the action of the first matching classifier rule. Congestion is
+/// signalled by the active queue management from the occupancy of the queue: ECN-capable packets
+/// are marked with congestion experienced, others must be dropped. Frames other than IP are
+/// never dropped. Call `mark` for each packet before queueing it, e.g. in the filter of
+/// `Phy::forward` with the pending packets of the output, or attach the marker to a class of a
+/// `Qos` scheduler. The random decisions of RED come from a fixed seed.
+pub struct Marker {
+    classifier: Acl<u8>,
+    aqm: Option<Aqm>,
+    rng: Rng,
+    /// The moving average of the occupancy, for RED.
+    average: f64,
+    /// When the occupancy exceeded the target of CoDel for an interval.
+    above_until: Option<Instant>,
+    /// The next signal while CoDel is signalling.
+    next_signal: Option<Instant>,
+    signals: u32,
+    stats: MarkerStats,
+}
+
+/// The weight of a new occupancy in the moving average of RED.
+const WEIGHT: f64 = 0.002;
+
+impl Marker {
+    /// A marker which changes nothing until a classifier or queue management is set.
+    pub fn new() -> Self {
+        Marker {
+            classifier: Acl::new(Vec::new()),
+            aqm: None,
+            rng: Rng::new(0),
+            average: 0.0,
+            above_until: None,
+            next_signal: None,
+            signals: 0,
+            stats: MarkerStats::default(),
+        }
+    }
+
+    pub fn classifier(&self) -> &Acl<u8> {
+        &self.classifier
+    }
+
+    /// Replace the classifier, its actions are DSCP values.
+    pub fn set_classifier(&mut self, classifier: Acl<u8>) {
+        self.classifier = classifier;
+    }
+
+    pub fn aqm(&self) -> Option<Aqm> {
+        self.aqm
+    }
+
+    /// Replace the active queue management, resetting its state.
+    pub fn set_aqm(&mut self, aqm: Option<Aqm>) {
+        self.aqm = aqm;
+        self.average = 0.0;
+        self.above_until = None;
+        self.next_signal = None;
+        self.signals = 0;
+    }
+
+    pub fn stats(&self) -> MarkerStats {
+        self.stats
+    }
+
+    /// Mark a frame entering a queue of `occupancy` packets, from an interface of the classifier.
+    ///
+    /// Returns `false` if the packet must be dropped to signal congestion.
+    pub fn mark(&mut self, interface: usize, frame: &mut [u8], occupancy: usize) -> bool {
+        if let Some(&dscp) = self.classifier.classify(interface, frame) {
+            if frame::set_dscp(frame, dscp) {
+                self.stats.remarked += 1;
+            }
+        }
+
+        if !self.congested(occupancy, Instant::now()) {
+            return true;
+        }
+        match frame::set_ecn_ce(frame) {
+            Some(true) => self.stats.ce_marked += 1,
+            Some(false) => {
+                self.stats.dropped += 1;
+                return false;
+            },
+            None => (),
+        }
+        true
+    }
+
+    fn congested(&mut self, occupancy: usize, now: Instant) -> bool {
+        match self.aqm {
+            Some(Aqm::Red { min, max, probability }) => self.red(occupancy, min, max, probability),
+            Some(Aqm::CoDel { target, interval }) => self.codel(occupancy, target, interval, now),
+            None => false,
+        }
+    }
+
+    fn red(&mut self, occupancy: usize, min: usize, max: usize, probability: f64) -> bool {
+        self.average += (occupancy as f64 - self.average) * WEIGHT;
+        if self.average < min as f64 {
+            false
+        } else if self.average >= max as f64 {
+            true
+        } else {
+            let above = (self.average - min as f64) / (max - min) as f64;
+            self.rng.chance(probability * above)
+        }
+    }
+
+    fn codel(&mut self, occupancy: usize, target: usize, interval: Duration, now: Instant) -> bool {
+        if occupancy <= target {
+            self.above_until = None;
+            self.next_signal = None;
+            return false;
+        }
+
+        let above_until = *self.above_until.get_or_insert(now + interval);
+        match self.next_signal {
+            None if now >= above_until => self.signals = 1,
+            Some(next) if now >= next => self.signals += 1,
+            _ => return false,
+        }
+        // The control law of CoDel, the interval shrinks with the root of the signals.
+        let gap = interval.as_secs_f64() / f64::from(self.signals).sqrt();
+        self.next_signal = Some(now + Duration::from_secs_f64(gap));
+        true
+    }
+}
+
+impl Default for Marker {
+    fn default() -> Self {
+        Marker::new()
+    }
+}
@@ -0,0 +1,355 @@
+//! Mirroring the traffic of a phy to a second device or a capture.
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::checksum::{read_u16, write_u16};
+use crate::frame::{self, ETHERNET_HEADER, ETHERTYPE_VLAN, PROTO_GRE};
+use crate::rate::Bucket;
+use crate::{Acl, Direction, Dump, Phy, Queues, Rate};
+
+/// Counters of a mirror.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MirrorStats {
+    /// Frames handed to the sink.
+    pub mirrored: u64,
+
+    /// Frames not selected by the filter.
+    pub filtered: u64,
+
+    /// Frames above the rate of the mirror, and their bytes.
+    pub dropped: u64,
+    pub dropped_bytes: u64,
+}
+
+/// Selects the mirrored frames of a `Capture` and limits their rate.
+///
+/// Wrap the sink of a capture to mirror only part of the traffic, e.g.
+/// `Capture::new(phy, Mirror::new(MirrorPort::new(span)))` sends a copy of every frame of `phy`
+/// out of the phy `span`. The filter is a classifier whose interface is 0 for received and 1 for
+/// sent frames, a frame is mirrored if the first matching rule says so. Frames other than IP
+/// match no rule and are not mirrored while a filter is set.
+///
+/// The rate limit only applies to the mirrored copies, so an overloaded analyzer never slows the
+/// mirrored traffic itself.
+pub struct Mirror<K> {
+    sink: K,
+    filter: Option<Acl<bool>>,
+    bucket: Option<Bucket>,
+    stats: MirrorStats,
+}
+
+/// A sink sending mirrored frames out of a phy, e.g. to an analyzer on a SPAN port.
+///
+/// Frames are dropped instead of queued once the phy has too many frames waiting, so a slow
+/// mirror device does not exhaust the buffers. The phy should be polled like any other to reclaim
+/// its sent buffers.
+pub struct MirrorPort<D> {
+    phy: Phy<D>,
+    max_pending: usize,
+    dropped: u64,
+}
+
+/// How a remote mirror carries frames to the analyzer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encapsulation {
+    /// ERSPAN type II in GRE with sequence numbers, as sent by many switches.
+    Erspan {
+        /// The session, of 10 bits, which tells the mirrors of an analyzer apart.
+        session: u16,
+    },
+
+    /// TZSP in UDP with the frame count and the original length, as received by Wireshark.
+    Tzsp,
+}
+
+/// A sink sending mirrored frames across an IP network to a remote analyzer.
+///
+/// Each frame is truncated to the snap length and encapsulated in an IPv4 packet to the analyzer,
+/// which is sent out of the phy to the next hop given by its link-layer address, it is not
+/// resolved with ARP. Every encapsulated frame carries a sequence number so the analyzer can tell
+/// frames lost on the way, and truncated frames are marked or carry their original length.
+pub struct RemoteMirror<D> {
+    port: MirrorPort<D>,
+    encapsulation: Encapsulation,
+    source: Ipv4Addr,
+    analyzer: Ipv4Addr,
+    next_hop: [u8; 6],
+    snaplen: usize,
+    sequence: u32,
+    truncated: u64,
+    buffer: Vec<u8>,
+}
+
+/// The GRE protocol of ERSPAN type II.
+const GRE_ERSPAN: u16 = 0x88be;
+
+/// The IPv4, GRE with a sequence number and ERSPAN type II headers.
+const ERSPAN_OVERHEAD: usize = 20 + 8 + 8;
+
+/// The UDP port of TZSP.
+const TZSP_PORT: u16 = 37008;
+
+/// The IPv4, UDP and TZSP headers with the frame count, the original length and the end tags.
+const TZSP_OVERHEAD: usize = 20 + 8 + 4 + 6 + 4 + 1;
+
+impl<K> Mirror<K> {
+    /// Mirror all frames to the sink.
+    pub fn new(sink: K) -> Self {
+        Mirror {
+            sink,
+            filter: None,
+            bucket: None,
+            stats: MirrorStats::default(),
+        }
+    }
+
+    pub fn filter(&self) -> Option<&Acl<bool>> {
+        self.filter.as_ref()
+    }
+
+    /// Only mirror frames the classifier selects, `None` mirrors all frames.
+    pub fn set_filter(&mut self, filter: Option<Acl<bool>>) {
+        self.filter = filter;
+    }
+
+    pub fn rate(&self) -> Option<Rate> {
+        self.bucket.as_ref().map(Bucket::rate)
+    }
+
+    /// Limit the rate of mirrored frames, starting with a full bucket.
+    pub fn set_rate(&mut self, rate: Option<Rate>) {
+        self.bucket = rate.map(Bucket::new);
+    }
+
+    pub fn stats(&self) -> MirrorStats {
+        self.stats
+    }
+
+    pub fn sink(&self) -> &K {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut K {
+        &mut self.sink
+    }
+
+    pub fn into_inner(self) -> K {
+        self.sink
+    }
+}
+
+impl<K: Dump> Dump for Mirror<K> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        if let Some(filter) = &mut self.filter {
+            let interface = match direction {
+                Direction::Rx => 0,
+                Direction::Tx => 1,
+            };
+            if filter.classify(interface, frame) != Some(&true) {
+                self.stats.filtered += 1;
+                return Ok(());
+            }
+        }
+
+        if let Some(bucket) = &mut self.bucket {
+            bucket.fill(Instant::now());
+            if !bucket.take(frame.len()) {
+                self.stats.dropped += 1;
+                self.stats.dropped_bytes += frame.len() as u64;
+                return Ok(());
+            }
+        }
+
+        self.stats.mirrored += 1;
+        self.sink.dump(direction, timestamp, frame)
+    }
+
+    fn sample(&mut self, direction: Direction) -> bool {
+        self.sink.sample(direction)
+    }
+}
+
+impl<D> MirrorPort<D> {
+    /// The default number of frames waiting in the phy above which frames are dropped.
+    pub const MAX_PENDING: usize = 1024;
+
+    pub fn new(phy: Phy<D>) -> Self {
+        MirrorPort {
+            phy,
+            max_pending: Self::MAX_PENDING,
+            dropped: 0,
+        }
+    }
+
+    pub fn max_pending(&self) -> usize {
+        self.max_pending
+    }
+
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    /// The frames dropped because the phy had too many waiting or no free buffer.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+}
+
+impl<D: Queues> Dump for MirrorPort<D> {
+    fn dump(&mut self, _: Direction, _: Duration, frame: &[u8]) -> io::Result<()> {
+        if self.phy.tx_pending() >= self.max_pending || !self.phy.send_frame(frame) {
+            self.dropped += 1;
+            return Ok(());
+        }
+        self.phy.poll_flush();
+        Ok(())
+    }
+}
+
+impl<D> RemoteMirror<D> {
+    /// The default snap length, frames fit a path MTU of 1500 bytes with either encapsulation.
+    pub const SNAPLEN: usize = 1450;
+
+    /// Send frames out of the phy from the source address to the analyzer through a next hop.
+    pub fn new(
+        phy: Phy<D>,
+        encapsulation: Encapsulation,
+        source: Ipv4Addr,
+        analyzer: Ipv4Addr,
+        next_hop: [u8; 6],
+    ) -> Self {
+        RemoteMirror {
+            port: MirrorPort::new(phy),
+            encapsulation,
+            source,
+            analyzer,
+            next_hop,
+            snaplen: Self::SNAPLEN,
+            sequence: 0,
+            truncated: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn encapsulation(&self) -> Encapsulation {
+        self.encapsulation
+    }
+
+    pub fn analyzer(&self) -> Ipv4Addr {
+        self.analyzer
+    }
+
+    pub fn snaplen(&self) -> usize {
+        self.snaplen
+    }
+
+    /// Truncate mirrored frames to `snaplen` bytes.
+    pub fn set_snaplen(&mut self, snaplen: usize) {
+        self.snaplen = snaplen;
+    }
+
+    /// The sequence number of the next encapsulated frame.
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    /// The number of frames which were truncated.
+    pub fn truncated(&self) -> u64 {
+        self.truncated
+    }
+
+    /// The sink of the encapsulated frames, e.g. for its drops.
+    pub fn port(&self) -> &MirrorPort<D> {
+        &self.port
+    }
+
+    pub fn port_mut(&mut self) -> &mut MirrorPort<D> {
+        &mut self.port
+    }
+
+    pub fn into_inner(self) -> Phy<D> {
+        self.port.into_inner()
+    }
+
+    /// Write the encapsulated frame into the buffer.
+    fn encapsulate(&mut self, frame: &[u8], mac: [u8; 6]) {
+        let captured = frame.len().min(self.snaplen);
+        let truncated = captured < frame.len();
+        let overhead = match self.encapsulation {
+            Encapsulation::Erspan { .. } => ERSPAN_OVERHEAD,
+            Encapsulation::Tzsp => TZSP_OVERHEAD,
+        };
+        let inner = ETHERNET_HEADER + overhead;
+        self.buffer.clear();
+        self.buffer.resize(inner + captured, 0);
+        self.buffer[inner..].copy_from_slice(&frame[..captured]);
+
+        let buffer = &mut self.buffer[..];
+        let sequence = self.sequence.to_be_bytes();
+        match self.encapsulation {
+            Encapsulation::Erspan { session } => {
+                let addresses = (self.source, self.analyzer);
+                frame::write_ipv4(buffer, mac, self.next_hop, addresses, PROTO_GRE);
+                let gre = &mut buffer[ETHERNET_HEADER + 20..];
+                // Only the flag of the sequence number, version 0.
+                write_u16(gre, 0, 0x1000);
+                write_u16(gre, 2, GRE_ERSPAN);
+                gre[4..8].copy_from_slice(&sequence);
+
+                let tagged = frame.len() >= 16 && read_u16(frame, 12) == ETHERTYPE_VLAN;
+                let (vlan, tag) = if tagged {
+                    (read_u16(frame, 14) & 0x0fff, 0b11)
+                } else {
+                    (0, 0)
+                };
+                let erspan = &mut gre[8..];
+                // Version 1 is type II.
+                write_u16(erspan, 0, 1 << 12 | vlan);
+                let flags = tag << 11 | u16::from(truncated) << 10;
+                write_u16(erspan, 2, flags | session & 0x03ff);
+                // Reserved and the index of the port, always zero.
+                erspan[4..8].copy_from_slice(&[0; 4]);
+            },
+            Encapsulation::Tzsp => {
+                let source = SocketAddrV4::new(self.source, TZSP_PORT);
+                let destination = SocketAddrV4::new(self.analyzer, TZSP_PORT);
+                frame::write_udp_ipv4(buffer, mac, self.next_hop, source, destination);
+                let tzsp = &mut buffer[frame::UDP_IPV4_HEADERS..];
+                // Version 1, a received frame, of Ethernet.
+                tzsp[0..4].copy_from_slice(&[1, 0, 0, 1]);
+                // The frame count and the original length, then the end of the tags.
+                tzsp[4..6].copy_from_slice(&[40, 4]);
+                tzsp[6..10].copy_from_slice(&sequence);
+                tzsp[10..12].copy_from_slice(&[41, 2]);
+                write_u16(tzsp, 12, frame.len() as u16);
+                tzsp[14] = 1;
+            },
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        if truncated {
+            self.truncated += 1;
+        }
+    }
+}
+
+impl<D: Queues> Dump for RemoteMirror<D> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        let mac = self.port.phy().mac_address();
+        self.encapsulate(frame, mac);
+        self.port.dump(direction, timestamp, &self.buffer)
+    }
+}
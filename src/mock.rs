@@ -0,0 +1,190 @@
+//! A scripted device for deterministic tests.
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ixy::{DeviceStats, IxyDevice};
+use ixy::memory::{self, Mempool, Packet as IxyPacket};
+
+/// An ixy device whose received frames and tx ring capacity are scripted.
+///
+/// Each call to `rx_batch` delivers at most one scripted batch, which makes short batches
+/// reproducible. Each call to `tx_batch` accepts at most the next scripted capacity, so a full tx
+/// ring can be simulated. Everything accepted for sending is recorded.
+pub struct MockDevice {
+    pool: Rc<Mempool>,
+    mac: Cell<[u8; 6]>,
+    link_speed: u16,
+    rx_script: VecDeque<VecDeque<Vec<u8>>>,
+    tx_script: VecDeque<usize>,
+    transmitted: Vec<Vec<u8>>,
+    rx_calls: usize,
+    tx_calls: usize,
+    /// Counters since the last read.
+    stats: Cell<DeviceStats>,
+}
+
+impl MockDevice {
+    /// A locally administered address.
+    pub const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+    /// Create a device allocating received frames from a pool.
+    pub fn new(pool: Rc<Mempool>) -> Self {
+        MockDevice {
+            pool,
+            mac: Cell::new(Self::MAC),
+            link_speed: 10_000,
+            rx_script: VecDeque::new(),
+            tx_script: VecDeque::new(),
+            transmitted: Vec::new(),
+            rx_calls: 0,
+            tx_calls: 0,
+            stats: Cell::new(DeviceStats::default()),
+        }
+    }
+
+    /// Deliver a frame on its own in a later call to `rx_batch`.
+    ///
+    /// The frame is not validated, malformed frames are delivered as is.
+    pub fn push_rx(&mut self, frame: &[u8]) {
+        self.push_rx_batch(Some(frame.to_vec()));
+    }
+
+    /// Deliver some frames together in a later call to `rx_batch`.
+    ///
+    /// Frames beyond the requested number of packets stay in the batch for the next call.
+    pub fn push_rx_batch<I: IntoIterator<Item=Vec<u8>>>(&mut self, frames: I) {
+        self.rx_script.push_back(frames.into_iter().collect());
+    }
+
+    /// Accept at most `capacity` packets in a later call to `tx_batch`.
+    ///
+    /// Calls without a scripted capacity accept all packets.
+    pub fn push_tx_capacity(&mut self, capacity: usize) {
+        self.tx_script.push_back(capacity);
+    }
+
+    /// Report a link speed, zero for a link that is down.
+    pub fn set_link_speed(&mut self, speed: u16) {
+        self.link_speed = speed;
+    }
+
+    /// The frames accepted for sending, in order.
+    pub fn transmitted(&self) -> &[Vec<u8>] {
+        &self.transmitted
+    }
+
+    /// Take the frames accepted for sending so far.
+    pub fn take_transmitted(&mut self) -> Vec<Vec<u8>> {
+        std::mem::replace(&mut self.transmitted, Vec::new())
+    }
+
+    /// The number of scripted rx batches not yet delivered.
+    pub fn rx_remaining(&self) -> usize {
+        self.rx_script.len()
+    }
+
+    /// The number of calls to `rx_batch` so far.
+    pub fn rx_calls(&self) -> usize {
+        self.rx_calls
+    }
+
+    /// The number of calls to `tx_batch` so far.
+    pub fn tx_calls(&self) -> usize {
+        self.tx_calls
+    }
+}
+
+impl IxyDevice for MockDevice {
+    fn get_driver_name(&self) -> &str {
+        "mock"
+    }
+
+    fn get_pci_addr(&self) -> &str {
+        "mock"
+    }
+
+    fn get_mac_addr(&self) -> [u8; 6] {
+        self.mac.get()
+    }
+
+    fn set_mac_addr(&self, mac: [u8; 6]) {
+        self.mac.set(mac)
+    }
+
+    fn rx_batch(&mut self, _: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize) -> usize {
+        self.rx_calls += 1;
+        let batch = match self.rx_script.front_mut() {
+            Some(batch) => batch,
+            None => return 0,
+        };
+
+        let mut stats = self.stats.get();
+        let mut count = 0;
+        while count < num_packets {
+            let frame = match batch.front() {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            // An exhausted pool delays the frame, like a missing rx descriptor.
+            let mut packet = match memory::alloc_pkt(&self.pool, frame.len()) {
+                Some(packet) => packet,
+                None => break,
+            };
+            packet.copy_from_slice(frame);
+            stats.rx_pkts += 1;
+            stats.rx_bytes += frame.len() as u64;
+            buffer.push_back(packet);
+            batch.pop_front();
+            count += 1;
+        }
+
+        if batch.is_empty() {
+            self.rx_script.pop_front();
+        }
+        self.stats.set(stats);
+        count
+    }
+
+    fn tx_batch(&mut self, _: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        self.tx_calls += 1;
+        let capacity = self.tx_script.pop_front().unwrap_or(usize::max_value());
+        let count = capacity.min(buffer.len());
+
+        let mut stats = self.stats.get();
+        for packet in buffer.drain(..count) {
+            stats.tx_pkts += 1;
+            stats.tx_bytes += packet.len() as u64;
+            self.transmitted.push(packet.to_vec());
+        }
+        self.stats.set(stats);
+        count
+    }
+
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        let new = self.stats.replace(DeviceStats::default());
+        stats.rx_pkts += new.rx_pkts;
+        stats.tx_pkts += new.tx_pkts;
+        stats.rx_bytes += new.rx_bytes;
+        stats.tx_bytes += new.tx_bytes;
+    }
+
+    fn reset_stats(&mut self) {
+        self.stats.set(DeviceStats::default());
+    }
+
+    fn get_link_speed(&self) -> u16 {
+        self.link_speed
+    }
+
+    fn recv_pool(&self, _: u32) -> Option<&Rc<Mempool>> {
+        Some(&self.pool)
+    }
+}
+
+/// A mempool for the tests, which ixy allocates in hugepages.
+#[cfg(test)]
+pub(crate) fn test_pool() -> Rc<Mempool> {
+    Mempool::allocate(1024, 2048).expect("The tests need hugepages for the ixy mempool")
+}
@@ -0,0 +1,307 @@
+//! Label switching of MPLS packets over Ethernet.
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::{read_u16, write_u16};
+use crate::frame::{Headers, ETHERNET_HEADER, ETHERTYPE_IPV4, ETHERTYPE_IPV6, ETHERTYPE_MPLS};
+use crate::frame::{MIN_FRAME, ipv4, ipv6};
+use crate::{Lpm, Prefix};
+
+/// What a label switching router does with the top label of a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LabelOp {
+    /// Replace the top label, along the label switched path.
+    ///
+    /// Swapping to the implicit null label 3 pops instead, as the hop before the end of the path.
+    Swap(u32),
+
+    /// Remove the top label, at the end of the path.
+    Pop,
+}
+
+/// Where the packets with an incoming label go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LabelEntry {
+    pub op: LabelOp,
+
+    /// The index of the output port, chosen by the user.
+    pub port: usize,
+
+    /// The link-layer address of the next hop.
+    pub next_hop: [u8; 6],
+}
+
+/// The labels pushed on IP packets to a prefix entering the label switched network.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fec {
+    /// The label stack, the first label is the top.
+    pub labels: Vec<u32>,
+
+    /// The index of the output port, chosen by the user.
+    pub port: usize,
+
+    /// The link-layer address of the next hop.
+    pub next_hop: [u8; 6],
+}
+
+/// Counters of a label switch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MplsStats {
+    /// IP packets which entered a label switched path.
+    pub pushed: u64,
+
+    pub swapped: u64,
+    pub popped: u64,
+
+    /// Labelled packets with a label not in the table.
+    pub unknown_label: u64,
+
+    /// IP packets without a forwarding equivalence class.
+    pub no_route: u64,
+
+    /// Packets whose TTL ran out.
+    pub ttl_exceeded: u64,
+
+    /// Malformed or tagged frames, and frames without room for the labels.
+    pub dropped: u64,
+}
+
+/// A label forwarding table switching MPLS packets between ports.
+///
+/// Labelled packets are forwarded by their top label, which is swapped or popped, as by a P
+/// router or the egress of a PE router. IP packets are forwarded by the longest prefix of their
+/// destination which has a forwarding equivalence class, whose labels are pushed, as by the
+/// ingress of a PE router. Together these build simple label switched paths across phys, the
+/// labels are configured statically.
+///
+/// The TTL of an entering packet is copied into its labels and decremented, then decremented on
+/// every hop and copied down when a label is popped. The TTL of the IP packet itself is left as
+/// it is, the path counts as one hop. The traffic class is taken from the precedence of the IP
+/// packet and kept while switching. Packets whose TTL runs out are dropped without an error.
+///
+/// Only untagged frames are switched. The destination address of a forwarded frame is its next
+/// hop, its source address must be set to the one of the output port by the user.
+pub struct Mpls {
+    labels: HashMap<u32, LabelEntry>,
+    fecs: Lpm<Fec>,
+    stats: MplsStats,
+}
+
+/// The reserved label which pops the top label.
+const IMPLICIT_NULL: u32 = 3;
+
+/// The number of label values.
+const LABELS: u32 = 1 << 20;
+
+impl Mpls {
+    pub fn new() -> Self {
+        Mpls {
+            labels: HashMap::new(),
+            fecs: Lpm::new(),
+            stats: MplsStats::default(),
+        }
+    }
+
+    /// Switch packets with an incoming label, returning the previous entry of the label.
+    ///
+    /// ## Panics
+    /// This function panics if a label does not fit into 20 bits.
+    pub fn add_label(&mut self, label: u32, entry: LabelEntry) -> Option<LabelEntry> {
+        let swapped = match entry.op {
+            LabelOp::Swap(swapped) => swapped,
+            LabelOp::Pop => 0,
+        };
+        assert!(label < LABELS && swapped < LABELS, "Labels have 20 bits");
+        self.labels.insert(label, entry)
+    }
+
+    pub fn remove_label(&mut self, label: u32) -> Option<LabelEntry> {
+        self.labels.remove(&label)
+    }
+
+    pub fn label(&self, label: u32) -> Option<&LabelEntry> {
+        self.labels.get(&label)
+    }
+
+    /// All incoming labels and their entries, in no particular order.
+    pub fn labels(&self) -> impl Iterator<Item=(u32, &LabelEntry)> {
+        self.labels.iter().map(|(&label, entry)| (label, entry))
+    }
+
+    /// Push labels on IP packets to a prefix, returning the previous class of the prefix.
+    ///
+    /// ## Panics
+    /// This function panics if there are no labels or a label does not fit into 20 bits.
+    pub fn add_fec(&mut self, prefix: Prefix, fec: Fec) -> Option<Fec> {
+        assert!(!fec.labels.is_empty(), "A forwarding equivalence class needs labels");
+        assert!(fec.labels.iter().all(|&label| label < LABELS), "Labels have 20 bits");
+        self.fecs.insert(prefix, fec)
+    }
+
+    pub fn remove_fec(&mut self, prefix: Prefix) -> Option<Fec> {
+        self.fecs.remove(prefix)
+    }
+
+    pub fn fec(&self, prefix: Prefix) -> Option<&Fec> {
+        self.fecs.get(prefix)
+    }
+
+    pub fn stats(&self) -> MplsStats {
+        self.stats
+    }
+
+    /// Switch a labelled packet or push labels on an IP packet, returning its output port.
+    ///
+    /// Returns `None` if the packet should be dropped.
+    pub fn forward(&mut self, packet: &mut IxyPacket) -> Option<usize> {
+        let labelled = packet.len() >= ETHERNET_HEADER
+            && read_u16(&packet[..], 12) == ETHERTYPE_MPLS;
+        let (port, next_hop) = if labelled {
+            self.switch(packet)?
+        } else {
+            self.push(packet)?
+        };
+        packet[0..6].copy_from_slice(&next_hop);
+        Some(port)
+    }
+
+    fn switch(&mut self, packet: &mut IxyPacket) -> Option<(usize, [u8; 6])> {
+        if packet.len() < ETHERNET_HEADER + 4 {
+            self.stats.dropped += 1;
+            return None;
+        }
+
+        let entry = read_u32(&packet[ETHERNET_HEADER..]);
+        let (label, ttl) = (entry >> 12, entry as u8);
+        let route = match self.labels.get(&label) {
+            Some(&route) => route,
+            None => {
+                self.stats.unknown_label += 1;
+                return None;
+            },
+        };
+        if ttl <= 1 {
+            self.stats.ttl_exceeded += 1;
+            return None;
+        }
+
+        match route.op {
+            LabelOp::Swap(IMPLICIT_NULL) | LabelOp::Pop => {
+                if !pop(packet, ttl - 1) {
+                    self.stats.dropped += 1;
+                    return None;
+                }
+                self.stats.popped += 1;
+            },
+            LabelOp::Swap(swapped) => {
+                // Keep the traffic class and the bottom of stack flag.
+                let entry = swapped << 12 | entry & 0x0f00 | u32::from(ttl - 1);
+                write_u32(&mut packet[ETHERNET_HEADER..], entry);
+                self.stats.swapped += 1;
+            },
+        }
+        Some((route.port, route.next_hop))
+    }
+
+    fn push(&mut self, packet: &mut IxyPacket) -> Option<(usize, [u8; 6])> {
+        let headers = match Headers::parse(&packet[..]) {
+            Some(headers) if headers.vlan.is_none() => headers,
+            _ => {
+                self.stats.dropped += 1;
+                return None;
+            },
+        };
+
+        let l3 = headers.l3;
+        let (destination, ttl, precedence) = match headers.ethertype {
+            ETHERTYPE_IPV4 => {
+                (IpAddr::V4(ipv4(&packet[l3 + 16..l3 + 20])), packet[l3 + 8], packet[l3 + 1] >> 5)
+            },
+            ETHERTYPE_IPV6 => {
+                let class = (read_u16(&packet[..], l3) >> 4) as u8;
+                (IpAddr::V6(ipv6(&packet[l3 + 24..l3 + 40])), packet[l3 + 7], class >> 5)
+            },
+            _ => {
+                self.stats.dropped += 1;
+                return None;
+            },
+        };
+
+        let fec = match self.fecs.lookup(destination) {
+            Some(fec) => fec,
+            None => {
+                self.stats.no_route += 1;
+                return None;
+            },
+        };
+        if ttl <= 1 {
+            self.stats.ttl_exceeded += 1;
+            return None;
+        }
+
+        let len = packet.len();
+        let shim = fec.labels.len() * 4;
+        if packet.try_resize(len + shim, 0u8).is_err() {
+            self.stats.dropped += 1;
+            return None;
+        }
+        let frame: &mut [u8] = packet.as_mut();
+        frame.copy_within(ETHERNET_HEADER..len, ETHERNET_HEADER + shim);
+        write_u16(frame, 12, ETHERTYPE_MPLS);
+        let bottom = fec.labels.len() - 1;
+        for (index, &label) in fec.labels.iter().enumerate() {
+            let flag = if index == bottom { 0x100 } else { 0 };
+            let entry = label << 12 | u32::from(precedence) << 9 | flag | u32::from(ttl - 1);
+            write_u32(&mut frame[ETHERNET_HEADER + 4 * index..], entry);
+        }
+
+        self.stats.pushed += 1;
+        Some((fec.port, fec.next_hop))
+    }
+}
+
+impl Default for Mpls {
+    fn default() -> Self {
+        Mpls::new()
+    }
+}
+
+/// Remove the top label of a packet, copying the TTL into the next label.
+///
+/// Returns `false` if the packet under the last label is not IP.
+fn pop(packet: &mut IxyPacket, ttl: u8) -> bool {
+    let len = packet.len();
+    let bottom = read_u32(&packet[ETHERNET_HEADER..]) & 0x100 != 0;
+    let below = ETHERNET_HEADER + 4;
+
+    let ethertype = if !bottom {
+        if len < below + 4 {
+            return false;
+        }
+        packet[below + 3] = ttl;
+        ETHERTYPE_MPLS
+    } else {
+        match packet.get(below).map(|&version| version >> 4) {
+            Some(4) => ETHERTYPE_IPV4,
+            Some(6) => ETHERTYPE_IPV6,
+            _ => return false,
+        }
+    };
+
+    let frame: &mut [u8] = packet.as_mut();
+    frame.copy_within(below..len, ETHERNET_HEADER);
+    write_u16(frame, 12, ethertype);
+    // Shrinking within the mempool entry can not fail, short frames keep the rest as padding.
+    let _ = packet.try_resize((len - 4).max(MIN_FRAME), 0u8);
+    true
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from(read_u16(data, 0)) << 16 | u32::from(read_u16(data, 2))
+}
+
+fn write_u32(data: &mut [u8], value: u32) {
+    data[..4].copy_from_slice(&value.to_be_bytes());
+}
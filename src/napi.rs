@@ -0,0 +1,77 @@
+//! Switching between busy polling and interrupts by the receive rate.
+use std::time::{Duration, Instant};
+
+/// Thresholds for switching a phy between busy polling and interrupts, similar to NAPI.
+///
+/// The receive rate is measured over windows of `window`. When it drops below `low_pps` the phy
+/// switches to interrupts: it waits for an interrupt whenever a poll finds no packets, for at
+/// most `timeout`. It returns to pure polling once the rate exceeds `high_pps`. The gap between
+/// the two thresholds avoids flapping between the modes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Napi {
+    /// The rate below which to switch to interrupts.
+    pub low_pps: u64,
+
+    /// The rate above which to switch back to polling.
+    pub high_pps: u64,
+
+    /// The length of a measurement window.
+    pub window: Duration,
+
+    /// The longest wait for a single interrupt.
+    pub timeout: Duration,
+}
+
+pub(crate) struct NapiState {
+    pub config: Napi,
+    interrupts: bool,
+    window_start: Instant,
+    packets: u64,
+}
+
+impl Default for Napi {
+    fn default() -> Self {
+        Napi {
+            low_pps: 10_000,
+            high_pps: 50_000,
+            window: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+impl NapiState {
+    pub fn new(config: Napi) -> Self {
+        NapiState {
+            config,
+            interrupts: false,
+            window_start: Instant::now(),
+            packets: 0,
+        }
+    }
+
+    /// Whether the phy is currently in interrupt mode.
+    pub fn interrupts(&self) -> bool {
+        self.interrupts
+    }
+
+    /// Account for a poll, returns if the phy should wait for an interrupt.
+    pub fn poll(&mut self, received: usize) -> bool {
+        self.packets += received as u64;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= self.config.window {
+            let pps = (self.packets as f64 / elapsed.as_secs_f64()) as u64;
+            if self.interrupts && pps > self.config.high_pps {
+                self.interrupts = false;
+            } else if !self.interrupts && pps < self.config.low_pps {
+                self.interrupts = true;
+            }
+            self.window_start = Instant::now();
+            self.packets = 0;
+        }
+
+        // Like NAPI, keep polling while there are packets.
+        self.interrupts && received == 0
+    }
+}
@@ -0,0 +1,454 @@
+//! Source NAT of IPv4 with port translation.
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::checksum::{read_u16, update, write_u16};
+use crate::conntrack::{Connection, Conntrack, ConntrackTimeouts};
+use crate::flow::FiveTuple;
+use crate::frame::{Headers, ETHERTYPE_IPV4, PROTO_ICMP, PROTO_TCP, PROTO_UDP, ipv4};
+
+/// How long translations stay without packets.
+pub type NatTimeouts = ConntrackTimeouts;
+
+/// A translation of the NAT table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NatEntry {
+    pub protocol: u8,
+
+    /// The address and port, or ICMP identifier, of the internal host.
+    pub internal: SocketAddrV4,
+
+    /// The translated address and port of the internal host.
+    pub external: SocketAddrV4,
+
+    /// The peer of the internal host.
+    pub remote: SocketAddrV4,
+
+    /// Packets and their IP bytes sent by the internal host.
+    pub packets_out: u64,
+    pub bytes_out: u64,
+
+    /// Packets and their IP bytes received from the remote.
+    pub packets_in: u64,
+    pub bytes_in: u64,
+
+    /// Whether a FIN or RST of the TCP connection was seen.
+    pub closing: bool,
+
+    /// When the last packet was translated.
+    pub last_seen: Instant,
+}
+
+/// Counters of a NAT.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NatStats {
+    /// Packets from internal hosts which were translated.
+    pub translated_out: u64,
+
+    /// Packets to internal hosts which were translated.
+    pub translated_in: u64,
+
+    /// Packets from the outside without a translation.
+    pub unmatched: u64,
+
+    /// Packets dropped since the table was full or no external port was free.
+    pub exhausted: u64,
+
+    /// Packets which are not translated, e.g. fragments, or whose TTL expired.
+    pub unsupported: u64,
+
+    /// Translations removed after their timeout.
+    pub expired: u64,
+}
+
+/// Source NAT of the internal hosts behind a single external address.
+///
+/// Packets of internal hosts get the external address and a free external port as their source,
+/// and the replies of their peers are translated back. Each connection is mapped separately, a
+/// host gets different external ports for different peers. TCP, UDP and ICMP echo queries are
+/// translated, by their identifier instead of ports for the latter. Other packets, including
+/// fragments and ICMP errors, are dropped.
+///
+/// The NAT is the gateway of the internal hosts, so the TTL is decremented as well. The caller
+/// rewrites the Ethernet headers for the next hop, see `outbound` and `inbound`. Expired
+/// translations are removed during these calls about once a second.
+pub struct Nat {
+    external: Ipv4Addr,
+    ports: (u16, u16),
+    next_port: u16,
+    capacity: usize,
+    /// Translations by the flow of the internal host and the reply flow from the outside.
+    entries: Conntrack<NatEntry>,
+    last_sweep: Instant,
+    stats: NatStats,
+}
+
+/// A flow by its protocol and endpoints, with identifiers as ports for ICMP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Flow {
+    pub protocol: u8,
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+}
+
+/// The translated fields of a packet.
+pub(crate) struct Parsed {
+    pub l3: usize,
+    pub l4: usize,
+    pub flow: Flow,
+    /// The length of the IP packet.
+    pub len: usize,
+    /// Whether TCP FIN or RST is set.
+    pub closing: bool,
+}
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+impl Nat {
+    /// The default range of external ports.
+    pub const PORTS: (u16, u16) = (1024, 65535);
+
+    /// The default maximum number of translations.
+    pub const CAPACITY: usize = 65536;
+
+    /// How often expired translations are removed.
+    const SWEEP: Duration = Duration::from_secs(1);
+
+    /// Translate internal hosts to an external address.
+    pub fn new(external: Ipv4Addr) -> Self {
+        let mut entries = Conntrack::new();
+        // The NAT drops new connections instead of evicting translations.
+        entries.set_capacity(usize::max_value());
+        Nat {
+            external,
+            ports: Self::PORTS,
+            next_port: Self::PORTS.0,
+            capacity: Self::CAPACITY,
+            entries,
+            last_sweep: Instant::now(),
+            stats: NatStats::default(),
+        }
+    }
+
+    pub fn external(&self) -> Ipv4Addr {
+        self.external
+    }
+
+    /// The first and last external port.
+    pub fn ports(&self) -> (u16, u16) {
+        self.ports
+    }
+
+    /// Use another range of external ports, for new translations.
+    ///
+    /// ## Panics
+    /// This function panics if `first` is zero or larger than `last`.
+    pub fn set_ports(&mut self, first: u16, last: u16) {
+        assert!(first > 0 && first <= last, "Invalid port range");
+        self.ports = (first, last);
+        self.next_port = first;
+    }
+
+    pub fn timeouts(&self) -> NatTimeouts {
+        self.entries.timeouts()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: NatTimeouts) {
+        self.entries.set_timeouts(timeouts);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Limit the number of translations, existing ones are kept.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// The number of translations.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// All translations, including expired ones which were not removed yet.
+    pub fn entries(&self) -> impl Iterator<Item=&NatEntry> {
+        self.entries.iter().map(|connection| &connection.data)
+    }
+
+    /// The connections of the translations.
+    pub fn conntrack(&self) -> &Conntrack<NatEntry> {
+        &self.entries
+    }
+
+    pub fn stats(&self) -> NatStats {
+        self.stats
+    }
+
+    /// Remove all translations.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Translate a frame of an internal host in place.
+    ///
+    /// Returns `false` if the frame must be dropped. Otherwise set its Ethernet addresses for the
+    /// next hop on the outside and send it.
+    pub fn outbound(&mut self, frame: &mut [u8]) -> bool {
+        self.sweep();
+        let packet = match self.parse(frame) {
+            Some(packet) => packet,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let flow = FiveTuple::from(packet.flow);
+        self.remove_expired(&flow, now);
+        if self.entries.get(&flow).is_none() && !self.insert(packet.flow, now) {
+            self.stats.exhausted += 1;
+            return false;
+        }
+
+        let connection = self.entries.update(&flow, packet.len, packet.closing, now).unwrap();
+        let external = sync(connection).external;
+        decrement_ttl(frame, packet.l3);
+        rewrite(frame, &packet, false, external);
+        self.stats.translated_out += 1;
+        true
+    }
+
+    /// Translate a frame from the outside in place.
+    ///
+    /// Returns `false` if the frame must be dropped, e.g. as it is not a reply to a translated
+    /// packet. Otherwise set its Ethernet addresses for the internal host and send it.
+    pub fn inbound(&mut self, frame: &mut [u8]) -> bool {
+        self.sweep();
+        let packet = match self.parse(frame) {
+            Some(packet) => packet,
+            None => return false,
+        };
+
+        let now = Instant::now();
+        let flow = FiveTuple::from(packet.flow);
+        self.remove_expired(&flow, now);
+        let internal = match self.entries.get(&flow) {
+            Some(connection) if connection.reply == flow => connection.data.internal,
+            _ => {
+                self.stats.unmatched += 1;
+                return false;
+            },
+        };
+
+        let connection = self.entries.update(&flow, packet.len, packet.closing, now).unwrap();
+        sync(connection);
+        decrement_ttl(frame, packet.l3);
+        rewrite(frame, &packet, true, internal);
+        self.stats.translated_in += 1;
+        true
+    }
+
+    /// Remove all expired translations, returns how many.
+    pub fn expire(&mut self) -> usize {
+        let now = Instant::now();
+        self.last_sweep = now;
+
+        let expired = self.entries.expire(now);
+        self.stats.expired += expired as u64;
+        expired
+    }
+
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() >= Self::SWEEP {
+            self.expire();
+        }
+    }
+
+    /// Create a translation with a free external port.
+    fn insert(&mut self, flow: Flow, now: Instant) -> bool {
+        if self.entries.len() >= self.capacity {
+            return false;
+        }
+
+        let (first, last) = self.ports;
+        let count = u32::from(last - first) + 1;
+        for _ in 0..count {
+            let port = self.next_port;
+            self.next_port = if port >= last { first } else { port + 1 };
+
+            let entry = NatEntry {
+                protocol: flow.protocol,
+                internal: flow.src,
+                external: SocketAddrV4::new(self.external, port),
+                remote: flow.dst,
+                packets_out: 0,
+                bytes_out: 0,
+                packets_in: 0,
+                bytes_in: 0,
+                closing: false,
+                last_seen: now,
+            };
+            let reply = FiveTuple::from(entry.reply());
+            if self.entries.get(&reply).is_none() {
+                self.entries.insert(flow.into(), reply, entry, now);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Remove the expired translation of a flow of either direction.
+    fn remove_expired(&mut self, flow: &FiveTuple, now: Instant) {
+        let expired = self.entries
+            .get(flow)
+            .map_or(false, |connection| self.entries.is_expired(connection, now));
+        if expired {
+            self.entries.remove(flow);
+            self.stats.expired += 1;
+        }
+    }
+
+    /// Locate the translated fields of a frame, counting those which are not translated.
+    fn parse(&mut self, frame: &[u8]) -> Option<Parsed> {
+        let packet = parse(frame);
+        if packet.is_none() {
+            self.stats.unsupported += 1;
+        }
+        packet
+    }
+}
+
+impl NatEntry {
+    /// The flow of replies from the remote to the external address.
+    fn reply(&self) -> Flow {
+        let src = match self.protocol {
+            // Echo replies carry the identifier of the translated request.
+            PROTO_ICMP => SocketAddrV4::new(*self.remote.ip(), self.external.port()),
+            _ => self.remote,
+        };
+        Flow { protocol: self.protocol, src, dst: self.external }
+    }
+}
+
+impl From<Flow> for FiveTuple {
+    fn from(flow: Flow) -> Self {
+        FiveTuple {
+            src: (*flow.src.ip()).into(),
+            dst: (*flow.dst.ip()).into(),
+            src_port: flow.src.port(),
+            dst_port: flow.dst.port(),
+            protocol: flow.protocol,
+        }
+    }
+}
+
+/// Copy the counters of a connection into its translation.
+fn sync(connection: &mut Connection<NatEntry>) -> &NatEntry {
+    let entry = &mut connection.data;
+    entry.packets_out = connection.packets;
+    entry.bytes_out = connection.bytes;
+    entry.packets_in = connection.reply_packets;
+    entry.bytes_in = connection.reply_bytes;
+    entry.closing = connection.closing;
+    entry.last_seen = connection.last_seen;
+    entry
+}
+
+/// Locate the fields of an untagged, unfragmented IPv4 packet with a live TTL.
+pub(crate) fn parse(frame: &[u8]) -> Option<Parsed> {
+    let headers = Headers::parse(frame)?;
+    if headers.ethertype != ETHERTYPE_IPV4 || headers.vlan.is_some() {
+        return None;
+    }
+
+    let (l3, end) = (headers.l3, headers.end);
+    // Fragments have no transport header to translate.
+    let (l4, protocol) = headers.l4?;
+    // Forwarding decrements the TTL.
+    if frame[l3 + 8] <= 1 {
+        return None;
+    }
+
+    let (ports, closing) = match protocol {
+        PROTO_TCP if end >= l4 + 20 => {
+            let closing = frame[l4 + 13] & 0x05 != 0;
+            ((read_u16(frame, l4), read_u16(frame, l4 + 2)), closing)
+        },
+        PROTO_UDP if end >= l4 + 8 => ((read_u16(frame, l4), read_u16(frame, l4 + 2)), false),
+        PROTO_ICMP if end >= l4 + 8 => {
+            if frame[l4] != ICMP_ECHO_REQUEST && frame[l4] != ICMP_ECHO_REPLY {
+                return None;
+            }
+            let identifier = read_u16(frame, l4 + 4);
+            ((identifier, identifier), false)
+        },
+        _ => return None,
+    };
+
+    let src = SocketAddrV4::new(ipv4(&frame[l3 + 12..l3 + 16]), ports.0);
+    let dst = SocketAddrV4::new(ipv4(&frame[l3 + 16..l3 + 20]), ports.1);
+    Some(Parsed {
+        l3,
+        l4,
+        flow: Flow { protocol, src, dst },
+        len: end - l3,
+        closing,
+    })
+}
+
+/// Decrement the TTL of an IPv4 packet, updating its checksum.
+pub(crate) fn decrement_ttl(frame: &mut [u8], l3: usize) {
+    let old = read_u16(frame, l3 + 8);
+    let ttl = old - 0x0100;
+    write_u16(frame, l3 + 10, update(read_u16(frame, l3 + 10), old, ttl));
+    write_u16(frame, l3 + 8, ttl);
+}
+
+/// Replace the source or destination of a packet.
+///
+/// The checksums are updated incrementally. A zero UDP checksum stays zero, as it was not
+/// calculated by the sender.
+pub(crate) fn rewrite(frame: &mut [u8], packet: &Parsed, destination: bool, addr: SocketAddrV4) {
+    let Parsed { l3, l4, flow, .. } = *packet;
+    let addr_at = l3 + if destination { 16 } else { 12 };
+    let port_at = l4 + if destination { 2 } else { 0 };
+    let (port_at, checksum_at) = match flow.protocol {
+        PROTO_TCP => (port_at, Some(l4 + 16)),
+        PROTO_UDP if read_u16(frame, l4 + 6) == 0 => (port_at, None),
+        PROTO_UDP => (port_at, Some(l4 + 6)),
+        // The identifier of echo messages.
+        _ => (l4 + 4, Some(l4 + 2)),
+    };
+
+    let mut ip_sum = read_u16(frame, l3 + 10);
+    let mut l4_sum = checksum_at.map(|at| read_u16(frame, at));
+
+    let octets = addr.ip().octets();
+    for word in 0..2 {
+        let at = addr_at + 2 * word;
+        let old = read_u16(frame, at);
+        let new = u16::from_be_bytes([octets[2 * word], octets[2 * word + 1]]);
+        ip_sum = update(ip_sum, old, new);
+        // ICMP has no pseudo header.
+        if flow.protocol != PROTO_ICMP {
+            l4_sum = l4_sum.map(|sum| update(sum, old, new));
+        }
+        write_u16(frame, at, new);
+    }
+
+    let old = read_u16(frame, port_at);
+    l4_sum = l4_sum.map(|sum| update(sum, old, addr.port()));
+    write_u16(frame, port_at, addr.port());
+
+    write_u16(frame, l3 + 10, ip_sum);
+    if let (Some(at), Some(sum)) = (checksum_at, l4_sum) {
+        // A calculated UDP checksum of zero is sent as all ones.
+        let sum = if flow.protocol == PROTO_UDP && sum == 0 { 0xffff } else { sum };
+        write_u16(frame, at, sum);
+    }
+}
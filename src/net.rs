@@ -0,0 +1,241 @@
+//! Blocking sockets shaped like `std::net`, enabled by the `sockets` feature.
+//!
+//! These mirror the standard library types so that ordinary socket programs port with few
+//! changes: the constructors additionally take the `Stack` to use, and every blocking call polls
+//! that stack, and with it the phy, until it can complete. Only IPv4 addresses are supported.
+//!
+//! All sockets of a stack live on one thread. While one of them blocks the others still make
+//! progress in the network, but the thread can not serve them.
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::time::Duration;
+
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+use crate::Queues;
+use crate::sockets::{self, Stack};
+use crate::sockets::blocking::BlockingStream;
+
+/// A TCP connection, see `std::net::TcpStream`.
+pub struct TcpStream<D: Queues + 'static> {
+    inner: BlockingStream<D>,
+}
+
+/// A TCP socket listening for connections, see `std::net::TcpListener`.
+pub struct TcpListener<D: Queues + 'static> {
+    stack: Stack<D>,
+    inner: sockets::TcpListener<D>,
+}
+
+/// A UDP socket, see `std::net::UdpSocket`.
+pub struct UdpSocket<D: Queues + 'static> {
+    stack: Stack<D>,
+    inner: sockets::UdpSocket<D>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<D: Queues + 'static> TcpStream<D> {
+    /// Connect to the first of the addresses which accepts the connection.
+    pub fn connect(stack: &Stack<D>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            let stream = stack.block_on(sockets::TcpStream::connect(stack, endpoint(addr)?))?;
+            Ok(TcpStream { inner: BlockingStream::new(stream) })
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        socket_addr(self.inner.get_ref().remote_endpoint())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        socket_addr(self.inner.get_ref().local_endpoint())
+    }
+
+    /// Close the writing half of the connection.
+    ///
+    /// The reading half can not be closed on its own, data of the peer is still received and
+    /// shutting it down has no effect.
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match how {
+            Shutdown::Read => (),
+            Shutdown::Write | Shutdown::Both => self.inner.get_mut().close(),
+        }
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.inner.read_timeout()
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.inner.set_read_timeout(timeout);
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.inner.write_timeout()
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.inner.set_write_timeout(timeout);
+        Ok(())
+    }
+
+    /// The connection of the underlying socket layer.
+    pub fn into_inner(self) -> sockets::TcpStream<D> {
+        self.inner.into_inner()
+    }
+}
+
+impl<D: Queues + 'static> TcpListener<D> {
+    /// Listen on the port of the first address.
+    ///
+    /// The socket accepts connections to any address of the interface. Port `0` is not assigned
+    /// an ephemeral port, a listener needs a known port.
+    pub fn bind(stack: &Stack<D>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            if addr.port() == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Listening on port 0"));
+            }
+            let inner = sockets::TcpListener::bind(stack, addr.port())?;
+            Ok(TcpListener { stack: stack.clone(), inner })
+        })
+    }
+
+    /// Wait for the next connection.
+    pub fn accept(&mut self) -> io::Result<(TcpStream<D>, SocketAddr)> {
+        let stream = self.stack.block_on(self.inner.accept())?;
+        let peer = socket_addr(stream.remote_endpoint())?;
+        Ok((TcpStream { inner: BlockingStream::new(stream) }, peer))
+    }
+
+    /// The unspecified address with the listening port.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, self.inner.port()).into())
+    }
+}
+
+impl<D: Queues + 'static> UdpSocket<D> {
+    /// Bind to the first of the addresses, port `0` assigns an ephemeral port.
+    pub fn bind(stack: &Stack<D>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        each_addr(addr, |addr| {
+            let mut local = endpoint(addr)?;
+            if local.port == 0 {
+                local.port = stack.ephemeral_port();
+            }
+
+            Ok(UdpSocket {
+                stack: stack.clone(),
+                inner: sockets::UdpSocket::bind(stack, local)?,
+                read_timeout: None,
+                write_timeout: None,
+            })
+        })
+    }
+
+    /// Send a datagram to the first of the addresses.
+    pub fn send_to(&self, buf: &[u8], addr: impl ToSocketAddrs) -> io::Result<usize> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))?;
+        let remote = endpoint(addr)?;
+        self.stack.block(self.write_timeout, || self.inner.try_send_to(buf, remote))?;
+        Ok(buf.len())
+    }
+
+    /// Receive a datagram, truncating it if it does not fit the buffer.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (len, remote) = self.stack.block(self.read_timeout, || self.inner.try_recv_from(buf))?;
+        Ok((len, socket_addr(remote)?))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        socket_addr(self.inner.local_endpoint())
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        check_timeout(timeout)?;
+        self.write_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl<D: Queues + 'static> Read for TcpStream<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<D: Queues + 'static> Write for TcpStream<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Try each of the addresses in turn, returning the last error if none succeeds.
+fn each_addr<T>(addr: impl ToSocketAddrs, mut f: impl FnMut(SocketAddr) -> io::Result<T>)
+    -> io::Result<T>
+{
+    let mut last = None;
+    for addr in addr.to_socket_addrs()? {
+        match f(addr) {
+            Ok(value) => return Ok(value),
+            Err(err) => last = Some(err),
+        }
+    }
+
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "Could not resolve to any addresses")
+    }))
+}
+
+pub(crate) fn endpoint(addr: SocketAddr) -> io::Result<IpEndpoint> {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let ip = Ipv4Address::from_bytes(&addr.ip().octets());
+            Ok(IpEndpoint::new(IpAddress::Ipv4(ip), addr.port()))
+        },
+        SocketAddr::V6(_) => Err(unsupported()),
+    }
+}
+
+pub(crate) fn socket_addr(endpoint: IpEndpoint) -> io::Result<SocketAddr> {
+    let ip = match endpoint.addr {
+        IpAddress::Ipv4(ip) => Ipv4Addr::from(ip.0),
+        IpAddress::Unspecified => Ipv4Addr::UNSPECIFIED,
+        _ => return Err(unsupported()),
+    };
+    Ok(SocketAddrV4::new(ip, endpoint.port).into())
+}
+
+/// Like the standard library, reject a zero timeout.
+fn check_timeout(timeout: Option<Duration>) -> io::Result<()> {
+    if timeout == Some(Duration::from_secs(0)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Zero duration timeout"));
+    }
+    Ok(())
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Only IPv4 addresses are supported")
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ethox::nic;
+use ixy::memory::{self, Mempool, Packet as IxyPacket};
+
+use crate::checksum::{self, read_u16, write_u16};
+use crate::frame::{Headers, ETHERTYPE_IPV4, ETHERTYPE_QINQ, ETHERTYPE_VLAN, PROTO_TCP, PROTO_UDP};
+
+/// The offloading features supported and enabled on a device.
+///
+/// All features default to unsupported, which is accurate for the ixy drivers as these never
+/// enable hardware offloads. Devices implementing `Queues` directly may report more.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Offloads {
+    /// The NIC validates IPv4 header checksums of received packets.
+    pub rx_ipv4_checksum: bool,
+
+    /// The NIC validates TCP and UDP checksums of received packets.
+    pub rx_l4_checksum: bool,
+
+    /// The NIC inserts IPv4 header checksums into sent packets.
+    pub tx_ipv4_checksum: bool,
+
+    /// The NIC inserts TCP checksums into sent packets.
+    pub tx_tcp_checksum: bool,
+
+    /// The NIC inserts UDP checksums into sent packets.
+    pub tx_udp_checksum: bool,
+
+    /// The NIC strips VLAN tags from received packets.
+    pub vlan_strip: bool,
+
+    /// The NIC inserts VLAN tags into sent packets.
+    pub vlan_insert: bool,
+
+    /// The NIC segments TCP packets larger than the maximum segment size.
+    pub tso: bool,
+}
+
+/// Offloads requested for a single sent packet.
+///
+/// Requested through the `Handle`. In addition, all checksums for which the device advertises
+/// insertion are requested implicitly as the network stack leaves them empty.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TxOffload {
+    /// Insert the IPv4 header checksum.
+    pub ipv4_checksum: bool,
+
+    /// Insert the TCP or UDP checksum.
+    pub l4_checksum: bool,
+
+    /// Split a TCP packet into segments with at most this much payload.
+    ///
+    /// This lets the stack send a single segment larger than the MTU, provided the tx pool has
+    /// large enough entries. The device segments it in hardware if it supports TSO, otherwise the
+    /// phy copies the payload into new buffers. Checksums of all segments are inserted as well.
+    pub mss: Option<u16>,
+
+    /// Insert a VLAN tag with this tag control information.
+    ///
+    /// Spliced into the frame in software if the device does not support insertion. Frames
+    /// without room for the tag in their buffer are then dropped.
+    pub vlan: Option<u16>,
+}
+
+impl Offloads {
+    /// The checksum capabilities in the representation of ethox.
+    pub fn capabilities(&self) -> nic::Capabilities {
+        let mut capabilities = nic::Capabilities::no_support();
+        *capabilities.ipv4_mut() = checksum_capability(self.rx_ipv4_checksum, self.tx_ipv4_checksum);
+        *capabilities.tcp_mut() = checksum_capability(self.rx_l4_checksum, self.tx_tcp_checksum);
+        *capabilities.udp_mut() = checksum_capability(self.rx_l4_checksum, self.tx_udp_checksum);
+        capabilities
+    }
+
+    /// The offloads supported by both devices.
+    pub fn common(&self, other: &Offloads) -> Self {
+        Offloads {
+            rx_ipv4_checksum: self.rx_ipv4_checksum && other.rx_ipv4_checksum,
+            rx_l4_checksum: self.rx_l4_checksum && other.rx_l4_checksum,
+            tx_ipv4_checksum: self.tx_ipv4_checksum && other.tx_ipv4_checksum,
+            tx_tcp_checksum: self.tx_tcp_checksum && other.tx_tcp_checksum,
+            tx_udp_checksum: self.tx_udp_checksum && other.tx_udp_checksum,
+            vlan_strip: self.vlan_strip && other.vlan_strip,
+            vlan_insert: self.vlan_insert && other.vlan_insert,
+            tso: self.tso && other.tso,
+        }
+    }
+
+    /// The personality of a device with these offloads.
+    pub fn personality(&self) -> nic::Personality {
+        let mut personality = nic::Personality::baseline();
+        personality.capabilities = self.capabilities();
+        personality
+    }
+}
+
+impl TxOffload {
+    /// Check if no offload is requested.
+    pub fn is_empty(&self) -> bool {
+        *self == TxOffload::default()
+    }
+
+    /// Add the insertions which the device advertises.
+    pub(crate) fn with_advertised(mut self, offloads: &Offloads) -> Self {
+        self.ipv4_checksum |= offloads.tx_ipv4_checksum;
+        self.l4_checksum |= offloads.tx_tcp_checksum || offloads.tx_udp_checksum;
+        self
+    }
+}
+
+/// Insert the requested checksums into an Ethernet frame in software.
+///
+/// Frames which are not IP, or too short for their headers, are left unchanged.
+pub(crate) fn insert_checksums(frame: &mut [u8], offload: TxOffload) {
+    let headers = match Headers::parse(frame) {
+        Some(headers) => headers,
+        None => return,
+    };
+
+    if offload.ipv4_checksum && headers.ethertype == ETHERTYPE_IPV4 {
+        let l3 = headers.l3;
+        let ihl = usize::from(frame[l3] & 0x0f) * 4;
+        write_u16(frame, l3 + 10, 0);
+        let sum = checksum::finish(checksum::sum(&frame[l3..l3 + ihl], 0));
+        write_u16(frame, l3 + 10, sum);
+    }
+
+    if !offload.l4_checksum {
+        return;
+    }
+
+    let (offset, field) = match headers.l4 {
+        Some((offset, PROTO_TCP)) => (offset, offset + 16),
+        Some((offset, PROTO_UDP)) => (offset, offset + 6),
+        _ => return,
+    };
+
+    if headers.end < field + 2 {
+        return;
+    }
+
+    let protocol = headers.l4.unwrap().1;
+    write_u16(frame, field, 0);
+    let acc = headers.pseudo_header(frame, protocol, headers.end - offset);
+    let mut sum = checksum::finish(checksum::sum(&frame[offset..headers.end], acc));
+    if protocol == PROTO_UDP && sum == 0 {
+        // Zero means no checksum for UDP.
+        sum = 0xffff;
+    }
+    write_u16(frame, field, sum);
+}
+
+/// Splice a VLAN tag into a frame in software.
+///
+/// Returns `false` if the frame is too short or has no room for the tag.
+pub(crate) fn insert_vlan(packet: &mut IxyPacket, tci: u16) -> bool {
+    insert_tag(packet, ETHERTYPE_VLAN, tci)
+}
+
+/// Splice an outermost tag with the given protocol identifier into a frame in software.
+///
+/// Returns `false` if the frame is too short or has no room for the tag.
+pub(crate) fn insert_tag(packet: &mut IxyPacket, tpid: u16, tci: u16) -> bool {
+    let len = packet.len();
+    if len < 12 || packet.try_resize(len + 4, 0u8).is_err() {
+        return false;
+    }
+
+    let frame: &mut [u8] = packet.as_mut();
+    frame.copy_within(12..len, 16);
+    write_u16(frame, 12, tpid);
+    write_u16(frame, 14, tci);
+    true
+}
+
+/// Remove the outer VLAN tag of a frame in software.
+///
+/// Returns the tag control information of the removed tag.
+pub(crate) fn strip_vlan(packet: &mut IxyPacket) -> Option<u16> {
+    if packet.len() < 18 || read_u16(&packet[..], 12) != ETHERTYPE_VLAN {
+        return None;
+    }
+    strip_tag(packet).map(|(_, tci)| tci)
+}
+
+/// Remove the outermost tag of a frame in software, a VLAN or a service tag.
+///
+/// Returns the protocol identifier and the tag control information of the removed tag.
+pub(crate) fn strip_tag(packet: &mut IxyPacket) -> Option<(u16, u16)> {
+    let len = packet.len();
+    if len < 18 {
+        return None;
+    }
+    let tpid = read_u16(&packet[..], 12);
+    if tpid != ETHERTYPE_VLAN && tpid != ETHERTYPE_QINQ {
+        return None;
+    }
+
+    let tci = read_u16(&packet[..], 14);
+    let frame: &mut [u8] = packet.as_mut();
+    frame.copy_within(16..len, 12);
+    // Shrinking within the mempool entry can not fail.
+    let _ = packet.try_resize(len - 4, 0u8);
+    Some((tpid, tci))
+}
+
+/// Split a TCP frame into segments of at most `mss` payload bytes, in software.
+///
+/// The segments are allocated from `pool` and appended to `out` with all checksums inserted. Only
+/// the last segment retains the FIN and PSH flags, only the first CWR. Returns `None` without
+/// queueing anything if the frame is not TCP or needs no segmentation. Segments which can not be
+/// allocated are lost, just as if the wire had dropped them, and their number is returned.
+pub(crate) fn segment(
+    frame: &[u8],
+    mss: usize,
+    pool: &Rc<Mempool>,
+    out: &mut VecDeque<IxyPacket>,
+) -> Option<usize> {
+    let headers = match Headers::parse(frame) {
+        Some(headers) => headers,
+        None => return None,
+    };
+
+    let tcp = match headers.transport(PROTO_TCP) {
+        Some(tcp) if tcp + 20 <= headers.end => tcp,
+        _ => return None,
+    };
+
+    let header_len = tcp + usize::from(frame[tcp + 12] >> 4) * 4;
+    if mss == 0 || header_len > headers.end || headers.end - header_len <= mss {
+        return None;
+    }
+
+    let payload = &frame[header_len..headers.end];
+    let seq = u32::from_be_bytes([frame[tcp + 4], frame[tcp + 5], frame[tcp + 6], frame[tcp + 7]]);
+    let ident = read_u16(frame, headers.l3 + 4);
+    let chunks = (payload.len() + mss - 1) / mss;
+
+    for (index, chunk) in payload.chunks(mss).enumerate() {
+        let len = header_len + chunk.len();
+        let mut packet = match memory::alloc_pkt(pool, len) {
+            Some(packet) => packet,
+            None => return Some(chunks - index),
+        };
+
+        let segment: &mut [u8] = packet.as_mut();
+        segment[..header_len].copy_from_slice(&frame[..header_len]);
+        segment[header_len..].copy_from_slice(chunk);
+
+        let l3 = headers.l3;
+        if headers.ethertype == ETHERTYPE_IPV4 {
+            write_u16(segment, l3 + 2, (len - l3) as u16);
+            write_u16(segment, l3 + 4, ident.wrapping_add(index as u16));
+        } else {
+            // The payload length includes the extension headers in front of the TCP header.
+            let extensions = tcp - (l3 + 40);
+            write_u16(segment, l3 + 4, (extensions + len - tcp) as u16);
+        }
+
+        let offset = (index * mss) as u32;
+        segment[tcp + 4..tcp + 8].copy_from_slice(&seq.wrapping_add(offset).to_be_bytes());
+        if index + 1 != chunks {
+            // Clear FIN and PSH.
+            segment[tcp + 13] &= !0x09;
+        }
+        if index != 0 {
+            // Clear CWR.
+            segment[tcp + 13] &= !0x80;
+        }
+
+        insert_checksums(segment, TxOffload {
+            ipv4_checksum: true,
+            l4_checksum: true,
+            mss: None,
+            vlan: None,
+        });
+        out.push_back(packet);
+    }
+
+    Some(0)
+}
+
+fn checksum_capability(rx: bool, tx: bool) -> nic::Checksum {
+    match (rx, tx) {
+        (true, true) => nic::Checksum::Both,
+        (true, false) => nic::Checksum::Rx,
+        (false, true) => nic::Checksum::Tx,
+        (false, false) => nic::Checksum::None,
+    }
+}
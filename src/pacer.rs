@@ -0,0 +1,255 @@
+//! Sending packets at their earliest departure time.
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ixy::memory::{self, Packet as IxyPacket};
+
+use crate::{Handle, Packet, Phy, Queues};
+
+/// Counters of a pacer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PacerStats {
+    /// Packets handed to the device.
+    pub sent: u64,
+
+    /// Packets dropped since their departure was beyond the horizon.
+    pub beyond_horizon: u64,
+
+    /// The largest delay of a packet after its departure time.
+    pub max_lag: Duration,
+}
+
+/// Holds the packets sent through a phy until their earliest departure time.
+///
+/// Packets of the network stack depart at the pacing rate, one after the other, or immediately
+/// without a rate. Other packets can be scheduled at any time with `send_at`, e.g. by a
+/// congestion controller like BBR. Each due packet is flushed to the device on its own, so its
+/// departure is as precise as the loop calling the pacer.
+///
+/// The pacer is either driven by a timer, calling `poll` at `next_departure`, or busy waits in
+/// `spin` for the most precise timing. Packets scheduled further ahead than the horizon are
+/// dropped, to bound the buffers held.
+pub struct Pacer<D> {
+    phy: Phy<D>,
+    /// The pacing rate in bits per second.
+    rate: Option<u64>,
+    horizon: Duration,
+    /// The departure after the last packet paced at the rate.
+    next_free: Instant,
+    scheduled: BinaryHeap<Scheduled>,
+    sequence: u64,
+    stats: PacerStats,
+}
+
+/// A packet and its departure, ordered so the earliest departs first, in scheduling order.
+struct Scheduled {
+    departure: Instant,
+    sequence: u64,
+    packet: IxyPacket,
+}
+
+impl<D> Pacer<D> {
+    /// The default horizon.
+    pub const HORIZON: Duration = Duration::from_secs(10);
+
+    /// Pace the packets of a phy, sending them immediately until a rate is set.
+    pub fn new(phy: Phy<D>) -> Self {
+        Pacer {
+            phy,
+            rate: None,
+            horizon: Self::HORIZON,
+            next_free: Instant::now(),
+            scheduled: BinaryHeap::new(),
+            sequence: 0,
+            stats: PacerStats::default(),
+        }
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    /// The pacing rate of the network stack in bits per second.
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    /// Pace the packets of the network stack, counting the bits of whole frames.
+    ///
+    /// ## Panics
+    /// This function panics if the rate is zero.
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        assert!(rate != Some(0), "Pacing rate must not be zero");
+        self.rate = rate;
+    }
+
+    pub fn horizon(&self) -> Duration {
+        self.horizon
+    }
+
+    pub fn set_horizon(&mut self, horizon: Duration) {
+        self.horizon = horizon;
+    }
+
+    /// The number of packets waiting for their departure.
+    pub fn scheduled(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    /// The earliest departure of a waiting packet.
+    pub fn next_departure(&self) -> Option<Instant> {
+        self.scheduled.peek().map(|scheduled| scheduled.departure)
+    }
+
+    pub fn stats(&self) -> PacerStats {
+        self.stats
+    }
+
+    /// Unwrap the phy, dropping all waiting packets.
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+
+    /// Schedule a packet, dropping it if its departure is beyond the horizon.
+    fn schedule(&mut self, packet: IxyPacket, departure: Instant, now: Instant) -> bool {
+        if departure > now + self.horizon {
+            self.stats.beyond_horizon += 1;
+            return false;
+        }
+
+        self.sequence += 1;
+        self.scheduled.push(Scheduled { departure, sequence: self.sequence, packet });
+        true
+    }
+
+    /// The departure of a packet of the network stack.
+    fn pace(&mut self, len: usize, now: Instant) -> Instant {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return now,
+        };
+
+        let departure = self.next_free.max(now);
+        let nanos = len as u128 * 8 * 1_000_000_000 / u128::from(rate);
+        self.next_free = departure + Duration::from_nanos(nanos as u64);
+        departure
+    }
+}
+
+impl<D: Queues> Pacer<D> {
+    /// Queue a copy of a raw frame for sending at a point in time.
+    ///
+    /// Returns `false` if the pool had no free buffer or the departure is beyond the horizon.
+    pub fn send_at(&mut self, frame: &[u8], departure: Instant) -> bool {
+        let mut packet = match memory::alloc_pkt(&self.phy.pool, frame.len()) {
+            Some(packet) => packet,
+            None => return false,
+        };
+
+        packet.copy_from_slice(frame);
+        self.schedule(packet, departure, Instant::now())
+    }
+
+    /// Send the packets which are due, returning their number.
+    pub fn poll(&mut self) -> usize {
+        let mut sent = 0;
+        while self.next_departure().map_or(false, |departure| departure <= Instant::now()) {
+            sent += self.release();
+        }
+        sent
+    }
+
+    /// Busy wait until all packets departed or the deadline, sending each one when it is due.
+    ///
+    /// Returns the number of packets sent.
+    pub fn spin(&mut self, deadline: Instant) -> usize {
+        let mut sent = 0;
+        while let Some(departure) = self.next_departure() {
+            if departure > deadline {
+                break;
+            }
+            while Instant::now() < departure {
+                std::hint::spin_loop();
+            }
+            sent += self.release();
+        }
+        sent
+    }
+
+    /// Flush the earliest packet to the device.
+    fn release(&mut self) -> usize {
+        let scheduled = match self.scheduled.pop() {
+            Some(scheduled) => scheduled,
+            None => return 0,
+        };
+
+        let lag = Instant::now().saturating_duration_since(scheduled.departure);
+        self.stats.max_lag = self.stats.max_lag.max(lag);
+        self.phy.tx_queue.push_back(scheduled.packet);
+        let sent = self.phy.flush();
+        self.stats.sent += sent as u64;
+        sent
+    }
+
+    /// Run an operation of the network stack, then schedule what it queued instead of sending.
+    fn deferred<T>(&mut self, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, start) = self.phy.deferred(op);
+
+        let now = Instant::now();
+        for packet in self.phy.tx_queue.split_off(start) {
+            let departure = self.pace(packet.len(), now);
+            self.schedule(packet, departure, now);
+        }
+        self.poll();
+        result
+    }
+}
+
+impl<D: Queues> nic::Device for Pacer<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.phy)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.tx(max, sender))
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.rx(max, receptor))
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // Reversed, the binary heap pops the largest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.departure, other.sequence).cmp(&(self.departure, self.sequence))
+    }
+}
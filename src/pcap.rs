@@ -0,0 +1,529 @@
+//! Capturing the traffic of a device to a file.
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ethox::wire::Payload;
+
+use crate::Queued;
+
+/// The direction of a captured frame, relative to the local host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The frame was received from the device.
+    Rx,
+
+    /// The frame was queued for sending.
+    Tx,
+}
+
+/// A destination of captured frames.
+pub trait Dump {
+    /// Record a single frame.
+    ///
+    /// The timestamp is the time since the unix epoch.
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()>;
+
+    /// Decide if the next frame should be recorded at all.
+    ///
+    /// Called before the frame is inspected, skipped frames cost no more than this call.
+    fn sample(&mut self, _: Direction) -> bool {
+        true
+    }
+}
+
+/// Which frames a `Sampled` dump records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Sampling {
+    /// Every frame.
+    All,
+
+    /// Every n-th frame.
+    OneIn(u32),
+
+    /// At most one frame per interval.
+    Interval(Duration),
+
+    /// No frames.
+    None,
+}
+
+/// Records only a sample of the frames to the inner dump.
+///
+/// Full captures can not keep up with line rate. The sampling can be changed at runtime, e.g.
+/// through `Capture::dump_mut`.
+pub struct Sampled<K> {
+    inner: K,
+    sampling: Sampling,
+    skipped: u32,
+    last: Option<Instant>,
+}
+
+/// Writes frames in the classic pcap format, with microsecond timestamps.
+pub struct Pcap<W> {
+    writer: W,
+    snaplen: u32,
+}
+
+/// A device wrapper writing every received and sent frame to a `Dump`.
+///
+/// Traffic is passed through unchanged. Frames are captured as received when they are handed to
+/// the receiver, and as sent when the sender queued them. Capturing stops after the first error
+/// of the dump, which can be retrieved with `take_error`.
+pub struct Capture<D, K> {
+    device: D,
+    dump: K,
+    error: Option<io::Error>,
+}
+
+/// Wraps the sender or receiver of the inner device.
+struct Tap<'a, T, K> {
+    inner: T,
+    dump: &'a mut K,
+    error: &'a mut Option<io::Error>,
+}
+
+impl<W: Write> Pcap<W> {
+    /// The default maximum length of captured frames.
+    pub const SNAPLEN: u32 = 65535;
+
+    /// Start a capture file, writing its header.
+    pub fn new(writer: W) -> io::Result<Self> {
+        Self::with_snaplen(writer, Self::SNAPLEN)
+    }
+
+    /// Start a capture file truncating frames to `snaplen` bytes.
+    pub fn with_snaplen(mut writer: W, snaplen: u32) -> io::Result<Self> {
+        let mut header = [0; 24];
+        header[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // Bytes 8..16 are the zone offset and accuracy, always zero.
+        header[16..20].copy_from_slice(&snaplen.to_le_bytes());
+        // Link type ethernet.
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Pcap { writer, snaplen })
+    }
+
+    /// Record a frame which may have been truncated before, originally `len` bytes long.
+    pub fn record(&mut self, timestamp: Duration, frame: &[u8], len: usize) -> io::Result<()> {
+        let captured = frame.len().min(self.snaplen as usize);
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(captured as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(len.max(captured) as u32).to_le_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&frame[..captured])
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Dump for Pcap<W> {
+    fn dump(&mut self, _: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        self.record(timestamp, frame, frame.len())
+    }
+}
+
+impl<K> Sampled<K> {
+    pub fn new(inner: K, sampling: Sampling) -> Self {
+        Sampled {
+            inner,
+            sampling,
+            skipped: 0,
+            last: None,
+        }
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        self.sampling
+    }
+
+    /// Change the sampling, effective from the next frame.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+        self.skipped = 0;
+        self.last = None;
+    }
+
+    pub fn inner(&self) -> &K {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut K {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> K {
+        self.inner
+    }
+}
+
+impl<K: Dump> Dump for Sampled<K> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        self.inner.dump(direction, timestamp, frame)
+    }
+
+    fn sample(&mut self, direction: Direction) -> bool {
+        let sampled = match self.sampling {
+            Sampling::All => true,
+            Sampling::None => false,
+            Sampling::OneIn(n) => {
+                self.skipped += 1;
+                if self.skipped >= n {
+                    self.skipped = 0;
+                    true
+                } else {
+                    false
+                }
+            },
+            Sampling::Interval(interval) => {
+                let now = Instant::now();
+                match self.last {
+                    Some(last) if now.duration_since(last) < interval => false,
+                    _ => {
+                        self.last = Some(now);
+                        true
+                    },
+                }
+            },
+        };
+
+        sampled && self.inner.sample(direction)
+    }
+}
+
+impl<D, K> Capture<D, K> {
+    pub fn new(device: D, dump: K) -> Self {
+        Capture {
+            device,
+            dump,
+            error: None,
+        }
+    }
+
+    /// The error which stopped the capture, if any.
+    ///
+    /// Capturing resumes after the error was taken.
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    pub fn dump(&self) -> &K {
+        &self.dump
+    }
+
+    pub fn dump_mut(&mut self) -> &mut K {
+        &mut self.dump
+    }
+
+    pub fn into_inner(self) -> (D, K) {
+        (self.device, self.dump)
+    }
+}
+
+impl<'a, T, K: Dump> Tap<'a, T, K> {
+    fn new(inner: T, dump: &'a mut K, error: &'a mut Option<io::Error>) -> Self {
+        Tap { inner, dump, error }
+    }
+
+    fn record(&mut self, direction: Direction, frame: &[u8]) {
+        if self.error.is_some() || !self.dump.sample(direction) {
+            return;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if let Err(err) = self.dump.dump(direction, timestamp, frame) {
+            *self.error = Some(err);
+        }
+    }
+}
+
+impl<D, K> nic::Device for Capture<D, K>
+where
+    D: nic::Device,
+    D::Handle: Queued,
+    K: Dump,
+{
+    type Handle = D::Handle;
+    type Payload = D::Payload;
+
+    fn personality(&self) -> nic::Personality {
+        self.device.personality()
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        let tap = Tap::new(sender, &mut self.dump, &mut self.error);
+        self.device.tx(max, tap)
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        let tap = Tap::new(receptor, &mut self.dump, &mut self.error);
+        self.device.rx(max, tap)
+    }
+}
+
+impl<H, P, S, K> nic::Send<H, P> for Tap<'_, S, K>
+where
+    H: Queued + ?Sized,
+    P: Payload + ?Sized,
+    S: nic::Send<H, P>,
+    K: Dump,
+{
+    fn send(&mut self, packet: nic::Packet<H, P>) {
+        let nic::Packet { handle, payload } = packet;
+        self.inner.send(nic::Packet { handle: &mut *handle, payload: &mut *payload });
+        if handle.is_queued() {
+            self.record(Direction::Tx, payload.payload().as_slice());
+        }
+    }
+}
+
+impl<H, P, R, K> nic::Recv<H, P> for Tap<'_, R, K>
+where
+    H: Queued + ?Sized,
+    P: Payload + ?Sized,
+    R: nic::Recv<H, P>,
+    K: Dump,
+{
+    fn receive(&mut self, packet: nic::Packet<H, P>) {
+        let nic::Packet { handle, payload } = packet;
+        self.record(Direction::Rx, payload.payload().as_slice());
+        self.inner.receive(nic::Packet { handle: &mut *handle, payload: &mut *payload });
+        // The receiver may answer in place.
+        if handle.is_queued() {
+            self.record(Direction::Tx, payload.payload().as_slice());
+        }
+    }
+}
+
+/// Writes frames in the pcapng format, with nanosecond timestamps and direction flags.
+///
+/// A file can hold several interfaces. Share it between the captures of several phys with
+/// `Interface`, e.g. for both ports of a forwarder, so that their frames can be correlated on a
+/// single timeline. Used directly as a `Dump` it records to its first interface.
+///
+/// The timestamps are taken in software when the frame passes the capture, the ixy drivers do
+/// not provide hardware timestamps.
+pub struct PcapNg<W> {
+    writer: W,
+    interfaces: u32,
+    buffer: Vec<u8>,
+}
+
+/// One interface of a pcapng file shared between several captures.
+pub struct Interface<W> {
+    file: Rc<RefCell<PcapNg<W>>>,
+    id: u32,
+}
+
+impl<W: Write> PcapNg<W> {
+    /// Start a capture file, writing its section header.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut file = PcapNg {
+            writer,
+            interfaces: 0,
+            buffer: Vec::new(),
+        };
+
+        let mut body = Vec::with_capacity(16);
+        body.extend_from_slice(&0x1a2b_3c4du32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Unspecified section length.
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        file.block(0x0a0d_0d0a, &body)?;
+        Ok(file)
+    }
+
+    /// Describe a new interface, returning its index.
+    pub fn add_interface(&mut self, name: &str) -> io::Result<u32> {
+        let mut body = Vec::with_capacity(32 + name.len());
+        // Link type ethernet, reserved, no snap length.
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut body, 2, name.as_bytes());
+        // Timestamps in nanoseconds.
+        option(&mut body, 9, &[9]);
+        option(&mut body, 0, &[]);
+        self.block(1, &body)?;
+
+        let id = self.interfaces;
+        self.interfaces += 1;
+        Ok(id)
+    }
+
+    /// Record a frame on an interface.
+    pub fn packet(&mut self, interface: u32, direction: Direction, timestamp: Duration, frame: &[u8])
+        -> io::Result<()>
+    {
+        let nanos = timestamp.as_nanos() as u64;
+        let flags: u32 = match direction {
+            Direction::Rx => 0b01,
+            Direction::Tx => 0b10,
+        };
+
+        let mut body = std::mem::replace(&mut self.buffer, Vec::new());
+        body.clear();
+        body.extend_from_slice(&interface.to_le_bytes());
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(frame);
+        pad(&mut body);
+        option(&mut body, 2, &flags.to_le_bytes());
+        option(&mut body, 0, &[]);
+
+        let result = self.block(6, &body);
+        self.buffer = body;
+        result
+    }
+
+    /// Share the file between several captures.
+    pub fn shared(self) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn block(&mut self, kind: u32, body: &[u8]) -> io::Result<()> {
+        let total = (body.len() + 12) as u32;
+        self.writer.write_all(&kind.to_le_bytes())?;
+        self.writer.write_all(&total.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&total.to_le_bytes())
+    }
+}
+
+impl<W: Write> Interface<W> {
+    /// Add an interface to a shared file.
+    pub fn new(file: Rc<RefCell<PcapNg<W>>>, name: &str) -> io::Result<Self> {
+        let id = file.borrow_mut().add_interface(name)?;
+        Ok(Interface { file, id })
+    }
+
+    /// The index of the interface in the file.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl<W: Write> Dump for PcapNg<W> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        if self.interfaces == 0 {
+            self.add_interface("ixy")?;
+        }
+        self.packet(0, direction, timestamp, frame)
+    }
+}
+
+impl<W: Write> Dump for Interface<W> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        self.file.borrow_mut().packet(self.id, direction, timestamp, frame)
+    }
+}
+
+/// Append an option with its value padded to 32 bits.
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    let padded = (body.len() + 3) & !3;
+    body.resize(padded, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn pcap_truncates_to_snaplen() {
+        let mut pcap = Pcap::with_snaplen(Vec::new(), 4).unwrap();
+        let timestamp = Duration::new(7, 1_500);
+        pcap.dump(Direction::Rx, timestamp, &[1, 2, 3, 4, 5, 6]).unwrap();
+        let file = pcap.into_inner();
+
+        assert_eq!(file.len(), 24 + 16 + 4);
+        assert_eq!(u32_at(&file, 0), 0xa1b2_c3d4);
+        assert_eq!(u32_at(&file, 16), 4);
+        assert_eq!(u32_at(&file, 20), 1);
+        let record = &file[24..];
+        assert_eq!(u32_at(record, 0), 7);
+        assert_eq!(u32_at(record, 4), 1);
+        assert_eq!(u32_at(record, 8), 4);
+        assert_eq!(u32_at(record, 12), 6);
+        assert_eq!(&record[16..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn pcapng_blocks_are_framed() {
+        let file = PcapNg::new(Vec::new()).unwrap().shared();
+        let mut first = Interface::new(file.clone(), "rx0").unwrap();
+        let mut second = Interface::new(file.clone(), "tx0").unwrap();
+        first.dump(Direction::Rx, Duration::from_nanos((1 << 32) | 5), &[0xaa; 5]).unwrap();
+        second.dump(Direction::Tx, Duration::from_nanos(9), &[0xbb; 8]).unwrap();
+        drop((first, second));
+        let file = Rc::try_unwrap(file).ok().unwrap().into_inner().into_inner();
+
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < file.len() {
+            let total = u32_at(&file, offset + 4) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_at(&file, offset + total - 4) as usize, total);
+            blocks.push((u32_at(&file, offset), &file[offset + 8..offset + total - 4]));
+            offset += total;
+        }
+        assert_eq!(offset, file.len());
+
+        let kinds: Vec<_> = blocks.iter().map(|&(kind, _)| kind).collect();
+        assert_eq!(kinds, [0x0a0d_0d0a, 1, 1, 6, 6]);
+
+        let (_, packet) = blocks[3];
+        assert_eq!(u32_at(packet, 0), 0);
+        assert_eq!((u32_at(packet, 4), u32_at(packet, 8)), (1, 5));
+        assert_eq!((u32_at(packet, 12), u32_at(packet, 16)), (5, 5));
+        assert_eq!(&packet[20..25], &[0xaa; 5]);
+        // The flags option follows the padded frame.
+        assert_eq!(&packet[28..32], &[2, 0, 4, 0]);
+        assert_eq!(u32_at(packet, 32), 0b01);
+
+        let (_, packet) = blocks[4];
+        assert_eq!(u32_at(packet, 0), 1);
+        assert_eq!(&packet[20..28], &[0xbb; 8]);
+        assert_eq!(u32_at(packet, 32), 0b10);
+    }
+}
@@ -0,0 +1,164 @@
+//! Polling several phys from one thread.
+use crate::{Control, Idler, Phy, Queues};
+
+/// Owns several phys and round-robins the work between them.
+///
+/// Each phy gets a turn per round with its own budget. The first phy of a round rotates, so that
+/// no phy is consistently served first. Typical for the two sides of a forwarder. Rounds in which
+/// no phy received or sent packets count as idle for the idle strategy of `run`.
+pub struct Poller<D> {
+    phys: Vec<Phy<D>>,
+    budgets: Vec<usize>,
+    round: usize,
+    idle: Idler,
+}
+
+/// The turn of one phy within a round.
+pub struct Turn<'a, D> {
+    index: usize,
+    budget: usize,
+    phys: &'a mut [Phy<D>],
+}
+
+impl<D: Queues> Poller<D> {
+    pub fn new() -> Self {
+        Poller {
+            phys: Vec::new(),
+            budgets: Vec::new(),
+            round: 0,
+            idle: Idler::default(),
+        }
+    }
+
+    /// Add a phy with a budget of packets per turn, returning its index.
+    pub fn add(&mut self, phy: Phy<D>, budget: usize) -> usize {
+        self.phys.push(phy);
+        self.budgets.push(budget);
+        self.phys.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.phys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phys.is_empty()
+    }
+
+    pub fn phy(&self, index: usize) -> &Phy<D> {
+        &self.phys[index]
+    }
+
+    pub fn phy_mut(&mut self, index: usize) -> &mut Phy<D> {
+        &mut self.phys[index]
+    }
+
+    /// Change the budget of a phy.
+    pub fn set_budget(&mut self, index: usize, budget: usize) {
+        self.budgets[index] = budget;
+    }
+
+    /// Set what `run` does while there is no traffic, by default spin.
+    pub fn set_idle(&mut self, idle: Idler) {
+        self.idle = idle;
+    }
+
+    pub fn into_phys(self) -> Vec<Phy<D>> {
+        self.phys
+    }
+
+    /// Give each phy one turn.
+    ///
+    /// After each turn the flush policy of its phy is honored. Stops early if a turn returns
+    /// `Control::Stop`.
+    pub fn poll<F>(&mut self, mut turn: F) -> Control
+        where F: FnMut(Turn<D>) -> Control
+    {
+        let count = self.phys.len();
+        if count == 0 {
+            return Control::Continue;
+        }
+
+        let start = self.round % count;
+        self.round = self.round.wrapping_add(1);
+        for offset in 0..count {
+            let index = (start + offset) % count;
+            let control = turn(Turn {
+                index,
+                budget: self.budgets[index],
+                phys: &mut self.phys,
+            });
+            self.phys[index].poll_flush();
+            if control == Control::Stop {
+                return Control::Stop;
+            }
+        }
+
+        Control::Continue
+    }
+
+    /// Poll in rounds until a turn stops.
+    pub fn run<F>(&mut self, mut turn: F)
+        where F: FnMut(Turn<D>) -> Control
+    {
+        loop {
+            let before = self.packets();
+            if self.poll(&mut turn) == Control::Stop {
+                return;
+            }
+            let after = self.packets();
+            self.idle.poll(after != before);
+        }
+    }
+
+    /// The total packets received and sent by all phys.
+    fn packets(&self) -> u64 {
+        self.phys
+            .iter()
+            .map(|phy| phy.stats.rx_packets + phy.stats.tx_packets)
+            .sum()
+    }
+}
+
+impl<D: Queues> Default for Poller<D> {
+    fn default() -> Self {
+        Poller::new()
+    }
+}
+
+impl<'a, D> Turn<'a, D> {
+    /// The index of the phy whose turn it is.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The maximum number of packets to receive and send in this turn.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// The phy whose turn it is.
+    pub fn phy(&mut self) -> &mut Phy<D> {
+        &mut self.phys[self.index]
+    }
+
+    /// The phy whose turn it is together with another, e.g. to forward between them.
+    ///
+    /// ## Panics
+    /// This function panics if `other` is the index of this turn or out of bounds.
+    pub fn with(&mut self, other: usize) -> (&mut Phy<D>, &mut Phy<D>) {
+        assert_ne!(self.index, other, "Can not pair a phy with itself");
+        if self.index < other {
+            let (low, high) = self.phys.split_at_mut(other);
+            (&mut low[self.index], &mut high[0])
+        } else {
+            let (low, high) = self.phys.split_at_mut(self.index);
+            (&mut high[0], &mut low[other])
+        }
+    }
+
+    /// All phys of the poller.
+    pub fn phys(&mut self) -> &mut [Phy<D>] {
+        &mut self.phys[..]
+    }
+}
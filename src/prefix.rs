@@ -0,0 +1,75 @@
+use std::fmt;
+use std::net::IpAddr;
+
+/// An IP address prefix, such as `10.0.0.0/8`.
+///
+/// The host bits of the address are always zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Prefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Prefix {
+    /// The prefix of the first `len` bits of an address.
+    ///
+    /// ## Panics
+    /// This function panics if `len` exceeds the bits of the address.
+    pub fn new(addr: IpAddr, len: u8) -> Self {
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                assert!(len <= 32, "Prefix longer than the address");
+                IpAddr::V4((u32::from(addr) & mask_v4(len)).into())
+            },
+            IpAddr::V6(addr) => {
+                assert!(len <= 128, "Prefix longer than the address");
+                IpAddr::V6((u128::from(addr) & mask_v6(len)).into())
+            },
+        };
+        Prefix { addr, len }
+    }
+
+    /// The prefix containing only this address.
+    pub fn host(addr: IpAddr) -> Self {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        Prefix { addr, len }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The number of leading bits of the prefix.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Check if an address starts with the prefix.
+    ///
+    /// Addresses of the other IP version are never contained.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(prefix), IpAddr::V4(addr)) => {
+                u32::from(addr) & mask_v4(self.len) == u32::from(prefix)
+            },
+            (IpAddr::V6(prefix), IpAddr::V6(addr)) => {
+                u128::from(addr) & mask_v6(self.len) == u128::from(prefix)
+            },
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+fn mask_v4(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+}
+
+fn mask_v6(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
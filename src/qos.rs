@@ -0,0 +1,276 @@
+//! Scheduling sent packets by traffic class.
+use std::collections::VecDeque;
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ixy::memory::Packet as IxyPacket;
+
+use crate::frame::{Headers, ETHERTYPE_IPV4, ETHERTYPE_IPV6};
+use crate::{Acl, Handle, Marker, Packet, Phy, Queues};
+
+/// Which packet a full class drops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QosDrop {
+    /// The arriving packet.
+    Tail,
+
+    /// The oldest queued packet, keeping the queueing delay of the rest low.
+    Head,
+}
+
+/// A traffic class of a scheduler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct QosClass {
+    /// The strict priority, lower values are always served first.
+    pub priority: u8,
+
+    /// The packets per round among the classes of the same priority.
+    pub weight: u32,
+
+    /// The maximum number of queued packets.
+    pub limit: usize,
+    pub drop: QosDrop,
+}
+
+/// Counters of a traffic class.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct QosStats {
+    pub enqueued: u64,
+
+    /// Packets handed to the phy for sending.
+    pub dequeued: u64,
+    pub dropped: u64,
+}
+
+/// A queueing discipline between the network stack and the send queue of a phy.
+///
+/// Sent packets are sorted into classes by a classifier whose actions are class indices, frames
+/// other than IP such as ARP belong to the first class and IP packets without a matching rule to
+/// the default class. The classes are served by strict priority, classes of the same priority by
+/// weighted round robin. A batch is only handed to the phy once the device accepted the previous
+/// one, so a saturated send ring backs up into the class queues where the priorities apply,
+/// instead of into the phy. A class may have a `Marker`, which sees the occupancy of its queue.
+pub struct Qos<D> {
+    phy: Phy<D>,
+    classifier: Acl<usize>,
+    default: usize,
+    classes: Vec<Class>,
+    /// The class next in the round robin.
+    turn: usize,
+}
+
+impl QosClass {
+    /// A class with a weight of one and a limit of 1024 packets, dropping at the tail.
+    pub fn new(priority: u8) -> Self {
+        QosClass { priority, weight: 1, limit: 1024, drop: QosDrop::Tail }
+    }
+}
+
+struct Class {
+    config: QosClass,
+    queue: VecDeque<IxyPacket>,
+    /// The packets left in the current round.
+    credit: u32,
+    marker: Option<Marker>,
+    stats: QosStats,
+}
+
+impl<D> Qos<D> {
+    /// Schedule the packets of a phy by classes, all IP packets in the default class.
+    ///
+    /// ## Panics
+    /// This function panics if there are no classes, the default class does not exist or a
+    /// weight is zero.
+    pub fn new(phy: Phy<D>, classes: Vec<QosClass>, default: usize) -> Self {
+        assert!(default < classes.len(), "The default class does not exist");
+        assert!(classes.iter().all(|class| class.weight > 0), "Class weights must not be zero");
+        Qos {
+            phy,
+            classifier: Acl::new(Vec::new()),
+            default,
+            classes: classes
+                .into_iter()
+                .map(|config| Class {
+                    config,
+                    queue: VecDeque::new(),
+                    credit: config.weight,
+                    marker: None,
+                    stats: QosStats::default(),
+                })
+                .collect(),
+            turn: 0,
+        }
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    pub fn classifier(&self) -> &Acl<usize> {
+        &self.classifier
+    }
+
+    /// Replace the classifier, its actions are indices of classes.
+    ///
+    /// All packets are classified as interface 0. Packets of a rule with a class that does not
+    /// exist belong to the default class.
+    pub fn set_classifier(&mut self, classifier: Acl<usize>) {
+        self.classifier = classifier;
+    }
+
+    pub fn class(&self, index: usize) -> QosClass {
+        self.classes[index].config
+    }
+
+    /// The number of queued packets of a class.
+    pub fn queued(&self, index: usize) -> usize {
+        self.classes[index].queue.len()
+    }
+
+    pub fn marker(&self, index: usize) -> Option<&Marker> {
+        self.classes[index].marker.as_ref()
+    }
+
+    /// Mark the packets entering a class, as interface 0 of the classifier of the marker.
+    pub fn set_marker(&mut self, index: usize, marker: Option<Marker>) {
+        self.classes[index].marker = marker;
+    }
+
+    pub fn stats(&self, index: usize) -> QosStats {
+        self.classes[index].stats
+    }
+
+    /// Unwrap the phy, dropping all queued packets.
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+
+    fn enqueue(&mut self, mut packet: IxyPacket) {
+        let index = match self.classifier.classify(0, &packet) {
+            Some(&index) if index < self.classes.len() => index,
+            Some(_) => self.default,
+            None if is_ip(&packet) => self.default,
+            None => 0,
+        };
+
+        let class = &mut self.classes[index];
+        if let Some(marker) = &mut class.marker {
+            if !marker.mark(0, &mut packet, class.queue.len()) {
+                class.stats.dropped += 1;
+                return;
+            }
+        }
+        if class.queue.len() >= class.config.limit {
+            class.stats.dropped += 1;
+            match class.config.drop {
+                QosDrop::Tail => return,
+                QosDrop::Head => drop(class.queue.pop_front()),
+            }
+        }
+        class.stats.enqueued += 1;
+        class.queue.push_back(packet);
+    }
+
+    /// The class to serve next.
+    fn next(&mut self) -> Option<usize> {
+        let priority = self.classes
+            .iter()
+            .filter(|class| !class.queue.is_empty())
+            .map(|class| class.config.priority)
+            .min()?;
+        let count = self.classes.len();
+        let eligible = |class: &Class| !class.queue.is_empty() && class.config.priority == priority;
+
+        loop {
+            let found = (0..count)
+                .map(|offset| (self.turn + offset) % count)
+                .find(|&index| eligible(&self.classes[index]) && self.classes[index].credit > 0);
+            if let Some(index) = found {
+                return Some(index);
+            }
+            // A new round for the classes of this priority.
+            for class in self.classes.iter_mut().filter(|class| eligible(class)) {
+                class.credit = class.config.weight;
+            }
+        }
+    }
+
+    fn dequeue(&mut self) -> Option<IxyPacket> {
+        let index = self.next()?;
+        let class = &mut self.classes[index];
+        class.credit -= 1;
+        if class.credit == 0 {
+            self.turn = index + 1;
+        }
+        class.stats.dequeued += 1;
+        class.queue.pop_front()
+    }
+}
+
+impl<D: Queues> Qos<D> {
+    /// Hand queued packets to the phy and flush them while the device accepts them.
+    ///
+    /// Returns the number of packets sent.
+    pub fn flush(&mut self) -> usize {
+        let mut sent = 0;
+        loop {
+            if self.phy.tx_queue.is_empty() {
+                while self.phy.tx_queue.len() < self.phy.batch_size {
+                    match self.dequeue() {
+                        Some(packet) => self.phy.tx_queue.push_back(packet),
+                        None => break,
+                    }
+                }
+            }
+            if self.phy.tx_queue.is_empty() {
+                return sent;
+            }
+
+            sent += self.phy.flush();
+            // The send ring is full, the rest waits in the classes.
+            if !self.phy.tx_queue.is_empty() {
+                return sent;
+            }
+        }
+    }
+
+    /// Run an operation of the network stack, then classify what it queued.
+    fn deferred<T>(&mut self, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, start) = self.phy.deferred(op);
+        for packet in self.phy.tx_queue.split_off(start) {
+            self.enqueue(packet);
+        }
+        self.flush();
+        result
+    }
+}
+
+impl<D: Queues> nic::Device for Qos<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.phy)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.tx(max, sender))
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.rx(max, receptor))
+    }
+}
+
+fn is_ip(frame: &[u8]) -> bool {
+    let ethertype = Headers::parse(frame).map(|headers| headers.ethertype);
+    ethertype == Some(ETHERTYPE_IPV4) || ethertype == Some(ETHERTYPE_IPV6)
+}
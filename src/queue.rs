@@ -0,0 +1,316 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::Duration;
+
+use ixy::IxyDevice;
+use ixy::memory::Packet as IxyPacket;
+
+use super::{Error, FlowControl, FlowRule, Link, MacFilter, Metadata, Offloads, PauseStats, Phy};
+use super::TxOffload;
+use super::offload;
+
+/// The queue operations a `Phy` requires from its device.
+///
+/// Implemented for all ixy devices, and for `Shared` handles to a device whose queues are serviced
+/// by several phys.
+pub trait Queues {
+    /// Receive up to `num_packets` packets from a queue into the buffer.
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize;
+
+    /// Send the packets from the front of the buffer on a queue.
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize;
+
+    /// The MAC address programmed into the device.
+    fn mac_address(&self) -> [u8; 6];
+
+    /// The current state of the physical link.
+    fn link(&self) -> Link;
+
+    /// The offloads enabled on the device.
+    ///
+    /// The default implementation reports no offloads.
+    fn offloads(&self) -> Offloads {
+        Offloads::default()
+    }
+
+    /// Configure the device for frames of a maximum transmission unit.
+    ///
+    /// Only called for MTUs above the standard 1500 bytes. The default implementation does not
+    /// support jumbo frames, as the ixy drivers never enable them.
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        let _ = mtu;
+        Err(Error::Unsupported)
+    }
+
+    /// Configure 802.3x flow control.
+    ///
+    /// The default implementation does not support flow control.
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), Error> {
+        let _ = flow_control;
+        Err(Error::Unsupported)
+    }
+
+    /// The pause frame counters of the device.
+    ///
+    /// The default implementation provides no counters.
+    fn pause_stats(&self) -> Option<PauseStats> {
+        None
+    }
+
+    /// Program the destination address filter of the device.
+    ///
+    /// The default implementation does not support filtering, the phy filters in software.
+    fn set_mac_filter(&mut self, filter: &MacFilter) -> Result<(), Error> {
+        let _ = filter;
+        Err(Error::Unsupported)
+    }
+
+    /// Install a flow steering rule, e.g. in the flow director of the NIC.
+    ///
+    /// The default implementation does not support steering, the phy classifies in software.
+    fn add_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        let _ = rule;
+        Err(Error::Unsupported)
+    }
+
+    /// Remove an installed flow steering rule.
+    fn remove_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        let _ = rule;
+        Err(Error::Unsupported)
+    }
+
+    /// Deliver a packet classified in software on another receive queue.
+    ///
+    /// The default implementation can not reach other queues and returns the packet.
+    fn redirect(&mut self, queue: u32, packet: IxyPacket) -> Result<(), IxyPacket> {
+        let _ = queue;
+        Err(packet)
+    }
+
+    /// The hardware metadata of a packet received on a queue.
+    ///
+    /// The default implementation only reports the queue index.
+    fn rx_metadata(&self, queue: u32, packet: &IxyPacket) -> Metadata {
+        let _ = packet;
+        Metadata {
+            queue,
+            ..Metadata::default()
+        }
+    }
+
+    /// Apply offloads to a packet before it is queued for sending on a queue.
+    ///
+    /// Drivers translate these into tx descriptor flags. The default implementation inserts the
+    /// requested checksums in software.
+    fn tx_offload(&mut self, queue: u32, packet: &mut IxyPacket, offload: TxOffload) {
+        let _ = queue;
+        offload::insert_checksums(packet.as_mut(), offload)
+    }
+
+    /// Take back buffers of packets whose transmission completed on a queue, up to `max`.
+    ///
+    /// Lets the phy reuse these exact buffers for sending instead of allocating from the mempool.
+    /// The default implementation reclaims none, the ixy drivers free completed buffers to their
+    /// pool when cleaning the tx ring.
+    fn reclaim_tx(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, max: usize) -> usize {
+        let _ = (queue, buffer, max);
+        0
+    }
+
+    /// The number of packets handed to the device on a queue whose transmission did not complete.
+    ///
+    /// The default implementation does not know, the ixy drivers do not expose their tx ring.
+    fn tx_in_flight(&mut self, queue: u32) -> Option<usize> {
+        let _ = queue;
+        None
+    }
+
+    /// Arm the receive interrupt of a queue and block until it fires or the timeout expires.
+    ///
+    /// The default implementation does not support interrupts, the ixy drivers of this fork only
+    /// poll.
+    fn wait_rx(&mut self, queue: u32, timeout: Duration) -> Result<(), Error> {
+        let _ = (queue, timeout);
+        Err(Error::Unsupported)
+    }
+}
+
+/// A device shared between the phys of its queues.
+///
+/// The phys take turns borrowing the device, on the single thread that owns all of them. See
+/// `PhyQueue::split`.
+pub struct Shared<D> {
+    device: Rc<RefCell<D>>,
+
+    /// Packets steered to each queue in software.
+    redirected: Rc<RefCell<Vec<VecDeque<IxyPacket>>>>,
+}
+
+/// A phy servicing one rx/tx queue pair of a shared device.
+///
+/// Each instance implements `nic::Device` independently, so a separate network stack can be
+/// driven on each queue.
+///
+/// The queue handles are not `Send` and all of them must be polled from the thread that split
+/// the device. This is not a limitation of `Shared` that a lock would lift: ixy devices, their
+/// mempools and every packet reference the pools through an `Rc`, so no part of a device can
+/// leave its thread. Running queues on several cores requires one device per thread, see
+/// `Detached` to initialize a device on the thread that uses it.
+pub type PhyQueue<D> = Phy<Shared<D>>;
+
+impl<D: IxyDevice + ?Sized> Queues for D {
+    fn mac_address(&self) -> [u8; 6] {
+        IxyDevice::get_mac_addr(self)
+    }
+
+    fn link(&self) -> Link {
+        Link::from_speed(IxyDevice::get_link_speed(self))
+    }
+
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        IxyDevice::rx_batch(self, queue, buffer, num_packets)
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        IxyDevice::tx_batch(self, queue, buffer)
+    }
+}
+
+impl<D: IxyDevice> Queues for Shared<D> {
+    fn mac_address(&self) -> [u8; 6] {
+        self.device.borrow().get_mac_addr()
+    }
+
+    fn link(&self) -> Link {
+        Link::from_speed(self.device.borrow().get_link_speed())
+    }
+
+    fn offloads(&self) -> Offloads {
+        Queues::offloads(&*self.device.borrow())
+    }
+
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        // Packets steered from other queues come first, they were received earlier.
+        let mut redirected = self.redirected.borrow_mut();
+        let mailbox = &mut redirected[queue as usize];
+        let steered = mailbox.len().min(num_packets);
+        buffer.extend(mailbox.drain(..steered));
+        drop(redirected);
+
+        steered + self.device.borrow_mut().rx_batch(queue, buffer, num_packets - steered)
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        self.device.borrow_mut().tx_batch(queue, buffer)
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        Queues::set_mtu(&mut *self.device.borrow_mut(), mtu)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), Error> {
+        Queues::set_flow_control(&mut *self.device.borrow_mut(), flow_control)
+    }
+
+    fn pause_stats(&self) -> Option<PauseStats> {
+        Queues::pause_stats(&*self.device.borrow())
+    }
+
+    fn set_mac_filter(&mut self, filter: &MacFilter) -> Result<(), Error> {
+        Queues::set_mac_filter(&mut *self.device.borrow_mut(), filter)
+    }
+
+    fn add_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        Queues::add_flow_rule(&mut *self.device.borrow_mut(), rule)
+    }
+
+    fn remove_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        Queues::remove_flow_rule(&mut *self.device.borrow_mut(), rule)
+    }
+
+    fn redirect(&mut self, queue: u32, packet: IxyPacket) -> Result<(), IxyPacket> {
+        match self.redirected.borrow_mut().get_mut(queue as usize) {
+            Some(mailbox) => Ok(mailbox.push_back(packet)),
+            None => Err(packet),
+        }
+    }
+
+    fn rx_metadata(&self, queue: u32, packet: &IxyPacket) -> Metadata {
+        Queues::rx_metadata(&*self.device.borrow(), queue, packet)
+    }
+
+    fn tx_offload(&mut self, queue: u32, packet: &mut IxyPacket, offload: TxOffload) {
+        Queues::tx_offload(&mut *self.device.borrow_mut(), queue, packet, offload)
+    }
+
+    fn reclaim_tx(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, max: usize) -> usize {
+        Queues::reclaim_tx(&mut *self.device.borrow_mut(), queue, buffer, max)
+    }
+
+    fn tx_in_flight(&mut self, queue: u32) -> Option<usize> {
+        Queues::tx_in_flight(&mut *self.device.borrow_mut(), queue)
+    }
+
+    fn wait_rx(&mut self, queue: u32, timeout: Duration) -> Result<(), Error> {
+        Queues::wait_rx(&mut *self.device.borrow_mut(), queue, timeout)
+    }
+}
+
+impl<D> Shared<D> {
+    /// Inspect the underlying device.
+    ///
+    /// ## Panics
+    /// This function panics if another queue is currently borrowing the device mutably.
+    pub fn device(&self) -> Ref<D> {
+        self.device.borrow()
+    }
+
+    /// Mutably access the underlying device.
+    ///
+    /// ## Panics
+    /// This function panics if the device is currently borrowed.
+    pub fn device_mut(&self) -> RefMut<D> {
+        self.device.borrow_mut()
+    }
+}
+
+impl<D: IxyDevice> Phy<Shared<D>> {
+    /// Split a device into one phy for each of its first `queues` rx/tx queue pairs.
+    ///
+    /// Each phy allocates packets for sending from the receive pool of its queue. The phys stay
+    /// on the calling thread, see `PhyQueue`.
+    ///
+    /// ## Panics
+    /// This function panics if the device has no receive pool for one of the queues, i.e. it was
+    /// initialized with fewer queues.
+    pub fn split(device: D, queues: u32) -> Vec<Self> {
+        let pools = (0..queues)
+            .map(|queue| device
+                .recv_pool(queue)
+                .expect("No receive pool for the queue")
+                .clone())
+            .collect::<Vec<_>>();
+        let device = Rc::new(RefCell::new(device));
+        let redirected = (0..queues).map(|_| VecDeque::new()).collect();
+        let redirected = Rc::new(RefCell::new(redirected));
+
+        pools.into_iter()
+            .zip(0..queues)
+            .map(|(pool, queue)| {
+                let shared = Shared {
+                    device: device.clone(),
+                    redirected: Rc::clone(&redirected),
+                };
+                let mut phy = Phy::new(shared, pool);
+                phy.queue = queue;
+                phy
+            })
+            .collect()
+    }
+}
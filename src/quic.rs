@@ -0,0 +1,149 @@
+//! QUIC endpoints on the stack thread of a bridge, enabled by the `quic` feature.
+//!
+//! Implements the UDP abstraction of quinn on a UDP socket of a `Bridge`, so that a quinn
+//! `Endpoint` runs over a phy while the connections are used from any tokio runtime. The segments
+//! of a GSO-style transmit travel to the stack thread together and are queued on the socket at
+//! once, so they reach the tx queue of the phy in a single batch.
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use quinn::{AsyncUdpSocket, Endpoint, EndpointConfig, ServerConfig, TokioRuntime, UdpPoller};
+use quinn::udp::{RecvMeta, Transmit};
+use smoltcp::wire::IpEndpoint;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::sync::PollSender;
+
+use crate::bridge::{self, Datagrams, stopped};
+use crate::net::{endpoint, socket_addr};
+
+/// A bridged UDP socket usable by quinn.
+pub struct QuicSocket {
+    local: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<(Vec<u8>, IpEndpoint)>>,
+    outgoing: mpsc::Sender<Datagrams>,
+}
+
+/// Waits for room in the channel to the stack thread.
+struct Writable(PollSender<Datagrams>);
+
+impl QuicSocket {
+    /// The maximum number of datagrams quinn sends in one transmit.
+    pub const SEGMENTS: usize = 16;
+
+    /// Use a bridged socket, it must be bound to an IPv4 address.
+    pub fn new(socket: bridge::UdpSocket) -> io::Result<Self> {
+        let local = socket_addr(socket.local_endpoint())?;
+        Ok(QuicSocket {
+            local,
+            incoming: Mutex::new(socket.incoming),
+            outgoing: socket.outgoing,
+        })
+    }
+}
+
+/// Create a quinn endpoint on a bridged socket, a server if a configuration is given.
+pub fn endpoint_on(
+    socket: bridge::UdpSocket,
+    config: EndpointConfig,
+    server: Option<ServerConfig>,
+) -> io::Result<Endpoint> {
+    let socket = Arc::new(QuicSocket::new(socket)?);
+    Endpoint::new_with_abstract_socket(config, server, socket, Arc::new(TokioRuntime))
+}
+
+impl AsyncUdpSocket for QuicSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(Writable(PollSender::new(self.outgoing.clone())))
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let datagrams = Datagrams {
+            remote: endpoint(transmit.destination)?,
+            payload: transmit.contents.to_vec(),
+            segment: transmit.segment_size.unwrap_or(transmit.contents.len()),
+        };
+
+        self.outgoing.try_send(datagrams).map_err(|err| match err {
+            TrySendError::Full(_) => io::ErrorKind::WouldBlock.into(),
+            TrySendError::Closed(_) => stopped(),
+        })
+    }
+
+    fn poll_recv(&self, cx: &mut Context, bufs: &mut [IoSliceMut<'_>], meta: &mut [RecvMeta])
+        -> Poll<io::Result<usize>>
+    {
+        let mut incoming = self.incoming.lock().unwrap();
+        let mut received = match incoming.poll_recv(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(None) => return Poll::Ready(Err(stopped())),
+            Poll::Ready(Some(datagram)) => Some(datagram),
+        };
+
+        let mut count = 0;
+        while let Some((payload, remote)) = received {
+            let buf = &mut bufs[count];
+            let len = payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&payload[..len]);
+
+            let mut entry = RecvMeta::default();
+            entry.addr = socket_addr(remote)?;
+            entry.len = len;
+            entry.stride = len;
+            meta[count] = entry;
+            count += 1;
+
+            if count == bufs.len().min(meta.len()) {
+                break;
+            }
+            received = incoming.try_recv().ok();
+        }
+
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        Self::SEGMENTS
+    }
+
+    /// The stack does not fragment datagrams.
+    fn may_fragment(&self) -> bool {
+        false
+    }
+}
+
+impl UdpPoller for Writable {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let sender = &mut self.get_mut().0;
+        match sender.poll_reserve(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(_)) => Poll::Ready(Err(stopped())),
+            Poll::Ready(Ok(())) => {
+                // Only report the room, `try_send` uses it.
+                sender.abort_send();
+                Poll::Ready(Ok(()))
+            },
+        }
+    }
+}
+
+impl fmt::Debug for QuicSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QuicSocket")
+            .field("local", &self.local)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Writable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Writable").finish()
+    }
+}
@@ -0,0 +1,283 @@
+//! Policing the traffic between a device and its phys with token buckets.
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use ixy::memory::Packet as IxyPacket;
+
+use crate::{Error, FlowControl, FlowRule, Link, MacFilter, Metadata, Offloads, PauseStats};
+use crate::{Queues, TxOffload};
+
+/// The rate and burst size of a token bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rate {
+    /// Packets per second, with a burst of packets.
+    Packets { per_second: u64, burst: u64 },
+
+    /// Bits per second of whole frames, with a burst of bytes.
+    Bits { per_second: u64, burst: u64 },
+}
+
+/// Counters of a policed direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RateLimitStats {
+    pub passed: u64,
+    pub dropped: u64,
+
+    /// The bytes of the dropped frames.
+    pub dropped_bytes: u64,
+}
+
+/// A token bucket policer wrapping the queues of a device.
+///
+/// Wrap the device before creating a `Phy` to limit its received and sent traffic, e.g.
+/// `Phy::builder(RateLimit::new(device, None, Some(rate))).build()`. Each direction has one
+/// bucket for all queues, filled at its rate up to the burst size. A packet passes if the bucket
+/// has enough tokens for it, otherwise it is dropped, so the traffic is policed rather than
+/// shaped. Received packets are dropped before the phy sees them, sent packets when the phy
+/// flushes them.
+pub struct RateLimit<Q> {
+    inner: Q,
+    rx: Policer,
+    tx: Policer,
+}
+
+#[derive(Default)]
+struct Policer {
+    bucket: Option<Bucket>,
+    stats: RateLimitStats,
+}
+
+/// A token bucket of a rate.
+pub(crate) struct Bucket {
+    rate: Rate,
+    /// The tokens in units of a billionth packet or bit.
+    tokens: u128,
+    filled: Instant,
+}
+
+const NANOS: u128 = 1_000_000_000;
+
+impl<Q> RateLimit<Q> {
+    /// Wrap the queues with the rates of each direction, `None` passes everything.
+    pub fn new(inner: Q, rx: Option<Rate>, tx: Option<Rate>) -> Self {
+        RateLimit {
+            inner,
+            rx: Policer::new(rx),
+            tx: Policer::new(tx),
+        }
+    }
+
+    pub fn rx_rate(&self) -> Option<Rate> {
+        self.rx.bucket.as_ref().map(|bucket| bucket.rate)
+    }
+
+    /// Change the rate of received packets, starting with a full bucket.
+    pub fn set_rx_rate(&mut self, rate: Option<Rate>) {
+        self.rx.bucket = rate.map(Bucket::new);
+    }
+
+    pub fn tx_rate(&self) -> Option<Rate> {
+        self.tx.bucket.as_ref().map(|bucket| bucket.rate)
+    }
+
+    /// Change the rate of sent packets, starting with a full bucket.
+    pub fn set_tx_rate(&mut self, rate: Option<Rate>) {
+        self.tx.bucket = rate.map(Bucket::new);
+    }
+
+    pub fn rx_stats(&self) -> RateLimitStats {
+        self.rx.stats
+    }
+
+    pub fn tx_stats(&self) -> RateLimitStats {
+        self.tx.stats
+    }
+
+    pub fn inner(&self) -> &Q {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut Q {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.inner
+    }
+}
+
+impl Policer {
+    fn new(rate: Option<Rate>) -> Self {
+        Policer {
+            bucket: rate.map(Bucket::new),
+            stats: RateLimitStats::default(),
+        }
+    }
+
+    /// Keep the packets the bucket has tokens for, appending them to `out`.
+    fn police(&mut self, packets: VecDeque<IxyPacket>, out: &mut VecDeque<IxyPacket>) {
+        let now = Instant::now();
+        if let Some(bucket) = &mut self.bucket {
+            bucket.fill(now);
+        }
+
+        for packet in packets {
+            let conforms = self.bucket.as_mut().map_or(true, |bucket| bucket.take(packet.len()));
+            if conforms {
+                self.stats.passed += 1;
+                out.push_back(packet);
+            } else {
+                self.stats.dropped += 1;
+                self.stats.dropped_bytes += packet.len() as u64;
+            }
+        }
+    }
+
+    /// Return the tokens of packets which passed but were not accepted by the device.
+    fn refund(&mut self, packets: &VecDeque<IxyPacket>) {
+        self.stats.passed -= packets.len() as u64;
+        if let Some(bucket) = &mut self.bucket {
+            packets.iter().for_each(|packet| bucket.refund(packet.len()));
+        }
+    }
+}
+
+impl Bucket {
+    /// A full bucket.
+    pub(crate) fn new(rate: Rate) -> Self {
+        let mut bucket = Bucket { rate, tokens: 0, filled: Instant::now() };
+        bucket.tokens = bucket.capacity();
+        bucket
+    }
+
+    pub(crate) fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    fn capacity(&self) -> u128 {
+        match self.rate {
+            Rate::Packets { burst, .. } => u128::from(burst) * NANOS,
+            Rate::Bits { burst, .. } => u128::from(burst) * 8 * NANOS,
+        }
+    }
+
+    fn cost(&self, len: usize) -> u128 {
+        match self.rate {
+            Rate::Packets { .. } => NANOS,
+            Rate::Bits { .. } => len as u128 * 8 * NANOS,
+        }
+    }
+
+    pub(crate) fn fill(&mut self, now: Instant) {
+        let per_second = match self.rate {
+            Rate::Packets { per_second, .. } | Rate::Bits { per_second, .. } => per_second,
+        };
+        let elapsed = now.saturating_duration_since(self.filled);
+        // The tokens of a second are the rate times a billion, so a nanosecond adds the rate.
+        let added = elapsed.as_nanos() * u128::from(per_second);
+        self.tokens = (self.tokens + added).min(self.capacity());
+        self.filled = now;
+    }
+
+    /// Take the tokens of a frame if there are enough.
+    pub(crate) fn take(&mut self, len: usize) -> bool {
+        let cost = self.cost(len);
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+
+    fn refund(&mut self, len: usize) {
+        self.tokens = (self.tokens + self.cost(len)).min(self.capacity());
+    }
+}
+
+impl<Q: Queues> Queues for RateLimit<Q> {
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut received = VecDeque::with_capacity(num_packets);
+        self.inner.rx_batch(queue, &mut received, num_packets);
+
+        let before = buffer.len();
+        self.rx.police(received, buffer);
+        buffer.len() - before
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let taken = buffer.len();
+        let mut passed = VecDeque::with_capacity(taken);
+        self.tx.police(buffer.drain(..).collect(), &mut passed);
+        self.inner.tx_batch(queue, &mut passed);
+
+        // Packets the device did not accept are offered again with the next flush.
+        self.tx.refund(&passed);
+        let rejected = passed.len();
+        for packet in passed.into_iter().rev() {
+            buffer.push_front(packet);
+        }
+        taken - rejected
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.inner.mac_address()
+    }
+
+    fn link(&self) -> Link {
+        self.inner.link()
+    }
+
+    fn offloads(&self) -> Offloads {
+        self.inner.offloads()
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Result<(), Error> {
+        self.inner.set_mtu(mtu)
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> Result<(), Error> {
+        self.inner.set_flow_control(flow_control)
+    }
+
+    fn pause_stats(&self) -> Option<PauseStats> {
+        self.inner.pause_stats()
+    }
+
+    fn set_mac_filter(&mut self, filter: &MacFilter) -> Result<(), Error> {
+        self.inner.set_mac_filter(filter)
+    }
+
+    fn add_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        self.inner.add_flow_rule(rule)
+    }
+
+    fn remove_flow_rule(&mut self, rule: &FlowRule) -> Result<(), Error> {
+        self.inner.remove_flow_rule(rule)
+    }
+
+    fn redirect(&mut self, queue: u32, packet: IxyPacket) -> Result<(), IxyPacket> {
+        self.inner.redirect(queue, packet)
+    }
+
+    fn rx_metadata(&self, queue: u32, packet: &IxyPacket) -> Metadata {
+        self.inner.rx_metadata(queue, packet)
+    }
+
+    fn tx_offload(&mut self, queue: u32, packet: &mut IxyPacket, offload: TxOffload) {
+        self.inner.tx_offload(queue, packet, offload)
+    }
+
+    fn reclaim_tx(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, max: usize) -> usize {
+        self.inner.reclaim_tx(queue, buffer, max)
+    }
+
+    fn tx_in_flight(&mut self, queue: u32) -> Option<usize> {
+        self.inner.tx_in_flight(queue)
+    }
+
+    fn wait_rx(&mut self, queue: u32, timeout: Duration) -> Result<(), Error> {
+        self.inner.wait_rx(queue, timeout)
+    }
+}
@@ -0,0 +1,170 @@
+//! A busy polling event loop for a phy.
+use std::time::Duration;
+
+use ethox::time::Instant;
+
+use crate::{Idler, Phy, Queues, TimerWheel};
+use crate::wheel::later;
+
+/// The decision of a callback of the event loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Control {
+    /// Keep running.
+    Continue,
+
+    /// Return from `Reactor::run`.
+    Stop,
+}
+
+/// Repeatedly polls a phy, services timers and flushes.
+///
+/// Each iteration calls the poll callback with the phy and the poll budget, the maximum number of
+/// packets the callback should receive and send, e.g. as the `max` of `rx` and `tx` through the
+/// layers of the network stack. Afterwards the flush policy of the phy is honored, even when the
+/// callback did not touch the phy, and due timers of a `TimerWheel` are run. Iterations in which
+/// the phy neither received nor sent packets count as idle for the idle strategy.
+pub struct Reactor<'a, D> {
+    budget: usize,
+    idle: Idler,
+    timers: TimerWheel<Timer<'a, D>>,
+    /// Repeating timers which ran in the current iteration.
+    repeat: Vec<Timer<'a, D>>,
+}
+
+struct Timer<'a, D> {
+    due: Instant,
+    /// The period of repeating timers.
+    interval: Option<Duration>,
+    callback: Box<dyn FnMut(&mut Phy<D>) -> Control + 'a>,
+}
+
+impl<'a, D: Queues> Reactor<'a, D> {
+    /// The default poll budget.
+    pub const BUDGET: usize = 32;
+
+    pub fn new() -> Self {
+        Reactor {
+            budget: Self::BUDGET,
+            idle: Idler::default(),
+            timers: TimerWheel::new(Instant::now()),
+            repeat: Vec::new(),
+        }
+    }
+
+    /// Set the maximum number of packets per poll.
+    pub fn budget(mut self, budget: usize) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// Set what to do while there is no traffic, by default spin.
+    pub fn idle(mut self, idle: Idler) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// Run a callback periodically, first after one interval.
+    pub fn every<F>(&mut self, interval: Duration, callback: F)
+        where F: FnMut(&mut Phy<D>) -> Control + 'a
+    {
+        self.schedule(Timer {
+            due: later(Instant::now(), interval),
+            interval: Some(interval),
+            callback: Box::new(callback),
+        });
+    }
+
+    /// Run a callback once after a delay.
+    pub fn after<F>(&mut self, delay: Duration, callback: F)
+        where F: FnOnce(&mut Phy<D>) -> Control + 'a
+    {
+        let mut callback = Some(callback);
+        self.schedule(Timer {
+            due: later(Instant::now(), delay),
+            interval: None,
+            callback: Box::new(move |phy| match callback.take() {
+                Some(callback) => callback(phy),
+                None => Control::Continue,
+            }),
+        });
+    }
+
+    /// Stop the loop after some time.
+    pub fn deadline(&mut self, after: Duration) {
+        self.after(after, |_| Control::Stop)
+    }
+
+    /// Drive the phy until a callback stops the loop.
+    pub fn run<F>(&mut self, phy: &mut Phy<D>, mut poll: F)
+        where F: FnMut(&mut Phy<D>, usize) -> Control
+    {
+        loop {
+            let before = phy.stats.rx_packets + phy.stats.tx_packets;
+            if poll(phy, self.budget) == Control::Stop {
+                return;
+            }
+
+            phy.poll_flush();
+
+            if self.run_timers(phy) == Control::Stop {
+                return;
+            }
+
+            let after = phy.stats.rx_packets + phy.stats.tx_packets;
+            self.idle.poll(after != before);
+        }
+    }
+
+    fn run_timers(&mut self, phy: &mut Phy<D>) -> Control {
+        if self.timers.is_empty() {
+            return Control::Continue;
+        }
+
+        let now = Instant::now();
+        self.timers.advance(now);
+        let mut control = Control::Continue;
+        while let Some(mut timer) = self.timers.pop() {
+            if (timer.callback)(phy) == Control::Stop {
+                control = Control::Stop;
+            }
+
+            if let Some(interval) = timer.interval {
+                timer.due = later(timer.due, interval);
+                // Skip missed periods instead of running the callback repeatedly.
+                if timer.due <= now {
+                    timer.due = later(now, interval);
+                }
+                self.repeat.push(timer);
+            }
+        }
+
+        // Reschedule only now, a timer is due again at once if its interval is shorter than a
+        // millisecond.
+        while let Some(timer) = self.repeat.pop() {
+            self.schedule(timer);
+        }
+        control
+    }
+
+    fn schedule(&mut self, timer: Timer<'a, D>) {
+        let due = timer.due;
+        self.timers.insert(due, timer);
+    }
+}
+
+impl<'a, D: Queues> Default for Reactor<'a, D> {
+    fn default() -> Self {
+        Reactor::new()
+    }
+}
+
+/// Drive a phy with the default budget and without timers until the callback stops.
+///
+/// See `Reactor::run`.
+pub fn run<D, F>(phy: &mut Phy<D>, poll: F)
+where
+    D: Queues,
+    F: FnMut(&mut Phy<D>, usize) -> Control,
+{
+    Reactor::new().run(phy, poll)
+}
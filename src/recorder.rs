@@ -0,0 +1,127 @@
+//! Keeping the most recent frames for post-mortem debugging.
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::pcap::{Direction, Dump, Pcap};
+
+/// A dump keeping the last frames in memory.
+///
+/// Only the first `snaplen` bytes of each frame are kept, usually enough for the headers. The
+/// frames are written out as pcap on request with `save`, or automatically when the recorder is
+/// dropped during a panic, e.g. while the stack of a `Capture` using it unwinds. The latter does
+/// not happen if the binary aborts on panic.
+pub struct FlightRecorder {
+    frames: VecDeque<Record>,
+    capacity: usize,
+    snaplen: usize,
+    panic_path: Option<PathBuf>,
+}
+
+struct Record {
+    direction: Direction,
+    timestamp: Duration,
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl FlightRecorder {
+    /// The default number of bytes kept per frame.
+    pub const SNAPLEN: usize = 128;
+
+    /// Keep the last `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_snaplen(capacity, Self::SNAPLEN)
+    }
+
+    /// Keep the first `snaplen` bytes of the last `capacity` frames.
+    pub fn with_snaplen(capacity: usize, snaplen: usize) -> Self {
+        FlightRecorder {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            snaplen,
+            panic_path: None,
+        }
+    }
+
+    /// Save the frames to a file when dropped during a panic.
+    pub fn save_on_panic<P: Into<PathBuf>>(&mut self, path: P) {
+        self.panic_path = Some(path.into());
+    }
+
+    /// The number of frames kept.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Forget all kept frames.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Write the frames as pcap, oldest first.
+    pub fn write_pcap<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut pcap = Pcap::with_snaplen(writer, self.snaplen as u32)?;
+        for record in &self.frames {
+            pcap.record(record.timestamp, &record.bytes, record.len)?;
+        }
+        pcap.into_inner().flush()
+    }
+
+    /// Write the frames to a pcap file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_pcap(BufWriter::new(file))
+    }
+
+    /// Iterate over the direction and bytes of the kept frames, oldest first.
+    pub fn frames(&self) -> impl Iterator<Item=(Direction, &[u8])> {
+        self.frames.iter().map(|record| (record.direction, &record.bytes[..]))
+    }
+}
+
+impl Dump for FlightRecorder {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, frame: &[u8]) -> io::Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+
+        // Reuse the buffer of the oldest frame once full.
+        let mut bytes = if self.frames.len() >= self.capacity {
+            self.frames.pop_front().unwrap().bytes
+        } else {
+            Vec::with_capacity(self.snaplen)
+        };
+
+        bytes.clear();
+        bytes.extend_from_slice(&frame[..frame.len().min(self.snaplen)]);
+        self.frames.push_back(Record {
+            direction,
+            timestamp,
+            len: frame.len(),
+            bytes,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for FlightRecorder {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+
+        if let Some(path) = &self.panic_path {
+            match self.save(path) {
+                Ok(()) => eprintln!("Saved last {} frames to {}", self.frames.len(), path.display()),
+                Err(err) => eprintln!("Failed to save frames to {}: {}", path.display(), err),
+            }
+        }
+    }
+}
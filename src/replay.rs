@@ -0,0 +1,216 @@
+//! Transmitting captured traffic through a device.
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use ethox::nic::{self, Device};
+use ethox::wire::PayloadMut;
+
+/// The timing of a replay.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pace {
+    /// Keep the inter-packet gaps of the capture.
+    Original,
+
+    /// A fixed rate in packets per second.
+    Pps(f64),
+
+    /// A fixed rate in bits per second, counting the frame bytes only.
+    Bps(f64),
+
+    /// As fast as the device accepts the frames.
+    Unlimited,
+}
+
+/// The result of a replay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ReplayStats {
+    /// The number of frames queued on the device.
+    pub packets: u64,
+
+    /// The number of bytes queued on the device.
+    pub bytes: u64,
+
+    /// Frames skipped since they did not fit into a buffer of the device.
+    pub skipped: u64,
+
+    /// The time from the first to the last frame.
+    pub elapsed: Duration,
+}
+
+/// A capture loaded into memory for replaying onto the wire.
+///
+/// Unlike a capture device for tests, the frames are sent through a real device paced to a
+/// target rate, e.g. to reproduce production traffic patterns in the lab. The pacing busy waits
+/// on the calling thread.
+pub struct Replay {
+    frames: Vec<(Duration, Vec<u8>)>,
+}
+
+/// Copies the due frames into the buffers of the device.
+struct Emit<'a> {
+    frames: &'a [(Duration, Vec<u8>)],
+    next: usize,
+    end: usize,
+    stats: &'a mut ReplayStats,
+}
+
+impl Replay {
+    /// Load a pcap file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Load a pcap capture with micro- or nanosecond timestamps, in either byte order.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic = u32_le(&header[0..4]);
+        let (swapped, nanos) = match magic {
+            0xa1b2_c3d4 => (false, false),
+            0xa1b2_3c4d => (false, true),
+            0xd4c3_b2a1 => (true, false),
+            0x4d3c_b2a1 => (true, true),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a pcap file")),
+        };
+        let read = |bytes: &[u8]| if swapped { u32_le(bytes).swap_bytes() } else { u32_le(bytes) };
+
+        if read(&header[20..24]) != 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an Ethernet capture"));
+        }
+
+        let mut frames = Vec::new();
+        let mut record = [0; 16];
+        loop {
+            match reader.read_exact(&mut record) {
+                Ok(()) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+
+            let secs = u64::from(read(&record[0..4]));
+            let fraction = read(&record[4..8]);
+            let timestamp = if nanos {
+                Duration::new(secs, fraction)
+            } else {
+                Duration::new(secs, 0) + Duration::from_micros(u64::from(fraction))
+            };
+
+            let mut frame = vec![0; read(&record[8..12]) as usize];
+            reader.read_exact(&mut frame)?;
+            frames.push((timestamp, frame));
+        }
+
+        Ok(Replay { frames })
+    }
+
+    /// The number of frames in the capture.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Send all frames once.
+    ///
+    /// Frames are queued through `tx`, make sure the device flushes them, e.g. a `Phy` with the
+    /// default flush policy.
+    pub fn run<D>(&self, device: &mut D, pace: Pace) -> ReplayStats
+    where
+        D: Device,
+        D::Payload: PayloadMut,
+    {
+        const BATCH: usize = 32;
+
+        let offsets = self.offsets(pace);
+        let mut stats = ReplayStats::default();
+        let mut next = 0;
+        let start = Instant::now();
+
+        while next < self.frames.len() {
+            let now = start.elapsed();
+            let due = offsets[next..]
+                .iter()
+                .take(BATCH)
+                .take_while(|&&offset| offset <= now)
+                .count();
+
+            if due == 0 {
+                continue;
+            }
+
+            let mut emit = Emit {
+                frames: &self.frames,
+                next,
+                end: next + due,
+                stats: &mut stats,
+            };
+            // An error means the device has no buffers right now, retry.
+            let _ = device.tx(due, &mut emit);
+            next = emit.next;
+        }
+
+        stats.elapsed = start.elapsed();
+        stats
+    }
+
+    /// The send time of each frame relative to the first.
+    fn offsets(&self, pace: Pace) -> Vec<Duration> {
+        let first = self.frames.first().map(|(timestamp, _)| *timestamp).unwrap_or_default();
+        let mut bits = 0u64;
+        self.frames
+            .iter()
+            .enumerate()
+            .map(|(index, (timestamp, frame))| match pace {
+                Pace::Original => timestamp.checked_sub(first).unwrap_or_default(),
+                Pace::Pps(rate) => Duration::from_secs_f64(index as f64 / rate),
+                Pace::Bps(rate) => {
+                    let offset = Duration::from_secs_f64(bits as f64 / rate);
+                    bits += frame.len() as u64 * 8;
+                    offset
+                },
+                Pace::Unlimited => Duration::from_secs(0),
+            })
+            .collect()
+    }
+}
+
+impl<H, P> nic::Send<H, P> for &'_ mut Emit<'_>
+where
+    H: nic::Handle + ?Sized,
+    P: PayloadMut + ?Sized,
+{
+    fn send(&mut self, packet: nic::Packet<H, P>) {
+        // Skip frames which do not fit until one does.
+        while self.next < self.end {
+            let frame = &self.frames[self.next].1;
+            self.next += 1;
+
+            if packet.payload.resize(frame.len()).is_err() {
+                self.stats.skipped += 1;
+                continue;
+            }
+
+            packet.payload.payload_mut().as_mut_slice().copy_from_slice(frame);
+            match packet.handle.queue() {
+                Ok(()) => {
+                    self.stats.packets += 1;
+                    self.stats.bytes += frame.len() as u64;
+                },
+                // Retry the frame with the next buffer.
+                Err(_) => self.next -= 1,
+            }
+            return;
+        }
+    }
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    let mut raw = [0; 4];
+    raw.copy_from_slice(bytes);
+    u32::from_le_bytes(raw)
+}
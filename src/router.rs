@@ -0,0 +1,709 @@
+//! A software IP router between phys.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::{self, read_u16, write_u16};
+use crate::frame::{self, Arp, Headers, IcmpError, ARP_REQUEST, ETHERNET_HEADER};
+use crate::frame::{ETHERTYPE_ARP, ETHERTYPE_IPV4, ETHERTYPE_IPV6, PROTO_ICMP, PROTO_ICMPV6};
+use crate::frame::{ipv4, ipv6};
+use crate::{Ecmp, Lpm, Phy, Prefix, Queues, ipv6_multicast};
+
+/// A route to the addresses of a prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Route {
+    pub prefix: Prefix,
+
+    /// The gateway, or `None` if the destinations are attached to the port.
+    pub next_hop: Option<IpAddr>,
+
+    /// The index of the output port.
+    pub port: usize,
+}
+
+/// Counters of a router.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct RouterStats {
+    /// Packets sent to their next hop.
+    pub forwarded: u64,
+
+    /// Packets to an address of the router, echo requests are answered and others dropped.
+    pub local: u64,
+
+    /// Packets without a route, answered with destination unreachable.
+    pub no_route: u64,
+
+    /// Packets whose TTL or hop limit ran out, answered with time exceeded.
+    pub ttl_exceeded: u64,
+
+    /// Packets larger than the MTU of their output port, answered with packet too big unless
+    /// IPv4 fragmentation was allowed.
+    pub too_big: u64,
+
+    /// Packets dropped while the address of their next hop is resolved.
+    pub unresolved: u64,
+
+    /// ARP requests answered for an address behind another port.
+    pub proxied: u64,
+
+    /// Malformed or tagged frames, and IP packets to other hosts, multicast or broadcast.
+    pub dropped: u64,
+}
+
+/// Forwards IP packets between any number of phys by the longest matching route.
+///
+/// Each port has its own addresses, which add a route to their attached network. A prefix may
+/// have several weighted routes, over which its flows are spread by an `Ecmp` selector. Flows
+/// of a route whose port has no link fail over to the others, the link of each port is checked
+/// once a second. Next hops are
+/// resolved with ARP and neighbor discovery, requests and solicitations for the addresses of a
+/// port are answered. Packets are moved to the send queue of their output port without copying,
+/// with the TTL or hop limit decremented and the Ethernet addresses replaced. Packets to the
+/// router itself are only answered if they are echo requests.
+///
+/// A port can also answer ARP requests for the addresses of proxied prefixes which are routed out
+/// of another port, with its own MAC address, as proxy ARP. The traffic to these addresses then
+/// arrives at the router and is forwarded as usual. This inserts the router into a network
+/// without renumbering it, e.g. with a host route to the moved hosts on one port, proxied on the
+/// other port, and the route to the rest of the network the other way around.
+///
+/// Packets which can't be forwarded are turned into an ICMP error from the address of their
+/// input port, there is no rate limit. Packets to a next hop which is not yet resolved are
+/// dropped while a request is sent, at most once a second. IPv6 ports must also receive the
+/// solicited-node multicast groups of their addresses, see `MacFilter`.
+pub struct Router<D> {
+    ports: Vec<Port<D>>,
+    routes: Lpm<Paths>,
+    /// When the links of the ports were last checked.
+    checked: Instant,
+    neighbors: HashMap<IpAddr, Neighbor>,
+    solicited: HashMap<IpAddr, Instant>,
+    reachable: Duration,
+    stats: RouterStats,
+}
+
+struct Port<D> {
+    phy: Phy<D>,
+    addresses: Vec<(IpAddr, u8)>,
+    /// The IPv4 prefixes whose ARP requests are answered.
+    proxies: Vec<Prefix>,
+    up: bool,
+}
+
+/// The routes of a prefix and the selector among them.
+struct Paths {
+    routes: Vec<Route>,
+    ecmp: Ecmp,
+}
+
+#[derive(Clone, Copy)]
+struct Neighbor {
+    mac: [u8; 6],
+    port: usize,
+    /// When the address was last confirmed, `None` for static neighbors.
+    seen: Option<Instant>,
+}
+
+/// The minimum interval between requests for the same address.
+const RETRANSMIT: Duration = Duration::from_secs(1);
+
+/// The interval between checks of the links of the ports.
+const LINK_CHECK: Duration = Duration::from_secs(1);
+
+/// The number of outstanding requests after which old ones are forgotten.
+const SOLICITED: usize = 4096;
+
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// An Ethernet frame with a neighbor solicitation or advertisement and a link-layer option.
+const NDP_FRAME: usize = ETHERNET_HEADER + 40 + 32;
+
+impl<D: Queues> Router<D> {
+    /// The default time after which learned neighbors are confirmed again.
+    pub const REACHABLE: Duration = Duration::from_secs(300);
+
+    pub fn new() -> Self {
+        Router {
+            ports: Vec::new(),
+            routes: Lpm::new(),
+            checked: Instant::now(),
+            neighbors: HashMap::new(),
+            solicited: HashMap::new(),
+            reachable: Self::REACHABLE,
+            stats: RouterStats::default(),
+        }
+    }
+
+    /// Add a port without addresses, returning its index.
+    pub fn add_port(&mut self, phy: Phy<D>) -> usize {
+        let up = phy.link().up;
+        self.ports.push(Port { phy, addresses: Vec::new(), proxies: Vec::new(), up });
+        self.refresh_paths();
+        self.ports.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    pub fn port(&self, index: usize) -> &Phy<D> {
+        &self.ports[index].phy
+    }
+
+    pub fn port_mut(&mut self, index: usize) -> &mut Phy<D> {
+        &mut self.ports[index].phy
+    }
+
+    pub fn into_ports(self) -> Vec<Phy<D>> {
+        self.ports.into_iter().map(|port| port.phy).collect()
+    }
+
+    /// Assign an address to a port, adding the route to its attached network.
+    ///
+    /// ## Panics
+    /// This function panics if the port does not exist or `prefix_len` exceeds the bits of the
+    /// address.
+    pub fn add_address(&mut self, port: usize, addr: IpAddr, prefix_len: u8) {
+        let prefix = Prefix::new(addr, prefix_len);
+        self.ports[port].addresses.push((addr, prefix_len));
+        self.add_route(Route { prefix, next_hop: None, port });
+    }
+
+    /// The addresses of a port and the lengths of their network prefixes.
+    pub fn addresses(&self, port: usize) -> &[(IpAddr, u8)] {
+        &self.ports[port].addresses
+    }
+
+    /// Answer ARP requests on a port for the addresses of a prefix routed out of other ports.
+    ///
+    /// ## Panics
+    /// This function panics if the port does not exist or the prefix is not IPv4.
+    pub fn add_proxy(&mut self, port: usize, prefix: Prefix) {
+        assert!(prefix.addr().is_ipv4(), "Proxy ARP is only for IPv4 prefixes");
+        let proxies = &mut self.ports[port].proxies;
+        if !proxies.contains(&prefix) {
+            proxies.push(prefix);
+        }
+    }
+
+    pub fn remove_proxy(&mut self, port: usize, prefix: Prefix) {
+        self.ports[port].proxies.retain(|&other| other != prefix);
+    }
+
+    /// The prefixes proxied on a port.
+    pub fn proxies(&self, port: usize) -> &[Prefix] {
+        &self.ports[port].proxies
+    }
+
+    /// Add a route, returning the first of the routes it replaced for the same prefix.
+    pub fn add_route(&mut self, route: Route) -> Option<Route> {
+        self.insert_paths(route.prefix, &[(route, 1)])
+    }
+
+    /// Add routes for the same prefix with their weights, replacing its previous routes.
+    ///
+    /// Returns the first of the replaced routes.
+    ///
+    /// ## Panics
+    /// This function panics if there are no routes or their prefixes differ.
+    pub fn add_multipath(&mut self, routes: &[(Route, u32)]) -> Option<Route> {
+        let prefix = routes.first().expect("A multipath route needs a route").0.prefix;
+        assert!(routes.iter().all(|(route, _)| route.prefix == prefix), "Prefixes differ");
+        self.insert_paths(prefix, routes)
+    }
+
+    /// Remove the routes of a prefix, returning the first of them.
+    pub fn remove_route(&mut self, prefix: Prefix) -> Option<Route> {
+        self.routes.remove(prefix).map(|paths| paths.routes[0])
+    }
+
+    /// All routes, ordered by prefix.
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.iter().flat_map(|(_, paths)| paths.routes.iter().copied()).collect()
+    }
+
+    /// The first route with the longest prefix containing the address.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Route> {
+        self.routes.lookup(addr).map(|paths| paths.routes[0])
+    }
+
+    /// Add a neighbor which is never resolved or forgotten.
+    pub fn add_neighbor(&mut self, addr: IpAddr, mac: [u8; 6], port: usize) {
+        self.neighbors.insert(addr, Neighbor { mac, port, seen: None });
+    }
+
+    pub fn remove_neighbor(&mut self, addr: IpAddr) {
+        self.neighbors.remove(&addr);
+    }
+
+    /// The known neighbors, their link-layer addresses and ports.
+    pub fn neighbors(&self) -> Vec<(IpAddr, [u8; 6], usize)> {
+        self.neighbors
+            .iter()
+            .map(|(&addr, neighbor)| (addr, neighbor.mac, neighbor.port))
+            .collect()
+    }
+
+    pub fn reachable(&self) -> Duration {
+        self.reachable
+    }
+
+    /// Set the age after which learned neighbors are resolved again.
+    ///
+    /// The old address is used until a reply arrives, neighbors without a reply are forgotten
+    /// when twice as old.
+    pub fn set_reachable(&mut self, reachable: Duration) {
+        self.reachable = reachable;
+    }
+
+    pub fn stats(&self) -> RouterStats {
+        self.stats
+    }
+
+    /// Route up to `max` received packets of each port.
+    ///
+    /// Afterwards the flush policy of each port is honored. Returns the number of frames received.
+    pub fn poll(&mut self, max: usize) -> usize {
+        let now = Instant::now();
+        if now >= self.checked + LINK_CHECK {
+            self.check_links(now);
+        }
+
+        let mut received = 0;
+        for index in 0..self.ports.len() {
+            let phy = &mut self.ports[index].phy;
+            phy.get_rx(max);
+            let count = phy.rx_queue.len().min(max);
+            let frames = phy.rx_queue.drain(..count).collect::<Vec<_>>();
+            received += frames.len();
+            for frame in frames {
+                self.route(index, frame, now);
+            }
+        }
+
+        for port in &mut self.ports {
+            port.phy.poll_flush();
+        }
+        received
+    }
+
+    fn route(&mut self, from: usize, packet: IxyPacket, now: Instant) {
+        let headers = match Headers::parse(&packet) {
+            Some(headers) if headers.vlan.is_none() => headers,
+            _ => {
+                self.stats.dropped += 1;
+                return;
+            },
+        };
+
+        match headers.ethertype {
+            ETHERTYPE_ARP => self.arp(from, &packet, now),
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => self.ip(from, packet, &headers, now),
+            _ => self.stats.dropped += 1,
+        }
+    }
+
+    fn ip(&mut self, from: usize, mut packet: IxyPacket, headers: &Headers, now: Instant) {
+        let (l3, v6) = (headers.l3, headers.ethertype == ETHERTYPE_IPV6);
+        if let Some(l4) = headers.transport(PROTO_ICMPV6) {
+            let kind = packet.get(l4).copied();
+            if kind == Some(NEIGHBOR_SOLICITATION) || kind == Some(NEIGHBOR_ADVERTISEMENT) {
+                return self.ndp(from, &packet, headers, now);
+            }
+        }
+
+        let destination: IpAddr = if v6 {
+            ipv6(&packet[l3 + 24..l3 + 40]).into()
+        } else {
+            ipv4(&packet[l3 + 16..l3 + 20]).into()
+        };
+        let multicast = match destination {
+            IpAddr::V4(addr) => addr.is_multicast() || addr.is_broadcast(),
+            IpAddr::V6(addr) => addr.is_multicast(),
+        };
+        if packet[..6] != self.ports[from].phy.mac_address() || multicast {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        if self.ports.iter().any(|port| port.owns(destination)) {
+            self.stats.local += 1;
+            if echo_reply(&mut packet, headers) {
+                self.ports[from].phy.tx_queue.push_back(packet);
+            }
+            return;
+        }
+
+        let hop_limit = if v6 { l3 + 7 } else { l3 + 8 };
+        if packet[hop_limit] <= 1 {
+            self.stats.ttl_exceeded += 1;
+            return self.error(from, packet, IcmpError::TimeExceeded);
+        }
+
+        let route = match self.routes.lookup(destination) {
+            Some(paths) => paths.select(&packet),
+            None => {
+                self.stats.no_route += 1;
+                return self.error(from, packet, IcmpError::NetUnreachable);
+            },
+        };
+
+        let mtu = self.ports[route.port].phy.mtu();
+        if headers.end - l3 > mtu {
+            self.stats.too_big += 1;
+            // Only IPv4 packets without the don't fragment flag may be fragmented on the path.
+            if v6 || read_u16(&packet, l3 + 6) & 0x4000 != 0 {
+                self.error(from, packet, IcmpError::TooBig(mtu.min(0xffff) as u16));
+            }
+            return;
+        }
+
+        let next_hop = route.next_hop.unwrap_or(destination);
+        let mac = match self.resolve(route.port, next_hop, now) {
+            Some(mac) => mac,
+            None => {
+                self.stats.unresolved += 1;
+                return;
+            },
+        };
+
+        let ttl = packet[hop_limit] - 1;
+        set_hop_limit(&mut packet, l3, v6, ttl);
+        let output = &mut self.ports[route.port].phy;
+        packet[..6].copy_from_slice(&mac);
+        packet[6..12].copy_from_slice(&output.mac_address());
+        output.tx_queue.push_back(packet);
+        self.stats.forwarded += 1;
+    }
+
+    fn insert_paths(&mut self, prefix: Prefix, routes: &[(Route, u32)]) -> Option<Route> {
+        let mut paths = Paths { routes: Vec::new(), ecmp: Ecmp::new() };
+        for &(route, weight) in routes {
+            let path = paths.ecmp.add_path(weight);
+            paths.ecmp.set_up(path, self.ports.get(route.port).map_or(false, |port| port.up));
+            paths.routes.push(route);
+        }
+        self.routes.insert(prefix, paths).map(|paths| paths.routes[0])
+    }
+
+    /// Update the link state of the ports and fail over the routes of those which changed.
+    fn check_links(&mut self, now: Instant) {
+        self.checked = now;
+        let mut changed = false;
+        for port in &mut self.ports {
+            let up = port.phy.link().up;
+            changed |= up != port.up;
+            port.up = up;
+        }
+        if changed {
+            self.refresh_paths();
+        }
+    }
+
+    fn refresh_paths(&mut self) {
+        let ports = &self.ports;
+        for paths in self.routes.values_mut() {
+            for (path, route) in paths.routes.iter().enumerate() {
+                paths.ecmp.set_up(path, ports.get(route.port).map_or(false, |port| port.up));
+            }
+        }
+    }
+
+    /// Answer a packet with an ICMP error from the address of its input port.
+    fn error(&mut self, from: usize, mut packet: IxyPacket, error: IcmpError) {
+        let port = &mut self.ports[from];
+        let source = port.address(read_u16(&packet, 12) == ETHERTYPE_IPV6);
+        if source.is_some() && frame::icmp_error(&mut packet, error, source) {
+            port.phy.tx_queue.push_back(packet);
+        }
+    }
+
+    fn arp(&mut self, from: usize, frame: &[u8], now: Instant) {
+        let arp = match Arp::parse(frame) {
+            Some(arp) => arp,
+            None => {
+                self.stats.dropped += 1;
+                return;
+            },
+        };
+
+        // Only addresses talking to the router or already known are learned.
+        let sender = IpAddr::V4(arp.sender_ip);
+        let target = IpAddr::V4(arp.target_ip);
+        let ours = self.ports[from].owns(target);
+        let proxied = !ours && self.answers_for(from, arp.sender_ip.into(), target);
+        let talking = ours || proxied;
+        if !arp.sender_ip.is_unspecified() && (talking || self.neighbors.contains_key(&sender)) {
+            self.learn(sender, arp.sender_mac, from, now);
+        }
+
+        if talking && arp.operation == ARP_REQUEST {
+            let port = &mut self.ports[from].phy;
+            let reply = arp.reply(port.mac_address());
+            if port.send_frame(&reply) && proxied {
+                self.stats.proxied += 1;
+            }
+        }
+    }
+
+    /// Whether a port answers for a target of another port, but never to the target itself.
+    fn answers_for(&self, from: usize, sender: IpAddr, target: IpAddr) -> bool {
+        let proxied = self.ports[from].proxies.iter().any(|prefix| prefix.contains(target));
+        proxied && sender != target && self.lookup(target).map_or(false, |route| route.port != from)
+    }
+
+    fn ndp(&mut self, from: usize, frame: &[u8], headers: &Headers, now: Instant) {
+        let (l3, l4) = (headers.l3, headers.l3 + 40);
+        // A hop limit of 255 guarantees the message was not forwarded.
+        if frame[l3 + 7] != 255 || l4 + 24 > headers.end {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let source = ipv6(&frame[l3 + 8..l3 + 24]);
+        let target = ipv6(&frame[l4 + 8..l4 + 24]);
+        let mut sender = [0; 6];
+        sender.copy_from_slice(&frame[6..12]);
+        let options = &frame[l4 + 24..headers.end];
+
+        if frame[l4] == NEIGHBOR_SOLICITATION {
+            if !self.ports[from].owns(target.into()) {
+                return;
+            }
+            let mac = link_layer_option(options, 1).unwrap_or(sender);
+            // Duplicate address detection is answered to all nodes.
+            let destination = if source.is_unspecified() {
+                let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+                (ipv6_multicast(all_nodes), all_nodes)
+            } else {
+                self.learn(source.into(), mac, from, now);
+                (mac, source)
+            };
+            let port = &mut self.ports[from].phy;
+            let solicited = !source.is_unspecified();
+            let advertisement =
+                neighbor_advertisement(port.mac_address(), target, destination, solicited);
+            port.send_frame(&advertisement);
+        } else {
+            let target = IpAddr::V6(target);
+            if self.solicited.contains_key(&target) || self.neighbors.contains_key(&target) {
+                let mac = link_layer_option(options, 2).unwrap_or(sender);
+                self.learn(target, mac, from, now);
+            }
+        }
+    }
+
+    fn learn(&mut self, addr: IpAddr, mac: [u8; 6], port: usize, now: Instant) {
+        self.solicited.remove(&addr);
+        match self.neighbors.get(&addr) {
+            Some(Neighbor { seen: None, .. }) => (),
+            _ => {
+                self.neighbors.insert(addr, Neighbor { mac, port, seen: Some(now) });
+            },
+        }
+    }
+
+    /// The link-layer address of a neighbor on a port, soliciting it if unknown or old.
+    fn resolve(&mut self, port: usize, addr: IpAddr, now: Instant) -> Option<[u8; 6]> {
+        let neighbor = self.neighbors.get(&addr).copied();
+        let (mac, seen) = match neighbor {
+            Some(neighbor) if neighbor.port == port => (neighbor.mac, neighbor.seen),
+            _ => {
+                self.solicit(port, addr, now);
+                return None;
+            },
+        };
+
+        let age = match seen {
+            Some(seen) => now.duration_since(seen),
+            None => return Some(mac),
+        };
+        if age >= self.reachable * 2 {
+            self.neighbors.remove(&addr);
+            self.solicit(port, addr, now);
+            return None;
+        }
+        if age >= self.reachable {
+            self.solicit(port, addr, now);
+        }
+        Some(mac)
+    }
+
+    fn solicit(&mut self, port: usize, addr: IpAddr, now: Instant) {
+        if let Some(&sent) = self.solicited.get(&addr) {
+            if now.duration_since(sent) < RETRANSMIT {
+                return;
+            }
+        }
+        if self.solicited.len() >= SOLICITED {
+            self.solicited.retain(|_, sent| now.duration_since(*sent) < RETRANSMIT);
+        }
+
+        let port = &mut self.ports[port];
+        let mac = port.phy.mac_address();
+        let sent = match (addr, port.address(addr.is_ipv6())) {
+            (IpAddr::V4(target), Some(IpAddr::V4(ip))) => {
+                port.phy.send_frame(&frame::arp_request(mac, ip, target))
+            },
+            (IpAddr::V6(target), Some(IpAddr::V6(ip))) => {
+                port.phy.send_frame(&neighbor_solicitation(mac, ip, target))
+            },
+            _ => false,
+        };
+        if sent {
+            self.solicited.insert(addr, now);
+        }
+    }
+}
+
+impl<D: Queues> Default for Router<D> {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Paths {
+    /// The route of the flow of a packet, the first one if no port has a link.
+    fn select(&self, packet: &[u8]) -> Route {
+        let path = self.ecmp.select(packet).unwrap_or(0);
+        self.routes[path]
+    }
+}
+
+impl<D> Port<D> {
+    fn owns(&self, addr: IpAddr) -> bool {
+        self.addresses.iter().any(|&(own, _)| own == addr)
+    }
+
+    /// The first address of the IP version.
+    fn address(&self, v6: bool) -> Option<IpAddr> {
+        self.addresses
+            .iter()
+            .map(|&(addr, _)| addr)
+            .find(|addr| addr.is_ipv6() == v6)
+    }
+}
+
+/// Set the TTL or hop limit of an IP packet, updating the IPv4 header checksum.
+fn set_hop_limit(frame: &mut [u8], l3: usize, v6: bool, value: u8) {
+    if v6 {
+        frame[l3 + 7] = value;
+        return;
+    }
+
+    let old = read_u16(frame, l3 + 8);
+    frame[l3 + 8] = value;
+    let sum = checksum::update(read_u16(frame, l3 + 10), old, read_u16(frame, l3 + 8));
+    write_u16(frame, l3 + 10, sum);
+}
+
+/// Turn an echo request into its reply, in place.
+fn echo_reply(frame: &mut [u8], headers: &Headers) -> bool {
+    let (l3, v6) = (headers.l3, headers.ethertype == ETHERTYPE_IPV6);
+    let (l4, reply) = match (headers.l4, v6) {
+        (Some((l4, PROTO_ICMP)), false) if frame.get(l4) == Some(&8) => (l4, 0),
+        (Some((l4, PROTO_ICMPV6)), true) if frame.get(l4) == Some(&128) => (l4, 129),
+        _ => return false,
+    };
+    if l4 + 8 > headers.end {
+        return false;
+    }
+
+    let mut macs = [0; 12];
+    macs.copy_from_slice(&frame[..12]);
+    frame[..6].copy_from_slice(&macs[6..]);
+    frame[6..12].copy_from_slice(&macs[..6]);
+
+    // Swapping the addresses leaves all checksums unchanged.
+    let (start, half) = if v6 { (l3 + 8, 16) } else { (l3 + 12, 4) };
+    let mut source = [0; 16];
+    source[..half].copy_from_slice(&frame[start..start + half]);
+    frame.copy_within(start + half..start + 2 * half, start);
+    frame[start + half..start + 2 * half].copy_from_slice(&source[..half]);
+    set_hop_limit(frame, l3, v6, 64);
+
+    let old = read_u16(frame, l4);
+    frame[l4] = reply;
+    let sum = checksum::update(read_u16(frame, l4 + 2), old, read_u16(frame, l4));
+    write_u16(frame, l4 + 2, sum);
+    true
+}
+
+/// The link-layer address in the first option of a kind, 1 for source and 2 for target.
+fn link_layer_option(mut options: &[u8], kind: u8) -> Option<[u8; 6]> {
+    while options.len() >= 8 {
+        let len = usize::from(options[1]) * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if options[0] == kind {
+            let mut mac = [0; 6];
+            mac.copy_from_slice(&options[2..8]);
+            return Some(mac);
+        }
+        options = &options[len..];
+    }
+    None
+}
+
+/// A neighbor solicitation for `target` to its solicited-node multicast group.
+fn neighbor_solicitation(mac: [u8; 6], ip: Ipv6Addr, target: Ipv6Addr) -> [u8; NDP_FRAME] {
+    let low = target.octets();
+    let group = Ipv6Addr::from([
+        0xff, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xff, low[13], low[14], low[15],
+    ]);
+    let link = (ipv6_multicast(group), group);
+    ndp(mac, ip, link, (NEIGHBOR_SOLICITATION, 0, target, 1))
+}
+
+/// A neighbor advertisement from a router that `target` is at `mac`.
+fn neighbor_advertisement(
+    mac: [u8; 6],
+    target: Ipv6Addr,
+    destination: ([u8; 6], Ipv6Addr),
+    solicited: bool,
+) -> [u8; NDP_FRAME] {
+    // The router and override flags, and the solicited flag for unicast replies.
+    let flags = if solicited { 0xe0 } else { 0xa0 };
+    ndp(mac, target, destination, (NEIGHBOR_ADVERTISEMENT, flags, target, 2))
+}
+
+/// A neighbor discovery message with a link-layer address option for `mac`.
+fn ndp(
+    mac: [u8; 6],
+    source: Ipv6Addr,
+    (destination_mac, destination): ([u8; 6], Ipv6Addr),
+    (kind, flags, target, option): (u8, u8, Ipv6Addr, u8),
+) -> [u8; NDP_FRAME] {
+    let mut frame = [0; NDP_FRAME];
+    frame[0..6].copy_from_slice(&destination_mac);
+    frame[6..12].copy_from_slice(&mac);
+    write_u16(&mut frame, 12, ETHERTYPE_IPV6);
+
+    let ip = &mut frame[ETHERNET_HEADER..];
+    ip[0] = 0x60;
+    write_u16(ip, 4, 32);
+    ip[6] = PROTO_ICMPV6;
+    ip[7] = 255;
+    ip[8..24].copy_from_slice(&source.octets());
+    ip[24..40].copy_from_slice(&destination.octets());
+
+    let (header, icmp) = ip.split_at_mut(40);
+    icmp[0] = kind;
+    icmp[4] = flags;
+    icmp[8..24].copy_from_slice(&target.octets());
+    icmp[24] = option;
+    icmp[25] = 1;
+    icmp[26..32].copy_from_slice(&mac);
+    let pseudo = checksum::sum(&header[8..40], u32::from(PROTO_ICMPV6) + 32);
+    let sum = checksum::finish(checksum::sum(icmp, pseudo));
+    write_u16(icmp, 2, sum);
+    frame
+}
@@ -0,0 +1,166 @@
+//! Receive side scaling in software, for devices with a single queue.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::rc::Rc;
+
+use ixy::IxyDevice;
+use ixy::memory::Packet as IxyPacket;
+
+use crate::{FiveTuple, Link, Metadata, Offloads, Phy, Queues, TxOffload};
+
+/// Spreads the packets of a single queue device over several virtual queues.
+///
+/// The device is polled by whichever phy finds its own queue empty. Packets are dispatched by a
+/// symmetric hash of their 5-tuple, so both directions of a flow and thus its TCP state stay on
+/// one queue. Frames without an IP flow, such as ARP, go to the first queue. All virtual queues
+/// send on the only queue of the device.
+///
+/// Packets for other queues wait in bounded rings until their phy polls, and are dropped if that
+/// ring is full. Like `Shared` all phys live on the thread owning the device, as the packets and
+/// their pool can not leave it, see `PhyQueue`.
+pub struct Rss<D> {
+    inner: Rc<RefCell<Dispatch<D>>>,
+}
+
+struct Dispatch<D> {
+    device: D,
+    rings: Vec<VecDeque<IxyPacket>>,
+    ring_size: usize,
+    dropped: u64,
+    /// The batch received from the device before dispatching, kept for its allocation.
+    received: VecDeque<IxyPacket>,
+}
+
+impl<D: IxyDevice> Rss<D> {
+    /// The default number of packets waiting per virtual queue.
+    pub const RING_SIZE: usize = 1024;
+
+    /// Create one phy for each of `queues` virtual queues.
+    ///
+    /// All phys allocate packets for sending from the receive pool of the device.
+    ///
+    /// ## Panics
+    /// This function panics if `queues` is zero or the device has no receive pool.
+    pub fn split(device: D, queues: u32) -> Vec<Phy<Self>> {
+        assert!(queues > 0, "Need at least one queue");
+        let pool = device
+            .recv_pool(0)
+            .expect("No receive pool for the queue")
+            .clone();
+        let inner = Rc::new(RefCell::new(Dispatch {
+            device,
+            rings: (0..queues).map(|_| VecDeque::new()).collect(),
+            ring_size: Self::RING_SIZE,
+            dropped: 0,
+            received: VecDeque::new(),
+        }));
+
+        (0..queues)
+            .map(|queue| {
+                let rss = Rss { inner: inner.clone() };
+                let mut phy = Phy::new(rss, pool.clone());
+                phy.queue = queue;
+                phy
+            })
+            .collect()
+    }
+
+    /// The number of packets dropped since the ring of their queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.inner.borrow().dropped
+    }
+}
+
+impl<D: IxyDevice> Queues for Rss<D> {
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut inner = self.inner.borrow_mut();
+        let Dispatch { device, rings, ring_size, dropped, received } = &mut *inner;
+        let queues = rings.len() as u32;
+
+        let own = &mut rings[queue as usize];
+        let waiting = own.len().min(num_packets);
+        buffer.extend(own.drain(..waiting));
+        if waiting == num_packets {
+            return waiting;
+        }
+
+        device.rx_batch(0, received, num_packets);
+
+        let mut count = waiting;
+        for packet in received.drain(..) {
+            let target = flow_hash(&packet).map_or(0, |hash| hash % queues);
+            if target == queue && count < num_packets {
+                buffer.push_back(packet);
+                count += 1;
+            } else if rings[target as usize].len() < *ring_size {
+                rings[target as usize].push_back(packet);
+            } else {
+                *dropped += 1;
+            }
+        }
+        count
+    }
+
+    fn tx_batch(&mut self, _: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        self.inner.borrow_mut().device.tx_batch(0, buffer)
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        Queues::mac_address(&self.inner.borrow().device)
+    }
+
+    fn link(&self) -> Link {
+        Queues::link(&self.inner.borrow().device)
+    }
+
+    fn offloads(&self) -> Offloads {
+        Queues::offloads(&self.inner.borrow().device)
+    }
+
+    fn rx_metadata(&self, queue: u32, packet: &IxyPacket) -> Metadata {
+        Metadata {
+            queue,
+            rss_hash: flow_hash(packet),
+            ..Queues::rx_metadata(&self.inner.borrow().device, 0, packet)
+        }
+    }
+
+    fn reclaim_tx(&mut self, _: u32, buffer: &mut VecDeque<IxyPacket>, max: usize) -> usize {
+        Queues::reclaim_tx(&mut self.inner.borrow_mut().device, 0, buffer, max)
+    }
+
+    fn tx_in_flight(&mut self, _: u32) -> Option<usize> {
+        Queues::tx_in_flight(&mut self.inner.borrow_mut().device, 0)
+    }
+
+    fn tx_offload(&mut self, _: u32, packet: &mut IxyPacket, offload: TxOffload) {
+        Queues::tx_offload(&mut self.inner.borrow_mut().device, 0, packet, offload)
+    }
+}
+
+/// A hash of the flow of a frame which is the same for both directions.
+pub(crate) fn flow_hash(frame: &[u8]) -> Option<u32> {
+    let flow = FiveTuple::from_frame(frame)?;
+    let a = (flow.src, flow.src_port);
+    let b = (flow.dst, flow.dst_port);
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+
+    // FNV-1a over the ordered endpoints and the protocol.
+    let mut hash = 0x811c_9dc5u32;
+    let mut feed = |bytes: &[u8]| for &byte in bytes {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    };
+    for &(addr, port) in [low, high].iter() {
+        match addr {
+            IpAddr::V4(addr) => feed(&addr.octets()),
+            IpAddr::V6(addr) => feed(&addr.octets()),
+        }
+        feed(&port.to_be_bytes());
+    }
+    feed(&[flow.protocol]);
+    Some(hash)
+}
@@ -0,0 +1,162 @@
+//! A shared-nothing runtime running one phy per thread.
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::{Control, Phy, Queues, Reactor, affinity};
+use crate::stats::{PhyStats, QueueStats};
+
+/// Runs a worker thread per phy and collects their stats.
+///
+/// Nothing is shared between the workers. Since neither ixy devices nor their packets can move
+/// between threads, each worker creates its own device, mempool, phy and network stack, e.g. on
+/// its own port or virtual function, for example by attaching a `Detached` phy moved into the
+/// worker. Packets are processed to completion on the worker.
+pub struct Runtime {
+    threads: Vec<JoinHandle<()>>,
+    shared: Arc<State>,
+}
+
+/// The context of a worker thread.
+pub struct Worker {
+    index: usize,
+    cpu: Option<usize>,
+    shared: Arc<State>,
+}
+
+struct State {
+    stop: AtomicBool,
+    stats: Mutex<Vec<(QueueStats, PhyStats)>>,
+}
+
+impl Runtime {
+    /// The interval in which `Worker::run` publishes stats and checks for a stop.
+    pub const INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Spawn `workers` threads, each running `worker` with its own context.
+    ///
+    /// With `pin` each thread is pinned to a distinct physical core, see `affinity::allocate`.
+    pub fn spawn<F>(workers: usize, pin: bool, worker: F) -> io::Result<Self>
+        where F: Fn(Worker) + Send + Sync + 'static
+    {
+        let cpus = if pin {
+            affinity::allocate(workers, None)?
+        } else {
+            Vec::new()
+        };
+
+        let shared = Arc::new(State {
+            stop: AtomicBool::new(false),
+            stats: Mutex::new(vec![Default::default(); workers]),
+        });
+        let worker = Arc::new(worker);
+
+        let mut threads = Vec::with_capacity(workers);
+        for index in 0..workers {
+            let context = Worker {
+                index,
+                cpu: cpus.get(index).cloned(),
+                shared: shared.clone(),
+            };
+            let worker = worker.clone();
+            let thread = thread::Builder::new()
+                .name(format!("ixy-worker-{}", index))
+                .spawn(move || {
+                    if let Some(cpu) = context.cpu {
+                        if let Err(err) = affinity::pin_current_thread(cpu) {
+                            eprintln!("Failed to pin worker {} to cpu {}: {}", index, cpu, err);
+                        }
+                    }
+                    worker(context)
+                })?;
+            threads.push(thread);
+        }
+
+        Ok(Runtime { threads, shared })
+    }
+
+    /// The last stats published by each worker.
+    pub fn stats(&self) -> Vec<(QueueStats, PhyStats)> {
+        self.shared.stats.lock().unwrap().clone()
+    }
+
+    /// The sum of the last stats published by all workers.
+    pub fn total(&self) -> (QueueStats, PhyStats) {
+        let mut total = (QueueStats::default(), PhyStats::default());
+        for (queue, phy) in self.stats() {
+            total.0 += queue;
+            total.1 += phy;
+        }
+        total
+    }
+
+    /// Ask all workers to stop.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Wait for all workers to finish, returning if any panicked.
+    pub fn join(self) -> thread::Result<()> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            if let Err(err) = thread.join() {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+impl Worker {
+    /// The index of the worker, e.g. to select its device.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The cpu the worker is pinned to.
+    pub fn cpu(&self) -> Option<usize> {
+        self.cpu
+    }
+
+    /// If the runtime asked the workers to stop.
+    pub fn is_stopped(&self) -> bool {
+        self.shared.stop.load(Ordering::Relaxed)
+    }
+
+    /// Make the stats of a phy visible to the runtime.
+    pub fn publish<D>(&self, phy: &Phy<D>) {
+        let stats = (phy.queue_stats(), phy.phy_stats());
+        self.shared.stats.lock().unwrap()[self.index] = stats;
+    }
+
+    /// Drive a phy until the runtime stops or the callback does.
+    ///
+    /// Uses a `Reactor` which publishes the stats periodically and once more at the end.
+    pub fn run<D, F>(&self, phy: &mut Phy<D>, poll: F)
+    where
+        D: Queues,
+        F: FnMut(&mut Phy<D>, usize) -> Control,
+    {
+        self.run_with(Reactor::new(), phy, poll)
+    }
+
+    /// Drive a phy with a configured reactor, see `run`.
+    pub fn run_with<'a, D, F>(&'a self, mut reactor: Reactor<'a, D>, phy: &mut Phy<D>, poll: F)
+    where
+        D: Queues,
+        F: FnMut(&mut Phy<D>, usize) -> Control,
+    {
+        reactor.every(Runtime::INTERVAL, |phy| {
+            self.publish(phy);
+            if self.is_stopped() {
+                Control::Stop
+            } else {
+                Control::Continue
+            }
+        });
+        reactor.run(phy, poll);
+        self.publish(phy);
+    }
+}
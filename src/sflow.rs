@@ -0,0 +1,464 @@
+//! Sampling frames and interface counters to an sFlow collector.
+use std::cell::RefCell;
+use std::io;
+use std::net::{IpAddr, ToSocketAddrs, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::fault::Rng;
+use crate::stats::{PhyStats, QueueStats};
+use crate::{Direction, Dump, Link};
+
+/// The counters of an interface, sent as its generic interface counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SflowCounters {
+    pub link: Link,
+
+    /// The traffic of the interface, summed over its queues.
+    pub queue: QueueStats,
+
+    /// The software drops of the interface, summed over its queues.
+    pub phy: PhyStats,
+}
+
+/// Counters of an sFlow agent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SflowStats {
+    pub datagrams: u64,
+    pub flow_samples: u64,
+    pub counter_samples: u64,
+
+    /// Samples lost because their datagram could not be sent.
+    pub dropped: u64,
+}
+
+/// An sFlow version 5 agent sampling the frames of its interfaces.
+///
+/// As a `Dump` of a `Capture`, one in about every `rate` frames has its headers, truncated to the
+/// snap length, sent to the collector together with the number of frames they stand for. The
+/// frames to sample are chosen at random so periodic traffic does not bias the sample. The
+/// counters of all interfaces are sent once per interval from `poll`, which should be called
+/// regularly as it also sends the samples which did not fill a datagram within a second.
+///
+/// An agent can serve several interfaces, e.g. both ports of a forwarder. Share it between their
+/// captures with `SflowInterface`. Used directly as a `Dump` it samples for its first interface.
+pub struct Sflow {
+    socket: UdpSocket,
+    agent: IpAddr,
+    sub_agent: u32,
+    rate: u32,
+    snaplen: usize,
+    interval: Duration,
+    interfaces: Vec<Source>,
+    rng: Rng,
+    boot: Instant,
+    /// The sequence number of the next datagram.
+    sequence: u32,
+    /// The encoded samples of the next datagram.
+    buffer: Vec<u8>,
+    samples: u32,
+    /// When the oldest sample in the buffer was taken.
+    oldest: Option<Instant>,
+    last_counters: Option<Instant>,
+    stats: SflowStats,
+}
+
+/// One interface of an agent shared between several captures.
+pub struct SflowInterface {
+    agent: Rc<RefCell<Sflow>>,
+    id: usize,
+}
+
+/// The sampling state of an interface.
+struct Source {
+    if_index: u32,
+    /// The frames until the next sample.
+    skip: u32,
+    /// The frames seen, sampled or not.
+    pool: u32,
+    flow_sequence: u32,
+    counter_sequence: u32,
+}
+
+const VERSION: u32 = 5;
+const FLOW_SAMPLE: u32 = 1;
+const COUNTER_SAMPLE: u32 = 2;
+const RAW_HEADER: u32 = 1;
+const GENERIC_COUNTERS: u32 = 1;
+const PROTOCOL_ETHERNET: u32 = 1;
+const IF_TYPE_ETHERNET: u32 = 6;
+
+/// The largest datagram, to fit an IPv4 and UDP packet into an Ethernet frame.
+const MAX_DATAGRAM: usize = 1400;
+
+/// The datagram header with an IPv6 agent address.
+const MAX_HEADER: usize = 40;
+
+/// A flow sample without the header of the frame.
+const FLOW_SAMPLE_LEN: usize = 8 + 32 + 8 + 16;
+
+/// A counter sample with the generic interface counters.
+const COUNTER_SAMPLE_LEN: usize = 8 + 12 + 8 + 88;
+
+/// The value of counters the phy does not keep.
+const UNKNOWN: u32 = u32::max_value();
+
+impl Sflow {
+    /// The default mean number of frames per sample.
+    pub const RATE: u32 = 1000;
+
+    /// The default number of bytes of a sampled frame sent to the collector.
+    pub const SNAPLEN: usize = 128;
+
+    /// The default interval between sending the counters.
+    pub const INTERVAL: Duration = Duration::from_secs(20);
+
+    /// The longest time a sample waits for more samples to fill its datagram.
+    const MAX_DELAY: Duration = Duration::from_secs(1);
+
+    /// Sample to a collector from an unspecified local address of its IP version.
+    ///
+    /// The agent address identifies the agent to the collector, e.g. the management address of
+    /// the host.
+    pub fn new(collector: impl ToSocketAddrs, agent: IpAddr) -> io::Result<Self> {
+        let collector = collector
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No collector address"))?;
+        let local = if collector.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(collector)?;
+        socket.set_nonblocking(true)?;
+        Ok(Sflow::with_socket(socket, agent))
+    }
+
+    /// Sample with a socket connected to the collector.
+    ///
+    /// The first interface has the index 1.
+    pub fn with_socket(socket: UdpSocket, agent: IpAddr) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let mut sflow = Sflow {
+            socket,
+            agent,
+            sub_agent: 0,
+            rate: Self::RATE,
+            snaplen: Self::SNAPLEN,
+            interval: Self::INTERVAL,
+            interfaces: Vec::new(),
+            rng: Rng::new(seed),
+            boot: Instant::now(),
+            sequence: 0,
+            buffer: Vec::with_capacity(MAX_DATAGRAM),
+            samples: 0,
+            oldest: None,
+            last_counters: None,
+            stats: SflowStats::default(),
+        };
+        sflow.add_interface(1);
+        sflow
+    }
+
+    /// Add an interface by its `ifIndex`, returning its id.
+    pub fn add_interface(&mut self, if_index: u32) -> usize {
+        let skip = self.skip();
+        self.interfaces.push(Source {
+            if_index,
+            skip,
+            pool: 0,
+            flow_sequence: 0,
+            counter_sequence: 0,
+        });
+        self.interfaces.len() - 1
+    }
+
+    /// The number of interfaces.
+    pub fn interfaces(&self) -> usize {
+        self.interfaces.len()
+    }
+
+    pub fn sub_agent(&self) -> u32 {
+        self.sub_agent
+    }
+
+    /// Change the sub-agent id, which tells several agents with the same address apart.
+    pub fn set_sub_agent(&mut self, sub_agent: u32) {
+        self.sub_agent = sub_agent;
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Sample one in about `rate` frames, effective after the next sample.
+    ///
+    /// ## Panics
+    /// This function panics if the rate is zero.
+    pub fn set_rate(&mut self, rate: u32) {
+        assert!(rate > 0, "The sampling rate must not be zero");
+        self.rate = rate;
+    }
+
+    pub fn snaplen(&self) -> usize {
+        self.snaplen
+    }
+
+    /// Truncate sampled frames to `snaplen` bytes, at most what fits into a datagram.
+    pub fn set_snaplen(&mut self, snaplen: usize) {
+        self.snaplen = snaplen.min(MAX_DATAGRAM - MAX_HEADER - FLOW_SAMPLE_LEN);
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    pub fn stats(&self) -> SflowStats {
+        self.stats
+    }
+
+    /// Send the counters if the interval has elapsed, and samples waiting for too long.
+    ///
+    /// The counters of each interface are only created when they are due, by its id. Returns if
+    /// counters were sent.
+    pub fn poll(&mut self, mut counters: impl FnMut(usize) -> SflowCounters) -> io::Result<bool> {
+        let now = Instant::now();
+        let due = match self.last_counters {
+            None => true,
+            Some(last) => now.duration_since(last) >= self.interval,
+        };
+
+        let mut result = Ok(());
+        if due {
+            self.last_counters = Some(now);
+            for id in 0..self.interfaces.len() {
+                if let Err(err) = self.counter_sample(id, &counters(id)) {
+                    result = Err(err);
+                }
+            }
+        }
+
+        let waited = self.oldest
+            .map_or(false, |oldest| now.duration_since(oldest) >= Self::MAX_DELAY);
+        if waited {
+            if let Err(err) = self.flush() {
+                result = Err(err);
+            }
+        }
+        result.map(|()| due)
+    }
+
+    /// Send the buffered samples now.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.samples == 0 {
+            return Ok(());
+        }
+
+        let mut datagram = Vec::with_capacity(MAX_HEADER + self.buffer.len());
+        put(&mut datagram, VERSION);
+        match self.agent {
+            IpAddr::V4(addr) => {
+                put(&mut datagram, 1);
+                datagram.extend_from_slice(&addr.octets());
+            },
+            IpAddr::V6(addr) => {
+                put(&mut datagram, 2);
+                datagram.extend_from_slice(&addr.octets());
+            },
+        }
+        put(&mut datagram, self.sub_agent);
+        put(&mut datagram, self.sequence);
+        put(&mut datagram, self.boot.elapsed().as_millis() as u32);
+        put(&mut datagram, self.samples);
+        datagram.extend_from_slice(&self.buffer);
+
+        let samples = u64::from(self.samples);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.buffer.clear();
+        self.samples = 0;
+        self.oldest = None;
+
+        match self.socket.send(&datagram) {
+            Ok(_) => {
+                self.stats.datagrams += 1;
+                Ok(())
+            },
+            Err(err) => {
+                self.stats.dropped += samples;
+                Err(err)
+            },
+        }
+    }
+
+    /// Share the agent between several captures.
+    pub fn shared(self) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(self))
+    }
+
+    pub fn into_inner(self) -> UdpSocket {
+        self.socket
+    }
+
+    /// Count a frame of an interface, returns if it is sampled.
+    fn count(&mut self, id: usize) -> bool {
+        let source = &mut self.interfaces[id];
+        source.pool = source.pool.wrapping_add(1);
+        source.skip -= 1;
+        if source.skip > 0 {
+            return false;
+        }
+        self.interfaces[id].skip = self.skip();
+        true
+    }
+
+    /// The frames until the next sample, uniform with a mean of the rate.
+    fn skip(&mut self) -> u32 {
+        1 + self.rng.below(2 * u64::from(self.rate) - 1) as u32
+    }
+
+    fn flow_sample(&mut self, id: usize, direction: Direction, frame: &[u8]) -> io::Result<()> {
+        let header = &frame[..frame.len().min(self.snaplen)];
+        let padded = (header.len() + 3) & !3;
+        self.reserve(FLOW_SAMPLE_LEN + padded)?;
+
+        let rate = self.rate;
+        let source = &mut self.interfaces[id];
+        source.flow_sequence = source.flow_sequence.wrapping_add(1);
+        let (input, output) = match direction {
+            Direction::Rx => (source.if_index, 0),
+            Direction::Tx => (0, source.if_index),
+        };
+
+        let buffer = &mut self.buffer;
+        put(buffer, FLOW_SAMPLE);
+        put(buffer, (FLOW_SAMPLE_LEN - 8 + padded) as u32);
+        put(buffer, source.flow_sequence);
+        put(buffer, source.if_index);
+        put(buffer, rate);
+        put(buffer, source.pool);
+        // Drops of the sampling itself, it never drops.
+        put(buffer, 0);
+        put(buffer, input);
+        put(buffer, output);
+        put(buffer, 1);
+
+        put(buffer, RAW_HEADER);
+        put(buffer, (16 + padded) as u32);
+        put(buffer, PROTOCOL_ETHERNET);
+        put(buffer, frame.len() as u32);
+        // No bytes were stripped, the frame check sequence is not part of the frame.
+        put(buffer, 0);
+        put(buffer, header.len() as u32);
+        buffer.extend_from_slice(header);
+        buffer.resize(buffer.len() + padded - header.len(), 0);
+
+        self.stats.flow_samples += 1;
+        self.push_sample();
+        Ok(())
+    }
+
+    fn counter_sample(&mut self, id: usize, counters: &SflowCounters) -> io::Result<()> {
+        self.reserve(COUNTER_SAMPLE_LEN)?;
+
+        let source = &mut self.interfaces[id];
+        source.counter_sequence = source.counter_sequence.wrapping_add(1);
+        let SflowCounters { link, queue, phy } = counters;
+
+        let buffer = &mut self.buffer;
+        put(buffer, COUNTER_SAMPLE);
+        put(buffer, (COUNTER_SAMPLE_LEN - 8) as u32);
+        put(buffer, source.counter_sequence);
+        put(buffer, source.if_index);
+        put(buffer, 1);
+
+        put(buffer, GENERIC_COUNTERS);
+        put(buffer, 88);
+        put(buffer, source.if_index);
+        put(buffer, IF_TYPE_ETHERNET);
+        buffer.extend_from_slice(&(u64::from(link.speed) * 1_000_000).to_be_bytes());
+        put(buffer, if link.full_duplex { 1 } else { 2 });
+        // Administratively up, and operationally with the link.
+        put(buffer, if link.up { 0b11 } else { 0b01 });
+        buffer.extend_from_slice(&queue.rx_bytes.to_be_bytes());
+        // All packets count as unicast, the phy does not tell them apart.
+        put(buffer, queue.rx_packets as u32);
+        put(buffer, UNKNOWN);
+        put(buffer, UNKNOWN);
+        put(buffer, (phy.rx_filtered + phy.rx_steered_dropped) as u32);
+        put(buffer, UNKNOWN);
+        put(buffer, UNKNOWN);
+        buffer.extend_from_slice(&queue.tx_bytes.to_be_bytes());
+        put(buffer, queue.tx_packets as u32);
+        put(buffer, UNKNOWN);
+        put(buffer, UNKNOWN);
+        put(buffer, (phy.tx_dropped + phy.tx_alloc_failed) as u32);
+        put(buffer, UNKNOWN);
+        // Not promiscuous.
+        put(buffer, 2);
+
+        self.stats.counter_samples += 1;
+        self.push_sample();
+        Ok(())
+    }
+
+    /// Make room for a sample, sending the buffered samples if it would not fit.
+    fn reserve(&mut self, len: usize) -> io::Result<()> {
+        if MAX_HEADER + self.buffer.len() + len > MAX_DATAGRAM {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn push_sample(&mut self) {
+        self.samples += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+    }
+}
+
+impl Dump for Sflow {
+    fn dump(&mut self, direction: Direction, _: Duration, frame: &[u8]) -> io::Result<()> {
+        self.flow_sample(0, direction, frame)
+    }
+
+    fn sample(&mut self, _: Direction) -> bool {
+        self.count(0)
+    }
+}
+
+impl SflowInterface {
+    /// Sample for an interface of a shared agent by its id.
+    ///
+    /// ## Panics
+    /// This function panics if the agent has no such interface.
+    pub fn new(agent: Rc<RefCell<Sflow>>, id: usize) -> Self {
+        assert!(id < agent.borrow().interfaces(), "No such interface");
+        SflowInterface { agent, id }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn agent(&self) -> &Rc<RefCell<Sflow>> {
+        &self.agent
+    }
+}
+
+impl Dump for SflowInterface {
+    fn dump(&mut self, direction: Direction, _: Duration, frame: &[u8]) -> io::Result<()> {
+        self.agent.borrow_mut().flow_sample(self.id, direction, frame)
+    }
+
+    fn sample(&mut self, _: Direction) -> bool {
+        self.agent.borrow_mut().count(self.id)
+    }
+}
+
+fn put(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
@@ -0,0 +1,139 @@
+//! The `smoltcp` device implementation, enabled by the `smoltcp` feature.
+//!
+//! The tokens hand the DMA buffers of ixy to smoltcp directly, no frame is copied through an
+//! intermediate buffer in either direction.
+use ixy::memory::Packet as IxyPacket;
+use smoltcp::phy::{self, Checksum, DeviceCapabilities};
+use smoltcp::time::Instant;
+
+use crate::{Handle, Phy, Queues, enqueue, frame_size};
+
+/// A received packet, handed to smoltcp.
+///
+/// Owns the ixy packet, its buffer returns to the mempool when the token is dropped, whether it
+/// was consumed or not.
+pub struct RxToken(IxyPacket);
+
+/// The right to send a single packet on a phy.
+///
+/// A buffer is only taken from the phy when the token is consumed, and smoltcp writes the frame
+/// into it in place. If smoltcp fails to emit the frame, or the token is dropped unconsumed, the
+/// buffer stays with the phy for the next packet.
+pub struct TxToken<'a, D>(&'a mut Phy<D>);
+
+/// Polled by an `EthernetInterface` of smoltcp.
+///
+/// Packets are received in batches of the configured size and handed out one at a time. Sent
+/// packets are queued and handed to the device according to the flush policy, use a policy
+/// other than `FlushPolicy::Always` and call `flush` after each poll of the interface to batch
+/// them.
+impl<'a, D: Queues + 'a> phy::Device<'a> for Phy<D> {
+    type RxToken = RxToken;
+    type TxToken = TxToken<'a, D>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        self.get_rx(1);
+        let packet = self.rx_queue.pop_front()?;
+        Some((RxToken(packet), TxToken(self)))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        self.get_tx(1);
+        if self.tx_empty.is_empty() {
+            return None;
+        }
+        Some(TxToken(self))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let offloads = self.offloads();
+        let mut capabilities = DeviceCapabilities::default();
+        // smoltcp counts the Ethernet header, but not a VLAN tag.
+        capabilities.max_transmission_unit = frame_size(self.mtu) - 4;
+        capabilities.max_burst_size = Some(self.batch_size);
+        // Received checksums are always verified, the validation of the NIC is not reported.
+        capabilities.checksum.ipv4 = tx_checksum(offloads.tx_ipv4_checksum);
+        capabilities.checksum.tcp = tx_checksum(offloads.tx_tcp_checksum);
+        capabilities.checksum.udp = tx_checksum(offloads.tx_udp_checksum);
+        capabilities
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, _: Instant, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&[u8]) -> smoltcp::Result<R>
+    {
+        f(&self.0)
+    }
+}
+
+impl<D: Queues> phy::TxToken for TxToken<'_, D> {
+    fn consume<R, F>(self, _: Instant, len: usize, f: F) -> smoltcp::Result<R>
+        where F: FnOnce(&mut [u8]) -> smoltcp::Result<R>
+    {
+        let phy = self.0;
+        // The token of a received packet comes without a buffer, and the batch may be used up.
+        phy.get_tx(1);
+        let mut packet = match phy.tx_empty.pop_front() {
+            Some(packet) => packet,
+            None => return Err(smoltcp::Error::Exhausted),
+        };
+
+        if packet.try_resize(len, 0u8).is_err() {
+            phy.tx_empty.push_front(packet);
+            return Err(smoltcp::Error::Truncated);
+        }
+
+        let result = f(&mut packet);
+        if result.is_err() {
+            phy.tx_empty.push_front(packet);
+            return result;
+        }
+
+        // Queue like the ethox stack does, with the checksums smoltcp left to the device.
+        let mut handle = Handle::new(phy.clock.now());
+        handle.offloads = phy.offloads();
+        let recycled = enqueue(
+            &mut phy.device, phy.queue, &phy.pool, &mut phy.tx_queue, &mut phy.phy_stats,
+            packet, &handle);
+        phy.tx_empty.extend(recycled);
+        phy.poll_flush();
+        result
+    }
+}
+
+/// Leave a checksum to the NIC if it inserts it.
+fn tx_checksum(offloaded: bool) -> Checksum {
+    if offloaded {
+        Checksum::Rx
+    } else {
+        Checksum::Both
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use smoltcp::phy::{Device, RxToken as _, TxToken as _};
+
+    use super::*;
+    use crate::mock::test_pool;
+    use crate::MockDevice;
+
+    #[test]
+    fn replies_on_a_fresh_phy() {
+        let pool = test_pool();
+        let mut device = MockDevice::new(pool.clone());
+        device.push_rx(&[0x11; 60]);
+        let mut phy = Phy::new(device, pool);
+
+        let (rx, tx) = phy.receive().expect("A frame was scripted");
+        let request = rx.consume(Instant::from_millis(0), |frame| Ok(frame.to_vec())).unwrap();
+        tx.consume(Instant::from_millis(0), request.len(), |frame| {
+            frame.copy_from_slice(&request);
+            Ok(())
+        }).expect("No buffer for the reply");
+
+        phy.flush();
+        assert_eq!(phy.ixy().transmitted(), [request]);
+    }
+}
@@ -0,0 +1,472 @@
+//! Async sockets on top of smoltcp, enabled by the `sockets` feature.
+//!
+//! The stack is polled by the application, e.g. in the idle hook of a single-threaded executor
+//! or in a loop beside it. Each poll processes a batch of packets and wakes all tasks which wait
+//! on a socket if its readiness may have changed. Without an executor, the `try_` operations and
+//! the `readiness` module offer the same sockets in the style of mio, and `blocking` adapts a
+//! connection to `std::io`.
+use std::cell::{Cell, RefCell};
+use std::future::{Future, poll_fn};
+use std::io;
+use std::rc::Rc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::time::Duration;
+
+use smoltcp::iface::EthernetInterface;
+use smoltcp::socket::{AnySocket, SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::socket::{UdpPacketMetadata, UdpSocket as RawUdpSocket, UdpSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::IpEndpoint;
+
+use crate::{Phy, Queues};
+
+pub mod blocking;
+pub mod readiness;
+
+type Tcp = TcpSocket<'static>;
+type Udp = RawUdpSocket<'static, 'static>;
+
+/// An interface with its sockets, shared by all socket handles.
+///
+/// Cloning creates another handle to the same stack.
+pub struct Stack<D: Queues + 'static> {
+    inner: Rc<RefCell<Inner<D>>>,
+}
+
+struct Inner<D: Queues + 'static> {
+    iface: EthernetInterface<'static, 'static, 'static, Phy<D>>,
+    sockets: SocketSet<'static, 'static, 'static>,
+    /// Tasks waiting for any socket.
+    wakers: Vec<Waker>,
+    /// Closed TCP sockets to remove once their connection is finished.
+    closing: Vec<SocketHandle>,
+    /// The next ephemeral port to try.
+    next_port: u16,
+}
+
+/// A TCP connection.
+///
+/// Dropping the stream closes the connection gracefully, its socket is removed once the close
+/// has completed.
+pub struct TcpStream<D: Queues + 'static> {
+    stack: Stack<D>,
+    /// Shared with readiness registrations, which end when the stream is dropped.
+    handle: Rc<Cell<SocketHandle>>,
+}
+
+/// Accepts TCP connections on a port.
+pub struct TcpListener<D: Queues + 'static> {
+    stack: Stack<D>,
+    port: u16,
+    /// The socket in the listen state, shared with readiness registrations.
+    handle: Rc<Cell<SocketHandle>>,
+}
+
+/// A UDP socket.
+pub struct UdpSocket<D: Queues + 'static> {
+    stack: Stack<D>,
+    /// Shared with readiness registrations, which end when the socket is dropped.
+    handle: Rc<Cell<SocketHandle>>,
+}
+
+impl<D: Queues + 'static> Stack<D> {
+    /// The size of the receive and send buffers of each TCP socket.
+    pub const TCP_BUFFER: usize = 64 * 1024;
+
+    /// The number of datagrams buffered in each direction of a UDP socket.
+    pub const UDP_PACKETS: usize = 64;
+
+    /// The size of the payload buffers in each direction of a UDP socket.
+    pub const UDP_BUFFER: usize = 64 * 1024;
+
+    const EPHEMERAL_PORTS: u16 = 49152;
+
+    /// Wrap a configured interface over a phy.
+    pub fn new(iface: EthernetInterface<'static, 'static, 'static, Phy<D>>) -> Self {
+        let inner = Inner {
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            wakers: Vec::new(),
+            closing: Vec::new(),
+            next_port: Self::EPHEMERAL_PORTS,
+        };
+
+        Stack { inner: Rc::new(RefCell::new(inner)) }
+    }
+
+    /// Process received packets and send pending data.
+    ///
+    /// Returns whether the readiness of sockets may have changed, in which case all waiting tasks
+    /// were woken.
+    pub fn poll(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let Inner { iface, sockets, wakers, closing, .. } = &mut *inner;
+
+        // Errors concern single malformed packets, which were still consumed.
+        let changed = iface.poll(sockets, Instant::now()).unwrap_or(true);
+        iface.device_mut().flush();
+
+        closing.retain(|&handle| {
+            let closed = sockets.get::<TcpSocket>(handle).state() == TcpState::Closed;
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
+
+        let woken = if changed {
+            std::mem::replace(wakers, Vec::new())
+        } else {
+            Vec::new()
+        };
+        // Tasks may use the stack as soon as they are woken.
+        drop(inner);
+        woken.into_iter().for_each(Waker::wake);
+        changed
+    }
+
+    /// How long the caller may wait before the next poll is required by a timer.
+    ///
+    /// Returns `None` if no timer is pending. Received packets are not accounted for, the device
+    /// can not signal them.
+    pub fn poll_delay(&self) -> Option<Duration> {
+        let inner = self.inner.borrow();
+        inner.iface.poll_delay(&inner.sockets, Instant::now())
+            .map(|delay| Duration::from_millis(delay.total_millis()))
+    }
+
+    /// Run a future of the sockets to completion, polling the stack while it is pending.
+    ///
+    /// This drives the sockets without an executor. Other tasks waiting on the stack are woken
+    /// but can not run until the future completed.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            self.poll();
+        }
+    }
+
+    /// Inspect the phy of the interface.
+    pub fn with_phy<T>(&self, f: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        f(self.inner.borrow_mut().iface.device_mut())
+    }
+
+    fn add<S: Into<smoltcp::socket::Socket<'static, 'static>>>(&self, socket: S) -> SocketHandle {
+        self.inner.borrow_mut().sockets.add(socket)
+    }
+
+    fn remove(&self, handle: SocketHandle) {
+        self.inner.borrow_mut().sockets.remove(handle);
+    }
+
+    fn with<S, T>(&self, handle: SocketHandle, f: impl FnOnce(&mut S) -> T) -> T
+        where S: AnySocket<'static, 'static>
+    {
+        let mut inner = self.inner.borrow_mut();
+        let mut socket = inner.sockets.get::<S>(handle);
+        f(&mut *socket)
+    }
+
+    /// Poll an operation on a socket, registering the task if it is not ready.
+    fn poll_socket<S, T>(
+        &self,
+        cx: &mut Context,
+        handle: SocketHandle,
+        f: impl FnOnce(&mut S) -> Poll<T>,
+    ) -> Poll<T>
+        where S: AnySocket<'static, 'static>
+    {
+        let result = self.with(handle, f);
+        if result.is_pending() {
+            let wakers = &mut self.inner.borrow_mut().wakers;
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        result
+    }
+
+    /// Poll a non-blocking operation on a socket, waiting while it would block.
+    fn poll_io<S, T>(
+        &self,
+        cx: &mut Context,
+        handle: SocketHandle,
+        f: impl FnOnce(&mut S) -> io::Result<T>,
+    ) -> Poll<io::Result<T>>
+        where S: AnySocket<'static, 'static>
+    {
+        self.poll_socket(cx, handle, |socket| match f(socket) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            result => Poll::Ready(result),
+        })
+    }
+
+    /// Retry a non-blocking operation while polling the stack, until it no longer would block.
+    ///
+    /// Fails with `TimedOut` once the timeout expired.
+    pub(crate) fn block<T>(&self, timeout: Option<Duration>, mut f: impl FnMut() -> io::Result<T>)
+        -> io::Result<T>
+    {
+        let start = std::time::Instant::now();
+        loop {
+            match f() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+
+            if timeout.map_or(false, |timeout| start.elapsed() >= timeout) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.poll();
+        }
+    }
+
+    pub(crate) fn ephemeral_port(&self) -> u16 {
+        let mut inner = self.inner.borrow_mut();
+        let port = inner.next_port;
+        inner.next_port = port.checked_add(1).unwrap_or(Self::EPHEMERAL_PORTS);
+        port
+    }
+
+    fn tcp_socket(&self) -> SocketHandle {
+        let rx = TcpSocketBuffer::new(vec![0; Self::TCP_BUFFER]);
+        let tx = TcpSocketBuffer::new(vec![0; Self::TCP_BUFFER]);
+        self.add(TcpSocket::new(rx, tx))
+    }
+}
+
+impl<D: Queues + 'static> TcpStream<D> {
+    /// Connect to a remote endpoint from an ephemeral port.
+    pub async fn connect(stack: &Stack<D>, remote: IpEndpoint) -> io::Result<Self> {
+        let handle = stack.tcp_socket();
+        let local = stack.ephemeral_port();
+        let stream = TcpStream { stack: stack.clone(), handle: Rc::new(Cell::new(handle)) };
+        stack.with(handle, |socket: &mut Tcp| socket.connect(remote, local))
+            .map_err(io_error)?;
+
+        poll_fn(|cx| stream.stack.poll_socket(cx, handle, |socket: &mut Tcp| {
+            match socket.state() {
+                TcpState::SynSent | TcpState::SynReceived => Poll::Pending,
+                TcpState::Closed => Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into())),
+                _ => Poll::Ready(Ok(())),
+            }
+        })).await?;
+
+        Ok(stream)
+    }
+
+    /// Receive some data, returns `0` once the peer has closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let handle = self.handle.get();
+        poll_fn(|cx| self.stack.poll_io(cx, handle, |socket: &mut Tcp| tcp_recv(socket, buf))).await
+    }
+
+    /// Receive some data without waiting, fails with `WouldBlock` if none is buffered.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stack.with(self.handle.get(), |socket: &mut Tcp| tcp_recv(socket, buf))
+    }
+
+    /// Queue some data for sending, as much as fits the send buffer.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let handle = self.handle.get();
+        poll_fn(|cx| self.stack.poll_io(cx, handle, |socket: &mut Tcp| tcp_send(socket, buf))).await
+    }
+
+    /// Queue some data without waiting, fails with `WouldBlock` if the send buffer is full.
+    pub fn try_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stack.with(self.handle.get(), |socket: &mut Tcp| tcp_send(socket, buf))
+    }
+
+    /// Queue all of the data for sending.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let written = self.write(buf).await?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    /// Close the sending half of the connection.
+    pub fn close(&mut self) {
+        self.stack.with(self.handle.get(), |socket: &mut Tcp| socket.close());
+    }
+
+    pub fn local_endpoint(&self) -> IpEndpoint {
+        self.stack.with(self.handle.get(), |socket: &mut Tcp| socket.local_endpoint())
+    }
+
+    pub fn remote_endpoint(&self) -> IpEndpoint {
+        self.stack.with(self.handle.get(), |socket: &mut Tcp| socket.remote_endpoint())
+    }
+}
+
+impl<D: Queues + 'static> TcpListener<D> {
+    /// Listen on a local port.
+    pub fn bind(stack: &Stack<D>, port: u16) -> io::Result<Self> {
+        let handle = Self::listen(stack, port)?;
+        Ok(TcpListener { stack: stack.clone(), port, handle: Rc::new(Cell::new(handle)) })
+    }
+
+    /// Wait for the next connection.
+    pub async fn accept(&mut self) -> io::Result<TcpStream<D>> {
+        let handle = self.handle.get();
+        poll_fn(|cx| self.stack.poll_io(cx, handle, tcp_established)).await?;
+        self.take()
+    }
+
+    /// Accept a connection without waiting, fails with `WouldBlock` if none is established.
+    pub fn try_accept(&mut self) -> io::Result<TcpStream<D>> {
+        self.stack.with(self.handle.get(), tcp_established)?;
+        self.take()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Hand out the established connection of the listening socket.
+    fn take(&mut self) -> io::Result<TcpStream<D>> {
+        // The listening socket became the connection, listen on a fresh one.
+        let handle = self.handle.replace(Self::listen(&self.stack, self.port)?);
+        Ok(TcpStream { stack: self.stack.clone(), handle: Rc::new(Cell::new(handle)) })
+    }
+
+    fn listen(stack: &Stack<D>, port: u16) -> io::Result<SocketHandle> {
+        let handle = stack.tcp_socket();
+        let listening = stack.with(handle, |socket: &mut Tcp| socket.listen(port));
+        if let Err(err) = listening {
+            stack.remove(handle);
+            return Err(io_error(err));
+        }
+        Ok(handle)
+    }
+}
+
+impl<D: Queues + 'static> UdpSocket<D> {
+    /// Bind a socket to a local endpoint.
+    pub fn bind(stack: &Stack<D>, local: IpEndpoint) -> io::Result<Self> {
+        let buffer = || UdpSocketBuffer::new(
+            vec![UdpPacketMetadata::EMPTY; Stack::<D>::UDP_PACKETS],
+            vec![0; Stack::<D>::UDP_BUFFER]);
+        let handle = stack.add(RawUdpSocket::new(buffer(), buffer()));
+        let socket = UdpSocket { stack: stack.clone(), handle: Rc::new(Cell::new(handle)) };
+        stack.with(handle, |socket: &mut Udp| socket.bind(local))
+            .map_err(io_error)?;
+        Ok(socket)
+    }
+
+    /// Send a datagram, waiting for room in the send buffer.
+    pub async fn send_to(&self, buf: &[u8], remote: IpEndpoint) -> io::Result<()> {
+        let handle = self.handle.get();
+        poll_fn(|cx| self.stack.poll_io(cx, handle, |socket: &mut Udp| {
+            socket.send_slice(buf, remote).map_err(io_error)
+        })).await
+    }
+
+    /// Send a datagram without waiting, fails with `WouldBlock` if the send buffer is full.
+    pub fn try_send_to(&self, buf: &[u8], remote: IpEndpoint) -> io::Result<()> {
+        self.stack.with(self.handle.get(), |socket: &mut Udp| {
+            socket.send_slice(buf, remote).map_err(io_error)
+        })
+    }
+
+    /// Receive a datagram into the buffer, truncating it if it does not fit.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpEndpoint)> {
+        let handle = self.handle.get();
+        poll_fn(|cx| self.stack.poll_io(cx, handle, |socket: &mut Udp| {
+            socket.recv_slice(buf).map_err(io_error)
+        })).await
+    }
+
+    /// Receive a datagram without waiting, fails with `WouldBlock` if none is buffered.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, IpEndpoint)> {
+        self.stack.with(self.handle.get(), |socket: &mut Udp| {
+            socket.recv_slice(buf).map_err(io_error)
+        })
+    }
+
+    pub fn local_endpoint(&self) -> IpEndpoint {
+        self.stack.with(self.handle.get(), |socket: &mut Udp| socket.endpoint())
+    }
+}
+
+impl<D: Queues + 'static> Clone for Stack<D> {
+    fn clone(&self) -> Self {
+        Stack { inner: Rc::clone(&self.inner) }
+    }
+}
+
+impl<D: Queues + 'static> Drop for TcpStream<D> {
+    fn drop(&mut self) {
+        self.close();
+        self.stack.inner.borrow_mut().closing.push(self.handle.get());
+    }
+}
+
+impl<D: Queues + 'static> Drop for TcpListener<D> {
+    fn drop(&mut self) {
+        self.stack.remove(self.handle.get());
+    }
+}
+
+impl<D: Queues + 'static> Drop for UdpSocket<D> {
+    fn drop(&mut self) {
+        self.stack.remove(self.handle.get());
+    }
+}
+
+fn tcp_recv(socket: &mut Tcp, buf: &mut [u8]) -> io::Result<usize> {
+    if socket.can_recv() {
+        socket.recv_slice(buf).map_err(io_error)
+    } else if !socket.may_recv() {
+        Ok(0)
+    } else {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+fn tcp_send(socket: &mut Tcp, buf: &[u8]) -> io::Result<usize> {
+    if !socket.may_send() {
+        Err(io::ErrorKind::BrokenPipe.into())
+    } else if socket.can_send() {
+        socket.send_slice(buf).map_err(io_error)
+    } else {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+/// Check if a listening socket has established its connection.
+fn tcp_established(socket: &mut Tcp) -> io::Result<()> {
+    match socket.state() {
+        TcpState::Listen | TcpState::SynReceived => Err(io::ErrorKind::WouldBlock.into()),
+        _ => Ok(()),
+    }
+}
+
+/// A waker for futures which are polled in a loop anyways.
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+    // The vtable ignores the data pointer entirely.
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
+
+fn io_error(err: smoltcp::Error) -> io::Error {
+    let kind = match err {
+        smoltcp::Error::Exhausted => io::ErrorKind::WouldBlock,
+        smoltcp::Error::Illegal => io::ErrorKind::InvalidInput,
+        smoltcp::Error::Unaddressable => io::ErrorKind::AddrNotAvailable,
+        smoltcp::Error::Truncated => io::ErrorKind::InvalidData,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, err.to_string())
+}
@@ -0,0 +1,89 @@
+//! Blocking `std::io` access to a TCP connection.
+//!
+//! The adapter polls the stack itself while it waits, so code written against `Read` and `Write`
+//! such as parsers or TLS libraries runs unchanged on top of a phy. Other sockets of the stack
+//! progress during these polls as well, but nothing else runs on the thread in the meantime.
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use super::{Tcp, TcpStream};
+use crate::Queues;
+
+/// A TCP connection implementing `Read` and `Write`.
+///
+/// Reads wait for data and return `0` once the peer has closed the connection. Writes wait for
+/// room in the send buffer. Without a timeout both wait indefinitely, otherwise they fail with
+/// `TimedOut`.
+pub struct BlockingStream<D: Queues + 'static> {
+    stream: TcpStream<D>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl<D: Queues + 'static> BlockingStream<D> {
+    pub fn new(stream: TcpStream<D>) -> Self {
+        BlockingStream { stream, read_timeout: None, write_timeout: None }
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    pub fn get_ref(&self) -> &TcpStream<D> {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut TcpStream<D> {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> TcpStream<D> {
+        self.stream
+    }
+}
+
+impl<D: Queues + 'static> Read for BlockingStream<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stream = &mut self.stream;
+        stream.stack.clone().block(self.read_timeout, || stream.try_read(buf))
+    }
+}
+
+impl<D: Queues + 'static> Write for BlockingStream<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let stream = &mut self.stream;
+        stream.stack.clone().block(self.write_timeout, || stream.try_write(buf))
+    }
+
+    /// Poll the stack once, so that queued data is handed to the phy.
+    ///
+    /// This does not wait for the peer to acknowledge the data.
+    fn flush(&mut self) -> io::Result<()> {
+        let stack = &self.stream.stack;
+        stack.poll();
+        let handle = self.stream.handle.get();
+        if stack.with(handle, |socket: &mut Tcp| socket.may_send() || socket.send_queue() == 0) {
+            Ok(())
+        } else {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+}
+
+impl<D: Queues + 'static> From<TcpStream<D>> for BlockingStream<D> {
+    fn from(stream: TcpStream<D>) -> Self {
+        BlockingStream::new(stream)
+    }
+}
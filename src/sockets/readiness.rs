@@ -0,0 +1,250 @@
+//! Readiness events of sockets, in the style of mio, without an async runtime.
+//!
+//! Register sockets with a `Poll` under a `Token`, then wait for events and perform the
+//! non-blocking `try_` operations of the ready sockets. Events are level-triggered: a socket is
+//! reported by every call to `Poll::poll` for as long as it remains ready.
+use std::cell::Cell;
+use std::io;
+use std::ops::BitOr;
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+
+use smoltcp::socket::SocketHandle;
+
+use super::{Stack, Tcp, TcpListener, TcpStream, Udp, UdpSocket, tcp_established};
+use crate::Queues;
+
+/// Identifies a registered socket in its events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+/// The readiness a socket is registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Interest(u8);
+
+/// The readiness of a registered socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Event {
+    token: Token,
+    readable: bool,
+    writable: bool,
+    closed: bool,
+}
+
+/// A buffer of events, filled by `Poll::poll`.
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    events: Vec<Event>,
+}
+
+/// Waits for the readiness of registered sockets while polling their stack.
+pub struct Poll<D: Queues + 'static> {
+    stack: Stack<D>,
+    registrations: Vec<Registration>,
+}
+
+/// A socket which can be registered with a `Poll`.
+pub trait Source: private::Sealed {}
+
+struct Registration {
+    /// The socket, which changes for a listener with each accepted connection. Shared with the
+    /// socket, the registration ends when it is dropped.
+    handle: Weak<Cell<SocketHandle>>,
+    kind: Kind,
+    token: Token,
+    interest: Interest,
+}
+
+mod private {
+    use std::cell::Cell;
+    use std::rc::{Rc, Weak};
+
+    use smoltcp::socket::SocketHandle;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Kind {
+        Stream,
+        Listener,
+        Datagram,
+    }
+
+    pub trait Sealed {
+        fn source(&self) -> (Rc<Cell<SocketHandle>>, Kind);
+    }
+}
+
+use private::Kind;
+
+impl Interest {
+    pub const READABLE: Interest = Interest(1);
+    pub const WRITABLE: Interest = Interest(2);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & Self::READABLE.0 != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & Self::WRITABLE.0 != 0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+impl Event {
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// Data can be received, the end of a stream was reached, or a connection can be accepted.
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    /// Data can be queued for sending.
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    /// The connection is fully closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Events {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Events { events: Vec::with_capacity(capacity) }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Event> {
+        self.events.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear()
+    }
+}
+
+impl<'a> IntoIterator for &'a Events {
+    type Item = &'a Event;
+    type IntoIter = std::slice::Iter<'a, Event>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.events.iter()
+    }
+}
+
+impl<D: Queues + 'static> Poll<D> {
+    pub fn new(stack: &Stack<D>) -> Self {
+        Poll { stack: stack.clone(), registrations: Vec::new() }
+    }
+
+    pub fn stack(&self) -> &Stack<D> {
+        &self.stack
+    }
+
+    /// Report the readiness of a socket under a token.
+    ///
+    /// A socket registered again replaces its previous registration.
+    pub fn register(&mut self, source: &impl Source, token: Token, interest: Interest) {
+        let (handle, kind) = source.source();
+        self.deregister(source);
+        let handle = Rc::downgrade(&handle);
+        self.registrations.push(Registration { handle, kind, token, interest });
+    }
+
+    /// Stop reporting a socket.
+    ///
+    /// Dropping a socket deregisters it as well.
+    pub fn deregister(&mut self, source: &impl Source) {
+        let (handle, _) = source.source();
+        let handle = Rc::downgrade(&handle);
+        self.registrations.retain(|registration| !registration.handle.ptr_eq(&handle));
+    }
+
+    /// Poll the stack until a registered socket is ready or the timeout expired.
+    ///
+    /// The device can not signal received packets, so this busy polls. A timeout of zero polls
+    /// exactly once, `None` waits indefinitely. The events replace the previous content.
+    pub fn poll(&mut self, events: &mut Events, timeout: Option<Duration>) -> io::Result<()> {
+        let start = Instant::now();
+        events.clear();
+        // Forget the registrations of dropped sockets, their handles may already be reused.
+        self.registrations.retain(|registration| registration.handle.strong_count() > 0);
+        loop {
+            self.stack.poll();
+            for registration in &self.registrations {
+                if let Some(event) = self.readiness(registration) {
+                    events.events.push(event);
+                }
+            }
+
+            let expired = timeout.map_or(false, |timeout| start.elapsed() >= timeout);
+            if !events.is_empty() || expired {
+                return Ok(());
+            }
+        }
+    }
+
+    fn readiness(&self, registration: &Registration) -> Option<Event> {
+        let handle = registration.handle.upgrade()?.get();
+        let (readable, writable, closed) = match registration.kind {
+            Kind::Stream => self.stack.with(handle, |socket: &mut Tcp| (
+                socket.can_recv() || !socket.may_recv(),
+                socket.can_send(),
+                !socket.is_open(),
+            )),
+            Kind::Listener => self.stack.with(handle, |socket: &mut Tcp| {
+                (tcp_established(socket).is_ok(), false, false)
+            }),
+            Kind::Datagram => self.stack.with(handle, |socket: &mut Udp| {
+                (socket.can_recv(), socket.can_send(), false)
+            }),
+        };
+
+        let interest = registration.interest;
+        let readable = readable && interest.is_readable();
+        let writable = writable && interest.is_writable();
+        if !readable && !writable && !closed {
+            return None;
+        }
+
+        Some(Event { token: registration.token, readable, writable, closed })
+    }
+}
+
+impl<D: Queues + 'static> Source for TcpStream<D> {}
+impl<D: Queues + 'static> Source for TcpListener<D> {}
+impl<D: Queues + 'static> Source for UdpSocket<D> {}
+
+impl<D: Queues + 'static> private::Sealed for TcpStream<D> {
+    fn source(&self) -> (Rc<Cell<SocketHandle>>, Kind) {
+        (Rc::clone(&self.handle), Kind::Stream)
+    }
+}
+
+impl<D: Queues + 'static> private::Sealed for TcpListener<D> {
+    fn source(&self) -> (Rc<Cell<SocketHandle>>, Kind) {
+        (Rc::clone(&self.handle), Kind::Listener)
+    }
+}
+
+impl<D: Queues + 'static> private::Sealed for UdpSocket<D> {
+    fn source(&self) -> (Rc<Cell<SocketHandle>>, Kind) {
+        (Rc::clone(&self.handle), Kind::Datagram)
+    }
+}
@@ -0,0 +1,279 @@
+//! A bounded lock-free single-producer single-consumer ring.
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::mem::MaybeUninit;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ixy::memory::{self, Mempool, Packet as IxyPacket};
+
+/// The sending side of a ring, see `channel`.
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    /// The tail owned by the producer.
+    tail: usize,
+    /// The last head seen, the consumer only ever advances it.
+    head: usize,
+}
+
+/// The receiving side of a ring, see `channel`.
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    /// The head owned by the consumer.
+    head: usize,
+    /// The last tail seen, the producer only ever advances it.
+    tail: usize,
+}
+
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    mask: usize,
+    /// The index of the next slot to read, written by the consumer.
+    head: Padded<AtomicUsize>,
+    /// The index of the next slot to write, written by the producer.
+    tail: Padded<AtomicUsize>,
+}
+
+/// Keeps the indices on separate cache lines to avoid false sharing.
+#[repr(align(64))]
+struct Padded<T>(T);
+
+// Safety: each slot is only accessed by one side at a time, ownership is transferred through the
+// release and acquire of the indices.
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// Create a ring for handing buffers from one thread to another.
+///
+/// Pushing and popping in batches amortizes the synchronization, like the batches of the rx and
+/// tx rings of the NIC. The capacity is rounded up to a power of two.
+///
+/// Note that ixy packets can not be sent between threads as they refer to their pool through an
+/// `Rc`, see the documentation of `Phy`. A ring of frames carries copies of them instead, see
+/// `Producer::push_packets` and `Consumer::pop_packets`.
+///
+/// ## Panics
+/// This function panics if `capacity` is zero.
+pub fn channel<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "Capacity must not be zero");
+    let capacity = capacity.next_power_of_two();
+    let slots = (0..capacity)
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        mask: capacity - 1,
+        head: Padded(AtomicUsize::new(0)),
+        tail: Padded(AtomicUsize::new(0)),
+    });
+
+    let producer = Producer { ring: ring.clone(), tail: 0, head: 0 };
+    let consumer = Consumer { ring, head: 0, tail: 0 };
+    (producer, consumer)
+}
+
+impl<T> Producer<T> {
+    /// Push a single element, returning it if the ring is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.free() == 0 {
+            return Err(value);
+        }
+
+        self.write(value);
+        self.ring.tail.0.store(self.tail, Ordering::Release);
+        Ok(())
+    }
+
+    /// Move elements from the front of the buffer into the ring, returns their number.
+    pub fn push_batch(&mut self, buffer: &mut VecDeque<T>) -> usize {
+        let count = self.free().min(buffer.len());
+        for value in buffer.drain(..count) {
+            self.write(value);
+        }
+
+        if count > 0 {
+            self.ring.tail.0.store(self.tail, Ordering::Release);
+        }
+        count
+    }
+
+    /// The number of free slots.
+    ///
+    /// Only grows concurrently, as the consumer pops.
+    pub fn free(&mut self) -> usize {
+        let capacity = self.capacity();
+        if self.tail.wrapping_sub(self.head) == capacity {
+            self.head = self.ring.head.0.load(Ordering::Acquire);
+        }
+        capacity - self.tail.wrapping_sub(self.head)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.mask + 1
+    }
+
+    /// Write into the next slot, which must be free.
+    fn write(&mut self, value: T) {
+        let slot = &self.ring.slots[self.tail & self.ring.mask];
+        // Safety: the slot is free, the consumer does not access it until the tail is published.
+        unsafe { (*slot.get()).as_mut_ptr().write(value) };
+        self.tail = self.tail.wrapping_add(1);
+    }
+}
+
+impl Producer<Vec<u8>> {
+    /// Copy packets from the front of the buffer into the ring, returns their number.
+    ///
+    /// The copied packets are freed to their pool on this thread.
+    pub fn push_packets(&mut self, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let count = self.free().min(buffer.len());
+        for packet in buffer.drain(..count) {
+            self.write(packet[..].to_vec());
+        }
+
+        if count > 0 {
+            self.ring.tail.0.store(self.tail, Ordering::Release);
+        }
+        count
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Pop a single element.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.available() == 0 {
+            return None;
+        }
+
+        let value = self.read();
+        self.ring.head.0.store(self.head, Ordering::Release);
+        Some(value)
+    }
+
+    /// Move up to `max` elements to the back of the buffer, returns their number.
+    pub fn pop_batch(&mut self, buffer: &mut VecDeque<T>, max: usize) -> usize {
+        let count = self.available().min(max);
+        buffer.reserve(count);
+        for _ in 0..count {
+            let value = self.read();
+            buffer.push_back(value);
+        }
+
+        if count > 0 {
+            self.ring.head.0.store(self.head, Ordering::Release);
+        }
+        count
+    }
+
+    /// The number of elements ready to be popped.
+    ///
+    /// Only grows concurrently, as the producer pushes.
+    pub fn available(&mut self) -> usize {
+        if self.tail == self.head {
+            self.tail = self.ring.tail.0.load(Ordering::Acquire);
+        }
+        self.tail.wrapping_sub(self.head)
+    }
+
+    /// If the producer was dropped.
+    ///
+    /// Elements pushed before can still be popped.
+    pub fn is_disconnected(&self) -> bool {
+        Arc::strong_count(&self.ring) == 1
+    }
+
+    /// The value in the next slot, which must be filled.
+    fn peek(&self) -> &T {
+        let slot = &self.ring.slots[self.head & self.ring.mask];
+        // Safety: the slot was filled and published by the producer, which no longer accesses it.
+        unsafe { &*(*slot.get()).as_ptr() }
+    }
+
+    /// Read the next slot, which must be filled.
+    fn read(&mut self) -> T {
+        let slot = &self.ring.slots[self.head & self.ring.mask];
+        // Safety: the slot was filled and published by the producer, which no longer accesses it.
+        let value = unsafe { (*slot.get()).as_ptr().read() };
+        self.head = self.head.wrapping_add(1);
+        value
+    }
+}
+
+impl Consumer<Vec<u8>> {
+    /// Copy up to `max` frames into packets from the pool at the back of the buffer, returns
+    /// their number.
+    ///
+    /// Stops early when the pool is exhausted, the remaining frames stay in the ring. Frames
+    /// longer than the entries of the pool are dropped.
+    pub fn pop_packets(&mut self, pool: &Rc<Mempool>, buffer: &mut VecDeque<IxyPacket>, max: usize)
+        -> usize
+    {
+        let available = self.available();
+        let (mut consumed, mut count) = (0, 0);
+        while consumed < available && count < max {
+            let len = self.peek().len();
+            if len <= pool.entry_size() {
+                let mut packet = match memory::alloc_pkt(pool, len) {
+                    Some(packet) => packet,
+                    None => break,
+                };
+                packet.copy_from_slice(self.peek());
+                buffer.push_back(packet);
+                count += 1;
+            }
+
+            drop(self.read());
+            consumed += 1;
+        }
+
+        if consumed > 0 {
+            self.ring.head.0.store(self.head, Ordering::Release);
+        }
+        count
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        let head = *self.head.0.get_mut();
+        let tail = *self.tail.0.get_mut();
+        let mut index = head;
+        while index != tail {
+            let slot = &self.slots[index & self.mask];
+            // Safety: slots between head and tail are filled, and no side is left to read them.
+            unsafe { (*slot.get()).as_mut_ptr().drop_in_place() };
+            index = index.wrapping_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::test_pool;
+
+    #[test]
+    fn carries_packets_as_copies() {
+        let pool = test_pool();
+        let (mut producer, mut consumer) = channel(4);
+        let mut packets: VecDeque<_> = (0..3u8)
+            .map(|index| {
+                let mut packet = memory::alloc_pkt(&pool, 60).unwrap();
+                packet.copy_from_slice(&[index; 60]);
+                packet
+            })
+            .collect();
+        assert_eq!(producer.push_packets(&mut packets), 3);
+        assert!(packets.is_empty());
+        // A frame which does not fit the entries of the pool is dropped.
+        assert!(producer.push(vec![0; pool.entry_size() + 1]).is_ok());
+
+        let mut received = VecDeque::new();
+        assert_eq!(consumer.pop_packets(&pool, &mut received, 2), 2);
+        assert_eq!(consumer.pop_packets(&pool, &mut received, 4), 1);
+        assert_eq!(consumer.available(), 0);
+        assert!(received.iter().enumerate()
+            .all(|(index, packet)| packet[..] == [index as u8; 60][..]));
+    }
+}
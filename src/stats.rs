@@ -0,0 +1,396 @@
+//! Software statistics of phys, and rates of the device counters.
+use std::fmt;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+use ixy::{DeviceStats, IxyDevice};
+
+pub mod prometheus;
+pub mod statsd;
+
+/// Counters of a single rx/tx queue pair, kept in software by its `Phy`.
+///
+/// Unlike the `DeviceStats` of ixy these are per queue, which makes imbalances between the queues
+/// of a multi-core setup visible.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct QueueStats {
+    /// The index of the queue.
+    pub queue: u32,
+
+    /// The number of packets received from the device.
+    pub rx_packets: u64,
+
+    /// The number of bytes received from the device.
+    pub rx_bytes: u64,
+
+    /// The number of packets handed to the device for sending.
+    pub tx_packets: u64,
+
+    /// The number of bytes handed to the device for sending.
+    pub tx_bytes: u64,
+
+    /// The number of times the device was polled for received packets.
+    pub rx_polls: u64,
+
+    /// The number of polls which returned no packets.
+    pub rx_empty_polls: u64,
+
+    /// The number of flushes which handed at least one packet to the device.
+    pub tx_flushes: u64,
+}
+
+/// Counters of packets lost or delayed in software by a `Phy`.
+///
+/// Makes silent loss observable. Note that frames missed by the NIC because its receive ring was
+/// full are not seen by the phy, these are only counted by the device itself.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PhyStats {
+    /// Buffers provided for sending which the sender did not queue.
+    ///
+    /// These are not lost but kept for the next call.
+    pub tx_unused: u64,
+
+    /// Queued packets dropped before reaching the device.
+    ///
+    /// Either there was no room to insert their VLAN tag, the pool was exhausted during software
+    /// segmentation, or the device did not accept them before `Phy::shutdown` timed out.
+    pub tx_dropped: u64,
+
+    /// Flushes after which packets remained queued because the tx ring was full.
+    pub tx_ring_full: u64,
+
+    /// Received frames discarded by the software MAC filter.
+    pub rx_filtered: u64,
+
+    /// Received frames discarded by flow steering rules, or steered to a missing queue.
+    pub rx_steered_dropped: u64,
+
+    /// Times the tx pool had fewer free buffers than the sender could have used.
+    ///
+    /// The sender was offered a short batch. Frequent exhaustion indicates an undersized pool or
+    /// buffers leaking, see `Phy::pool_usage`.
+    pub tx_alloc_failed: u64,
+}
+
+/// The buffers of a mempool held by a `Phy`.
+///
+/// The ixy mempool does not expose its free count, buffers held by the device in its rings are
+/// not accounted for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PoolUsage {
+    /// The size of each buffer.
+    pub entry_size: usize,
+
+    /// Allocated buffers waiting to be offered for sending.
+    pub tx_free: usize,
+
+    /// Queued packets waiting for a flush.
+    pub tx_pending: usize,
+
+    /// Received packets not yet handed to the network stack.
+    pub rx_buffered: usize,
+}
+
+impl PoolUsage {
+    /// All buffers held by the phy.
+    pub fn held(&self) -> usize {
+        self.tx_free + self.tx_pending + self.rx_buffered
+    }
+}
+
+impl AddAssign for QueueStats {
+    /// Add the counters of another queue, keeping the index of this one.
+    fn add_assign(&mut self, other: Self) {
+        self.rx_packets += other.rx_packets;
+        self.rx_bytes += other.rx_bytes;
+        self.tx_packets += other.tx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.rx_polls += other.rx_polls;
+        self.rx_empty_polls += other.rx_empty_polls;
+        self.tx_flushes += other.tx_flushes;
+    }
+}
+
+impl AddAssign for PhyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.tx_unused += other.tx_unused;
+        self.tx_dropped += other.tx_dropped;
+        self.tx_ring_full += other.tx_ring_full;
+        self.rx_filtered += other.rx_filtered;
+        self.rx_steered_dropped += other.rx_steered_dropped;
+        self.tx_alloc_failed += other.tx_alloc_failed;
+    }
+}
+
+impl QueueStats {
+    /// The average number of packets received by non-empty polls.
+    pub fn rx_batch_fill(&self) -> f64 {
+        let polls = self.rx_polls - self.rx_empty_polls;
+        if polls == 0 {
+            0.0
+        } else {
+            self.rx_packets as f64 / polls as f64
+        }
+    }
+
+    /// The average number of packets sent by each flush.
+    pub fn tx_batch_fill(&self) -> f64 {
+        if self.tx_flushes == 0 {
+            0.0
+        } else {
+            self.tx_packets as f64 / self.tx_flushes as f64
+        }
+    }
+}
+
+/// Throughput between two readings of the device counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    /// Received packets per second.
+    pub rx_pps: f64,
+
+    /// Sent packets per second.
+    pub tx_pps: f64,
+
+    /// Received Gbit/s on the wire.
+    pub rx_gbps: f64,
+
+    /// Sent Gbit/s on the wire.
+    pub tx_gbps: f64,
+}
+
+/// Measures the rates of a device by diffing its counters.
+///
+/// Create it once, then call `update` periodically, e.g. once per second.
+pub struct Measure {
+    last: DeviceStats,
+    at: Instant,
+}
+
+impl Rates {
+    /// The per-packet overhead on the wire of preamble, start delimiter and inter-frame gap.
+    pub const WIRE_OVERHEAD: u64 = 20;
+
+    /// Calculate the rates between two readings.
+    ///
+    /// Like ixy, the bit rates include the wire overhead of each packet.
+    pub fn between(old: &DeviceStats, new: &DeviceStats, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return Rates::default();
+        }
+
+        let rx_packets = new.rx_pkts.wrapping_sub(old.rx_pkts);
+        let tx_packets = new.tx_pkts.wrapping_sub(old.tx_pkts);
+        let rx_bytes = new.rx_bytes.wrapping_sub(old.rx_bytes);
+        let tx_bytes = new.tx_bytes.wrapping_sub(old.tx_bytes);
+        let gbps = |packets: u64, bytes: u64| {
+            (bytes + packets * Self::WIRE_OVERHEAD) as f64 * 8.0 / secs / 1e9
+        };
+
+        Rates {
+            rx_pps: rx_packets as f64 / secs,
+            tx_pps: tx_packets as f64 / secs,
+            rx_gbps: gbps(rx_packets, rx_bytes),
+            tx_gbps: gbps(tx_packets, tx_bytes),
+        }
+    }
+}
+
+impl Measure {
+    /// Start measuring from the current counters of the device.
+    pub fn new<D: IxyDevice + ?Sized>(device: &D) -> Self {
+        let mut last = DeviceStats::default();
+        device.read_stats(&mut last);
+        Measure {
+            last,
+            at: Instant::now(),
+        }
+    }
+
+    /// The rates since the last update, or since creation.
+    pub fn update<D: IxyDevice + ?Sized>(&mut self, device: &D) -> Rates {
+        let now = Instant::now();
+        // Drivers add the counters since their last read, they are cleared on read.
+        let mut stats = self.last;
+        device.read_stats(&mut stats);
+        let rates = Rates::between(&self.last, &stats, now - self.at);
+        self.last = stats;
+        self.at = now;
+        rates
+    }
+}
+
+impl fmt::Display for Rates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RX: {:.2} Mpps, {:.3} Gbit/s | TX: {:.2} Mpps, {:.3} Gbit/s",
+            self.rx_pps / 1e6, self.rx_gbps, self.tx_pps / 1e6, self.tx_gbps)
+    }
+}
+
+/// Accumulates traffic and latency samples over a measurement interval.
+///
+/// Benchmarks record into the window while running and periodically `finish` it to obtain a
+/// summary, which also starts the next interval.
+#[derive(Clone, Debug)]
+pub struct Window {
+    started: Instant,
+    packets: u64,
+    bytes: u64,
+    latencies: Vec<Duration>,
+}
+
+/// The summary of a finished `Window`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    /// The length of the interval.
+    pub duration: Duration,
+
+    /// The number of packets recorded.
+    pub packets: u64,
+
+    /// The number of bytes recorded.
+    pub bytes: u64,
+
+    /// Packets per second.
+    pub pps: f64,
+
+    /// Gbit/s, without wire overhead.
+    pub gbps: f64,
+
+    /// The distribution of latency samples, if any were recorded.
+    pub latency: Option<Latency>,
+}
+
+/// The distribution of latency samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Latency {
+    /// The number of samples.
+    pub samples: usize,
+
+    /// The smallest sample.
+    pub min: Duration,
+
+    /// The arithmetic mean of all samples.
+    pub mean: Duration,
+
+    /// The median.
+    pub p50: Duration,
+
+    /// The 90th percentile.
+    pub p90: Duration,
+
+    /// The 99th percentile.
+    pub p99: Duration,
+
+    /// The largest sample.
+    pub max: Duration,
+}
+
+impl Window {
+    /// Start a window now.
+    pub fn new() -> Self {
+        Window {
+            started: Instant::now(),
+            packets: 0,
+            bytes: 0,
+            latencies: Vec::new(),
+        }
+    }
+
+    /// Record traffic, e.g. after each poll.
+    pub fn record(&mut self, packets: u64, bytes: u64) {
+        self.packets += packets;
+        self.bytes += bytes;
+    }
+
+    /// Record a latency sample.
+    ///
+    /// All samples of the interval are kept until it is finished.
+    pub fn record_latency(&mut self, latency: Duration) {
+        self.latencies.push(latency);
+    }
+
+    /// The time since the interval started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Summarize the interval and start the next one.
+    pub fn finish(&mut self) -> Summary {
+        let now = Instant::now();
+        let duration = now - self.started;
+        let secs = duration.as_secs_f64();
+        let (pps, gbps) = if secs == 0.0 {
+            (0.0, 0.0)
+        } else {
+            (self.packets as f64 / secs, self.bytes as f64 * 8.0 / secs / 1e9)
+        };
+
+        let summary = Summary {
+            duration,
+            packets: self.packets,
+            bytes: self.bytes,
+            pps,
+            gbps,
+            latency: Latency::of(&mut self.latencies),
+        };
+
+        self.started = now;
+        self.packets = 0;
+        self.bytes = 0;
+        self.latencies.clear();
+        summary
+    }
+}
+
+impl Latency {
+    /// The distribution of some samples, reordering them.
+    fn of(samples: &mut [Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        samples.sort_unstable();
+        let total: Duration = samples.iter().sum();
+        // Nearest-rank percentile.
+        let rank = |percent: usize| {
+            let index = (samples.len() * percent + 99) / 100;
+            samples[index.max(1) - 1]
+        };
+
+        Some(Latency {
+            samples: samples.len(),
+            min: samples[0],
+            mean: total / samples.len() as u32,
+            p50: rank(50),
+            p90: rank(90),
+            p99: rank(99),
+            max: samples[samples.len() - 1],
+        })
+    }
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window::new()
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.2} Mpps, {:.3} Gbit/s", self.pps / 1e6, self.gbps)?;
+        if let Some(latency) = &self.latency {
+            write!(f, " | latency p50 {:?}, p99 {:?}, max {:?}",
+                latency.p50, latency.p99, latency.max)?;
+        }
+        Ok(())
+    }
+}
+
+/// The increase of a counter since the last report.
+///
+/// A counter below its reported value was reset in between, it counts from zero again.
+pub(crate) fn delta(current: u64, previous: u64) -> u64 {
+    current.checked_sub(previous).unwrap_or(current)
+}
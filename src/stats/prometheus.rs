@@ -0,0 +1,178 @@
+//! Rendering of device and phy counters in the Prometheus text exposition format.
+//!
+//! The output is independent of the transport. `respond` answers a scrape over any byte stream,
+//! e.g. a `std::net::TcpStream` accepted on a management interface.
+use std::fmt::{self, Write as _};
+use std::io::{self, Read, Write};
+
+use ixy::DeviceStats;
+
+use super::{PhyStats, QueueStats};
+
+/// The counters of one device and its queues, labelled by a device name.
+pub struct Device<'a> {
+    /// The value of the `device` label, e.g. the pci address.
+    pub name: &'a str,
+
+    /// The counters of the device itself.
+    pub stats: DeviceStats,
+
+    /// The counters of each of its phys.
+    pub queues: Vec<(QueueStats, PhyStats)>,
+}
+
+/// Render the counters of some devices.
+///
+/// Samples are grouped by metric family as the format requires, so all devices of a process
+/// should be rendered in a single call.
+pub fn render<W: fmt::Write>(out: &mut W, devices: &[Device]) -> fmt::Result {
+    let device_counters: [(&str, &str, fn(&DeviceStats) -> u64); 4] = [
+        ("ixy_rx_packets_total", "Packets received by the device.", |s| s.rx_pkts),
+        ("ixy_tx_packets_total", "Packets sent by the device.", |s| s.tx_pkts),
+        ("ixy_rx_bytes_total", "Bytes received by the device.", |s| s.rx_bytes),
+        ("ixy_tx_bytes_total", "Bytes sent by the device.", |s| s.tx_bytes),
+    ];
+
+    for &(name, help, value) in device_counters.iter() {
+        header(out, name, help)?;
+        for device in devices {
+            writeln!(out, "{}{{device=\"{}\"}} {}", name, Escaped(device.name), value(&device.stats))?;
+        }
+    }
+
+    let queue_counters: [(&str, &str, fn(&QueueStats, &PhyStats) -> u64); 13] = [
+        ("ixy_queue_rx_packets_total", "Packets received by the queue.", |q, _| q.rx_packets),
+        ("ixy_queue_tx_packets_total", "Packets sent by the queue.", |q, _| q.tx_packets),
+        ("ixy_queue_rx_bytes_total", "Bytes received by the queue.", |q, _| q.rx_bytes),
+        ("ixy_queue_tx_bytes_total", "Bytes sent by the queue.", |q, _| q.tx_bytes),
+        ("ixy_queue_rx_polls_total", "Receive polls of the queue.", |q, _| q.rx_polls),
+        ("ixy_queue_rx_empty_polls_total", "Receive polls without packets.", |q, _| q.rx_empty_polls),
+        ("ixy_queue_tx_flushes_total", "Flushes handing packets to the device.", |q, _| q.tx_flushes),
+        ("ixy_queue_tx_unused_total", "Send buffers not queued by the sender.", |_, p| p.tx_unused),
+        ("ixy_queue_tx_dropped_total", "Queued packets dropped in software.", |_, p| p.tx_dropped),
+        ("ixy_queue_tx_ring_full_total", "Flushes which left packets queued.", |_, p| p.tx_ring_full),
+        ("ixy_queue_rx_filtered_total", "Frames discarded by the MAC filter.", |_, p| p.rx_filtered),
+        ("ixy_queue_rx_steered_dropped_total", "Frames discarded by flow steering.", |_, p| p.rx_steered_dropped),
+        ("ixy_queue_tx_alloc_failed_total", "Exhaustions of the tx pool.", |_, p| p.tx_alloc_failed),
+    ];
+
+    for &(name, help, value) in queue_counters.iter() {
+        header(out, name, help)?;
+        for device in devices {
+            for (queue, phy) in &device.queues {
+                writeln!(out, "{}{{device=\"{}\",queue=\"{}\"}} {}",
+                    name, Escaped(device.name), queue.queue, value(queue, phy))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The longest request head that is read, longer requests are rejected.
+pub const MAX_HEAD: usize = 8 * 1024;
+
+/// Answer a single HTTP request on the stream with a rendered exposition.
+///
+/// The request itself is not interpreted beyond reading its head, every path is answered with
+/// the metrics. A head longer than `MAX_HEAD` is answered with 431, one ended early with 400.
+pub fn respond<S: Read + Write>(mut stream: S, devices: &[Device]) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return reject(stream, "431 Request Header Fields Too Large");
+        }
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return reject(stream, "400 Bad Request");
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+
+    let mut body = String::new();
+    render(&mut body, devices)
+        .expect("Writing to a string can not fail");
+
+    write!(stream, "HTTP/1.0 200 OK\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n", body.len())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+fn reject<S: Write>(mut stream: S, status: &str) -> io::Result<()> {
+    write!(stream, "HTTP/1.0 {}\r\n\
+        Content-Length: 0\r\n\
+        Connection: close\r\n\r\n", status)?;
+    stream.flush()
+}
+
+fn header<W: fmt::Write>(out: &mut W, name: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} counter", name)
+}
+
+/// A label value with backslash, quote and newline escaped.
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ch in self.0.chars() {
+            match ch {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                ch => f.write_char(ch)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// A stream reading a fixed request and recording the response.
+    struct Stream {
+        request: Cursor<Vec<u8>>,
+        response: Vec<u8>,
+    }
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.request.read(buf)
+        }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.response.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn status(request: Vec<u8>) -> String {
+        let mut stream = Stream { request: Cursor::new(request), response: Vec::new() };
+        respond(&mut stream, &[]).unwrap();
+        let response = String::from_utf8(stream.response).unwrap();
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn limits_the_request_head() {
+        assert_eq!(status(b"GET /metrics HTTP/1.0\r\n\r\n".to_vec()), "HTTP/1.0 200 OK");
+        assert_eq!(status(b"GET /metrics HTTP/1.0\r\n".to_vec()), "HTTP/1.0 400 Bad Request");
+
+        let mut long = b"GET /metrics HTTP/1.0\r\nX: ".to_vec();
+        long.resize(2 * MAX_HEAD, b'x');
+        assert_eq!(status(long), "HTTP/1.0 431 Request Header Fields Too Large");
+    }
+}
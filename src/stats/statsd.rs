@@ -0,0 +1,128 @@
+//! Pushing counters as statsd datagrams.
+//!
+//! For deployments without scraping infrastructure. The datagrams are sent through a kernel
+//! `UdpSocket`, which is expected to be bound on a management interface and not on a device
+//! driven by ixy.
+use std::fmt::Write as _;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::{delta, PhyStats, Rates};
+
+/// The values reported for one phy in one interval.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Sample {
+    /// The rates of the device, e.g. from a `Measure`.
+    pub rates: Rates,
+
+    /// The current software counters of the phy.
+    pub phy: PhyStats,
+
+    /// The number of packets waiting to be sent.
+    pub queue_depth: usize,
+}
+
+/// Periodically pushes samples to a statsd server.
+///
+/// Rates and the queue depth are sent as gauges. Drops are sent as counters with the increase
+/// since the previous push.
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    interval: Duration,
+    last_push: Option<Instant>,
+    last_phy: PhyStats,
+    buffer: String,
+}
+
+impl Statsd {
+    /// The default interval between two pushes.
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Push through an already connected socket.
+    ///
+    /// All metric names are prefixed with `prefix` and a dot.
+    pub fn new(socket: UdpSocket, prefix: &str) -> Self {
+        Statsd {
+            socket,
+            prefix: prefix.to_string(),
+            interval: Self::INTERVAL,
+            last_push: None,
+            last_phy: PhyStats::default(),
+            buffer: String::new(),
+        }
+    }
+
+    /// Bind an ephemeral socket and connect it to the statsd server.
+    pub fn connect<A: ToSocketAddrs>(server: A, prefix: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(server)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd::new(socket, prefix))
+    }
+
+    /// The interval between two pushes.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Change the interval between two pushes.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Push a sample if the interval has elapsed since the last push.
+    ///
+    /// The sample is only created when it is due. Returns if a sample was pushed.
+    pub fn poll(&mut self, sample: impl FnOnce() -> Sample) -> io::Result<bool> {
+        let due = match self.last_push {
+            None => true,
+            Some(last) => last.elapsed() >= self.interval,
+        };
+
+        if !due {
+            return Ok(false);
+        }
+
+        self.push(&sample())?;
+        Ok(true)
+    }
+
+    /// Push a sample immediately.
+    pub fn push(&mut self, sample: &Sample) -> io::Result<()> {
+        let prefix = &self.prefix;
+        let buffer = &mut self.buffer;
+        let last = &self.last_phy;
+        buffer.clear();
+
+        let gauges = [
+            ("rx_pps", sample.rates.rx_pps),
+            ("tx_pps", sample.rates.tx_pps),
+            ("rx_bps", sample.rates.rx_gbps * 1e9),
+            ("tx_bps", sample.rates.tx_gbps * 1e9),
+            ("queue_depth", sample.queue_depth as f64),
+        ];
+        for &(name, value) in gauges.iter() {
+            let _ = writeln!(buffer, "{}.{}:{:.0}|g", prefix, name, value);
+        }
+
+        let phy = &sample.phy;
+        let counters = [
+            ("tx_dropped", delta(phy.tx_dropped, last.tx_dropped)),
+            ("tx_ring_full", delta(phy.tx_ring_full, last.tx_ring_full)),
+            ("rx_filtered", delta(phy.rx_filtered, last.rx_filtered)),
+            ("rx_steered_dropped", delta(phy.rx_steered_dropped, last.rx_steered_dropped)),
+            ("tx_alloc_failed", delta(phy.tx_alloc_failed, last.tx_alloc_failed)),
+        ];
+        for &(name, value) in counters.iter() {
+            let _ = writeln!(buffer, "{}.{}:{}|c", prefix, name, value);
+        }
+
+        // The counters are considered reported even when the datagram was lost.
+        self.last_push = Some(Instant::now());
+        self.last_phy = sample.phy;
+        self.socket.send(self.buffer.trim_end().as_bytes())?;
+        Ok(())
+    }
+}
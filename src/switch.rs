@@ -0,0 +1,233 @@
+//! A learning Ethernet switch between phys.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use ixy::memory::{self, Packet as IxyPacket};
+
+use crate::frame::ETHERNET_HEADER;
+use crate::{Phy, Queues};
+
+/// Counters of a port of a switch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PortStats {
+    /// Frames received on the port and forwarded to a single port.
+    pub forwarded: u64,
+
+    /// Frames received on the port and flooded to all others.
+    pub flooded: u64,
+
+    /// Frames received on the port for an address on the same port, or too short.
+    pub filtered: u64,
+
+    /// Frames queued for sending on the port.
+    pub tx_packets: u64,
+
+    /// Copies of flooded frames for the port which could not be allocated.
+    pub tx_dropped: u64,
+}
+
+/// Forwards frames between any number of phys like an Ethernet switch.
+///
+/// The port of each source address is learned from received frames and expires after the aging
+/// time. Frames to a learned address are moved to the send queue of its port without copying,
+/// like in `Phy::forward` the buffer returns to the receive pool once sent. Broadcast, multicast
+/// and frames to unknown addresses are flooded to all other ports, a copy is allocated from the
+/// pool of each port except the last. Frames to an address on their own port are dropped.
+///
+/// There is no spanning tree, the ports must not form a loop.
+pub struct Switch<D> {
+    ports: Vec<Phy<D>>,
+    stats: Vec<PortStats>,
+    learned: HashMap<[u8; 6], Learned>,
+    aging: Duration,
+    capacity: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Learned {
+    port: usize,
+    seen: Instant,
+}
+
+impl<D: Queues> Switch<D> {
+    /// The default time after which learned addresses expire.
+    pub const AGING: Duration = Duration::from_secs(300);
+
+    /// The default maximum number of learned addresses.
+    pub const CAPACITY: usize = 8192;
+
+    pub fn new() -> Self {
+        Switch {
+            ports: Vec::new(),
+            stats: Vec::new(),
+            learned: HashMap::new(),
+            aging: Self::AGING,
+            capacity: Self::CAPACITY,
+        }
+    }
+
+    /// Add a port, returning its index.
+    pub fn add_port(&mut self, phy: Phy<D>) -> usize {
+        self.ports.push(phy);
+        self.stats.push(PortStats::default());
+        self.ports.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.ports.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+    }
+
+    pub fn port(&self, index: usize) -> &Phy<D> {
+        &self.ports[index]
+    }
+
+    pub fn port_mut(&mut self, index: usize) -> &mut Phy<D> {
+        &mut self.ports[index]
+    }
+
+    pub fn stats(&self, index: usize) -> PortStats {
+        self.stats[index]
+    }
+
+    pub fn aging(&self) -> Duration {
+        self.aging
+    }
+
+    pub fn set_aging(&mut self, aging: Duration) {
+        self.aging = aging;
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Limit the number of learned addresses, frames to others are flooded.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    /// The learned addresses and their ports.
+    pub fn learned(&self) -> Vec<([u8; 6], usize)> {
+        self.learned
+            .iter()
+            .filter(|(_, learned)| learned.seen.elapsed() < self.aging)
+            .map(|(&mac, learned)| (mac, learned.port))
+            .collect()
+    }
+
+    /// Forget all learned addresses.
+    pub fn clear_learned(&mut self) {
+        self.learned.clear();
+    }
+
+    pub fn into_ports(self) -> Vec<Phy<D>> {
+        self.ports
+    }
+
+    /// Forward up to `max` received frames of each port.
+    ///
+    /// Afterwards the flush policy of each port is honored. Returns the number of frames received.
+    pub fn poll(&mut self, max: usize) -> usize {
+        let now = Instant::now();
+        let mut received = 0;
+        for index in 0..self.ports.len() {
+            let port = &mut self.ports[index];
+            port.get_rx(max);
+            let count = port.rx_queue.len().min(max);
+            let frames = port.rx_queue.drain(..count).collect::<Vec<_>>();
+            received += frames.len();
+            for frame in frames {
+                self.switch(index, frame, now);
+            }
+        }
+
+        for port in &mut self.ports {
+            port.poll_flush();
+        }
+        received
+    }
+
+    fn switch(&mut self, from: usize, frame: IxyPacket, now: Instant) {
+        if frame.len() < ETHERNET_HEADER {
+            self.stats[from].filtered += 1;
+            return;
+        }
+
+        let mut source = [0; 6];
+        source.copy_from_slice(&frame[6..12]);
+        if source[0] & 1 == 0 {
+            self.learn(source, from, now);
+        }
+
+        let mut destination = [0; 6];
+        destination.copy_from_slice(&frame[0..6]);
+        let target = match destination[0] & 1 {
+            0 => self.lookup(destination, now),
+            _ => None,
+        };
+
+        match target {
+            Some(to) if to == from => self.stats[from].filtered += 1,
+            Some(to) => {
+                self.stats[from].forwarded += 1;
+                self.stats[to].tx_packets += 1;
+                self.ports[to].tx_queue.push_back(frame);
+            },
+            None => self.flood(from, frame),
+        }
+    }
+
+    /// Send a frame on all ports but the one it was received on.
+    fn flood(&mut self, from: usize, frame: IxyPacket) {
+        self.stats[from].flooded += 1;
+        let last = match (0..self.ports.len()).rev().find(|&index| index != from) {
+            Some(last) => last,
+            None => return,
+        };
+
+        for to in (0..last).filter(|&index| index != from) {
+            let port = &mut self.ports[to];
+            match memory::alloc_pkt(&port.pool, frame.len()) {
+                Some(mut copy) => {
+                    copy.copy_from_slice(&frame);
+                    port.tx_queue.push_back(copy);
+                    self.stats[to].tx_packets += 1;
+                },
+                None => self.stats[to].tx_dropped += 1,
+            }
+        }
+
+        self.ports[last].tx_queue.push_back(frame);
+        self.stats[last].tx_packets += 1;
+    }
+
+    fn learn(&mut self, mac: [u8; 6], port: usize, now: Instant) {
+        if self.learned.len() >= self.capacity && !self.learned.contains_key(&mac) {
+            let aging = self.aging;
+            self.learned.retain(|_, learned| now.duration_since(learned.seen) < aging);
+            if self.learned.len() >= self.capacity {
+                return;
+            }
+        }
+        self.learned.insert(mac, Learned { port, seen: now });
+    }
+
+    fn lookup(&mut self, mac: [u8; 6], now: Instant) -> Option<usize> {
+        let learned = *self.learned.get(&mac)?;
+        if now.duration_since(learned.seen) >= self.aging {
+            self.learned.remove(&mac);
+            return None;
+        }
+        Some(learned.port)
+    }
+}
+
+impl<D: Queues> Default for Switch<D> {
+    fn default() -> Self {
+        Switch::new()
+    }
+}
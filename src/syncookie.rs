@@ -0,0 +1,511 @@
+//! SYN cookies in front of the TCP listeners of the network stack.
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::SocketAddrV4;
+use std::time::{Duration, Instant};
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ixy::memory::{self, Packet as IxyPacket};
+
+use crate::checksum::{self, read_u16, update, write_u16};
+use crate::frame::{Headers, ETHERNET_HEADER, ETHERTYPE_IPV4, MIN_FRAME, PROTO_TCP, ipv4};
+use crate::{Conntrack, FiveTuple, Handle, Packet, Phy, Queues};
+
+/// Counters of a SYN guard.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SynGuardStats {
+    /// SYNs passed to the network stack while it had few half-open connections.
+    pub passed: u64,
+
+    /// SYNs answered with a cookie instead.
+    pub cookies_sent: u64,
+
+    /// ACKs with a valid cookie, whose connection was opened with the network stack.
+    pub cookies_accepted: u64,
+}
+
+/// Protects the TCP listeners of the network stack from SYN floods with SYN cookies.
+///
+/// The guard counts the connections the network stack did not yet establish. Once they reach the
+/// threshold, further SYNs are not passed to the stack but answered by the guard, with a cookie
+/// as the initial sequence number which encodes the connection, the time and the MSS of the
+/// client. Only a client which acknowledges a valid cookie gets a connection: the guard then
+/// opens it with the network stack on behalf of the client and translates the sequence numbers
+/// of the stack for the rest of the connection, like a SYN proxy.
+///
+/// Connections opened from cookies offer no TCP options but the MSS, e.g. no window scaling.
+/// Cookies are valid for one to two minutes and keyed by a random secret. Only untagged IPv4 is
+/// guarded, to the ports of the listeners or all ports.
+pub struct SynGuard<D> {
+    phy: Phy<D>,
+    ports: Vec<u16>,
+    threshold: usize,
+    keys: RandomState,
+    epoch: Instant,
+    /// SYNs passed to the network stack whose handshake did not complete.
+    half_open: HashMap<FiveTuple, Instant>,
+    /// Connections opened from cookies, by the flow of the client.
+    proxied: Conntrack<Proxy>,
+    /// When the last cookie was sent, ACKs are only checked for cookies for a while after.
+    last_cookie: Option<Instant>,
+    last_sweep: Instant,
+    stats: SynGuardStats,
+}
+
+struct Proxy {
+    /// The sequence number the guard chose for the server.
+    cookie: u32,
+    /// The initial sequence number of the network stack minus the cookie, once it answered.
+    delta: Option<u32>,
+    /// The ACK of the client, passed on once the network stack answered.
+    ack: Option<IxyPacket>,
+}
+
+/// The TCP fields of an IPv4 packet.
+struct Segment {
+    l4: usize,
+    /// The length of the IP packet.
+    len: usize,
+    flow: FiveTuple,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+}
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+
+/// The MSS values a cookie can encode.
+const MSS: [u16; 8] = [536, 1220, 1300, 1360, 1400, 1440, 1460, 8960];
+
+/// The window offered with a cookie, without scaling.
+const WINDOW: u16 = 65535;
+
+/// The lifetime of a cookie in seconds, which is the resolution of the time encoded in it.
+const COOKIE_PERIOD: u64 = 64;
+
+/// An Ethernet frame with IPv4 and TCP with the MSS option.
+const SEGMENT_FRAME: usize = ETHERNET_HEADER + 20 + 24;
+
+impl<D> SynGuard<D> {
+    /// The default number of half-open connections before cookies are used.
+    pub const THRESHOLD: usize = 256;
+
+    /// The time after which a SYN passed to the network stack no longer counts as half-open.
+    pub const HALF_OPEN: Duration = Duration::from_secs(10);
+
+    /// How often stale half-open and proxied connections are removed.
+    const SWEEP: Duration = Duration::from_secs(1);
+
+    /// Guard all ports of the network stack.
+    pub fn new(phy: Phy<D>) -> Self {
+        SynGuard {
+            phy,
+            ports: Vec::new(),
+            threshold: Self::THRESHOLD,
+            keys: RandomState::new(),
+            epoch: Instant::now(),
+            half_open: HashMap::new(),
+            proxied: Conntrack::new(),
+            last_cookie: None,
+            last_sweep: Instant::now(),
+            stats: SynGuardStats::default(),
+        }
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    /// The guarded ports, all ports if empty.
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Only guard the ports of listeners, an empty list guards all ports.
+    pub fn set_ports(&mut self, ports: Vec<u16>) {
+        self.ports = ports;
+    }
+
+    pub fn threshold(&self) -> usize {
+        self.threshold
+    }
+
+    /// Use cookies once the network stack has this many half-open connections, zero always uses
+    /// cookies.
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    /// The number of half-open connections of the network stack.
+    pub fn half_open(&self) -> usize {
+        self.half_open.len()
+    }
+
+    /// The number of connections opened from cookies, including idle ones not yet removed.
+    pub fn proxied(&self) -> usize {
+        self.proxied.len()
+    }
+
+    pub fn stats(&self) -> SynGuardStats {
+        self.stats
+    }
+
+    /// Unwrap the phy, connections opened from cookies break.
+    pub fn into_inner(self) -> Phy<D> {
+        self.phy
+    }
+
+    fn guards(&self, port: u16) -> bool {
+        self.ports.is_empty() || self.ports.contains(&port)
+    }
+
+    /// The current period of cookies.
+    fn period(&self, now: Instant) -> u32 {
+        (now.duration_since(self.epoch).as_secs() / COOKIE_PERIOD) as u32
+    }
+
+    /// The cookie of a client flow with its initial sequence number, in a period.
+    fn cookie(&self, flow: &FiveTuple, isn: u32, period: u32, mss: usize) -> u32 {
+        let mut hasher = self.keys.build_hasher();
+        (flow, isn, period).hash(&mut hasher);
+        (period & 0x1f) << 27 | (mss as u32) << 24 | (hasher.finish() as u32 & 0x00ff_ffff)
+    }
+
+    /// The MSS of a valid cookie of the current or previous period.
+    fn check(&self, flow: &FiveTuple, isn: u32, cookie: u32, now: Instant) -> Option<u16> {
+        let current = self.period(now);
+        let age = current.wrapping_sub(cookie >> 27) & 0x1f;
+        if age > 1 || age > current {
+            return None;
+        }
+        let mss = (cookie >> 24 & 0x07) as usize;
+        if self.cookie(flow, isn, current - age, mss) == cookie {
+            Some(MSS[mss])
+        } else {
+            None
+        }
+    }
+
+    /// Remove stale half-open and proxied connections about once a second.
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < Self::SWEEP {
+            return;
+        }
+        self.last_sweep = now;
+        self.half_open.retain(|_, &mut seen| now.duration_since(seen) < Self::HALF_OPEN);
+        self.proxied.expire(now);
+    }
+}
+
+impl<D: Queues> SynGuard<D> {
+    /// Receive up to `max` packets into the phy and guard them.
+    fn receive(&mut self, max: usize) {
+        let now = Instant::now();
+        self.sweep(now);
+
+        // Packets buffered from earlier calls have been checked already.
+        let checked = self.phy.rx_queue.len();
+        self.phy.get_rx(max);
+        for packet in self.phy.rx_queue.split_off(checked) {
+            self.inbound(packet, now);
+        }
+    }
+
+    fn inbound(&mut self, mut packet: IxyPacket, now: Instant) {
+        let segment = match Segment::parse(&packet) {
+            Some(segment) if self.guards(segment.dst.port()) => segment,
+            _ => return self.phy.rx_queue.push_back(packet),
+        };
+
+        let flow = segment.flow;
+        let closing = segment.flags & (FIN | RST) != 0;
+        if let Some(connection) = self.proxied.update(&flow, segment.len, closing, now) {
+            // Segments before the network stack answered are retransmitted by the client.
+            if let Some(delta) = connection.data.delta {
+                translate(&mut packet, &segment, 8, delta);
+                self.phy.rx_queue.push_back(packet);
+            }
+            return;
+        }
+
+        match segment.flags & (SYN | ACK | RST) {
+            SYN if self.half_open.len() < self.threshold || self.half_open.contains_key(&flow) => {
+                self.half_open.insert(flow, now);
+                self.stats.passed += 1;
+                self.phy.rx_queue.push_back(packet);
+            },
+            SYN => {
+                let mss = segment.mss.unwrap_or(MSS[0]);
+                let index = MSS.iter().rposition(|&value| value <= mss).unwrap_or(0);
+                let cookie = self.cookie(&flow, segment.seq, self.period(now), index);
+                let mtu_mss = self.phy.mtu().saturating_sub(40).min(0xffff) as u16;
+                if syn_ack(&mut packet, &segment, cookie, mss.min(mtu_mss)) {
+                    self.last_cookie = Some(now);
+                    self.stats.cookies_sent += 1;
+                    self.phy.tx_queue.push_back(packet);
+                }
+            },
+            ACK if self.half_open.remove(&flow).is_some() => self.phy.rx_queue.push_back(packet),
+            ACK if self.cookies_recent(now) => {
+                let (isn, cookie) = (segment.seq.wrapping_sub(1), segment.ack.wrapping_sub(1));
+                match self.check(&flow, isn, cookie, now) {
+                    Some(mss) => self.open(packet, &segment, mss, now),
+                    None => self.phy.rx_queue.push_back(packet),
+                }
+            },
+            _ => {
+                if segment.flags & RST != 0 {
+                    self.half_open.remove(&flow);
+                }
+                self.phy.rx_queue.push_back(packet);
+            },
+        }
+    }
+
+    fn cookies_recent(&self, now: Instant) -> bool {
+        let valid = Duration::from_secs(2 * COOKIE_PERIOD);
+        self.last_cookie.map_or(false, |sent| now.duration_since(sent) < valid)
+    }
+
+    /// Open the connection of a valid cookie with the network stack, holding the ACK.
+    fn open(&mut self, ack: IxyPacket, segment: &Segment, mss: u16, now: Instant) {
+        let mut syn = match memory::alloc_pkt(&self.phy.pool, MIN_FRAME) {
+            Some(packet) => packet,
+            None => return,
+        };
+
+        syn.copy_from_slice(&[0; MIN_FRAME]);
+        syn[..6].copy_from_slice(&self.phy.mac_address());
+        syn[6..12].copy_from_slice(&ack[6..12]);
+        let (isn, window) = (segment.seq.wrapping_sub(1), segment.window);
+        let addresses = (segment.src, segment.dst);
+        write_segment(&mut syn, addresses, isn, 0, SYN, window, Some(mss));
+
+        let proxy = Proxy { cookie: segment.ack.wrapping_sub(1), delta: None, ack: Some(ack) };
+        let flow = segment.flow;
+        self.proxied.insert(flow, flow.reversed(), proxy, now);
+        self.proxied.update(&flow, segment.len, false, now);
+        self.stats.cookies_accepted += 1;
+        self.phy.rx_queue.push_back(syn);
+    }
+
+    /// Pass the packets the network stack queued after the first `start`, translating those of
+    /// connections opened from cookies.
+    fn transmit(&mut self, start: usize) {
+        let now = Instant::now();
+        for packet in self.phy.tx_queue.split_off(start) {
+            match Segment::parse(&packet) {
+                Some(segment) if self.guards(segment.src.port()) => {
+                    self.outbound(packet, &segment, now);
+                },
+                _ => self.phy.tx_queue.push_back(packet),
+            }
+        }
+    }
+
+    fn outbound(&mut self, mut packet: IxyPacket, segment: &Segment, now: Instant) {
+        let closing = segment.flags & (FIN | RST) != 0;
+        let proxy = match self.proxied.update(&segment.flow, segment.len, closing, now) {
+            Some(connection) => &mut connection.data,
+            None => return self.phy.tx_queue.push_back(packet),
+        };
+
+        if let Some(delta) = proxy.delta {
+            translate(&mut packet, segment, 4, delta.wrapping_neg());
+            return self.phy.tx_queue.push_back(packet);
+        }
+
+        if segment.flags & (SYN | ACK) == SYN | ACK {
+            // The handshake with the network stack completes with the held ACK of the client.
+            let delta = segment.seq.wrapping_sub(proxy.cookie);
+            proxy.delta = Some(delta);
+            if let Some(mut ack) = proxy.ack.take() {
+                if let Some(held) = Segment::parse(&ack) {
+                    translate(&mut ack, &held, 8, delta);
+                    self.phy.rx_queue.push_back(ack);
+                }
+            }
+        } else if segment.flags & RST != 0 {
+            // The network stack refused the connection, e.g. as nothing listens on the port.
+            let cookie = proxy.cookie;
+            self.proxied.remove(&segment.flow);
+            let old = segment.seq;
+            translate(&mut packet, segment, 4, cookie.wrapping_add(1).wrapping_sub(old));
+            self.phy.tx_queue.push_back(packet);
+        }
+    }
+
+    /// Run an operation of the network stack, then guard what it queued before sending.
+    fn deferred<T>(&mut self, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, start) = self.phy.deferred(op);
+        self.transmit(start);
+        self.phy.poll_flush();
+        result
+    }
+}
+
+impl<D: Queues> nic::Device for SynGuard<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.phy)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.tx(max, sender))
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.receive(max);
+        // Only offer the guarded packets, the phy must not fetch unchecked ones.
+        let count = max.min(self.phy.rx_queue.len());
+        self.deferred(|phy| phy.rx(count, receptor))
+    }
+}
+
+impl Segment {
+    /// Locate the fields of an untagged, unfragmented IPv4 TCP segment.
+    fn parse(frame: &[u8]) -> Option<Self> {
+        let headers = Headers::parse(frame)?;
+        if headers.ethertype != ETHERTYPE_IPV4 || headers.vlan.is_some() {
+            return None;
+        }
+        let l4 = headers.transport(PROTO_TCP)?;
+        let header = usize::from(*frame.get(l4 + 12)? >> 4) * 4;
+        if header < 20 || l4 + header > headers.end {
+            return None;
+        }
+
+        let l3 = headers.l3;
+        let (src_port, dst_port) = (read_u16(frame, l4), read_u16(frame, l4 + 2));
+        let src = SocketAddrV4::new(ipv4(&frame[l3 + 12..l3 + 16]), src_port);
+        let dst = SocketAddrV4::new(ipv4(&frame[l3 + 16..l3 + 20]), dst_port);
+        let flags = frame[l4 + 13];
+        Some(Segment {
+            l4,
+            len: headers.end - l3,
+            flow: FiveTuple {
+                src: (*src.ip()).into(),
+                dst: (*dst.ip()).into(),
+                src_port,
+                dst_port,
+                protocol: PROTO_TCP,
+            },
+            src,
+            dst,
+            seq: read_u32(frame, l4 + 4),
+            ack: read_u32(frame, l4 + 8),
+            flags,
+            window: read_u16(frame, l4 + 14),
+            mss: if flags & SYN != 0 { mss_option(&frame[l4 + 20..l4 + header]) } else { None },
+        })
+    }
+}
+
+/// Turn a SYN into the SYN-ACK with a cookie, in place.
+fn syn_ack(packet: &mut IxyPacket, segment: &Segment, cookie: u32, mss: u16) -> bool {
+    if packet.try_resize(SEGMENT_FRAME.max(MIN_FRAME), 0u8).is_err() {
+        return false;
+    }
+
+    let mut macs = [0; 12];
+    macs.copy_from_slice(&packet[..12]);
+    packet[..6].copy_from_slice(&macs[6..]);
+    packet[6..12].copy_from_slice(&macs[..6]);
+    let ack = segment.seq.wrapping_add(1);
+    let addresses = (segment.dst, segment.src);
+    write_segment(packet, addresses, cookie, ack, SYN | ACK, WINDOW, Some(mss));
+    true
+}
+
+/// Write the IPv4 and TCP headers of a segment without payload after the Ethernet addresses.
+fn write_segment(
+    frame: &mut [u8],
+    (src, dst): (SocketAddrV4, SocketAddrV4),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+) {
+    let tcp_len = if mss.is_some() { 24 } else { 20 };
+    write_u16(frame, 12, ETHERTYPE_IPV4);
+
+    let ip = &mut frame[ETHERNET_HEADER..ETHERNET_HEADER + 20 + tcp_len];
+    ip[..12].copy_from_slice(&[0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, PROTO_TCP, 0, 0]);
+    write_u16(ip, 2, (20 + tcp_len) as u16);
+    ip[12..16].copy_from_slice(&src.ip().octets());
+    ip[16..20].copy_from_slice(&dst.ip().octets());
+    let sum = checksum::finish(checksum::sum(&ip[..20], 0));
+    write_u16(ip, 10, sum);
+
+    let pseudo = checksum::sum(&ip[12..20], u32::from(PROTO_TCP) + tcp_len as u32);
+    let tcp = &mut ip[20..];
+    write_u16(tcp, 0, src.port());
+    write_u16(tcp, 2, dst.port());
+    tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+    tcp[8..12].copy_from_slice(&ack.to_be_bytes());
+    tcp[12..20].copy_from_slice(&[(tcp_len as u8 / 4) << 4, flags, 0, 0, 0, 0, 0, 0]);
+    write_u16(tcp, 14, window);
+    if let Some(mss) = mss {
+        tcp[20..22].copy_from_slice(&[2, 4]);
+        write_u16(tcp, 22, mss);
+    }
+    let sum = checksum::finish(checksum::sum(tcp, pseudo));
+    write_u16(tcp, 16, sum);
+}
+
+/// Add to the sequence number at an offset in the TCP header, updating the checksum.
+fn translate(frame: &mut [u8], segment: &Segment, offset: usize, delta: u32) {
+    let at = segment.l4 + offset;
+    let old = read_u32(frame, at);
+    let new = old.wrapping_add(delta);
+    let mut sum = read_u16(frame, segment.l4 + 16);
+    sum = update(sum, (old >> 16) as u16, (new >> 16) as u16);
+    sum = update(sum, old as u16, new as u16);
+    frame[at..at + 4].copy_from_slice(&new.to_be_bytes());
+    write_u16(frame, segment.l4 + 16, sum);
+}
+
+/// The value of the MSS option.
+fn mss_option(mut options: &[u8]) -> Option<u16> {
+    while let Some(&kind) = options.first() {
+        match kind {
+            0 => return None,
+            1 => options = &options[1..],
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                if len < 2 || len > options.len() {
+                    return None;
+                }
+                if kind == 2 && len == 4 {
+                    return Some(read_u16(options, 2));
+                }
+                options = &options[len..];
+            },
+        }
+    }
+    None
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
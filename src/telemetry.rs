@@ -0,0 +1,88 @@
+//! Reporting of the software counters through the `metrics` facade.
+//!
+//! The hot path only updates the plain counters of the phy. They are forwarded to the recorder
+//! as deltas once every `PERIOD` batches, which keeps the cost of a recorder call and of the
+//! label allocation out of the per-packet and per-batch work.
+use metrics::{counter, gauge, histogram};
+
+use crate::stats::{delta, PhyStats, QueueStats};
+
+pub(crate) struct Reporter {
+    /// The `queue` label, formatted on first report.
+    label: Option<String>,
+    batches: u32,
+    reported: QueueStats,
+    reported_phy: PhyStats,
+    /// The sizes of non-empty rx batches since the last report.
+    rx_batches: Vec<u32>,
+}
+
+impl Reporter {
+    /// The number of batches between two reports.
+    const PERIOD: u32 = 64;
+
+    pub fn new() -> Self {
+        Reporter {
+            label: None,
+            batches: 0,
+            reported: QueueStats::default(),
+            reported_phy: PhyStats::default(),
+            rx_batches: Vec::with_capacity(Self::PERIOD as usize),
+        }
+    }
+
+    /// Note the size of a received batch.
+    pub fn rx_batch(&mut self, received: usize) {
+        if self.rx_batches.len() < self.rx_batches.capacity() {
+            self.rx_batches.push(received as u32);
+        }
+    }
+
+    /// Account for a processed batch, reporting if the period is over.
+    pub fn tick(&mut self, stats: &QueueStats, phy: &PhyStats, tx_pending: usize) {
+        self.batches += 1;
+        if self.batches >= Self::PERIOD {
+            self.report(stats, phy, tx_pending);
+        }
+    }
+
+    fn report(&mut self, stats: &QueueStats, phy: &PhyStats, tx_pending: usize) {
+        let label = self.label
+            .get_or_insert_with(|| stats.queue.to_string())
+            .clone();
+        let old = &self.reported;
+        let old_phy = &self.reported_phy;
+
+        let counters = [
+            ("ixy_net_rx_packets", delta(stats.rx_packets, old.rx_packets)),
+            ("ixy_net_tx_packets", delta(stats.tx_packets, old.tx_packets)),
+            ("ixy_net_rx_bytes", delta(stats.rx_bytes, old.rx_bytes)),
+            ("ixy_net_tx_bytes", delta(stats.tx_bytes, old.tx_bytes)),
+            ("ixy_net_rx_polls", delta(stats.rx_polls, old.rx_polls)),
+            ("ixy_net_rx_empty_polls", delta(stats.rx_empty_polls, old.rx_empty_polls)),
+            ("ixy_net_tx_flushes", delta(stats.tx_flushes, old.tx_flushes)),
+            ("ixy_net_tx_dropped", delta(phy.tx_dropped, old_phy.tx_dropped)),
+            ("ixy_net_tx_ring_full", delta(phy.tx_ring_full, old_phy.tx_ring_full)),
+            ("ixy_net_rx_filtered", delta(phy.rx_filtered, old_phy.rx_filtered)),
+            ("ixy_net_rx_steered_dropped",
+                delta(phy.rx_steered_dropped, old_phy.rx_steered_dropped)),
+            ("ixy_net_tx_alloc_failed", delta(phy.tx_alloc_failed, old_phy.tx_alloc_failed)),
+        ];
+
+        for &(name, increase) in counters.iter() {
+            if increase > 0 {
+                counter!(name, increase, "queue" => label.clone());
+            }
+        }
+
+        gauge!("ixy_net_tx_pending", tx_pending as f64, "queue" => label.clone());
+        for &size in &self.rx_batches {
+            histogram!("ixy_net_rx_batch_size", f64::from(size), "queue" => label.clone());
+        }
+
+        self.batches = 0;
+        self.rx_batches.clear();
+        self.reported = *stats;
+        self.reported_phy = *phy;
+    }
+}
@@ -0,0 +1,105 @@
+//! TLS with rustls over a blocking stream, enabled by the `tls` feature.
+//!
+//! Meant for the `Read` and `Write` adapters of the sockets, `sockets::blocking::BlockingStream`
+//! and `net::TcpStream`, which poll the stack while rustls waits for records.
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use rustls::{ClientConfig, ClientConnection, Connection, ServerConfig, ServerConnection};
+use rustls::pki_types::ServerName;
+
+/// A TLS session over a stream.
+///
+/// The handshake completes on construction. Reads return `0` once the peer sent its close
+/// notification, a peer which closes the connection without one fails the read with
+/// `UnexpectedEof`.
+pub struct TlsStream<S> {
+    connection: Connection,
+    stream: S,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    /// Start a session as the client, verifying the server name.
+    pub fn connect(stream: S, config: Arc<ClientConfig>, name: ServerName<'static>)
+        -> io::Result<Self>
+    {
+        let connection = ClientConnection::new(config, name).map_err(tls_error)?;
+        TlsStream::handshake(connection.into(), stream)
+    }
+
+    /// Start a session as the server.
+    pub fn accept(stream: S, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let connection = ServerConnection::new(config).map_err(tls_error)?;
+        TlsStream::handshake(connection.into(), stream)
+    }
+
+    /// The state of the session, e.g. the negotiated protocol.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Send the close notification.
+    ///
+    /// The stream stays open, e.g. to wait for the notification of the peer.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.connection.send_close_notify();
+        self.flush()
+    }
+
+    pub fn into_inner(self) -> (Connection, S) {
+        (self.connection, self.stream)
+    }
+
+    fn handshake(mut connection: Connection, mut stream: S) -> io::Result<Self> {
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+        Ok(TlsStream { connection, stream })
+    }
+
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut self.stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.connection.reader().read(buf) {
+                // No plaintext yet, receive more records.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                result => return result,
+            }
+            self.connection.complete_io(&mut self.stream)?;
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.connection.writer().write(buf)?;
+        self.write_records()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer().flush()?;
+        self.write_records()?;
+        self.stream.flush()
+    }
+}
+
+fn tls_error(err: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
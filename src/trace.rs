@@ -0,0 +1,198 @@
+//! Printing the traffic of a device for debugging.
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+
+use crate::Queued;
+use crate::flow::FiveTuple;
+use crate::frame::{self, Headers};
+use crate::pcap::{Capture, Direction, Dump, Sampled, Sampling};
+
+/// A device wrapper calling a closure with each received and sent frame.
+///
+/// The frames are observed exactly as by `Capture`. Their `Display` gives a one line summary,
+/// `Frame::hexdump` the raw bytes. To keep up with high rates only trace a sample of the frames,
+/// see `set_sampling`.
+pub struct Tracer<D, F> {
+    capture: Capture<D, Sampled<Trace<F>>>,
+}
+
+/// A frame passing a `Tracer`.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame<'a> {
+    direction: Direction,
+    timestamp: Duration,
+    bytes: &'a [u8],
+}
+
+/// Formats bytes as lines of offset, hex and ascii.
+#[derive(Clone, Copy, Debug)]
+pub struct Hexdump<'a>(pub &'a [u8]);
+
+struct Trace<F>(F);
+
+impl<D, F> Tracer<D, F>
+where
+    F: FnMut(Frame),
+{
+    /// Trace all frames.
+    pub fn new(device: D, trace: F) -> Self {
+        Self::sampled(device, Sampling::All, trace)
+    }
+
+    /// Trace only a sample of the frames.
+    pub fn sampled(device: D, sampling: Sampling, trace: F) -> Self {
+        Tracer {
+            capture: Capture::new(device, Sampled::new(Trace(trace), sampling)),
+        }
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        self.capture.dump().sampling()
+    }
+
+    /// Change the sampling, effective from the next frame.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.capture.dump_mut().set_sampling(sampling)
+    }
+
+    pub fn device(&self) -> &D {
+        self.capture.device()
+    }
+
+    pub fn device_mut(&mut self) -> &mut D {
+        self.capture.device_mut()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.capture.into_inner().0
+    }
+}
+
+impl<'a> Frame<'a> {
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The time since the unix epoch at which the frame passed the tracer.
+    pub fn timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn hexdump(&self) -> Hexdump<'a> {
+        Hexdump(self.bytes)
+    }
+}
+
+impl<F: FnMut(Frame)> Dump for Trace<F> {
+    fn dump(&mut self, direction: Direction, timestamp: Duration, bytes: &[u8]) -> io::Result<()> {
+        (self.0)(Frame { direction, timestamp, bytes });
+        Ok(())
+    }
+}
+
+impl<D, F> nic::Device for Tracer<D, F>
+where
+    D: nic::Device,
+    D::Handle: Queued,
+    F: FnMut(Frame),
+{
+    type Handle = D::Handle;
+    type Payload = D::Payload;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Device::personality(&self.capture)
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        nic::Device::tx(&mut self.capture, max, sender)
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        nic::Device::rx(&mut self.capture, max, receptor)
+    }
+}
+
+impl fmt::Display for Frame<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        };
+        write!(f, "{} {}B", direction, self.bytes.len())?;
+
+        let headers = match Headers::parse(self.bytes) {
+            Some(headers) => headers,
+            None => return write!(f, " malformed"),
+        };
+
+        write!(f, " {} > {}", Mac(&self.bytes[6..12]), Mac(&self.bytes[0..6]))?;
+        if let Some(vlan) = headers.vlan {
+            write!(f, " vlan {}", vlan)?;
+        }
+
+        if let Some(flow) = FiveTuple::from_headers(self.bytes, &headers) {
+            let protocol = match flow.protocol {
+                frame::PROTO_TCP => "TCP",
+                frame::PROTO_UDP => "UDP",
+                frame::PROTO_ICMP => "ICMP",
+                frame::PROTO_ICMPV6 => "ICMPv6",
+                _ => "IP",
+            };
+            let src = SocketAddr::new(flow.src, flow.src_port);
+            let dst = SocketAddr::new(flow.dst, flow.dst_port);
+            return match flow.protocol {
+                frame::PROTO_TCP | frame::PROTO_UDP => write!(f, " {} {} > {}", protocol, src, dst),
+                other => write!(f, " {} ({}) {} > {}", protocol, other, flow.src, flow.dst),
+            };
+        }
+
+        match headers.ethertype {
+            frame::ETHERTYPE_ARP => write!(f, " ARP"),
+            other => write!(f, " ethertype {:#06x}", other),
+        }
+    }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (line, chunk) in self.0.chunks(16).enumerate() {
+            write!(f, "{:04x} ", line * 16)?;
+            for column in 0..16 {
+                match chunk.get(column) {
+                    Some(byte) => write!(f, " {:02x}", byte)?,
+                    None => f.write_str("   ")?,
+                }
+            }
+            f.write_str("  ")?;
+            for &byte in chunk {
+                let ch = if byte.is_ascii_graphic() { byte as char } else { '.' };
+                write!(f, "{}", ch)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a MAC address.
+struct Mac<'a>(&'a [u8]);
+
+impl fmt::Display for Mac<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4], b[5])
+    }
+}
@@ -0,0 +1,297 @@
+//! 802.1Q VLAN and 802.1ad QinQ sub-interfaces of a single queue device.
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use ixy::IxyDevice;
+use ixy::memory::Packet as IxyPacket;
+
+use crate::checksum::read_u16;
+use crate::frame::{ETHERTYPE_QINQ, ETHERTYPE_VLAN};
+use crate::offload::{insert_tag, strip_tag};
+use crate::{Link, Phy, Queues};
+
+/// The tags of the frames of a VLAN sub-interface.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VlanId {
+    /// Frames with a customer tag, of 802.1Q, 0 for the native VLAN of untagged frames.
+    Customer(u16),
+
+    /// Frames with a service tag, of 802.1ad, outside of a customer tag. The customer 0 are the
+    /// frames with only the service tag.
+    Stacked { service: u16, customer: u16 },
+}
+
+/// Counters of a VLAN sub-interface.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VlanStats {
+    /// Frames received on the VLAN.
+    pub rx_packets: u64,
+
+    /// Frames of the VLAN dropped since its ring was full.
+    pub rx_dropped: u64,
+
+    /// Frames sent on the VLAN.
+    pub tx_packets: u64,
+
+    /// Frames which did not fit a buffer once tagged.
+    pub tx_dropped: u64,
+}
+
+/// One VLAN of a trunk device, the queue of a phy.
+///
+/// The phys of `split` are the sub-interfaces of the VLANs, each a network device of its own with
+/// the MAC address of the trunk. Frames they send are tagged with their VLAN identifier and
+/// received frames are handed to the sub-interface of their tag, with the tag removed. The VLAN 0
+/// is the native VLAN, its frames are sent untagged and it receives the untagged and
+/// priority-tagged frames. Frames of other VLANs are dropped.
+///
+/// With `split_stacked` the sub-interfaces can also be the customer VLANs of service VLANs, as
+/// on the customer side of a provider bridge. Their frames carry a service tag outside of the
+/// customer tag, and both are removed on receipt.
+///
+/// The tags are inserted and removed in software, the VLAN offloads of the device are not used
+/// and its other offloads are not offered to the sub-interfaces. Tagged frames are 4 bytes longer,
+/// the device must accept them at the MTU of the sub-interfaces.
+pub struct Vlan<D> {
+    inner: Rc<RefCell<Trunk<D>>>,
+    vlan: usize,
+}
+
+struct Trunk<D> {
+    device: D,
+    mac: [u8; 6],
+    vlans: Vec<Interface>,
+    /// Tagged frames waiting for the device.
+    pending: VecDeque<IxyPacket>,
+    ring_size: usize,
+    /// Received frames of VLANs without a sub-interface.
+    unknown: u64,
+}
+
+struct Interface {
+    id: VlanId,
+    ring: VecDeque<IxyPacket>,
+    stats: VlanStats,
+}
+
+impl<D: IxyDevice> Vlan<D> {
+    /// Create one phy for each of the VLANs with the given identifiers.
+    ///
+    /// All phys allocate packets for sending from the receive pool of the device.
+    ///
+    /// ## Panics
+    /// This function panics if no VLAN is given, an identifier does not fit into 12 bits or is
+    /// given twice, or the device has no receive pool.
+    pub fn split(device: D, vids: &[u16]) -> Vec<Phy<Self>> {
+        let ids = vids.iter().map(|&vid| VlanId::Customer(vid)).collect::<Vec<_>>();
+        Self::split_stacked(device, &ids)
+    }
+
+    /// Create one phy for each of the VLANs with the given tags.
+    ///
+    /// All phys allocate packets for sending from the receive pool of the device.
+    ///
+    /// ## Panics
+    /// This function panics if no VLAN is given, an identifier does not fit into 12 bits or the
+    /// same tags are given twice, or the device has no receive pool.
+    pub fn split_stacked(device: D, ids: &[VlanId]) -> Vec<Phy<Self>> {
+        assert!(!ids.is_empty(), "Need at least one VLAN");
+        let valid = ids.iter().all(|&id| match id {
+            VlanId::Customer(vid) => vid < 4095,
+            VlanId::Stacked { service, customer } => service < 4095 && customer < 4095,
+        });
+        assert!(valid, "VLAN identifiers have 12 bits");
+        let unique = ids.iter().enumerate().all(|(index, id)| !ids[..index].contains(id));
+        assert!(unique, "VLAN identifiers must be unique");
+        let pool = device
+            .recv_pool(0)
+            .expect("No receive pool for the queue")
+            .clone();
+        let inner = Rc::new(RefCell::new(Trunk {
+            mac: device.get_mac_addr(),
+            device,
+            vlans: ids.iter().map(|&id| Interface::new(id)).collect(),
+            pending: VecDeque::new(),
+            ring_size: Self::RING_SIZE,
+            unknown: 0,
+        }));
+
+        (0..ids.len())
+            .map(|vlan| {
+                let sub = Vlan { inner: inner.clone(), vlan };
+                let mut phy = Phy::new(sub, pool.clone());
+                phy.queue = vlan as u32;
+                phy
+            })
+            .collect()
+    }
+}
+
+impl<D> Vlan<D> {
+    /// The default number of frames waiting per VLAN and for the device.
+    pub const RING_SIZE: usize = 1024;
+
+    /// The customer VLAN identifier of the sub-interface, 0 for the native VLAN.
+    pub fn vid(&self) -> u16 {
+        match self.id() {
+            VlanId::Customer(vid) | VlanId::Stacked { customer: vid, .. } => vid,
+        }
+    }
+
+    /// The tags of the sub-interface.
+    pub fn id(&self) -> VlanId {
+        self.inner.borrow().vlans[self.vlan].id
+    }
+
+    pub fn stats(&self) -> VlanStats {
+        self.inner.borrow().vlans[self.vlan].stats
+    }
+
+    /// The received frames of VLANs without a sub-interface, shared by all sub-interfaces.
+    pub fn unknown(&self) -> u64 {
+        self.inner.borrow().unknown
+    }
+}
+
+impl<D: IxyDevice> Trunk<D> {
+    /// Remove the tags of a received frame, returning its VLAN and the frame.
+    fn untag(&mut self, mut packet: IxyPacket) -> Option<(usize, IxyPacket)> {
+        let service = match tpid(&packet) {
+            Some(ETHERTYPE_QINQ) => Some(strip_tag(&mut packet)?.1 & 0x0fff),
+            _ => None,
+        };
+        let customer = match tpid(&packet) {
+            Some(ETHERTYPE_VLAN) => strip_tag(&mut packet)?.1 & 0x0fff,
+            _ => 0,
+        };
+        let id = match service {
+            Some(service) => VlanId::Stacked { service, customer },
+            None => VlanId::Customer(customer),
+        };
+
+        match self.vlans.iter().position(|vlan| vlan.id == id) {
+            Some(index) => {
+                self.vlans[index].stats.rx_packets += 1;
+                Some((index, packet))
+            },
+            None => {
+                self.unknown += 1;
+                None
+            },
+        }
+    }
+
+    /// Tag a frame of a VLAN and queue it for the device.
+    fn tag(&mut self, index: usize, mut packet: IxyPacket) {
+        let vlan = &mut self.vlans[index];
+        let (service, customer) = match vlan.id {
+            VlanId::Customer(customer) => (None, customer),
+            VlanId::Stacked { service, customer } => (Some(service), customer),
+        };
+        let tagged = (customer == 0 || insert_tag(&mut packet, ETHERTYPE_VLAN, customer))
+            && service.map_or(true, |service| insert_tag(&mut packet, ETHERTYPE_QINQ, service));
+        if !tagged {
+            vlan.stats.tx_dropped += 1;
+            return;
+        }
+        vlan.stats.tx_packets += 1;
+        self.pending.push_back(packet);
+    }
+
+    /// Hand the waiting frames to the device.
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.device.tx_batch(0, &mut self.pending);
+        }
+    }
+}
+
+impl Interface {
+    fn new(id: VlanId) -> Self {
+        Interface {
+            id,
+            ring: VecDeque::new(),
+            stats: VlanStats::default(),
+        }
+    }
+}
+
+/// The protocol identifier of the outermost tag of a frame, if it is tagged.
+fn tpid(frame: &[u8]) -> Option<u16> {
+    if frame.len() < 18 {
+        return None;
+    }
+    match read_u16(frame, 12) {
+        tpid @ ETHERTYPE_VLAN | tpid @ ETHERTYPE_QINQ => Some(tpid),
+        _ => None,
+    }
+}
+
+impl<D: IxyDevice> Queues for Vlan<D> {
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut inner = self.inner.borrow_mut();
+        let trunk = &mut *inner;
+        let queue = queue as usize;
+
+        let own = &mut trunk.vlans[queue].ring;
+        let waiting = own.len().min(num_packets);
+        buffer.extend(own.drain(..waiting));
+        if waiting == num_packets {
+            return waiting;
+        }
+
+        let mut received = VecDeque::with_capacity(num_packets);
+        trunk.device.rx_batch(0, &mut received, num_packets);
+
+        let mut count = waiting;
+        for packet in received {
+            let (index, frame) = match trunk.untag(packet) {
+                Some(untagged) => untagged,
+                None => continue,
+            };
+            let vlan = &mut trunk.vlans[index];
+            if index == queue && count < num_packets {
+                buffer.push_back(frame);
+                count += 1;
+            } else if vlan.ring.len() < trunk.ring_size {
+                vlan.ring.push_back(frame);
+            } else {
+                vlan.stats.rx_dropped += 1;
+            }
+        }
+        count
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let mut inner = self.inner.borrow_mut();
+        let mut taken = 0;
+        while inner.pending.len() < inner.ring_size {
+            match buffer.pop_front() {
+                Some(packet) => inner.tag(queue as usize, packet),
+                None => break,
+            }
+            taken += 1;
+        }
+        inner.flush();
+        taken
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.inner.borrow().mac
+    }
+
+    fn link(&self) -> Link {
+        Queues::link(&self.inner.borrow().device)
+    }
+
+    /// Includes the tagged frames waiting for the device, and sends them.
+    fn tx_in_flight(&mut self, _: u32) -> Option<usize> {
+        let mut inner = self.inner.borrow_mut();
+        inner.flush();
+        let in_flight = Queues::tx_in_flight(&mut inner.device, 0)?;
+        Some(in_flight + inner.pending.len())
+    }
+}
@@ -0,0 +1,441 @@
+//! VXLAN segments over a single queue device.
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use ixy::IxyDevice;
+use ixy::memory::{self, Packet as IxyPacket};
+
+use crate::checksum::read_u16;
+use crate::frame::{self, Arp, Headers, ARP_REQUEST, ETHERNET_HEADER, ETHERTYPE_IPV4, ipv4};
+use crate::frame::{PROTO_UDP, UDP_IPV4_HEADERS};
+use crate::rss::flow_hash;
+use crate::{Link, Phy, Queues, ipv4_multicast};
+
+/// Counters of a segment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VxlanStats {
+    /// Frames decapsulated for the segment.
+    pub rx_packets: u64,
+
+    /// Frames of the segment dropped since its ring was full.
+    pub rx_dropped: u64,
+
+    /// Frames sent to a learned endpoint.
+    pub tx_packets: u64,
+
+    /// Frames sent to the group and all remotes since their endpoint was unknown.
+    pub flooded: u64,
+
+    /// Frames which did not fit a buffer once encapsulated, or could not be flooded anywhere.
+    pub tx_dropped: u64,
+}
+
+/// One VXLAN segment of a device, the queue of a phy.
+///
+/// The phys of `split` are the inner interfaces of the segments. Frames they send are wrapped in
+/// UDP datagrams from the local address and sent via the router with address `next_hop`. Like a
+/// VXLAN tunnel endpoint, the remote endpoint of each inner address is learned from received
+/// frames. Frames to other addresses, broadcast and multicast are flooded to the multicast group
+/// of the segment, if any, and replicated to each of its configured remotes. Learned endpoints
+/// expire after the aging time.
+///
+/// On the outside, ARP requests for the local address are answered and all frames other than
+/// datagrams to the VXLAN port of the local address or a group are dropped. The ixy drivers enable
+/// promiscuous mode, so frames to a group are received without joining it, but a router must
+/// still be told to forward the group, e.g. by a static route.
+///
+/// Encapsulation adds `OVERHEAD` bytes to each frame, either the underlay supports correspondingly
+/// larger frames or the inner stacks use a smaller MTU. Frames which no longer fit a buffer are
+/// dropped. Offloads of the device are not offered to the inner stacks.
+pub struct Vxlan<D> {
+    inner: Rc<RefCell<Overlay<D>>>,
+    segment: usize,
+}
+
+struct Overlay<D> {
+    device: D,
+    mac: [u8; 6],
+    local: Ipv4Addr,
+    next_hop: [u8; 6],
+    segments: Vec<Segment>,
+    /// Encapsulated frames and ARP replies waiting for the device.
+    pending: VecDeque<IxyPacket>,
+    ring_size: usize,
+    aging: Duration,
+}
+
+struct Segment {
+    vni: u32,
+    group: Option<Ipv4Addr>,
+    remotes: Vec<Ipv4Addr>,
+    learned: HashMap<[u8; 6], Learned>,
+    ring: VecDeque<IxyPacket>,
+    stats: VxlanStats,
+}
+
+#[derive(Clone, Copy)]
+struct Learned {
+    remote: Ipv4Addr,
+    seen: Instant,
+}
+
+/// The maximum number of learned addresses per segment.
+const LEARNED: usize = 4096;
+
+impl<D: IxyDevice> Vxlan<D> {
+    /// Create one phy for each of the segments with the given network identifiers.
+    ///
+    /// All phys allocate packets for sending from the receive pool of the device.
+    ///
+    /// ## Panics
+    /// This function panics if no segment is given, an identifier does not fit into 24 bits, or
+    /// the device has no receive pool.
+    pub fn split(device: D, local: Ipv4Addr, next_hop: [u8; 6], vnis: &[u32]) -> Vec<Phy<Self>> {
+        assert!(!vnis.is_empty(), "Need at least one segment");
+        assert!(vnis.iter().all(|&vni| vni < 1 << 24), "Network identifiers have 24 bits");
+        let pool = device
+            .recv_pool(0)
+            .expect("No receive pool for the queue")
+            .clone();
+        let inner = Rc::new(RefCell::new(Overlay {
+            mac: device.get_mac_addr(),
+            device,
+            local,
+            next_hop,
+            segments: vnis.iter().map(|&vni| Segment::new(vni)).collect(),
+            pending: VecDeque::new(),
+            ring_size: Self::RING_SIZE,
+            aging: Self::AGING,
+        }));
+
+        (0..vnis.len())
+            .map(|segment| {
+                let vxlan = Vxlan { inner: inner.clone(), segment };
+                let mut phy = Phy::new(vxlan, pool.clone());
+                phy.queue = segment as u32;
+                phy
+            })
+            .collect()
+    }
+}
+
+impl<D> Vxlan<D> {
+    /// The UDP port assigned to VXLAN.
+    pub const PORT: u16 = 4789;
+
+    /// The outer Ethernet, IPv4, UDP and VXLAN headers added to each frame.
+    pub const OVERHEAD: usize = UDP_IPV4_HEADERS + 8;
+
+    /// The default number of frames waiting per segment and for the device.
+    pub const RING_SIZE: usize = 1024;
+
+    /// The default time after which learned endpoints expire.
+    pub const AGING: Duration = Duration::from_secs(300);
+
+    /// The network identifier of the segment.
+    pub fn vni(&self) -> u32 {
+        self.inner.borrow().segments[self.segment].vni
+    }
+
+    /// The multicast group to which frames of the segment are flooded.
+    pub fn group(&self) -> Option<Ipv4Addr> {
+        self.inner.borrow().segments[self.segment].group
+    }
+
+    /// Flood to a multicast group, or not at all with `None`.
+    ///
+    /// Datagrams to the group are received for this segment.
+    ///
+    /// ## Panics
+    /// This function panics if the address is not a multicast group.
+    pub fn set_group(&self, group: Option<Ipv4Addr>) {
+        assert!(group.map_or(true, |group| group.is_multicast()), "Not a multicast group");
+        self.inner.borrow_mut().segments[self.segment].group = group;
+    }
+
+    /// The endpoints to which frames are replicated when flooding.
+    pub fn remotes(&self) -> Vec<Ipv4Addr> {
+        self.inner.borrow().segments[self.segment].remotes.clone()
+    }
+
+    /// Replicate flooded frames to an endpoint, for underlays without multicast.
+    pub fn add_remote(&self, remote: Ipv4Addr) {
+        let remotes = &mut self.inner.borrow_mut().segments[self.segment].remotes;
+        if !remotes.contains(&remote) {
+            remotes.push(remote);
+        }
+    }
+
+    pub fn remove_remote(&self, remote: Ipv4Addr) {
+        let remotes = &mut self.inner.borrow_mut().segments[self.segment].remotes;
+        remotes.retain(|&other| other != remote);
+    }
+
+    /// The inner addresses of the segment and their learned endpoints.
+    pub fn learned(&self) -> Vec<([u8; 6], Ipv4Addr)> {
+        let inner = self.inner.borrow();
+        let aging = inner.aging;
+        inner.segments[self.segment].learned
+            .iter()
+            .filter(|(_, learned)| learned.seen.elapsed() < aging)
+            .map(|(&mac, learned)| (mac, learned.remote))
+            .collect()
+    }
+
+    /// Forget all learned endpoints of the segment.
+    pub fn clear_learned(&self) {
+        self.inner.borrow_mut().segments[self.segment].learned.clear();
+    }
+
+    pub fn aging(&self) -> Duration {
+        self.inner.borrow().aging
+    }
+
+    /// Change after how long learned endpoints expire, for all segments.
+    pub fn set_aging(&self, aging: Duration) {
+        self.inner.borrow_mut().aging = aging;
+    }
+
+    pub fn stats(&self) -> VxlanStats {
+        self.inner.borrow().segments[self.segment].stats
+    }
+}
+
+impl<D: IxyDevice> Overlay<D> {
+    /// Unwrap a received datagram, returning the segment and the inner frame.
+    fn decapsulate(&mut self, mut packet: IxyPacket) -> Option<(usize, IxyPacket)> {
+        if let Some(arp) = Arp::parse(&packet) {
+            let reply = arp.reply(self.mac);
+            let request = arp.operation == ARP_REQUEST && arp.target_ip == self.local;
+            if request && packet.try_resize(reply.len(), 0u8).is_ok() {
+                packet.copy_from_slice(&reply);
+                self.pending.push_back(packet);
+            }
+            return None;
+        }
+
+        let headers = Headers::parse(&packet)?;
+        if headers.ethertype != ETHERTYPE_IPV4 || headers.vlan.is_some() {
+            return None;
+        }
+
+        let udp = headers.transport(PROTO_UDP)?;
+        let start = udp + 16;
+        if headers.end < start + ETHERNET_HEADER {
+            return None;
+        }
+        if read_u16(&packet, udp + 2) != Vxlan::<D>::PORT {
+            return None;
+        }
+
+        // Only the flag of a valid identifier is defined.
+        let header = &packet[udp + 8..start];
+        if header[0] & 0x08 == 0 {
+            return None;
+        }
+        let vni = u32::from_be_bytes([0, header[4], header[5], header[6]]);
+
+        let ip = &packet[headers.l3..];
+        let source = ipv4(&ip[12..16]);
+        let destination = ipv4(&ip[16..20]);
+        let local = self.local;
+        let index = self.segments.iter().position(|segment| {
+            segment.vni == vni && (destination == local || segment.group == Some(destination))
+        })?;
+
+        let aging = self.aging;
+        let segment = &mut self.segments[index];
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&packet[start + 6..start + 12]);
+        if mac[0] & 1 == 0 {
+            segment.learn(mac, source, aging);
+        }
+
+        packet.copy_within(start..headers.end, 0);
+        // Shrinking within the mempool entry can not fail.
+        let _ = packet.try_resize(headers.end - start, 0u8);
+        segment.stats.rx_packets += 1;
+        Some((index, packet))
+    }
+
+    /// Wrap a frame of a segment and queue it for its endpoint, or flood it.
+    fn encapsulate(&mut self, index: usize, mut packet: IxyPacket) {
+        let len = packet.len();
+        let inner = Vxlan::<D>::OVERHEAD;
+        let aging = self.aging;
+        let segment = &mut self.segments[index];
+        if len < ETHERNET_HEADER || packet.try_resize(inner + len, 0u8).is_err() {
+            segment.stats.tx_dropped += 1;
+            return;
+        }
+        packet.copy_within(..len, inner);
+
+        let mut destination = [0; 6];
+        destination.copy_from_slice(&packet[inner..inner + 6]);
+        let remote = match destination[0] & 1 {
+            0 => segment.lookup(destination, aging),
+            _ => None,
+        };
+
+        if let Some(remote) = remote {
+            segment.stats.tx_packets += 1;
+            self.frame(index, remote, &mut packet);
+            self.pending.push_back(packet);
+            return;
+        }
+
+        let targets: Vec<_> = segment.group.iter().chain(segment.remotes.iter()).copied().collect();
+        let (last, others) = match targets.split_last() {
+            Some(split) => split,
+            None => {
+                segment.stats.tx_dropped += 1;
+                return;
+            },
+        };
+
+        segment.stats.flooded += 1;
+        for &remote in others {
+            match memory::alloc_pkt(packet.get_pool(), packet.len()) {
+                Some(mut copy) => {
+                    copy.copy_from_slice(&packet);
+                    self.frame(index, remote, &mut copy);
+                    self.pending.push_back(copy);
+                },
+                None => self.segments[index].stats.tx_dropped += 1,
+            }
+        }
+        self.frame(index, *last, &mut packet);
+        self.pending.push_back(packet);
+    }
+
+    /// Write the outer headers of an encapsulated frame to an endpoint or group.
+    fn frame(&self, index: usize, remote: Ipv4Addr, packet: &mut [u8]) {
+        let next_hop = if remote.is_multicast() {
+            ipv4_multicast(remote)
+        } else {
+            self.next_hop
+        };
+        // Spread flows over the paths of the underlay, as recommended for VXLAN.
+        let port = flow_hash(&packet[Vxlan::<D>::OVERHEAD..]).map_or(0, |hash| hash % 16384);
+        let source = SocketAddrV4::new(self.local, 49152 + port as u16);
+        let destination = SocketAddrV4::new(remote, Vxlan::<D>::PORT);
+        frame::write_udp_ipv4(packet, self.mac, next_hop, source, destination);
+
+        let vni = self.segments[index].vni.to_be_bytes();
+        let header = [0x08, 0, 0, 0, vni[1], vni[2], vni[3], 0];
+        packet[UDP_IPV4_HEADERS..Vxlan::<D>::OVERHEAD].copy_from_slice(&header);
+    }
+
+    /// Hand the waiting frames to the device.
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.device.tx_batch(0, &mut self.pending);
+        }
+    }
+}
+
+impl Segment {
+    fn new(vni: u32) -> Self {
+        Segment {
+            vni,
+            group: None,
+            remotes: Vec::new(),
+            learned: HashMap::new(),
+            ring: VecDeque::new(),
+            stats: VxlanStats::default(),
+        }
+    }
+
+    fn learn(&mut self, mac: [u8; 6], remote: Ipv4Addr, aging: Duration) {
+        if self.learned.len() >= LEARNED && !self.learned.contains_key(&mac) {
+            self.learned.retain(|_, learned| learned.seen.elapsed() < aging);
+            if self.learned.len() >= LEARNED {
+                return;
+            }
+        }
+        self.learned.insert(mac, Learned { remote, seen: Instant::now() });
+    }
+
+    fn lookup(&mut self, mac: [u8; 6], aging: Duration) -> Option<Ipv4Addr> {
+        let learned = *self.learned.get(&mac)?;
+        if learned.seen.elapsed() >= aging {
+            self.learned.remove(&mac);
+            return None;
+        }
+        Some(learned.remote)
+    }
+}
+
+impl<D: IxyDevice> Queues for Vxlan<D> {
+    fn rx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>, num_packets: usize)
+        -> usize
+    {
+        let mut inner = self.inner.borrow_mut();
+        let overlay = &mut *inner;
+        let queue = queue as usize;
+
+        let own = &mut overlay.segments[queue].ring;
+        let waiting = own.len().min(num_packets);
+        buffer.extend(own.drain(..waiting));
+        if waiting == num_packets {
+            return waiting;
+        }
+
+        let mut received = VecDeque::with_capacity(num_packets);
+        overlay.device.rx_batch(0, &mut received, num_packets);
+
+        let mut count = waiting;
+        for packet in received {
+            let (index, frame) = match overlay.decapsulate(packet) {
+                Some(decapsulated) => decapsulated,
+                None => continue,
+            };
+            let segment = &mut overlay.segments[index];
+            if index == queue && count < num_packets {
+                buffer.push_back(frame);
+                count += 1;
+            } else if segment.ring.len() < overlay.ring_size {
+                segment.ring.push_back(frame);
+            } else {
+                segment.stats.rx_dropped += 1;
+            }
+        }
+
+        // Send the ARP replies.
+        overlay.flush();
+        count
+    }
+
+    fn tx_batch(&mut self, queue: u32, buffer: &mut VecDeque<IxyPacket>) -> usize {
+        let mut inner = self.inner.borrow_mut();
+        let mut taken = 0;
+        while inner.pending.len() < inner.ring_size {
+            match buffer.pop_front() {
+                Some(packet) => inner.encapsulate(queue as usize, packet),
+                None => break,
+            }
+            taken += 1;
+        }
+        inner.flush();
+        taken
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.inner.borrow().mac
+    }
+
+    fn link(&self) -> Link {
+        Queues::link(&self.inner.borrow().device)
+    }
+
+    /// Includes the encapsulated frames waiting for the device, and sends them.
+    fn tx_in_flight(&mut self, _: u32) -> Option<usize> {
+        let mut inner = self.inner.borrow_mut();
+        inner.flush();
+        let in_flight = Queues::tx_in_flight(&mut inner.device, 0)?;
+        Some(in_flight + inner.pending.len())
+    }
+}
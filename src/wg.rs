@@ -0,0 +1,506 @@
+//! A WireGuard tunnel in front of a phy, enabled by the `wireguard` feature.
+use std::collections::VecDeque;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use boringtun::noise::{Tunn, TunnResult};
+use ethox::layer::Result as NicResult;
+use ethox::nic;
+use ixy::memory::{self, Mempool, Packet as IxyPacket};
+
+use crate::checksum::{read_u16, write_u16};
+use crate::frame::{self, Arp, Headers, ARP_REQUEST, ETHERNET_HEADER, ETHERTYPE_IPV4, ipv4};
+use crate::frame::{ETHERTYPE_IPV6, PROTO_UDP, UDP_IPV4_HEADERS};
+use crate::{Handle, Packet, Phy, Prefix, Queues};
+
+/// Counters of a tunnel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct WgStats {
+    /// Packets decrypted for the inner stack.
+    pub rx_packets: u64,
+
+    /// Datagrams of the tunnel which were rejected, came from outside the allowed IPs, or could
+    /// not be buffered.
+    pub rx_dropped: u64,
+
+    /// Packets of the inner stack sent encrypted.
+    pub tx_packets: u64,
+
+    /// Packets of the inner stack which were not IP or did not fit a buffer once encrypted.
+    pub tx_dropped: u64,
+
+    /// Handshake, cookie and keepalive messages sent.
+    pub control: u64,
+}
+
+/// A WireGuard tunnel between an inner network stack and a phy.
+///
+/// The inner stack uses the tunnel as its device and sends and receives Ethernet frames as usual.
+/// Their IP packets are encrypted into UDP datagrams to the endpoint of the peer, sent through the
+/// next hop, and datagrams from the peer are decrypted for the inner stack. The tunnel is point
+/// to point: ARP requests of the inner stack are answered with `PEER_MAC` for every address, so
+/// route everything through it. On the outside, ARP requests for the local address are answered
+/// and all other frames are dropped. Like WireGuard, the endpoint follows the peer when it sends
+/// authenticated data from a new address.
+///
+/// Decrypted packets are only offered to the inner stack when their source is in the allowed IPs
+/// of the peer, all addresses unless restricted with `set_allowed_ips`.
+///
+/// Encryption grows each packet by up to 75 bytes, the inner Ethernet header is replaced by the
+/// outer headers and the payload is padded. Configure the inner stack for frames correspondingly
+/// smaller than the buffers of the phy, larger ones are dropped. Offloads of the device are not
+/// offered to the inner stack.
+///
+/// The handshake and keepalive timers run during calls to `rx`, poll it regularly even when no
+/// traffic is expected.
+pub struct Wg<D> {
+    phy: Phy<D>,
+    tunn: Tunn,
+    local: SocketAddrV4,
+    endpoint: SocketAddrV4,
+    next_hop: [u8; 6],
+    /// The sources the peer may send from inside the tunnel.
+    allowed_ips: Vec<Prefix>,
+    /// Decrypted frames and ARP replies for the inner stack.
+    inner_rx: VecDeque<IxyPacket>,
+    scratch: Vec<u8>,
+    last_timers: Instant,
+    stats: WgStats,
+}
+
+/// The outer addressing of encrypted datagrams.
+struct Outer {
+    mac: [u8; 6],
+    next_hop: [u8; 6],
+    local: SocketAddrV4,
+    endpoint: SocketAddrV4,
+    pool: Rc<Mempool>,
+}
+
+/// The type of transport data messages.
+const DATA_MESSAGE: u8 = 4;
+
+impl<D: Queues> Wg<D> {
+    /// The address of the peer towards the inner stack, locally administered.
+    pub const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0x77, 0x67];
+
+    /// The period of the handshake and keepalive timers.
+    pub const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+
+    /// Room for the largest message of boringtun, a padded maximum IP packet with its framing.
+    const SCRATCH: usize = 65536 + 32;
+
+    /// Tunnel through a phy.
+    ///
+    /// The datagrams are sent from `local` to `endpoint` via the router with address `next_hop`.
+    /// The session itself, keys and keepalive, is configured in `tunn`.
+    pub fn new(
+        mut phy: Phy<D>,
+        tunn: Tunn,
+        local: SocketAddrV4,
+        endpoint: SocketAddrV4,
+        next_hop: [u8; 6],
+    ) -> Self {
+        phy.set_hardware_offloads(false);
+        Wg {
+            phy,
+            tunn,
+            local,
+            endpoint,
+            next_hop,
+            allowed_ips: vec![
+                Prefix::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+                Prefix::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+            ],
+            inner_rx: VecDeque::new(),
+            scratch: vec![0; Self::SCRATCH],
+            last_timers: Instant::now(),
+            stats: WgStats::default(),
+        }
+    }
+
+    pub fn phy(&self) -> &Phy<D> {
+        &self.phy
+    }
+
+    pub fn phy_mut(&mut self) -> &mut Phy<D> {
+        &mut self.phy
+    }
+
+    pub fn tunn(&self) -> &Tunn {
+        &self.tunn
+    }
+
+    pub fn tunn_mut(&mut self) -> &mut Tunn {
+        &mut self.tunn
+    }
+
+    /// The current endpoint of the peer.
+    pub fn endpoint(&self) -> SocketAddrV4 {
+        self.endpoint
+    }
+
+    pub fn set_endpoint(&mut self, endpoint: SocketAddrV4) {
+        self.endpoint = endpoint;
+    }
+
+    /// The sources the peer may send from inside the tunnel.
+    pub fn allowed_ips(&self) -> &[Prefix] {
+        &self.allowed_ips
+    }
+
+    /// Restrict the sources of the decrypted packets, others are dropped.
+    pub fn set_allowed_ips(&mut self, allowed_ips: impl IntoIterator<Item=Prefix>) {
+        self.allowed_ips = allowed_ips.into_iter().collect();
+    }
+
+    pub fn stats(&self) -> WgStats {
+        self.stats
+    }
+
+    /// Unwrap the phy, offering its offloads again, and the session.
+    pub fn into_inner(self) -> (Phy<D>, Tunn) {
+        let mut phy = self.phy;
+        phy.set_hardware_offloads(true);
+        (phy, self.tunn)
+    }
+
+    /// Run the timers of the session if their period elapsed.
+    fn update_timers(&mut self) {
+        if self.last_timers.elapsed() < Self::TIMER_INTERVAL {
+            return;
+        }
+        self.last_timers = Instant::now();
+
+        let outer = self.outer();
+        if let TunnResult::WriteToNetwork(message) = self.tunn.update_timers(&mut self.scratch) {
+            if outer.queue(message, &mut self.phy.tx_queue) {
+                self.stats.control += 1;
+            }
+        }
+    }
+
+    /// Receive the datagrams of the tunnel, decrypting them for the inner stack.
+    fn receive(&mut self, max: usize) {
+        self.phy.get_rx(max);
+        let received = std::mem::replace(&mut self.phy.rx_queue, VecDeque::new());
+        for packet in received {
+            if let Some(arp) = Arp::parse(&packet) {
+                if arp.operation == ARP_REQUEST && arp.target_ip == *self.local.ip() {
+                    let mac = self.phy.mac_address();
+                    self.phy.send_frame(&arp.reply(mac));
+                }
+                continue;
+            }
+
+            if let Some((source, payload)) = self.datagram(&packet) {
+                self.decapsulate(source, &packet[payload]);
+            }
+        }
+    }
+
+    /// The source and payload of a datagram for the local endpoint.
+    fn datagram(&self, packet: &[u8]) -> Option<(SocketAddrV4, std::ops::Range<usize>)> {
+        let headers = Headers::parse(packet)?;
+        if headers.ethertype != ETHERTYPE_IPV4 || headers.vlan.is_some() {
+            return None;
+        }
+
+        let udp = headers.transport(PROTO_UDP)?;
+        if headers.end < udp + 8 {
+            return None;
+        }
+
+        let ip = &packet[headers.l3..];
+        let destination = SocketAddrV4::new(ipv4(&ip[16..20]), read_u16(packet, udp + 2));
+        if destination != self.local {
+            return None;
+        }
+
+        let source = SocketAddrV4::new(ipv4(&ip[12..16]), read_u16(packet, udp));
+        Some((source, udp + 8..headers.end))
+    }
+
+    fn decapsulate(&mut self, source: SocketAddrV4, mut datagram: &[u8]) {
+        let outer = self.outer();
+        let mac = self.phy.mac_address();
+        let peer = Some(IpAddr::V4(*source.ip()));
+        let Wg { phy, tunn, scratch, inner_rx, stats, endpoint, allowed_ips, .. } = self;
+        loop {
+            let (ethertype, len, inner) = match tunn.decapsulate(peer, datagram, scratch) {
+                TunnResult::WriteToNetwork(message) => {
+                    if outer.queue(message, &mut phy.tx_queue) {
+                        stats.control += 1;
+                    }
+                    // Send the packets which waited for the handshake, until none remain.
+                    datagram = &[];
+                    continue;
+                },
+                TunnResult::WriteToTunnelV4(packet, source) => {
+                    (ETHERTYPE_IPV4, packet.len(), IpAddr::V4(source))
+                },
+                TunnResult::WriteToTunnelV6(packet, source) => {
+                    (ETHERTYPE_IPV6, packet.len(), IpAddr::V6(source))
+                },
+                TunnResult::Done => return,
+                TunnResult::Err(_) => {
+                    stats.rx_dropped += 1;
+                    return;
+                },
+            };
+
+            // Only authenticated data moves the endpoint, even when its inner source is refused.
+            *endpoint = source;
+            if !allowed_ips.iter().any(|prefix| prefix.contains(inner)) {
+                stats.rx_dropped += 1;
+                return;
+            }
+
+            let macs = (mac, Self::PEER_MAC);
+            match frame_packet(&phy.pool, macs, ethertype, &scratch[..len]) {
+                Some(frame) => {
+                    inner_rx.push_back(frame);
+                    stats.rx_packets += 1;
+                },
+                None => stats.rx_dropped += 1,
+            }
+            return;
+        }
+    }
+
+    /// Encrypt the frames the inner stack queued after the first `start`.
+    fn transmit(&mut self, start: usize) {
+        let outer = self.outer();
+        let plain = self.phy.tx_queue.split_off(start);
+        for mut packet in plain {
+            if let Some(arp) = Arp::parse(&packet) {
+                let reply = arp.reply(Self::PEER_MAC);
+                if arp.operation == ARP_REQUEST && packet.try_resize(reply.len(), 0u8).is_ok() {
+                    packet.copy_from_slice(&reply);
+                    self.inner_rx.push_back(packet);
+                }
+                continue;
+            }
+
+            let end = match Headers::parse(&packet) {
+                Some(headers) if headers.vlan.is_none() && is_ip(headers.ethertype) => headers.end,
+                _ => {
+                    self.stats.tx_dropped += 1;
+                    continue;
+                },
+            };
+
+            match self.tunn.encapsulate(&packet[ETHERNET_HEADER..end], &mut self.scratch) {
+                TunnResult::WriteToNetwork(message) => {
+                    // Without a session, the packet waits and a handshake is sent instead.
+                    let data = message[0] == DATA_MESSAGE;
+                    if outer.write(message, &mut packet) {
+                        self.phy.tx_queue.push_back(packet);
+                        if data {
+                            self.stats.tx_packets += 1;
+                        } else {
+                            self.stats.control += 1;
+                        }
+                    } else {
+                        self.stats.tx_dropped += 1;
+                    }
+                },
+                // Queued until the handshake completes.
+                TunnResult::Done => (),
+                _ => self.stats.tx_dropped += 1,
+            }
+        }
+    }
+
+    /// Run an operation of the inner stack, then encrypt what it queued before sending.
+    fn deferred<T>(&mut self, op: impl FnOnce(&mut Phy<D>) -> T) -> T {
+        let (result, start) = self.phy.deferred(op);
+        self.transmit(start);
+        self.phy.poll_flush();
+        result
+    }
+
+    fn outer(&self) -> Outer {
+        Outer {
+            mac: self.phy.mac_address(),
+            next_hop: self.next_hop,
+            local: self.local,
+            endpoint: self.endpoint,
+            pool: self.phy.pool.clone(),
+        }
+    }
+}
+
+impl Outer {
+    /// Queue a message of the session in a new buffer.
+    fn queue(&self, message: &[u8], tx_queue: &mut VecDeque<IxyPacket>) -> bool {
+        match memory::alloc_pkt(&self.pool, UDP_IPV4_HEADERS + message.len()) {
+            Some(mut packet) => {
+                self.frame(message, &mut packet);
+                tx_queue.push_back(packet);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Replace the contents of a packet with a message, if it fits.
+    fn write(&self, message: &[u8], packet: &mut IxyPacket) -> bool {
+        if packet.try_resize(UDP_IPV4_HEADERS + message.len(), 0u8).is_err() {
+            return false;
+        }
+        self.frame(message, packet);
+        true
+    }
+
+    fn frame(&self, message: &[u8], buf: &mut [u8]) {
+        buf[UDP_IPV4_HEADERS..].copy_from_slice(message);
+        frame::write_udp_ipv4(buf, self.mac, self.next_hop, self.local, self.endpoint);
+    }
+}
+
+impl<D: Queues> nic::Device for Wg<D> {
+    type Handle = Handle;
+    type Payload = Packet;
+
+    fn personality(&self) -> nic::Personality {
+        nic::Personality::baseline()
+    }
+
+    fn tx(&mut self, max: usize, sender: impl nic::Send<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.deferred(|phy| phy.tx(max, sender))
+    }
+
+    fn rx(&mut self, max: usize, receptor: impl nic::Recv<Self::Handle, Self::Payload>)
+        -> NicResult<usize>
+    {
+        self.update_timers();
+        self.receive(max);
+        // Offer the inner frames in place of the received ones, the phy fetches no more.
+        let count = max.min(self.inner_rx.len());
+        std::mem::swap(&mut self.phy.rx_queue, &mut self.inner_rx);
+        let (result, start) = self.phy.deferred(|phy| phy.rx(count, receptor));
+        std::mem::swap(&mut self.phy.rx_queue, &mut self.inner_rx);
+        self.transmit(start);
+        self.phy.poll_flush();
+        result
+    }
+}
+
+/// An Ethernet frame between two addresses containing a decrypted packet.
+fn frame_packet(
+    pool: &Rc<Mempool>,
+    (destination, source): ([u8; 6], [u8; 6]),
+    ethertype: u16,
+    packet: &[u8],
+) -> Option<IxyPacket> {
+    let mut frame = memory::alloc_pkt(pool, ETHERNET_HEADER + packet.len())?;
+    frame[0..6].copy_from_slice(&destination);
+    frame[6..12].copy_from_slice(&source);
+    write_u16(&mut frame, 12, ethertype);
+    frame[ETHERNET_HEADER..].copy_from_slice(packet);
+    Some(frame)
+}
+
+fn is_ip(ethertype: u16) -> bool {
+    ethertype == ETHERTYPE_IPV4 || ethertype == ETHERTYPE_IPV6
+}
+
+#[cfg(test)]
+mod tests {
+    use boringtun::x25519::{PublicKey, StaticSecret};
+
+    use super::*;
+    use crate::mock::test_pool;
+    use crate::{FlushPolicy, MockDevice};
+
+    #[test]
+    fn deadline_never_flushes_plaintext() {
+        let pool = test_pool();
+        let mut phy = Phy::new(MockDevice::new(pool.clone()), pool);
+        phy.set_flush_policy(FlushPolicy::Manual);
+        phy.set_flush_deadline(Some(Duration::from_secs(0)));
+
+        let peer = PublicKey::from(&StaticSecret::from([2; 32]));
+        let tunn = Tunn::new(StaticSecret::from([1; 32]), peer, None, None, 0, None);
+        let local = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 51820);
+        let endpoint = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 51820);
+        let mut wg = Wg::new(phy, tunn, local, endpoint, [0x02, 0, 0, 0, 0, 0x09]);
+
+        let mut plain = vec![0; UDP_IPV4_HEADERS + 16];
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1234);
+        let destination = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234);
+        let macs = ([0x02, 0, 0, 0, 0, 0x01], Wg::<MockDevice>::PEER_MAC);
+        frame::write_udp_ipv4(&mut plain, macs.0, macs.1, source, destination);
+
+        wg.deferred(|phy| {
+            assert!(phy.send_frame(&plain));
+            // The deadline has passed, but the frame is not encrypted yet.
+            assert_eq!(phy.poll_flush(), 0);
+        });
+
+        // Without a session the frame waits and a handshake initiation is sent instead.
+        let sent = wg.phy().ixy().transmitted();
+        assert_eq!(sent.len(), 1);
+        assert_ne!(sent[0], plain);
+        assert_eq!(read_u16(&sent[0], 12), ETHERTYPE_IPV4);
+        assert_eq!(sent[0][ETHERNET_HEADER + 9], PROTO_UDP);
+        assert_eq!(read_u16(&sent[0], ETHERNET_HEADER + 22), endpoint.port());
+    }
+    /// A frame carrying a UDP datagram with the payload.
+    fn udp(source: SocketAddrV4, destination: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; UDP_IPV4_HEADERS + payload.len()];
+        let macs = ([0x02, 0, 0, 0, 0, 0x01], [0x02, 0, 0, 0, 0, 0x02]);
+        frame::write_udp_ipv4(&mut frame, macs.0, macs.1, source, destination);
+        frame[UDP_IPV4_HEADERS..].copy_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn refuses_sources_outside_allowed_ips() {
+        let pool = test_pool();
+        let phy = Phy::new(MockDevice::new(pool.clone()), pool);
+        let (secret, peer_secret) = (StaticSecret::from([1; 32]), StaticSecret::from([2; 32]));
+        let public = PublicKey::from(&secret);
+        let tunn = Tunn::new(secret, PublicKey::from(&peer_secret), None, None, 0, None);
+        let mut peer = Tunn::new(peer_secret, public, None, None, 1, None);
+        let local = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 51820);
+        let endpoint = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 2), 51820);
+        let mut wg = Wg::new(phy, tunn, local, endpoint, [0x02, 0, 0, 0, 0, 0x09]);
+        wg.set_allowed_ips(Some(Prefix::new(Ipv4Addr::new(10, 0, 0, 0).into(), 24)));
+
+        // The peer initiates, the tunnel responds and the peer confirms with a keepalive.
+        let mut buffer = vec![0; 2048];
+        let exchange = |wg: &mut Wg<MockDevice>, message: &[u8]| {
+            wg.phy.device.push_rx(&udp(endpoint, local, message));
+            wg.receive(16);
+            wg.phy_mut().flush();
+        };
+        match peer.format_handshake_initiation(&mut buffer, false) {
+            TunnResult::WriteToNetwork(message) => exchange(&mut wg, message),
+            _ => panic!("No handshake initiation"),
+        }
+        let response = wg.phy.device.take_transmitted().remove(0);
+        match peer.decapsulate(None, &response[UDP_IPV4_HEADERS..], &mut buffer) {
+            TunnResult::WriteToNetwork(message) => exchange(&mut wg, message),
+            _ => panic!("No keepalive after the handshake"),
+        }
+
+        let mut plain = vec![0; UDP_IPV4_HEADERS + 16];
+        let destination = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1234);
+        for (source, admitted) in [([10, 0, 1, 1], 0), ([10, 0, 0, 1], 1)] {
+            let source = SocketAddrV4::new(source.into(), 1234);
+            frame::write_udp_ipv4(&mut plain, [0; 6], [0; 6], source, destination);
+            match peer.encapsulate(&plain[ETHERNET_HEADER..], &mut buffer) {
+                TunnResult::WriteToNetwork(message) => exchange(&mut wg, message),
+                _ => panic!("No session for the data"),
+            }
+            assert_eq!(wg.inner_rx.len(), admitted);
+        }
+        assert_eq!(wg.stats().rx_packets, 1);
+        assert_eq!(wg.stats().rx_dropped, 1);
+    }
+}
@@ -0,0 +1,349 @@
+//! A hierarchical timer wheel.
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use ethox::time::Instant;
+
+/// Values which become due at a point in time, with a resolution of one millisecond.
+///
+/// Suited for the many timers of a network stack such as retransmissions, the expiry of neighbor
+/// entries and user timeouts, most of which are cancelled before they are due. Timers are sorted
+/// into levels of 64 slots, a slot of the lowest level spans one millisecond and each further
+/// level spans 64 times as much. Inserting and cancelling take constant time and advancing only
+/// visits occupied slots, timers of higher levels cascade into lower ones as they approach. The
+/// cost of a poll thus does not grow with the number of pending timers.
+pub struct TimerWheel<T> {
+    /// The current time in milliseconds.
+    now: i64,
+    levels: Vec<Level>,
+    /// Timers which are due, in the order they expired. Cancelled timers are left in place and
+    /// skipped when popping, their id no longer matches.
+    ready: VecDeque<(usize, u64)>,
+    /// The number of timers in the ready list which were not cancelled.
+    ready_len: usize,
+    timers: Vec<Option<Timer<T>>>,
+    free: Vec<usize>,
+    next_id: u64,
+    len: usize,
+}
+
+/// Identifies a timer of a wheel.
+///
+/// Stays unique after the timer expired or was cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId {
+    index: usize,
+    id: u64,
+}
+
+struct Level {
+    /// A bit for each slot containing timers.
+    occupied: u64,
+    slots: Vec<Vec<usize>>,
+}
+
+struct Timer<T> {
+    id: u64,
+    due: i64,
+    location: Location,
+    value: T,
+}
+
+#[derive(Clone, Copy)]
+enum Location {
+    Ready,
+    /// The level, the slot and the position in the slot.
+    Slot(usize, usize, usize),
+}
+
+const BITS: u32 = 6;
+const SLOTS: usize = 1 << BITS;
+/// Enough levels for the full range of millisecond timestamps.
+const LEVELS: usize = 11;
+
+impl<T> TimerWheel<T> {
+    pub fn new(now: Instant) -> Self {
+        let levels = (0..LEVELS)
+            .map(|_| Level { occupied: 0, slots: (0..SLOTS).map(|_| Vec::new()).collect() })
+            .collect();
+
+        TimerWheel {
+            now: now.total_millis(),
+            levels,
+            ready: VecDeque::new(),
+            ready_len: 0,
+            timers: Vec::new(),
+            free: Vec::new(),
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    /// The time the wheel was last advanced to.
+    pub fn now(&self) -> Instant {
+        Instant::from_millis(self.now)
+    }
+
+    /// The number of pending timers, including those that are due but not yet popped.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Schedule a value, it is due immediately if the time has already passed.
+    pub fn insert(&mut self, due: Instant, value: T) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let timer = Timer { id, due: due.total_millis(), location: Location::Ready, value };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.timers[index] = Some(timer);
+                index
+            },
+            None => {
+                self.timers.push(Some(timer));
+                self.timers.len() - 1
+            },
+        };
+
+        self.schedule(index);
+        self.len += 1;
+        TimerId { index, id }
+    }
+
+    /// Schedule a value after a delay from the current time of the wheel.
+    pub fn insert_after(&mut self, delay: Duration, value: T) -> TimerId {
+        let due = later(self.now(), delay);
+        self.insert(due, value)
+    }
+
+    /// Whether the timer is still pending.
+    pub fn contains(&self, timer: TimerId) -> bool {
+        self.get(timer).is_some()
+    }
+
+    /// The value of a pending timer.
+    pub fn get(&self, timer: TimerId) -> Option<&T> {
+        match self.timers.get(timer.index) {
+            Some(Some(pending)) if pending.id == timer.id => Some(&pending.value),
+            _ => None,
+        }
+    }
+
+    /// Remove a pending timer, returning its value.
+    pub fn cancel(&mut self, timer: TimerId) -> Option<T> {
+        if !self.contains(timer) {
+            return None;
+        }
+
+        let pending = self.timers[timer.index].take().unwrap();
+        match pending.location {
+            Location::Ready => self.ready_len -= 1,
+            Location::Slot(level, slot, position) => self.unlink(level, slot, position),
+        }
+
+        self.free.push(timer.index);
+        self.len -= 1;
+        Some(pending.value)
+    }
+
+    /// Move the time forward, making all timers due up to then ready.
+    ///
+    /// Earlier instants are ignored, the wheel never goes back in time.
+    pub fn advance(&mut self, now: Instant) {
+        let now = now.total_millis();
+        while let Some((level, slot, start)) = self.next_slot() {
+            if start > now {
+                break;
+            }
+
+            // Cascade the slot relative to its start, to the ready list or a lower level.
+            self.now = start;
+            let indices = std::mem::replace(&mut self.levels[level].slots[slot], Vec::new());
+            self.levels[level].occupied &= !(1 << slot);
+            for index in indices {
+                self.schedule(index);
+            }
+        }
+        self.now = self.now.max(now);
+    }
+
+    /// Take the value of a due timer, in the order they expired.
+    pub fn pop(&mut self) -> Option<T> {
+        while let Some((index, id)) = self.ready.pop_front() {
+            match &self.timers[index] {
+                Some(timer) if timer.id == id => (),
+                _ => continue,
+            }
+
+            let timer = self.timers[index].take().unwrap();
+            self.free.push(index);
+            self.ready_len -= 1;
+            self.len -= 1;
+            return Some(timer.value);
+        }
+        None
+    }
+
+    /// Advance the time and take the value of a due timer.
+    pub fn poll(&mut self, now: Instant) -> Option<T> {
+        self.advance(now);
+        self.pop()
+    }
+
+    /// The earliest time at which a timer may become due, `None` without timers.
+    ///
+    /// Timers on higher levels are only known by the start of their slot, so the result may be
+    /// somewhat earlier than the actual due time but never later. Suited to decide how long a
+    /// loop may sleep.
+    pub fn next_due(&self) -> Option<Instant> {
+        if self.ready_len > 0 {
+            return Some(self.now());
+        }
+        self.next_slot().map(|(_, _, start)| Instant::from_millis(start))
+    }
+
+    fn schedule(&mut self, index: usize) {
+        let timer = self.timers[index].as_mut().unwrap();
+        if timer.due <= self.now {
+            timer.location = Location::Ready;
+            self.ready.push_back((index, timer.id));
+            self.ready_len += 1;
+            return;
+        }
+
+        // The level of the highest bit in which the due time differs from now.
+        let masked = (self.now ^ timer.due) as u64 | (SLOTS as u64 - 1);
+        let level = ((63 - masked.leading_zeros()) / BITS) as usize;
+        let slot = ((timer.due as u64 >> (BITS * level as u32)) as usize) % SLOTS;
+        let indices = &mut self.levels[level].slots[slot];
+        timer.location = Location::Slot(level, slot, indices.len());
+        indices.push(index);
+        self.levels[level].occupied |= 1 << slot;
+    }
+
+    /// Remove the timer at a position of a slot, moving the last timer of the slot in its place.
+    fn unlink(&mut self, level: usize, slot: usize, position: usize) {
+        let indices = &mut self.levels[level].slots[slot];
+        indices.swap_remove(position);
+        if let Some(&moved) = indices.get(position) {
+            let timer = self.timers[moved].as_mut().unwrap();
+            timer.location = Location::Slot(level, slot, position);
+        }
+        if indices.is_empty() {
+            self.levels[level].occupied &= !(1 << slot);
+        }
+    }
+
+    /// The earliest occupied slot, as its level, index and start time.
+    ///
+    /// Timers of a level all lie in the window of the next level containing now, after the window
+    /// of the lower levels, so the lowest occupied level contains the earliest slot.
+    fn next_slot(&self) -> Option<(usize, usize, i64)> {
+        let now = self.now as u64;
+        for (index, level) in self.levels.iter().enumerate() {
+            let shift = BITS * index as u32;
+            let current = ((now >> shift) as usize) % SLOTS;
+            let ahead = level.occupied & (!0u64 << current);
+            if ahead == 0 {
+                continue;
+            }
+
+            let slot = ahead.trailing_zeros() as usize;
+            let window = shift + BITS;
+            let base = if window >= 64 { 0 } else { now & !((1u64 << window) - 1) };
+            return Some((index, slot, (base | ((slot as u64) << shift)) as i64));
+        }
+        None
+    }
+}
+
+/// An instant some time later.
+pub(crate) fn later(instant: Instant, delay: Duration) -> Instant {
+    Instant::from_millis(instant.total_millis() + delay.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_in_order() {
+        let start = Instant::from_millis(1_000);
+        let mut wheel = TimerWheel::new(start);
+        wheel.insert_after(Duration::from_millis(30), 'c');
+        wheel.insert_after(Duration::from_millis(10), 'a');
+        wheel.insert_after(Duration::from_millis(20), 'b');
+
+        assert_eq!(wheel.poll(later(start, Duration::from_millis(9))), None);
+        assert_eq!(wheel.poll(later(start, Duration::from_millis(25))), Some('a'));
+        assert_eq!(wheel.pop(), Some('b'));
+        assert_eq!(wheel.pop(), None);
+        assert_eq!(wheel.poll(later(start, Duration::from_millis(30))), Some('c'));
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn cancels_timers() {
+        let start = Instant::from_millis(0);
+        let mut wheel = TimerWheel::new(start);
+        let first = wheel.insert_after(Duration::from_millis(5), 1);
+        let second = wheel.insert_after(Duration::from_millis(5), 2);
+
+        assert_eq!(wheel.cancel(first), Some(1));
+        assert_eq!(wheel.cancel(first), None);
+        assert!(!wheel.contains(first));
+        // The slot is reused, the old id stays invalid.
+        let third = wheel.insert_after(Duration::from_millis(5), 3);
+        assert_eq!(wheel.get(first), None);
+        assert_eq!(wheel.get(third), Some(&3));
+
+        wheel.advance(later(start, Duration::from_millis(5)));
+        assert_eq!(wheel.cancel(second), Some(2));
+        assert_eq!(wheel.pop(), Some(3));
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn cancels_within_a_slot() {
+        let start = Instant::from_millis(0);
+        let mut wheel = TimerWheel::new(start);
+        let timers: Vec<_> = (0..4)
+            .map(|value| wheel.insert_after(Duration::from_millis(5), value))
+            .collect();
+
+        // Cancelling moves the last timer of the slot, which must stay cancellable.
+        assert_eq!(wheel.cancel(timers[1]), Some(1));
+        assert_eq!(wheel.cancel(timers[3]), Some(3));
+        assert_eq!(wheel.cancel(timers[0]), Some(0));
+        wheel.advance(later(start, Duration::from_millis(5)));
+        assert_eq!(wheel.pop(), Some(2));
+        assert_eq!(wheel.next_due(), None);
+    }
+
+    #[test]
+    fn cascades_from_higher_levels() {
+        let start = Instant::from_millis(123);
+        let mut wheel = TimerWheel::new(start);
+        let delays = [1u64, 63, 64, 65, 4_095, 4_096, 300_000, 86_400_000];
+        for &delay in delays.iter().rev() {
+            wheel.insert_after(Duration::from_millis(delay), delay);
+        }
+
+        let mut expired = Vec::new();
+        while let Some(due) = wheel.next_due() {
+            // The next due time may be early for higher levels, but never late.
+            let next = delays[expired.len()];
+            assert!(due <= later(start, Duration::from_millis(next)));
+            wheel.advance(due);
+            while let Some(delay) = wheel.pop() {
+                assert_eq!(wheel.now(), later(start, Duration::from_millis(delay)));
+                expired.push(delay);
+            }
+        }
+        assert_eq!(expired, delays);
+    }
+}